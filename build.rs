@@ -9,6 +9,7 @@ fn main() {
         .file("src/audio_stream.c")
         .file("src/media.c")
         .file("src/musicd.c")
+        .file("src/waveform.c")
         .compile("libmusicdc.a");
 
     println!("cargo:rustc-link-lib=dylib=pthread");
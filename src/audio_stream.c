@@ -51,8 +51,6 @@ struct AudioStream *audio_stream_open(const struct AudioStreamOptions *options)
     struct AudioStream *self = malloc(sizeof(struct AudioStream));
     memset(self, 0, sizeof(struct AudioStream));
 
-    // TODO track index
-
     result = avformat_open_input(&self->in_ctx, options->path, NULL, NULL);
     if (result < 0) {
         lav_error("avformat_open_input", result);
@@ -94,17 +92,37 @@ struct AudioStream *audio_stream_open(const struct AudioStreamOptions *options)
         self->dec_ctx->channel_layout = av_get_default_channel_layout(self->dec_ctx->channels);
     }
 
-    if (options->start > 0) {
-        int64_t seek_pos = options->start / av_q2d(self->in_stream->time_base);
-        result = av_seek_frame(self->in_ctx, 0, seek_pos, 0);
+    double start = options->start;
+    double length = options->length;
+
+    if (options->track_index > 0) {
+        // Track is a chapter, start and length are relative to it
+        double chapter_start, chapter_length;
+
+        if (!media_chapter_bounds(
+            self->in_ctx, options->track_index, &chapter_start, &chapter_length)) {
+            lav_error("chapter doesn't exist", 0);
+            goto fail;
+        }
+
+        start += chapter_start;
+
+        if (length <= 0 || length > chapter_length - options->start) {
+            length = chapter_length - options->start;
+        }
+    }
+
+    if (start > 0) {
+        int64_t seek_pos = start / av_q2d(self->in_stream->time_base);
+        result = av_seek_frame(self->in_ctx, self->in_stream->index, seek_pos, 0);
         if (result < 0) {
             lav_error("av_seek_frame", result);
             goto fail;
         }
     }
 
-    if (options->length > 0) {
-        self->end_pts = (options->start + options->length) / av_q2d(self->in_stream->time_base);
+    if (length > 0) {
+        self->end_pts = (start + length) / av_q2d(self->in_stream->time_base);
     }
 
     av_dump_format(self->in_ctx, 0, options->path, 0);
@@ -268,13 +286,14 @@ static int demux_decode(struct AudioStream *self, AVPacket *in_packet) {
         return STREAM_AGAIN;
     }
 
-    av_packet_rescale_ts(in_packet, self->in_stream->time_base, self->dec_ctx->time_base);
-
+    // end_pts is in stream time base, compare before rescaling
     if (self->end_pts > 0 && in_packet->pts > self->end_pts) {
         // Reached track end
         goto eof;
     }
 
+    av_packet_rescale_ts(in_packet, self->in_stream->time_base, self->dec_ctx->time_base);
+
     result = avcodec_send_packet(self->dec_ctx, in_packet);
     if (result < 0) {
         lav_error("avcodec_send_packet", result);
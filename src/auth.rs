@@ -0,0 +1,46 @@
+use rand::RngCore;
+
+pub const SESSION_COOKIE: &str = "musicd2-session";
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+pub fn hash_password(password: &str) -> String {
+    argon2::hash_encoded(
+        password.as_bytes(),
+        &random_bytes(16),
+        &argon2::Config::default(),
+    )
+    .expect("can't hash password")
+}
+
+pub fn verify_password(password_hash: &str, password: &str) -> bool {
+    argon2::verify_encoded(password_hash, password.as_bytes()).unwrap_or(false)
+}
+
+pub fn generate_token() -> String {
+    base64::encode_config(&random_bytes(32), base64::URL_SAFE_NO_PAD)
+}
+
+// Only token hashes are stored. Tokens are random so a fast unsalted hash is
+// enough, unlike with passwords.
+pub fn hash_token(token: &str) -> String {
+    blake2b_simd::blake2b(token.as_bytes()).to_hex().to_string()
+}
+
+pub fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+#[test]
+fn test_password() {
+    let hash = hash_password("secret");
+
+    assert_ne!(hash, hash_password("secret"));
+    assert!(verify_password(&hash, "secret"));
+    assert!(!verify_password(&hash, "Secret"));
+    assert!(!verify_password("invalid", "secret"));
+}
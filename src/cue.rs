@@ -1,172 +1,540 @@
 use std::rc::Rc;
 
-fn generate_commands(text: &str) -> Vec<Vec<Rc<str>>> {
-    let iter = text.chars().peekable();
-    let mut commands: Vec<Vec<Rc<str>>> = Vec::new();
-
-    let mut quote_delimited = false;
-    let mut command: Vec<Rc<str>> = Vec::new();
-    let mut string = String::new();
-
-    for ch in iter {
-        if ch == '\n' || (!quote_delimited && ch == ' ') || (quote_delimited && ch == '"') {
-            if !string.is_empty() || quote_delimited {
-                command.push(Rc::from(string));
-                string = String::new();
-                quote_delimited = false;
-            }
-
-            if ch == '\n' {
-                commands.push(command);
-                command = Vec::new();
-            }
-        } else if ch == '"' {
-            quote_delimited = true;
-            continue;
-        } else if quote_delimited
-            || (ch >= 'A' && ch <= 'Z')
-            || (ch >= 'a' && ch <= 'z')
-            || (ch >= '0' && ch <= '9')
-            || ch == ':'
-        {
-            string.push(ch);
-        }
-    }
-
-    if !string.is_empty() {
-        command.push(Rc::from(string));
-    }
+use encoding_rs::{Encoding, SHIFT_JIS, WINDOWS_1252};
 
-    commands.push(command);
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub line: usize,
+    pub message: String,
+}
 
-    commands
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
 }
 
+impl std::error::Error for Error {}
+
 #[derive(Debug, Clone)]
 pub struct Cue {
     pub title: Rc<str>,
     pub performer: Rc<str>,
+    pub songwriter: Option<Rc<str>>,
+    pub catalog: Option<Rc<str>>,
+    pub genre: Option<Rc<str>>,
+    pub date: Option<Rc<str>>,
+    pub disc_id: Option<Rc<str>>,
+    pub comment: Option<Rc<str>>,
     pub files: Vec<File>,
 }
 
 #[derive(Debug, Clone)]
 pub struct File {
     pub path: Rc<str>,
+    pub file_type: Rc<str>,
     pub tracks: Vec<Track>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Track {
     pub number: u32,
+    pub track_type: Rc<str>,
     pub title: Rc<str>,
     pub performer: Rc<str>,
+    pub songwriter: Option<Rc<str>>,
+    pub isrc: Option<Rc<str>>,
+    pub flags: Vec<Rc<str>>,
+    // PREGAP and POSTGAP are silence generated by the player, not present in the file
+    pub pregap: Option<f64>,
+    pub postgap: Option<f64>,
+    pub indexes: Vec<Index>,
+    // Position of INDEX 01
     pub start: f64,
 }
 
-pub fn parse_cue(text: &str) -> Cue {
-    let mut commands = generate_commands(text);
+#[derive(Debug, Clone, PartialEq)]
+pub struct Index {
+    pub number: u32,
+    pub position: f64,
+    // Position in Cue::files of the file the index refers to
+    pub file: usize,
+}
 
-    let mut cue = Cue {
-        title: Rc::from(""),
-        performer: Rc::from(""),
-        files: Vec::new(),
-    };
+impl Track {
+    pub fn is_audio(&self) -> bool {
+        self.track_type.as_ref() == "AUDIO"
+    }
+
+    // Position of INDEX 00, beginning of the pregap stored in the same file as
+    // the track itself
+    pub fn pregap_start(&self) -> Option<f64> {
+        let file = self.indexes.iter().find(|i| i.number == 1)?.file;
+
+        self.indexes
+            .iter()
+            .find(|i| i.number == 0 && i.file == file)
+            .map(|i| i.position)
+    }
+}
+
+// Decodes cue sheet bytes of unknown encoding. Byte order marks and valid
+// UTF-8 are trusted, otherwise Shift-JIS is preferred if it decodes cleanly
+// into Japanese text and Windows-1252 (a superset of Latin-1) is used as the
+// last resort, as it accepts any input.
+pub fn decode_cue(bytes: &[u8]) -> String {
+    if let Some((encoding, bom_len)) = Encoding::for_bom(bytes) {
+        return encoding
+            .decode_without_bom_handling(&bytes[bom_len..])
+            .0
+            .into_owned();
+    }
+
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return text.to_string();
+    }
+
+    let (text, had_errors) = SHIFT_JIS.decode_without_bom_handling(bytes);
+    if !had_errors
+        && text.chars().any(|ch| {
+            ('\u{3040}'..='\u{30ff}').contains(&ch) || ('\u{4e00}'..='\u{9fff}').contains(&ch)
+        })
+    {
+        return text.into_owned();
+    }
+
+    WINDOWS_1252
+        .decode_without_bom_handling(bytes)
+        .0
+        .into_owned()
+}
+
+fn tokenize(line: &str) -> Vec<Rc<str>> {
+    let mut tokens: Vec<Rc<str>> = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while let Some(ch) = chars.peek() {
+            if ch.is_whitespace() {
+                chars.next();
+            } else {
+                break;
+            }
+        }
+
+        let mut token = String::new();
+
+        match chars.peek() {
+            None => break,
+            Some('"') => {
+                chars.next();
+
+                // Unterminated quotes extend to the end of line
+                for ch in &mut chars {
+                    if ch == '"' {
+                        break;
+                    }
+                    token.push(ch);
+                }
+            }
+            Some(_) => {
+                while let Some(&ch) = chars.peek() {
+                    if ch.is_whitespace() {
+                        break;
+                    }
+                    token.push(ch);
+                    chars.next();
+                }
+            }
+        }
+
+        tokens.push(Rc::from(token));
+    }
+
+    tokens
+}
+
+// Parses mm:ss:ff, one frame being 1/75 seconds
+fn parse_position(s: &str) -> Option<f64> {
+    let parts: Vec<&str> = s.split(':').collect();
+
+    if parts.len() != 3 {
+        return None;
+    }
+
+    let mins: u32 = parts[0].parse().ok()?;
+    let secs: u32 = parts[1].parse().ok()?;
+    let frames: u32 = parts[2].parse().ok()?;
+
+    if secs >= 60 || frames >= 75 {
+        return None;
+    }
+
+    Some(f64::from(mins) * 60f64 + f64::from(secs) + f64::from(frames) / 75f64)
+}
+
+struct Parser {
+    cue: Cue,
+    track: Option<Track>,
+    // File the current track belongs to, the one its INDEX 01 is in
+    track_file: usize,
+    line: usize,
+}
 
-    let mut file: Option<File> = None;
-    let mut track: Option<Track> = None;
+impl Parser {
+    fn error<T>(&self, message: &str) -> Result<T, Error> {
+        Err(Error {
+            line: self.line,
+            message: message.to_string(),
+        })
+    }
 
-    let cmd_iter = commands.drain(0..commands.len()).filter(|c| c.len() > 1);
+    fn arg(&self, args: &[Rc<str>], i: usize) -> Result<Rc<str>, Error> {
+        match args.get(i) {
+            Some(a) => Ok(a.clone()),
+            None => self.error("missing argument"),
+        }
+    }
 
-    for mut cmd in cmd_iter {
-        let mut iter = cmd.drain(0..cmd.len());
+    fn finish_track(&mut self) -> Result<(), Error> {
+        if let Some(track) = self.track.take() {
+            if !track.indexes.iter().any(|i| i.number == 1) {
+                return self.error(&format!("track {} has no INDEX 01", track.number));
+            }
 
-        let instr = iter.next().unwrap();
-        let arg = iter.next().unwrap();
+            self.cue.files[self.track_file].tracks.push(track);
+        }
 
-        match instr.as_ref().as_ref() {
-            "TITLE" if file.is_none() => {
-                cue.title = arg;
+        Ok(())
+    }
+
+    fn command(&mut self, command: &str, args: &[Rc<str>]) -> Result<(), Error> {
+        match command {
+            "REM" => {
+                let key = match args.first() {
+                    Some(k) => k.to_ascii_uppercase(),
+                    None => return Ok(()),
+                };
+
+                let value: Option<Rc<str>> = if args.len() > 1 {
+                    Some(Rc::from(args[1..].join(" ")))
+                } else {
+                    None
+                };
+
+                if self.track.is_none() {
+                    match key.as_ref() {
+                        "GENRE" => self.cue.genre = value,
+                        "DATE" => self.cue.date = value,
+                        "DISCID" => self.cue.disc_id = value,
+                        "COMMENT" => self.cue.comment = value,
+                        _ => {}
+                    }
+                }
             }
-            "PERFORMER" if file.is_none() => {
-                cue.performer = arg;
+
+            "CATALOG" => {
+                self.cue.catalog = Some(self.arg(args, 0)?);
             }
 
-            "TITLE" if track.is_some() => {
-                if let Some(t) = track.as_mut() {
-                    t.title = arg;
+            "CDTEXTFILE" => {}
+
+            "TITLE" => {
+                let title = self.arg(args, 0)?;
+                match self.track.as_mut() {
+                    Some(t) => t.title = title,
+                    None => self.cue.title = title,
                 }
             }
-            "PERFORMER" if track.is_some() => {
-                if let Some(t) = track.as_mut() {
-                    t.performer = arg;
+
+            "PERFORMER" => {
+                let performer = self.arg(args, 0)?;
+                match self.track.as_mut() {
+                    Some(t) => t.performer = performer,
+                    None => self.cue.performer = performer,
                 }
             }
 
-            "FILE" => {
-                if let Some(f) = file {
-                    cue.files.push(f);
+            "SONGWRITER" => {
+                let songwriter = self.arg(args, 0)?;
+                match self.track.as_mut() {
+                    Some(t) => t.songwriter = Some(songwriter),
+                    None => self.cue.songwriter = Some(songwriter),
                 }
+            }
+
+            "FILE" => {
+                let path = self.arg(args, 0)?;
+                let file_type = args.get(1).cloned().unwrap_or_else(|| Rc::from("WAVE"));
 
-                file = Some(File {
-                    path: arg,
+                self.cue.files.push(File {
+                    path,
+                    file_type,
                     tracks: Vec::new(),
                 });
 
-                track = None;
+                // A track without INDEX 01 yet continues in the new file
+                let track_started = match &self.track {
+                    Some(t) => t.indexes.iter().any(|i| i.number == 1),
+                    None => false,
+                };
+
+                if track_started {
+                    self.finish_track()?;
+                }
             }
 
-            "TRACK" if file.is_some() => {
-                if let Some(t) = track {
-                    if let Some(f) = file.as_mut() {
-                        f.tracks.push(t);
-                    }
+            "TRACK" => {
+                if self.cue.files.is_empty() {
+                    return self.error("TRACK before FILE");
                 }
 
-                let number: u32 = arg.parse().unwrap_or(0);
+                let number: u32 = match self.arg(args, 0)?.parse() {
+                    Ok(n) if (1..=99).contains(&n) => n,
+                    _ => return self.error("invalid track number"),
+                };
+
+                self.finish_track()?;
 
-                track = Some(Track {
+                self.track = Some(Track {
                     number,
-                    title: cue.title.clone(),
-                    performer: cue.performer.clone(),
+                    track_type: args.get(1).cloned().unwrap_or_else(|| Rc::from("AUDIO")),
+                    title: self.cue.title.clone(),
+                    performer: self.cue.performer.clone(),
+                    songwriter: self.cue.songwriter.clone(),
+                    isrc: None,
+                    flags: Vec::new(),
+                    pregap: None,
+                    postgap: None,
+                    indexes: Vec::new(),
                     start: 0f64,
                 });
+
+                self.track_file = self.cue.files.len() - 1;
             }
 
-            "INDEX" if track.is_some() && arg.as_ref() == "01" => {
-                if let Some(pos_str) = iter.next() {
-                    let parts: Vec<&str> = pos_str.split(':').collect();
+            "INDEX" => {
+                if self.track.is_none() {
+                    return self.error("INDEX outside TRACK");
+                }
 
-                    if parts.len() == 3 {
-                        let mins: u32 = parts[0].parse().unwrap_or(0);
-                        let secs: u32 = parts[1].parse().unwrap_or(0);
-                        let frames: u32 = parts[2].parse().unwrap_or(0);
+                let number: u32 = match self.arg(args, 0)?.parse() {
+                    Ok(n) if n <= 99 => n,
+                    _ => return self.error("invalid index number"),
+                };
 
-                        // One frame is 1/75 seconds
-                        let start =
-                            f64::from(mins) * 60f64 + f64::from(secs) + f64::from(frames) / 75f64;
+                let position = match parse_position(&self.arg(args, 1)?) {
+                    Some(p) => p,
+                    None => return self.error("invalid index position"),
+                };
 
-                        if let Some(t) = track.as_mut() {
-                            t.start = start;
-                        }
-                    }
+                let file = self.cue.files.len() - 1;
+
+                let track = self.track.as_mut().unwrap();
+
+                if number == 1 {
+                    track.start = position;
+                    self.track_file = file;
                 }
+
+                track.indexes.push(Index {
+                    number,
+                    position,
+                    file,
+                });
             }
 
-            _ => {}
+            "ISRC" | "FLAGS" | "PREGAP" | "POSTGAP" if self.track.is_none() => {
+                return self.error(&format!("{} outside TRACK", command));
+            }
+
+            "ISRC" => {
+                let isrc = self.arg(args, 0)?;
+                self.track.as_mut().unwrap().isrc = Some(isrc);
+            }
+
+            "FLAGS" => {
+                self.track.as_mut().unwrap().flags = args.to_vec();
+            }
+
+            "PREGAP" | "POSTGAP" => {
+                let length = match parse_position(&self.arg(args, 0)?) {
+                    Some(p) => p,
+                    None => return self.error("invalid gap length"),
+                };
+
+                let track = self.track.as_mut().unwrap();
+
+                if command == "PREGAP" {
+                    track.pregap = Some(length);
+                } else {
+                    track.postgap = Some(length);
+                }
+            }
+
+            _ => {
+                trace!("ignoring unknown cue command '{}'", command);
+            }
         }
+
+        Ok(())
+    }
+}
+
+pub fn parse_cue(text: &str) -> Result<Cue, Error> {
+    let mut parser = Parser {
+        cue: Cue {
+            title: Rc::from(""),
+            performer: Rc::from(""),
+            songwriter: None,
+            catalog: None,
+            genre: None,
+            date: None,
+            disc_id: None,
+            comment: None,
+            files: Vec::new(),
+        },
+        track: None,
+        track_file: 0,
+        line: 0,
+    };
+
+    for (i, line) in text.lines().enumerate() {
+        parser.line = i + 1;
+
+        let tokens = tokenize(line);
+
+        let (command, args) = match tokens.split_first() {
+            Some((c, a)) => (c.to_ascii_uppercase(), a),
+            None => continue,
+        };
+
+        parser.command(&command, args)?;
     }
 
-    if let Some(t) = track {
-        if let Some(f) = file.as_mut() {
-            f.tracks.push(t);
+    parser.finish_track()?;
+
+    Ok(parser.cue)
+}
+
+// Cue sheets have no escapes, quotes inside values are replaced
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "'"))
+}
+
+fn format_position(position: f64) -> String {
+    let frames = (position.max(0f64) * 75f64).round() as u64;
+
+    format!(
+        "{:02}:{:02}:{:02}",
+        frames / (75 * 60),
+        frames / 75 % 60,
+        frames % 75
+    )
+}
+
+fn write_track_header(out: &mut String, track: &Track) {
+    out.push_str(&format!(
+        "  TRACK {:02} {}\n",
+        track.number, track.track_type
+    ));
+
+    if !track.flags.is_empty() {
+        out.push_str(&format!("    FLAGS {}\n", track.flags.join(" ")));
+    }
+    if !track.title.is_empty() {
+        out.push_str(&format!("    TITLE {}\n", quote(&track.title)));
+    }
+    if !track.performer.is_empty() {
+        out.push_str(&format!("    PERFORMER {}\n", quote(&track.performer)));
+    }
+    if let Some(songwriter) = &track.songwriter {
+        out.push_str(&format!("    SONGWRITER {}\n", quote(songwriter)));
+    }
+    if let Some(isrc) = &track.isrc {
+        out.push_str(&format!("    ISRC {}\n", isrc));
+    }
+    if let Some(pregap) = track.pregap {
+        out.push_str(&format!("    PREGAP {}\n", format_position(pregap)));
+    }
+}
+
+fn write_index(out: &mut String, index: &Index) {
+    out.push_str(&format!(
+        "    INDEX {:02} {}\n",
+        index.number,
+        format_position(index.position)
+    ));
+}
+
+// Inverse of parse_cue
+pub fn write_cue(cue: &Cue) -> String {
+    let mut out = String::new();
+
+    let rem = [
+        ("GENRE", &cue.genre),
+        ("DATE", &cue.date),
+        ("DISCID", &cue.disc_id),
+        ("COMMENT", &cue.comment),
+    ];
+
+    for (key, value) in rem.iter() {
+        if let Some(value) = value {
+            out.push_str(&format!("REM {} {}\n", key, quote(value)));
         }
     }
 
-    if let Some(f) = file {
-        cue.files.push(f);
+    if let Some(catalog) = &cue.catalog {
+        out.push_str(&format!("CATALOG {}\n", catalog));
+    }
+    if !cue.performer.is_empty() {
+        out.push_str(&format!("PERFORMER {}\n", quote(&cue.performer)));
+    }
+    if let Some(songwriter) = &cue.songwriter {
+        out.push_str(&format!("SONGWRITER {}\n", quote(songwriter)));
+    }
+    if !cue.title.is_empty() {
+        out.push_str(&format!("TITLE {}\n", quote(&cue.title)));
+    }
+
+    // Each FILE line is written right before the first track or index in it,
+    // so indexes of a track may be split across files
+    let mut next_file = 0;
+    let mut write_files = |out: &mut String, until: usize| {
+        while next_file <= until && next_file < cue.files.len() {
+            let file = &cue.files[next_file];
+            out.push_str(&format!("FILE {} {}\n", quote(&file.path), file.file_type));
+            next_file += 1;
+        }
+    };
+
+    for (i, file) in cue.files.iter().enumerate() {
+        for track in file.tracks.iter() {
+            write_files(
+                &mut out,
+                track.indexes.first().map_or(i, |index| index.file),
+            );
+            write_track_header(&mut out, track);
+
+            for index in track.indexes.iter() {
+                write_files(&mut out, index.file);
+                write_index(&mut out, index);
+            }
+
+            if let Some(postgap) = track.postgap {
+                out.push_str(&format!("    POSTGAP {}\n", format_position(postgap)));
+            }
+        }
     }
 
-    cue
+    write_files(&mut out, cue.files.len());
+
+    out
+}
+
+#[cfg(test)]
+fn position(mins: u32, secs: u32, frames: u32) -> f64 {
+    f64::from(mins) * 60f64 + f64::from(secs) + f64::from(frames) / 75f64
 }
 
 #[test]
@@ -194,5 +562,264 @@ FILE \"file.cue\" WAVE
     PERFORMER \"Performer\"
     INDEX 01 14:54:44";
 
-    println!("{:?}", parse_cue(data));
+    let cue = parse_cue(data).unwrap();
+
+    assert_eq!(cue.title.as_ref(), "Title");
+    assert_eq!(cue.performer.as_ref(), "Performer");
+    assert_eq!(cue.disc_id.as_deref(), Some("123456789"));
+    assert_eq!(cue.comment.as_deref(), Some("comment"));
+    assert_eq!(cue.files.len(), 1);
+
+    let file = &cue.files[0];
+    assert_eq!(file.path.as_ref(), "file.cue");
+    assert_eq!(file.file_type.as_ref(), "WAVE");
+    assert_eq!(file.tracks.len(), 4);
+
+    let numbers: Vec<u32> = file.tracks.iter().map(|t| t.number).collect();
+    assert_eq!(numbers, vec![1, 2, 3, 4]);
+
+    assert_eq!(file.tracks[0].title.as_ref(), "Track01");
+    assert_eq!(file.tracks[0].performer.as_ref(), "Performer01");
+    assert_eq!(file.tracks[0].start, 0f64);
+    assert_eq!(file.tracks[1].start, position(4, 9, 11));
+    assert_eq!(file.tracks[3].start, position(14, 54, 44));
+}
+
+#[test]
+fn test_parse_metadata() {
+    let data = "REM GENRE \"Progressive Rock\"\r
+REM DATE 1973\r
+CATALOG 0724382975229\r
+SONGWRITER \"Writer\"\r
+PERFORMER Band\r
+TITLE \"Album: Special Edition\"\r
+FILE \"Band - Album (Special Edition).flac\" WAVE\r
+  TRACK 01 AUDIO\r
+    TITLE \"Track \"\r
+    ISRC GBAYE7300001\r
+    FLAGS DCP PRE\r
+    INDEX 01 00:00:00\r
+  TRACK 02 AUDIO\r
+    SONGWRITER \"Other Writer\"\r
+    INDEX 01 03:00:00\r
+";
+
+    let cue = parse_cue(data).unwrap();
+
+    assert_eq!(cue.genre.as_deref(), Some("Progressive Rock"));
+    assert_eq!(cue.date.as_deref(), Some("1973"));
+    assert_eq!(cue.catalog.as_deref(), Some("0724382975229"));
+    assert_eq!(cue.songwriter.as_deref(), Some("Writer"));
+    assert_eq!(cue.performer.as_ref(), "Band");
+    assert_eq!(cue.title.as_ref(), "Album: Special Edition");
+
+    let file = &cue.files[0];
+    assert_eq!(file.path.as_ref(), "Band - Album (Special Edition).flac");
+
+    let track = &file.tracks[0];
+    assert_eq!(track.title.as_ref(), "Track ");
+    assert_eq!(track.performer.as_ref(), "Band");
+    assert_eq!(track.songwriter.as_deref(), Some("Writer"));
+    assert_eq!(track.isrc.as_deref(), Some("GBAYE7300001"));
+    assert_eq!(track.flags.len(), 2);
+
+    let track = &file.tracks[1];
+    assert_eq!(track.title.as_ref(), "Album: Special Edition");
+    assert_eq!(track.songwriter.as_deref(), Some("Other Writer"));
+    assert_eq!(track.isrc, None);
+}
+
+#[test]
+fn test_parse_gaps() {
+    let data = "
+FILE \"image.wav\" WAVE
+  TRACK 01 AUDIO
+    PREGAP 00:02:00
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    INDEX 00 04:58:10
+    INDEX 01 05:00:00
+    INDEX 02 06:00:00
+    POSTGAP 00:01:00
+";
+
+    let cue = parse_cue(data).unwrap();
+    let tracks = &cue.files[0].tracks;
+
+    assert_eq!(tracks[0].pregap, Some(2f64));
+    assert_eq!(tracks[0].pregap_start(), None);
+
+    assert_eq!(tracks[1].pregap_start(), Some(position(4, 58, 10)));
+    assert_eq!(tracks[1].start, 300f64);
+    assert_eq!(tracks[1].indexes.len(), 3);
+    assert_eq!(tracks[1].postgap, Some(1f64));
+}
+
+#[test]
+fn test_parse_multiple_files() {
+    let data = "
+FILE \"01.wav\" WAVE
+  TRACK 01 AUDIO
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    INDEX 00 03:58:00
+FILE \"02.wav\" WAVE
+    INDEX 01 00:00:00
+  TRACK 03 AUDIO
+    INDEX 00 03:59:00
+    INDEX 01 04:00:00
+FILE \"03.wav\" WAVE
+  TRACK 04 AUDIO
+    INDEX 01 00:30:00
+FILE \"04.wav\" WAVE
+";
+
+    let cue = parse_cue(data).unwrap();
+
+    assert_eq!(cue.files.len(), 4);
+
+    let numbers: Vec<Vec<u32>> = cue
+        .files
+        .iter()
+        .map(|f| f.tracks.iter().map(|t| t.number).collect())
+        .collect();
+    assert_eq!(numbers, vec![vec![1], vec![2, 3], vec![4], vec![]]);
+
+    let tracks = &cue.files[1].tracks;
+
+    // Pregap of track 2 is at the end of the previous file
+    assert_eq!(tracks[0].start, 0f64);
+    assert_eq!(tracks[0].indexes[0].file, 0);
+    assert_eq!(tracks[0].pregap_start(), None);
+
+    assert_eq!(tracks[1].start, 240f64);
+    assert_eq!(tracks[1].pregap_start(), Some(239f64));
+
+    assert_eq!(cue.files[2].tracks[0].start, 30f64);
+}
+
+#[test]
+fn test_parse_unicode() {
+    let data = "TITLE \"Ça plane pour moi — 東京\"
+FILE \"Ça plane.flac\" WAVE
+  TRACK 1 AUDIO
+    INDEX 1 0:0:0
+";
+
+    let cue = parse_cue(data).unwrap();
+
+    assert_eq!(cue.title.as_ref(), "Ça plane pour moi — 東京");
+    assert_eq!(cue.files[0].path.as_ref(), "Ça plane.flac");
+    assert_eq!(cue.files[0].tracks[0].number, 1);
+}
+
+#[test]
+fn test_parse_data_track() {
+    let data = "
+FILE \"image.bin\" BINARY
+  TRACK 01 MODE1/2352
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    INDEX 01 10:00:00
+";
+
+    let cue = parse_cue(data).unwrap();
+    let tracks = &cue.files[0].tracks;
+
+    assert!(!tracks[0].is_audio());
+    assert!(tracks[1].is_audio());
+}
+
+#[test]
+fn test_parse_errors() {
+    let error = parse_cue("TRACK 01 AUDIO\n").unwrap_err();
+    assert_eq!(error.line, 1);
+
+    let error = parse_cue("FILE \"a.wav\" WAVE\n  INDEX 01 00:00:00\n").unwrap_err();
+    assert_eq!(error.line, 2);
+
+    let error =
+        parse_cue("FILE \"a.wav\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:61:00\n").unwrap_err();
+    assert_eq!(error.line, 3);
+
+    let error = parse_cue("FILE \"a.wav\" WAVE\n  TRACK 01 AUDIO\n    TITLE \"x\"\n").unwrap_err();
+    assert_eq!(error.line, 3);
+
+    let error = parse_cue("FILE \"a.wav\" WAVE\n  TRACK AA AUDIO\n").unwrap_err();
+    assert_eq!(error.line, 2);
+}
+
+#[test]
+fn test_decode_cue() {
+    assert_eq!(decode_cue(b"TITLE \"Caf\xe9\""), "TITLE \"Café\"");
+    assert_eq!(
+        decode_cue(b"TITLE \"\x93Quoted\x94\""),
+        "TITLE \"\u{201c}Quoted\u{201d}\""
+    );
+    assert_eq!(decode_cue("TITLE \"Café\"".as_bytes()), "TITLE \"Café\"");
+    assert_eq!(decode_cue(b"\xef\xbb\xbfTITLE"), "TITLE");
+    assert_eq!(decode_cue(b"\xff\xfeT\x00I\x00"), "TI");
+    assert_eq!(
+        decode_cue(b"TITLE \"\x93\x8c\x8b\x9e\""),
+        "TITLE \"\u{6771}\u{4eac}\""
+    );
+}
+
+#[test]
+fn test_write_cue() {
+    let data = "
+REM GENRE Rock
+REM DATE 1999
+PERFORMER \"Band\"
+TITLE \"Album\"
+FILE \"01.wav\" WAVE
+  TRACK 01 AUDIO
+    TITLE \"One\"
+    ISRC ABCDE1234567
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE \"Two\"
+    PERFORMER \"Guest\"
+    INDEX 00 03:58:00
+FILE \"02.wav\" WAVE
+    INDEX 01 00:00:00
+  TRACK 03 AUDIO
+    TITLE \"Three\"
+    INDEX 01 04:00:74
+  TRACK 04 AUDIO
+    TITLE \"Four\"
+    INDEX 00 05:00:00
+FILE \"03.wav\" WAVE
+FILE \"04.wav\" WAVE
+    INDEX 01 00:00:00
+";
+
+    let text = write_cue(&parse_cue(data).unwrap());
+    // Every index is written under the FILE it belongs to
+    let layout = |text: &str| -> Vec<String> {
+        text.lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| l.starts_with("FILE") || l.starts_with("TRACK") || l.starts_with("INDEX"))
+            .collect()
+    };
+    assert_eq!(layout(&text), layout(data));
+    let cue = parse_cue(&text).unwrap();
+
+    assert_eq!(write_cue(&cue), text);
+
+    assert_eq!(cue.genre.as_deref(), Some("Rock"));
+    assert_eq!(cue.date.as_deref(), Some("1999"));
+    assert_eq!(cue.title.as_ref(), "Album");
+    assert_eq!(cue.files.len(), 4);
+    assert_eq!(cue.files[0].tracks.len(), 1);
+    assert!(cue.files[2].tracks.is_empty());
+    assert_eq!(cue.files[3].tracks[0].indexes[0].file, 1);
+    assert_eq!(cue.files[0].tracks[0].isrc.as_deref(), Some("ABCDE1234567"));
+
+    let tracks = &cue.files[1].tracks;
+    assert_eq!(tracks[0].title.as_ref(), "Two");
+    assert_eq!(tracks[0].performer.as_ref(), "Guest");
+    assert_eq!(tracks[0].indexes[0].file, 0);
+    assert_eq!(tracks[0].indexes[0].position, position(3, 58, 0));
+    assert_eq!(tracks[1].start, position(4, 0, 74));
 }
@@ -3,11 +3,13 @@ use rusqlite::{Connection, Result, NO_PARAMS};
 
 use crate::schema;
 
-pub fn ensure_schema(conn: &mut Connection, schema: &str) -> Result<bool> {
+pub fn ensure_schema(conn: &mut Connection, schema: &[&str]) -> Result<bool> {
     trace!("trying to get schema version");
 
     conn.execute_batch(schema::META_SCHEMA)?;
 
+    let latest_version = schema.len() as u32;
+
     let schema_version: Option<u32> = conn
         .query_row(
             "SELECT value FROM Musicd WHERE key = 'schema'",
@@ -16,30 +18,40 @@ pub fn ensure_schema(conn: &mut Connection, schema: &str) -> Result<bool> {
         )
         .optional()?;
 
-    if let Some(schema_version) = schema_version {
-        if schema_version != schema::SCHEMA_VERSION {
-            error!(
-                "unsupported schema version: got {}, expected {}",
-                schema_version,
-                schema::SCHEMA_VERSION
-            );
-            return Ok(false);
+    let schema_version = match schema_version {
+        Some(v) => v,
+        None => {
+            debug!("schema meta not present, creating schema");
+            0
         }
+    };
+
+    if schema_version > latest_version {
+        error!(
+            "unsupported schema version: got {}, expected at most {}",
+            schema_version, latest_version
+        );
+        return Ok(false);
+    }
 
+    if schema_version == latest_version {
         debug!("schema version up-to-date, doing nothing");
-    } else {
-        debug!("schema meta not present, creating schema");
-
-        let tran = conn.transaction()?;
+        return Ok(true);
+    }
 
-        tran.execute(
-            "INSERT INTO Musicd (key, value) VALUES ('schema', ?)",
-            &[schema::SCHEMA_VERSION],
-        )?;
-        tran.execute_batch(schema)?;
+    let tran = conn.transaction()?;
 
-        tran.commit()?;
+    for (version, migration) in schema.iter().enumerate().skip(schema_version as usize) {
+        debug!("migrating schema to version {}", version + 1);
+        tran.execute_batch(migration)?;
     }
 
+    tran.execute(
+        "INSERT OR REPLACE INTO Musicd (key, value) VALUES ('schema', ?)",
+        [latest_version],
+    )?;
+
+    tran.commit()?;
+
     Ok(true)
 }
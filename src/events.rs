@@ -0,0 +1,103 @@
+use std::path::Path;
+
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+// Events are only buffered briefly, clients falling behind are told to
+// refresh everything instead
+const EVENT_BUFFER: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub name: &'static str,
+    // Delivered only to this user if set
+    pub user_id: Option<i64>,
+    // Delivered only to users with access to this root if set
+    pub root: Option<String>,
+    pub data: Value,
+}
+
+impl Event {
+    pub fn global(name: &'static str, data: Value) -> Event {
+        Event {
+            name,
+            user_id: None,
+            root: None,
+            data,
+        }
+    }
+
+    pub fn user(user_id: i64, name: &'static str, data: Value) -> Event {
+        Event {
+            name,
+            user_id: Some(user_id),
+            root: None,
+            data,
+        }
+    }
+
+    // Scoped to the root of an index path
+    pub fn node(path: &Path, name: &'static str, data: Value) -> Event {
+        Event {
+            name,
+            user_id: None,
+            root: path.iter().next().map(|r| r.to_string_lossy().into_owned()),
+            data,
+        }
+    }
+
+    pub fn visible_to(&self, user_id: i64, roots: &[String]) -> bool {
+        if let Some(event_user_id) = self.user_id {
+            if event_user_id != user_id {
+                return false;
+            }
+        }
+
+        match &self.root {
+            Some(root) => roots.iter().any(|r| r == root),
+            None => true,
+        }
+    }
+
+    // Server-Sent Events message
+    pub fn to_sse(&self) -> String {
+        format!("event: {}\ndata: {}\n\n", self.name, self.data)
+    }
+}
+
+#[derive(Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Events {
+    pub fn new() -> Events {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+
+        Events { sender }
+    }
+
+    pub fn emit(&self, event: Event) {
+        // Fails only if nobody is listening
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+#[test]
+fn test_visible_to() {
+    let event = Event::node(
+        Path::new("music/a/b.flac"),
+        "node_added",
+        serde_json::json!({}),
+    );
+    let roots = vec!["music".to_string()];
+
+    assert!(event.visible_to(1, &roots));
+    assert!(!event.visible_to(1, &[]));
+    assert!(Event::user(1, "list_changed", Value::Null).visible_to(1, &[]));
+    assert!(!Event::user(1, "list_changed", Value::Null).visible_to(2, &roots));
+}
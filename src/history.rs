@@ -0,0 +1,363 @@
+use rusqlite::types::ToSql;
+use rusqlite::{Result, NO_PARAMS};
+use serde::Serialize;
+
+use crate::http_util::HttpQuery;
+use crate::index::Index;
+use crate::query::roots_clause;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+#[derive(Serialize)]
+pub struct HistoryItem {
+    pub play_id: i64,
+    pub time: i64,
+    pub duration: f64,
+    pub client: Option<String>,
+    pub title: String,
+    pub artist_name: String,
+    pub album_name: String,
+    // Not set if the track isn't in the index or accessible anymore
+    pub track_id: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct TopItem {
+    // track_id, album_id or artist_id, if in the index and accessible
+    pub item_id: Option<i64>,
+    pub name: String,
+    // Of tracks and albums
+    pub artist_name: Option<String>,
+    pub play_count: i64,
+    pub duration: f64,
+}
+
+#[derive(Serialize)]
+pub struct Summary {
+    pub year: i64,
+    pub play_count: i64,
+    pub duration: f64,
+    pub track_count: i64,
+    pub artist_count: i64,
+    pub album_count: i64,
+    // Artists not listened to before the year
+    pub new_artist_count: i64,
+    // Plays per month, January first
+    pub month_play_counts: Vec<i64>,
+    // Local date with the most plays
+    pub top_day: Option<String>,
+    pub top_tracks: Vec<TopItem>,
+    pub top_albums: Vec<TopItem>,
+    pub top_artists: Vec<TopItem>,
+}
+
+// Plays of a user within [since, until) joined with their store track and
+// accessible index track. Bind values are in placeholder order.
+fn plays(user_id: i64, since: i64, until: i64, roots: &[String]) -> (String, Vec<Box<dyn ToSql>>) {
+    let (roots_clause, mut values) = roots_clause("Track.node_id", roots);
+
+    let sql = format!(
+        "SELECT
+            StorePlay.play_id,
+            StorePlay.store_track_id,
+            StorePlay.time,
+            StorePlay.duration,
+            StorePlay.client,
+            StoreTrack.title,
+            StoreTrack.artist_name,
+            StoreTrack.album_name,
+            (
+                SELECT Track.track_id
+                FROM Track
+                WHERE
+                    Track.title = StoreTrack.title AND
+                    Track.artist_name = StoreTrack.artist_name AND
+                    Track.album_name = StoreTrack.album_name AND
+                    {}
+                LIMIT 1
+            ) AS track_id
+        FROM StorePlay
+        INNER JOIN StoreTrack ON StoreTrack.store_track_id = StorePlay.store_track_id
+        WHERE StorePlay.user_id = ? AND StorePlay.time >= ? AND StorePlay.time < ?",
+        roots_clause
+    );
+
+    values.push(Box::new(user_id));
+    values.push(Box::new(since));
+    values.push(Box::new(until));
+
+    (sql, values)
+}
+
+// Time range of the request as [since, until), the last "day", "week",
+// "month" or "year", or "all" given as "period" unless "since" is given
+pub fn period(query: &HttpQuery, now: i64, default: &str) -> Option<(i64, i64)> {
+    let since = match query.get_str("period").unwrap_or(default) {
+        "day" => now - SECONDS_PER_DAY,
+        "week" => now - 7 * SECONDS_PER_DAY,
+        "month" => now - 30 * SECONDS_PER_DAY,
+        "year" => now - 365 * SECONDS_PER_DAY,
+        "all" => 0,
+        _ => return None,
+    };
+
+    Some((
+        query.get_i64("since").unwrap_or(since),
+        query.get_i64("until").unwrap_or(i64::MAX),
+    ))
+}
+
+// Recently played first
+pub fn query_history(
+    index: &Index,
+    query: &HttpQuery,
+    user_id: i64,
+    since: i64,
+    until: i64,
+    roots: &[String],
+) -> Result<(i64, Vec<HistoryItem>)> {
+    let conn = index.connection();
+
+    let (plays, mut values) = plays(user_id, since, until, roots);
+
+    let total = conn.query_row(
+        &format!("SELECT COUNT(*) FROM ({})", plays),
+        &values,
+        |row| row.get(0),
+    )?;
+
+    values.push(Box::new(query.get_i64("limit").unwrap_or(-1)));
+    values.push(Box::new(query.get_i64("offset").unwrap_or(0)));
+
+    let mut st = conn.prepare(&format!(
+        "SELECT play_id, time, duration, client, title, artist_name, album_name, track_id
+        FROM ({})
+        ORDER BY time DESC, play_id DESC
+        LIMIT ? OFFSET ?",
+        plays
+    ))?;
+
+    let rows = st.query_map(&values, |row| {
+        Ok(HistoryItem {
+            play_id: row.get(0)?,
+            time: row.get(1)?,
+            duration: row.get(2)?,
+            client: row.get(3)?,
+            title: row.get(4)?,
+            artist_name: row.get(5)?,
+            album_name: row.get(6)?,
+            track_id: row.get(7)?,
+        })
+    })?;
+
+    Ok((total, rows.collect::<Result<_>>()?))
+}
+
+// Most played "tracks", "albums" or "artists" within [since, until), None if
+// `item_type` is unknown
+pub fn query_top(
+    index: &Index,
+    item_type: &str,
+    user_id: i64,
+    since: i64,
+    until: i64,
+    limit: i64,
+    roots: &[String],
+) -> Result<Option<Vec<TopItem>>> {
+    // Plays of tracks no longer in the index are still counted by name
+    let (columns, join, group) = match item_type {
+        "tracks" => (
+            "MAX(plays.track_id), plays.title, plays.artist_name",
+            "",
+            "plays.store_track_id",
+        ),
+        "albums" => (
+            "Track.album_id, plays.album_name, Album.artist_name",
+            "LEFT JOIN Track ON Track.track_id = plays.track_id
+            LEFT JOIN Album ON Album.album_id = Track.album_id",
+            "Track.album_id, plays.album_name",
+        ),
+        "artists" => (
+            "MAX(Track.artist_id), plays.artist_name, NULL",
+            "LEFT JOIN Track ON Track.track_id = plays.track_id",
+            "plays.artist_name",
+        ),
+        _ => return Ok(None),
+    };
+
+    let (plays, mut values) = plays(user_id, since, until, roots);
+    values.push(Box::new(limit));
+
+    let conn = index.connection();
+    let mut st = conn.prepare(&format!(
+        "SELECT {}, COUNT(*), SUM(plays.duration)
+        FROM ({}) AS plays
+        {}
+        GROUP BY {}
+        ORDER BY COUNT(*) DESC, SUM(plays.duration) DESC
+        LIMIT ?",
+        columns, plays, join, group
+    ))?;
+
+    let rows = st.query_map(&values, |row| {
+        Ok(TopItem {
+            item_id: row.get(0)?,
+            name: row.get(1)?,
+            artist_name: row.get(2)?,
+            play_count: row.get(3)?,
+            duration: row.get(4)?,
+        })
+    })?;
+
+    Ok(Some(rows.collect::<Result<_>>()?))
+}
+
+// Yearly review of the history, the current year if not given. Years from 1
+// to 9999 start at local midnight.
+pub fn summary(
+    index: &Index,
+    user_id: i64,
+    year: Option<i64>,
+    roots: &[String],
+) -> Result<Summary> {
+    let conn = index.connection();
+
+    let year = match year {
+        Some(year) => year,
+        None => conn.query_row(
+            "SELECT CAST(strftime('%Y', 'now', 'localtime') AS INTEGER)",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?,
+    };
+
+    let year_start = |year: i64| -> Result<i64> {
+        conn.query_row(
+            "SELECT CAST(strftime('%s', printf('%04d-01-01 00:00:00', ?), 'utc') AS INTEGER)",
+            [year],
+            |row| row.get(0),
+        )
+    };
+
+    let since = year_start(year)?;
+    // Dates past 9999 can't be formatted
+    let until = if year < 9999 {
+        year_start(year + 1)?
+    } else {
+        i64::MAX
+    };
+
+    let (plays, values) = plays(user_id, since, until, roots);
+
+    let (play_count, duration, track_count, artist_count, album_count) = conn.query_row(
+        &format!(
+            "SELECT
+                COUNT(*),
+                COALESCE(SUM(duration), 0),
+                COUNT(DISTINCT store_track_id),
+                COUNT(DISTINCT artist_name),
+                COUNT(DISTINCT album_name)
+            FROM ({})",
+            plays
+        ),
+        &values,
+        |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        },
+    )?;
+
+    let new_artist_count = conn.query_row(
+        &format!(
+            "SELECT COUNT(DISTINCT plays.artist_name)
+            FROM ({}) AS plays
+            WHERE NOT EXISTS (
+                SELECT StorePlay.play_id
+                FROM StorePlay
+                INNER JOIN StoreTrack ON StoreTrack.store_track_id = StorePlay.store_track_id
+                WHERE
+                    StorePlay.user_id = {} AND
+                    StorePlay.time < {} AND
+                    StoreTrack.artist_name = plays.artist_name
+            )",
+            plays, user_id, since
+        ),
+        &values,
+        |row| row.get(0),
+    )?;
+
+    let mut month_play_counts = vec![0; 12];
+
+    let mut st = conn.prepare(&format!(
+        "SELECT CAST(strftime('%m', time, 'unixepoch', 'localtime') AS INTEGER), COUNT(*)
+        FROM ({})
+        GROUP BY 1",
+        plays
+    ))?;
+    let mut rows = st.query(&values)?;
+
+    while let Some(row) = rows.next()? {
+        let month: i64 = row.get(0)?;
+        month_play_counts[(month - 1) as usize] = row.get(1)?;
+    }
+
+    let mut st = conn.prepare(&format!(
+        "SELECT date(time, 'unixepoch', 'localtime') AS day
+        FROM ({})
+        GROUP BY day
+        ORDER BY COUNT(*) DESC, day
+        LIMIT 1",
+        plays
+    ))?;
+    let top_day = st
+        .query_map(&values, |row| row.get(0))?
+        .next()
+        .transpose()?;
+
+    let top = |item_type| {
+        query_top(index, item_type, user_id, since, until, 5, roots)
+            .map(|items| items.unwrap_or_default())
+    };
+
+    Ok(Summary {
+        year,
+        play_count,
+        duration,
+        track_count,
+        artist_count,
+        album_count,
+        new_artist_count,
+        month_play_counts,
+        top_day,
+        top_tracks: top("tracks")?,
+        top_albums: top("albums")?,
+        top_artists: top("artists")?,
+    })
+}
+
+#[test]
+fn test_period() {
+    let now = 1_000_000_000;
+
+    assert_eq!(
+        period(&HttpQuery::from(""), now, "month"),
+        Some((now - 30 * SECONDS_PER_DAY, i64::MAX))
+    );
+    assert_eq!(
+        period(&HttpQuery::from("period=week&until=5"), now, "all"),
+        Some((now - 7 * SECONDS_PER_DAY, 5))
+    );
+    assert_eq!(
+        period(&HttpQuery::from("period=all&since=10"), now, "month"),
+        Some((10, i64::MAX))
+    );
+    assert_eq!(
+        period(&HttpQuery::from("period=decade"), now, "month"),
+        None
+    );
+}
@@ -3,18 +3,39 @@ use std::collections::HashMap;
 use std::error::Error as StdError;
 use std::io::{Cursor, Read, Seek, SeekFrom};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::sync::Arc;
+use std::time::Duration;
 
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
-use serde_json::json;
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::broadcast;
+use tokio_tls::TlsStream;
 
 use crate::audio_stream::AudioStream;
-use crate::http_util::HttpQuery;
+use crate::auth;
+use crate::cue;
+use crate::events::Event;
+use crate::history;
+use crate::http_util::{self, HttpQuery};
 use crate::index::TrackLyrics;
+use crate::index::{Index, Track};
+use crate::listener::Listener;
 use crate::lyrics;
 use crate::media;
+use crate::playlist;
+use crate::radio::{self, Seed};
+use crate::scrobble;
+use crate::smart_list::Rules;
+use crate::store::{PlaybackSession, Share, User};
+use crate::subsonic;
+use crate::tls::TlsReloader;
+use crate::upnp;
 use crate::Musicd;
 
 #[derive(Debug)]
@@ -66,8 +87,55 @@ impl StdError for Error {
     }
 }
 
-pub async fn run_api(musicd: Arc<crate::Musicd>, bind: SocketAddr) {
-    let make_service = make_service_fn(move |_socket: &AddrStream| {
+pub async fn run_api(musicd: Arc<crate::Musicd>, listener: Listener, tls: Option<TlsReloader>) {
+    match (listener, tls) {
+        (Listener::Tcp(listener), None) => {
+            let make_service = make_service_fn(move |_socket: &AddrStream| {
+                let musicd = musicd.clone();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                        process_request(req, musicd.clone())
+                    }))
+                }
+            });
+
+            info!("listening on {}", listener.local_addr().unwrap());
+
+            Server::from_tcp(listener)
+                .expect("can't listen")
+                .serve(make_service)
+                .await
+                .expect("running server failed");
+        }
+        (Listener::Tcp(listener), Some(tls)) => {
+            info!("listening on {} with TLS", listener.local_addr().unwrap());
+
+            let listener = TcpListener::from_std(listener).expect("can't listen");
+            run_api_tls(musicd, listener, tls).await;
+        }
+        // TLS on Unix sockets is refused at startup
+        (Listener::Unix(listener), _) => {
+            info!(
+                "listening on {}",
+                match listener.local_addr().unwrap().as_pathname() {
+                    Some(path) => path.to_string_lossy().into_owned(),
+                    None => "unnamed socket".to_string(),
+                }
+            );
+
+            let listener = UnixListener::from_std(listener).expect("can't listen");
+            run_api_unix(musicd, listener).await;
+        }
+    }
+}
+
+async fn serve_connections<IO>(
+    musicd: Arc<crate::Musicd>,
+    connections: tokio::sync::mpsc::Receiver<Result<IO, std::io::Error>>,
+) where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let make_service = make_service_fn(move |_connection: &IO| {
         let musicd = musicd.clone();
         async move {
             Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
@@ -76,18 +144,139 @@ pub async fn run_api(musicd: Arc<crate::Musicd>, bind: SocketAddr) {
         }
     });
 
-    info!("listening on {}", bind);
+    Server::builder(hyper::server::accept::from_stream(connections))
+        .serve(make_service)
+        .await
+        .expect("running server failed");
+}
+
+// Persistent errors such as running out of file descriptors would otherwise
+// make the accept loop spin
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+// Clients not completing the handshake in time are dropped so that they don't
+// hold on to their file descriptors
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn run_api_tls(musicd: Arc<crate::Musicd>, mut listener: TcpListener, tls: TlsReloader) {
+    let (sender, receiver) =
+        tokio::sync::mpsc::channel::<Result<TlsStream<TcpStream>, std::io::Error>>(16);
+
+    tokio::spawn(tls.clone().reload_on_hangup());
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("accepting connection failed: {}", e);
+                    tokio::time::delay_for(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+
+            let acceptor = tls.acceptor();
+            let mut sender = sender.clone();
+
+            // Handshakes run in their own tasks so that slow clients don't
+            // hold up accepting connections
+            tokio::spawn(async move {
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = sender.send(Ok(stream)).await;
+                    }
+                    Ok(Err(e)) => {
+                        debug!("TLS handshake with {} failed: {}", addr, e);
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake with {} timed out", addr);
+                    }
+                }
+            });
+        }
+    });
+
+    serve_connections(musicd, receiver).await;
+}
+
+async fn run_api_unix(musicd: Arc<crate::Musicd>, mut listener: UnixListener) {
+    let (mut sender, receiver) =
+        tokio::sync::mpsc::channel::<Result<UnixStream, std::io::Error>>(16);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _addr)) => {
+                    if sender.send(Ok(stream)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("accepting connection failed: {}", e);
+                    tokio::time::delay_for(ACCEPT_ERROR_DELAY).await;
+                }
+            }
+        }
+    });
+
+    serve_connections(musicd, receiver).await;
+}
+
+// Plain HTTP listener redirecting everything to the HTTPS listener
+pub async fn run_redirect(bind: SocketAddr, https_port: u16) {
+    let make_service = make_service_fn(move |_socket: &AddrStream| async move {
+        Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| async move {
+            Ok::<_, hyper::Error>(https_redirect(&req, https_port))
+        }))
+    });
+
+    info!("redirecting {} to HTTPS", bind);
 
     Server::bind(&bind)
         .serve(make_service)
         .await
-        .expect("running server failed");
+        .expect("running redirect server failed");
+}
+
+fn https_redirect(request: &Request<Body>, https_port: u16) -> Response<Body> {
+    let host = match request.headers().get("Host").and_then(|h| h.to_str().ok()) {
+        Some(h) => h,
+        None => {
+            return bad_request();
+        }
+    };
+
+    // Drop the port of the HTTP listener, IPv6 addresses are in brackets
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+
+    let location = if https_port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, https_port, path)
+    };
+
+    Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header("Location", location)
+        .body(Body::empty())
+        .unwrap()
 }
 
 static OK: &[u8] = b"OK";
 static BAD_REQUEST: &[u8] = b"Bad Request";
 static UNAUTHORIZED: &[u8] = b"Unauthorized";
+static FORBIDDEN: &[u8] = b"Forbidden";
 static NOT_FOUND: &[u8] = b"Not Found";
+static PAYLOAD_TOO_LARGE: &[u8] = b"Payload Too Large";
 static INTERNAL_SERVER_ERROR: &[u8] = b"Internal Server Error";
 
 fn bad_request() -> Response<Body> {
@@ -104,6 +293,13 @@ fn unauthorized() -> Response<Body> {
         .unwrap()
 }
 
+fn forbidden() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(FORBIDDEN.into())
+        .unwrap()
+}
+
 fn not_found() -> Response<Body> {
     Response::builder()
         .status(StatusCode::NOT_FOUND)
@@ -111,6 +307,13 @@ fn not_found() -> Response<Body> {
         .unwrap()
 }
 
+fn payload_too_large() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .body(PAYLOAD_TOO_LARGE.into())
+        .unwrap()
+}
+
 fn server_error() -> Response<Body> {
     Response::builder()
         .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -125,22 +328,56 @@ fn json_ok(json: &str) -> Response<Body> {
         .unwrap()
 }
 
-struct ApiRequest {
-    request: Request<Body>,
-    musicd: Arc<Musicd>,
-    query: HttpQuery,
-    cookies: HashMap<String, String>,
+pub(crate) struct ApiRequest {
+    pub request: Request<Body>,
+    pub musicd: Arc<Musicd>,
+    pub query: HttpQuery,
+    pub cookies: HashMap<String, String>,
+    pub user: Option<User>,
+    // Names of the roots visible to the user
+    pub roots: Vec<String>,
+}
+
+impl ApiRequest {
+    // Only available after authentication
+    pub fn user(&self) -> &User {
+        self.user.as_ref().expect("request not authenticated")
+    }
 }
 
+// Large enough for imported playlists
+const MAX_BODY_LENGTH: usize = 16 * 1024 * 1024;
+
 async fn process_request(
-    request: Request<Body>,
+    mut request: Request<Body>,
     musicd: Arc<Musicd>,
 ) -> Result<Response<Body>, hyper::Error> {
-    debug!("request {}", request.uri());
+    debug!("request {} {}", request.method(), request.uri().path());
+
+    let mut query_string = request.uri().query().unwrap_or_default().to_string();
+
+    // Form parameters of POST requests are merged into the query
+    let form = match request.headers().get("Content-Type") {
+        Some(content_type) => content_type
+            .as_bytes()
+            .starts_with(b"application/x-www-form-urlencoded"),
+        None => false,
+    };
+
+    if request.method() == Method::POST && form {
+        let body = match http_util::read_body(request.body_mut(), MAX_BODY_LENGTH).await? {
+            Some(b) => b,
+            None => {
+                return Ok(payload_too_large());
+            }
+        };
+        query_string.push('&');
+        query_string.push_str(&String::from_utf8_lossy(&body));
+    }
 
-    let query = HttpQuery::from(request.uri().query().unwrap_or_default());
+    let query = HttpQuery::from(&query_string);
 
-    let cookies = match crate::http_util::parse_cookies(request.headers()) {
+    let cookies = match http_util::parse_cookies(request.headers()) {
         Ok(c) => c,
         Err(e) => {
             debug!("invalid cookies {}", e);
@@ -148,11 +385,13 @@ async fn process_request(
         }
     };
 
-    let api_request = ApiRequest {
+    let mut api_request = ApiRequest {
         request,
         musicd,
         query,
         cookies,
+        user: None,
+        roots: Vec::new(),
     };
 
     let result = match (
@@ -160,7 +399,13 @@ async fn process_request(
         api_request.request.uri().path(),
     ) {
         (&Method::GET, "/api/musicd") => Some(api_musicd(&api_request)),
-        (&Method::GET, "/api/auth") => Some(api_auth(&api_request)),
+        (&Method::GET, "/api/auth") => Some(api_auth_status(&api_request)),
+        (&Method::POST, "/api/auth") => Some(api_auth(&api_request)),
+        (&Method::POST, "/api/logout") => Some(api_logout(&api_request)),
+        (&Method::GET, "/share") => Some(res_share(&api_request)),
+        (&Method::GET, "/api/share") => Some(api_share(&mut api_request)),
+        (&Method::GET, "/api/share_stream") => Some(api_share_stream(&mut api_request)),
+        (&Method::GET, "/api/share_image") => Some(api_share_image(&mut api_request)),
         _ => None,
     };
 
@@ -171,29 +416,101 @@ async fn process_request(
         };
     }
 
-    if let Some(auth_password) = api_request.cookies.get("musicd2-auth") {
-        if !api_request.musicd.password.is_empty() && api_request.musicd.password != *auth_password
-        {
-            debug!("invalid auth");
+    // Subsonic clients authenticate with their own scheme
+    if api_request.request.uri().path().starts_with("/rest/") {
+        return match subsonic::process_request(&mut api_request) {
+            Ok(res) => Ok(res),
+            Err(_e) => Ok(server_error()),
+        };
+    }
+
+    // UPnP clients don't authenticate, browsing uses the configured token
+    if api_request.request.uri().path().starts_with("/upnp/") {
+        return match upnp::process_request(&mut api_request).await {
+            Ok(res) => Ok(res),
+            Err(_e) => Ok(server_error()),
+        };
+    }
+
+    let (user, api_token_id) = match authenticate(&api_request) {
+        Ok(Some(a)) => a,
+        Ok(None) => {
+            debug!("unauthorized");
             return Ok(unauthorized());
         }
-    }
+        Err(_e) => return Ok(server_error()),
+    };
+
+    api_request.roots = match api_request
+        .musicd
+        .store()
+        .allowed_roots(&user, api_token_id)
+    {
+        Ok(r) => r,
+        Err(_e) => return Ok(server_error()),
+    };
+
+    api_request.user = Some(user);
 
     let result = match (
         api_request.request.method(),
         api_request.request.uri().path(),
     ) {
         (&Method::GET, "/api/audio_stream") => api_audio_stream(&api_request),
+        (&Method::GET, "/api/mpd_stream") => api_mpd_stream(&mut api_request),
         (&Method::GET, "/api/image_file") => api_image_file(&api_request),
         (&Method::GET, "/api/track_lyrics") => api_track_lyrics(&api_request).await,
+        (&Method::GET, "/api/track_waveform") => api_track_waveform(&api_request),
+        (&Method::GET, "/api/cue") => api_cue(&api_request),
         (&Method::GET, "/api/nodes") => api_nodes(&api_request),
         (&Method::GET, "/api/tracks") => api_tracks(&api_request),
         (&Method::GET, "/api/artists") => api_artists(&api_request),
         (&Method::GET, "/api/albums") => api_albums(&api_request),
         (&Method::GET, "/api/images") => api_images(&api_request),
+        (&Method::GET, "/api/events") => api_events(&api_request),
         (&Method::GET, "/api/scan") => api_scan(&api_request),
         (&Method::POST, "/api/scan") => api_scan(&api_request),
-        (&Method::GET, "/share") => res_share(&api_request),
+        (&Method::GET, "/api/api_tokens") => api_api_tokens(&api_request),
+        (&Method::POST, "/api/api_tokens") => api_api_token_create(&api_request),
+        (&Method::DELETE, "/api/api_tokens") => api_api_token_delete(&api_request),
+        (&Method::GET, "/api/users") => api_users(&api_request),
+        (&Method::POST, "/api/users") => api_user_create(&api_request),
+        (&Method::DELETE, "/api/users") => api_user_delete(&api_request),
+        (&Method::GET, "/api/roots") => api_roots(&api_request),
+        (&Method::POST, "/api/root_users") => api_root_user_add(&api_request),
+        (&Method::DELETE, "/api/root_users") => api_root_user_remove(&api_request),
+        (&Method::POST, "/api/track_play") => api_track_play(&api_request),
+        (&Method::GET, "/api/history") => api_history(&api_request),
+        (&Method::GET, "/api/history_top") => api_history_top(&api_request),
+        (&Method::GET, "/api/history_summary") => api_history_summary(&api_request),
+        (&Method::GET, "/api/radio") => api_radio(&api_request),
+        (&Method::POST, "/api/rating") => api_rating(&api_request),
+        (&Method::GET, "/api/lists") => api_lists(&api_request),
+        (&Method::POST, "/api/lists") => api_list_create(&api_request),
+        (&Method::DELETE, "/api/lists") => api_list_delete(&api_request),
+        (&Method::POST, "/api/list_import") => api_list_import(&mut api_request).await,
+        (&Method::GET, "/api/list_export") => api_list_export(&api_request),
+        (&Method::POST, "/api/list_rules") => api_list_rules(&api_request),
+        (&Method::POST, "/api/list_tracks") => api_list_track_add(&api_request),
+        (&Method::DELETE, "/api/list_tracks") => api_list_track_remove(&api_request),
+        (&Method::GET, "/api/bookmarks") => api_bookmarks(&mut api_request),
+        (&Method::POST, "/api/bookmarks") => api_bookmark_save(&api_request),
+        (&Method::DELETE, "/api/bookmarks") => api_bookmark_delete(&api_request),
+        (&Method::GET, "/api/playback_sessions") => api_playback_sessions(&api_request),
+        (&Method::GET, "/api/playback_session") => api_playback_session(&api_request),
+        (&Method::POST, "/api/playback_session") => api_playback_session_save(&api_request),
+        (&Method::DELETE, "/api/playback_session") => api_playback_session_delete(&api_request),
+        (&Method::POST, "/api/playback_session_handoff") => {
+            api_playback_session_handoff(&api_request)
+        }
+        (&Method::GET, "/api/shares") => api_shares(&api_request),
+        (&Method::POST, "/api/shares") => api_share_create(&api_request),
+        (&Method::DELETE, "/api/shares") => api_share_delete(&api_request),
+        (&Method::POST, "/api/subsonic_password") => api_subsonic_password_create(&api_request),
+        (&Method::DELETE, "/api/subsonic_password") => api_subsonic_password_delete(&api_request),
+        (&Method::GET, "/api/scrobbling") => api_scrobbling(&api_request),
+        (&Method::POST, "/api/scrobbling") => api_scrobbling_save(&api_request),
+        (&Method::DELETE, "/api/scrobbling") => api_scrobbling_delete(&api_request),
         _ => Ok(not_found()),
     };
 
@@ -203,29 +520,238 @@ async fn process_request(
     }
 }
 
+fn bearer_token(r: &ApiRequest) -> Option<&str> {
+    let authorization = r.request.headers().get("Authorization")?.to_str().ok()?;
+
+    if authorization.len() > 7 && authorization[..7].eq_ignore_ascii_case("bearer ") {
+        Some(authorization[7..].trim())
+    } else {
+        None
+    }
+}
+
+// Accepts either a bearer API token or a session cookie, returns the user and
+// the API token id if one was used
+fn authenticate(r: &ApiRequest) -> Result<Option<(User, Option<i64>)>, Error> {
+    let store = r.musicd.store();
+
+    if !store.auth_required()? {
+        return Ok(store.default_user()?.map(|u| (u, None)));
+    }
+
+    if let Some(token) = bearer_token(r) {
+        return Ok(store
+            .use_api_token(&auth::hash_token(token), auth::now())?
+            .map(|(u, api_token_id)| (u, Some(api_token_id))));
+    }
+
+    // UPnP renderers can't set headers, their media URLs are signed instead
+    if r.query.get_str("upnp_sig").is_some() {
+        return upnp::authenticate_media(r);
+    }
+
+    if let Some(token) = r.cookies.get(auth::SESSION_COOKIE) {
+        return Ok(store
+            .session_user(&auth::hash_token(token), auth::now())?
+            .map(|u| (u, None)));
+    }
+
+    Ok(None)
+}
+
+// Items in roots hidden from the user are treated as missing
+pub(crate) fn node_allowed(r: &ApiRequest, index: &Index, node_id: i64) -> Result<bool, Error> {
+    let node = match index.node(node_id)? {
+        Some(n) => n,
+        None => return Ok(false),
+    };
+
+    Ok(match node.path.iter().next().and_then(|c| c.to_str()) {
+        Some(root_name) => r.roots.iter().any(|name| name == root_name),
+        None => false,
+    })
+}
+
+fn session_cookie(r: &ApiRequest, value: &str, max_age: i64) -> String {
+    let mut cookie = format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+        auth::SESSION_COOKIE,
+        value,
+        max_age
+    );
+
+    if secure(r) {
+        cookie.push_str("; Secure");
+    }
+
+    cookie
+}
+
+// Served with TLS directly or behind a TLS terminating proxy
+fn secure(r: &ApiRequest) -> bool {
+    r.musicd.tls
+        || match r.request.headers().get("X-Forwarded-Proto") {
+            Some(proto) => proto.as_bytes().eq_ignore_ascii_case(b"https"),
+            None => false,
+        }
+}
+
 fn api_musicd(_: &ApiRequest) -> Result<Response<Body>, Error> {
     Ok(json_ok("{}"))
 }
 
+// Tells whether the client is logged in
+fn api_auth_status(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    if r.musicd.store().auth_required()? && authenticate(r)?.is_none() {
+        return Ok(unauthorized());
+    }
+
+    Ok(Response::builder().body(OK.into()).unwrap())
+}
+
+// Passwords are only accepted in POST bodies, URLs end up in logs and browser
+// history
 fn api_auth(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let store = r.musicd.store();
+
+    if !store.auth_required()? {
+        return Ok(Response::builder().body(OK.into()).unwrap());
+    }
+
+    let url_query = HttpQuery::from(r.request.uri().query().unwrap_or_default());
+    if url_query.get_str("password").is_some() {
+        return Ok(bad_request());
+    }
+
+    // Logging in without a user name is the legacy single password login
+    let name = r.query.get_str("user").unwrap_or("admin");
     let password = r.query.get_str("password").unwrap_or_default();
-    if r.musicd.password != password {
+
+    let user = match store.user_by_name(name)? {
+        Some(u) => u,
+        None => {
+            return Ok(unauthorized());
+        }
+    };
+
+    let valid = match &user.password_hash {
+        Some(password_hash) => auth::verify_password(password_hash, password),
+        None => false,
+    };
+
+    if !valid {
         return Ok(unauthorized());
     }
 
+    let token = auth::generate_token();
+    let now = auth::now();
+
+    store.create_session(
+        user.user_id,
+        &auth::hash_token(&token),
+        now,
+        now + r.musicd.session_lifetime,
+    )?;
+
+    Ok(Response::builder()
+        .header(
+            "Set-Cookie",
+            session_cookie(r, &token, r.musicd.session_lifetime),
+        )
+        .body(OK.into())
+        .unwrap())
+}
+
+fn api_logout(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    if let Some(token) = r.cookies.get(auth::SESSION_COOKIE) {
+        r.musicd.store().delete_session(&auth::hash_token(token))?;
+    }
+
     Ok(Response::builder()
-        .header("Set-Cookie", format!("musicd2-auth={}", r.musicd.password))
+        .header("Set-Cookie", session_cookie(r, "", 0))
         .body(OK.into())
         .unwrap())
 }
 
+fn api_api_tokens(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let items = r.musicd.store().api_tokens(r.user().user_id)?;
+
+    Ok(json_ok(
+        &json!({
+            "total": items.len(),
+            "items": items
+        })
+        .to_string(),
+    ))
+}
+
+fn api_api_token_create(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let name = match r.query.get_str("name") {
+        Some(n) if !n.is_empty() => n,
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    // Tokens can be limited to a subset of the roots visible to the user
+    let roots: Vec<String> = match r.query.get_str("roots") {
+        Some(roots) => roots.split(',').map(|root| root.to_string()).collect(),
+        None => Vec::new(),
+    };
+
+    if roots.iter().any(|root| !r.roots.contains(root)) {
+        return Ok(bad_request());
+    }
+
+    let token = auth::generate_token();
+
+    let api_token = r.musicd.store().create_api_token(
+        r.user().user_id,
+        name,
+        &auth::hash_token(&token),
+        auth::now(),
+        &roots,
+    )?;
+
+    // The token itself can't be retrieved later
+    Ok(json_ok(
+        &json!({
+            "api_token_id": api_token.api_token_id,
+            "name": api_token.name,
+            "created": api_token.created,
+            "roots": api_token.roots,
+            "token": token
+        })
+        .to_string(),
+    ))
+}
+
+fn api_api_token_delete(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let api_token_id = match r.query.get_i64("api_token_id") {
+        Some(id) => id,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    if !r
+        .musicd
+        .store()
+        .delete_api_token(r.user().user_id, api_token_id)?
+    {
+        return Ok(not_found());
+    }
+
+    Ok(Response::builder().body(OK.into()).unwrap())
+}
+
 static CODECS: &[(&str, &str)] = &[
     ("mp3", "audio/mpeg"),
     ("opus", "audio/ogg"),
     ("ogg", "audio/ogg"),
 ];
 
-fn api_audio_stream(r: &ApiRequest) -> Result<Response<Body>, Error> {
+pub(crate) fn api_audio_stream(r: &ApiRequest) -> Result<Response<Body>, Error> {
     let track_id = match r.query.get_i64("track_id") {
         Some(id) => id,
         None => {
@@ -241,7 +767,7 @@ fn api_audio_stream(r: &ApiRequest) -> Result<Response<Body>, Error> {
         }
     };
 
-    let start = r.query.get_i64("start").unwrap_or(0) as f64;
+    let mut start = r.query.get_i64("start").unwrap_or(0) as f64;
     if start < 0f64 {
         return Ok(bad_request());
     }
@@ -255,38 +781,72 @@ fn api_audio_stream(r: &ApiRequest) -> Result<Response<Body>, Error> {
         }
     };
 
-    let node = index.node(track.node_id)?.unwrap();
-    let fs_path = index.map_fs_path(&node.path).unwrap();
+    if !node_allowed(r, &index, track.node_id)? {
+        return Ok(not_found());
+    }
 
-    let audio_stream = AudioStream::open(
-        &fs_path,
-        track.stream_index as i32,
-        track.track_index.unwrap_or(0) as i32,
-        start + track.start.unwrap_or_default(),
-        if track.start.is_some() {
-            track.length - start
-        } else {
-            0f64
-        },
-        target_codec.0,
-    );
+    // An explicit start overrides the bookmark
+    if r.query.get_str("start").is_none() && r.query.get_i64("resume").unwrap_or(0) != 0 {
+        if let Some(user) = &r.user {
+            if let Some(position) = r.musicd.store().bookmark_position(user.user_id, &track)? {
+                if position < track.length {
+                    start = position;
+                }
+            }
+        }
+    }
 
-    let audio_stream = match audio_stream {
-        Some(s) => s,
-        None => {
-            error!(
-                "can't open audio stream from '{}'",
-                fs_path.to_string_lossy()
-            );
-            return Ok(server_error());
+    // Cue tracks continuing into the next files are streamed one segment after
+    // another, the start may fall into any of them
+    let segments = index.track_segments(&track)?;
+    let last = segments.len() - 1;
+
+    let mut audio_streams = Vec::new();
+    let mut offset = start;
+
+    for (i, segment) in segments.iter().enumerate() {
+        if i < last && offset >= segment.length {
+            offset -= segment.length;
+            continue;
         }
-    };
+
+        let node = index.node(segment.node_id)?.unwrap();
+        let fs_path = index.map_fs_path(&node.path).unwrap();
+
+        let audio_stream = AudioStream::open(
+            &fs_path,
+            segment.stream_index as i32,
+            segment.track_index.unwrap_or(0) as i32,
+            segment.start + offset,
+            if track.start.is_some() {
+                segment.length - offset
+            } else {
+                0f64
+            },
+            target_codec.0,
+        );
+
+        match audio_stream {
+            Some(s) => audio_streams.push(s),
+            None => {
+                error!(
+                    "can't open audio stream from '{}'",
+                    fs_path.to_string_lossy()
+                );
+                return Ok(server_error());
+            }
+        }
+
+        offset = 0f64;
+    }
 
     let (sender, receiver) =
         tokio::sync::mpsc::channel::<Result<Vec<u8>, Box<dyn StdError + Send + Sync>>>(5);
 
     tokio::spawn(async move {
-        audio_stream.execute(sender).await;
+        for audio_stream in audio_streams {
+            audio_stream.execute(sender.clone()).await;
+        }
     });
 
     Ok(Response::builder()
@@ -295,7 +855,22 @@ fn api_audio_stream(r: &ApiRequest) -> Result<Response<Body>, Error> {
         .unwrap())
 }
 
-fn api_image_file(r: &ApiRequest) -> Result<Response<Body>, Error> {
+// Streams the current track of the user's MPD player from its position
+fn api_mpd_stream(r: &mut ApiRequest) -> Result<Response<Body>, Error> {
+    let (track_id, elapsed) = match r.musicd.players.current_track(r.user().user_id) {
+        Some(t) => t,
+        None => {
+            return Ok(not_found());
+        }
+    };
+
+    r.query.set("track_id", &track_id.to_string());
+    r.query.set("start", &(elapsed as i64).to_string());
+
+    api_audio_stream(r)
+}
+
+pub(crate) fn api_image_file(r: &ApiRequest) -> Result<Response<Body>, Error> {
     let image_id = match r.query.get_i64("image_id") {
         Some(id) => id,
         None => {
@@ -314,6 +889,10 @@ fn api_image_file(r: &ApiRequest) -> Result<Response<Body>, Error> {
         }
     };
 
+    if !node_allowed(r, &index, image.node_id)? {
+        return Ok(not_found());
+    }
+
     let cache_str = format!("image:{}_{}", image_id, size);
 
     let cache = r.musicd.cache();
@@ -381,15 +960,18 @@ async fn api_track_lyrics(r: &ApiRequest) -> Result<Response<Body>, Error> {
     let (track, lyrics) = {
         let index = r.musicd.index();
 
-        (
-            match index.track(track_id)? {
-                Some(t) => t,
-                None => {
-                    return Ok(not_found());
-                }
-            },
-            index.track_lyrics(track_id)?,
-        )
+        let track = match index.track(track_id)? {
+            Some(t) => t,
+            None => {
+                return Ok(not_found());
+            }
+        };
+
+        if !node_allowed(r, &index, track.node_id)? {
+            return Ok(not_found());
+        }
+
+        (track, index.track_lyrics(track_id)?)
     };
 
     let lyrics = match lyrics {
@@ -434,20 +1016,572 @@ async fn api_track_lyrics(r: &ApiRequest) -> Result<Response<Body>, Error> {
     ))
 }
 
-fn api_nodes(r: &ApiRequest) -> Result<Response<Body>, Error> {
-    let (total, items) = crate::query::query_nodes(&r.musicd.index(), &r.query)?;
-
-    Ok(json_ok(
-        &json!({
-            "total": total,
-            "items": items
-        })
-        .to_string(),
-    ))
-}
-
-fn api_tracks(r: &ApiRequest) -> Result<Response<Body>, Error> {
-    let (total, items) = crate::query::query_tracks(&r.musicd.index(), &r.query)?;
+const WAVEFORM_DEFAULT_POINTS: i64 = 1000;
+const WAVEFORM_MAX_POINTS: i64 = 10000;
+
+fn api_track_waveform(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let track_id = match r.query.get_i64("track_id") {
+        Some(id) => id,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    let points = r.query.get_i64("points").unwrap_or(WAVEFORM_DEFAULT_POINTS);
+    if !(1..=WAVEFORM_MAX_POINTS).contains(&points) {
+        return Ok(bad_request());
+    }
+
+    let binary = match r.query.get_str("format").unwrap_or("json") {
+        "json" => false,
+        "binary" => true,
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    let index = r.musicd.index();
+
+    let track = match index.track(track_id)? {
+        Some(t) => t,
+        None => {
+            return Ok(not_found());
+        }
+    };
+
+    if !node_allowed(r, &index, track.node_id)? {
+        return Ok(not_found());
+    }
+
+    let segments = index.track_segments(&track)?;
+
+    // Track ids change when files are rescanned, so the audio is identified by
+    // the files and positions of its segments
+    let mut nodes = Vec::new();
+    let mut cache_str = format!("waveform:{}", points);
+
+    for segment in &segments {
+        let node = index.node(segment.node_id)?.unwrap();
+
+        cache_str.push_str(&format!(
+            "_{}_{}_{}_{}_{}_{}",
+            node.node_id,
+            node.modified,
+            segment.stream_index,
+            segment.track_index.unwrap_or(0),
+            segment.start,
+            segment.length
+        ));
+
+        nodes.push(node);
+    }
+
+    let cache = r.musicd.cache();
+
+    // Peaks are stored as interleaved min/max pairs of signed bytes
+    let peak_data = if let Some(peak_data) = cache.get_blob(&cache_str)? {
+        peak_data
+    } else {
+        let points = points as usize;
+        let last = segments.len() - 1;
+
+        let mut min = Vec::new();
+        let mut max = Vec::new();
+        let mut segment_end = 0f64;
+
+        // Points are divided between the segments of cue tracks continuing into
+        // the next files by their lengths, the last one gets the rest
+        for (i, (segment, node)) in segments.iter().zip(nodes).enumerate() {
+            segment_end += segment.length;
+
+            let segment_points = if i == last {
+                points.saturating_sub(min.len())
+            } else if track.length > 0f64 {
+                ((points as f64 * segment_end / track.length).round() as usize)
+                    .min(points)
+                    .saturating_sub(min.len())
+            } else {
+                0
+            };
+
+            if segment_points == 0 {
+                continue;
+            }
+
+            let fs_path = match index.map_fs_path(&node.path) {
+                Some(p) => p,
+                None => {
+                    return Ok(not_found());
+                }
+            };
+
+            debug!(
+                "computing {} waveform points from '{}'",
+                segment_points,
+                fs_path.to_string_lossy()
+            );
+
+            let (segment_min, segment_max) = match media::media_waveform_read(
+                &fs_path,
+                segment.stream_index as i32,
+                segment.track_index.unwrap_or(0) as i32,
+                segment.start,
+                segment.length,
+                segment_points,
+            ) {
+                Some(w) => w,
+                None => {
+                    error!("can't read waveform from '{}'", fs_path.to_string_lossy());
+                    return Ok(server_error());
+                }
+            };
+
+            min.extend(segment_min);
+            max.extend(segment_max);
+        }
+
+        let quantize = |v: f32| (v.clamp(-1f32, 1f32) * 127f32).round() as i8 as u8;
+
+        let peak_data: Vec<u8> = min
+            .iter()
+            .zip(max.iter())
+            .flat_map(|(&min, &max)| vec![quantize(min), quantize(max)])
+            .collect();
+
+        cache.set_blob(&cache_str, &peak_data)?;
+
+        peak_data
+    };
+
+    if binary {
+        return Ok(Response::builder()
+            .header("Content-Type", "application/octet-stream")
+            .body(peak_data.into())
+            .unwrap());
+    }
+
+    let dequantize = |v: u8| f32::from(v as i8) / 127f32;
+
+    Ok(json_ok(
+        &json!({
+            "track_id": track_id,
+            "points": peak_data.len() / 2,
+            "min": peak_data.iter().step_by(2).map(|&v| dequantize(v)).collect::<Vec<_>>(),
+            "max": peak_data.iter().skip(1).step_by(2).map(|&v| dequantize(v)).collect::<Vec<_>>(),
+        })
+        .to_string(),
+    ))
+}
+
+fn cue_file_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "mp3" => "MP3",
+        "aif" | "aiff" => "AIFF",
+        _ => "WAVE",
+    }
+}
+
+fn api_cue(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let index = r.musicd.index();
+
+    let (title, performer, tracks) = if let Some(album_id) = r.query.get_i64("album_id") {
+        let album = match index.album(album_id)? {
+            Some(a) => a,
+            None => {
+                return Ok(not_found());
+            }
+        };
+
+        (
+            album.name,
+            album.artist_name.unwrap_or_default(),
+            index.tracks_by_album(album_id)?,
+        )
+    } else if let Some(track_ids) = r.query.get_str("track_ids") {
+        let mut tracks = Vec::new();
+
+        for track_id in track_ids.split(',') {
+            let track_id: i64 = match track_id.parse() {
+                Ok(id) => id,
+                Err(_) => {
+                    return Ok(bad_request());
+                }
+            };
+
+            match index.track(track_id)? {
+                Some(t) if node_allowed(r, &index, t.node_id)? => tracks.push(t),
+                _ => {
+                    return Ok(not_found());
+                }
+            }
+        }
+
+        (
+            r.query.get_str("title").unwrap_or_default().to_string(),
+            String::new(),
+            tracks,
+        )
+    } else {
+        return Ok(bad_request());
+    };
+
+    let mut visible_tracks = Vec::new();
+
+    for track in tracks {
+        // Chapter positions aren't stored in the index, so chapters can't be described
+        if track.track_index.unwrap_or(0) == 0 && node_allowed(r, &index, track.node_id)? {
+            visible_tracks.push(track);
+        }
+    }
+
+    let tracks = visible_tracks;
+
+    if tracks.is_empty() {
+        return Ok(not_found());
+    }
+
+    if tracks.len() > 99 {
+        return Ok(bad_request());
+    }
+
+    let mut segments = Vec::new();
+    let mut nodes: HashMap<i64, PathBuf> = HashMap::new();
+
+    for track in tracks.iter() {
+        let track_segments = index.track_segments(track)?;
+
+        for segment in track_segments.iter() {
+            if let std::collections::hash_map::Entry::Vacant(e) = nodes.entry(segment.node_id) {
+                let node = index.node(segment.node_id)?.unwrap();
+                e.insert(node.path);
+            }
+        }
+
+        segments.push(track_segments);
+    }
+
+    // FILE paths are relative to the closest directory containing every file
+    let mut base = nodes[&tracks[0].node_id]
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .to_path_buf();
+
+    for path in nodes.values() {
+        while !path.starts_with(&base) && base.pop() {}
+    }
+
+    let cue_file = |node_id: i64| {
+        let path = &nodes[&node_id];
+
+        cue::File {
+            path: Rc::from(
+                path.strip_prefix(&base)
+                    .unwrap_or(path)
+                    .to_string_lossy()
+                    .as_ref(),
+            ),
+            file_type: Rc::from(cue_file_type(path)),
+            tracks: Vec::new(),
+        }
+    };
+
+    let mut files: Vec<cue::File> = Vec::new();
+    let mut previous: Option<(i64, f64)> = None;
+
+    for (i, track) in tracks.iter().enumerate() {
+        let start = track.start.unwrap_or_default();
+
+        // Tracks continue in the same FILE only when moving forward in it
+        let same_file = match previous {
+            Some((node_id, previous_start)) => node_id == track.node_id && start > previous_start,
+            None => false,
+        };
+
+        if !same_file {
+            files.push(cue_file(track.node_id));
+        }
+
+        let file = files.len() - 1;
+
+        files[file].tracks.push(cue::Track {
+            number: i as u32 + 1,
+            track_type: Rc::from("AUDIO"),
+            title: Rc::from(track.title.as_str()),
+            performer: Rc::from(track.artist_name.as_str()),
+            songwriter: None,
+            isrc: None,
+            flags: Vec::new(),
+            pregap: None,
+            postgap: None,
+            indexes: vec![cue::Index {
+                number: 1,
+                position: start,
+                file,
+            }],
+            start,
+        });
+
+        previous = Some((track.node_id, start));
+
+        // Files the track continues into follow, the next track may start in them
+        for segment in segments[i].iter().skip(1) {
+            files.push(cue_file(segment.node_id));
+            previous = Some((segment.node_id, 0f64));
+        }
+    }
+
+    let cue_text = cue::write_cue(&cue::Cue {
+        title: Rc::from(title.as_str()),
+        performer: Rc::from(performer.as_str()),
+        songwriter: None,
+        catalog: None,
+        genre: None,
+        date: None,
+        disc_id: None,
+        comment: None,
+        files,
+    });
+
+    Ok(Response::builder()
+        .header("Content-Type", "application/x-cue; charset=utf-8")
+        .header("Content-Disposition", attachment(&title, "cue"))
+        .body(cue_text.into())
+        .unwrap())
+}
+
+fn attachment(title: &str, extension: &str) -> String {
+    let file_name = if title.is_empty() {
+        "tracks".to_string()
+    } else {
+        title.replace(
+            |c: char| c == '"' || c == '/' || c == '\\' || c.is_control(),
+            "_",
+        )
+    };
+
+    format!("attachment; filename=\"{}.{}\"", file_name, extension)
+}
+
+// Index tracks a playlist entry refers to, relative paths are resolved against
+// the index path `base` or taken as index paths
+fn resolve_playlist_entry(
+    r: &ApiRequest,
+    index: &Index,
+    base: Option<&Path>,
+    location: &str,
+) -> Result<Vec<Track>, Error> {
+    let path = match playlist::parse_location(location) {
+        playlist::Location::Path(path) => path,
+        playlist::Location::TrackId(track_id) => {
+            return Ok(match index.track(track_id)? {
+                Some(t) if node_allowed(r, index, t.node_id)? => vec![t],
+                _ => Vec::new(),
+            });
+        }
+        playlist::Location::Unknown => return Ok(Vec::new()),
+    };
+
+    let candidates = if path.is_absolute() {
+        index.map_index_path(&path).into_iter().collect()
+    } else {
+        base.map(|b| b.join(&path))
+            .into_iter()
+            .chain(std::iter::once(path))
+            .collect::<Vec<_>>()
+    };
+
+    for candidate in candidates {
+        if let Some(node) = index.node_by_path(&playlist::normalize(&candidate))? {
+            if node_allowed(r, index, node.node_id)? {
+                return Ok(index.tracks_by_node(node.node_id)?);
+            }
+        }
+    }
+
+    Ok(Vec::new())
+}
+
+// Creates a list from an M3U, PLS or XSPF playlist in the request body or the
+// "playlist" form field
+async fn api_list_import(r: &mut ApiRequest) -> Result<Response<Body>, Error> {
+    let text = match r.query.get_str("playlist") {
+        Some(p) => p.to_string(),
+        None => match http_util::read_body(r.request.body_mut(), MAX_BODY_LENGTH).await? {
+            Some(body) => playlist::decode(&body),
+            None => {
+                return Ok(payload_too_large());
+            }
+        },
+    };
+
+    let format = match r.query.get_str("format") {
+        Some(name) => match playlist::Format::from_name(name) {
+            Some(f) => f,
+            None => {
+                return Ok(bad_request());
+            }
+        },
+        None => playlist::Format::detect(&text),
+    };
+
+    let imported = playlist::parse(&text, format);
+
+    let name = match r.query.get_str("name") {
+        Some(n) if !n.is_empty() => n.to_string(),
+        _ => match imported.title {
+            Some(t) if !t.is_empty() => t,
+            _ => {
+                return Ok(bad_request());
+            }
+        },
+    };
+
+    let base = r.query.get_str("base").map(PathBuf::from);
+
+    let index = r.musicd.index();
+    let store = r.musicd.store();
+    let user_id = r.user().user_id;
+
+    let mut tracks = Vec::new();
+    let mut unresolved = Vec::new();
+
+    for entry in imported.entries {
+        let entry_tracks = resolve_playlist_entry(r, &index, base.as_deref(), &entry.location)?;
+
+        if entry_tracks.is_empty() {
+            unresolved.push(entry.location);
+        }
+
+        tracks.extend(entry_tracks);
+    }
+
+    let list = store.import_list(user_id, &name, &tracks)?;
+
+    r.musicd
+        .events
+        .emit(Event::user(user_id, "list_created", json!(list)));
+
+    Ok(json_ok(
+        &json!({
+            "list": list,
+            "unresolved": unresolved
+        })
+        .to_string(),
+    ))
+}
+
+// Writes a list or album as M3U8 or XSPF, locations being either file system
+// paths or audio stream URLs
+fn api_list_export(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let index = r.musicd.index();
+    let user_id = r.user().user_id;
+
+    let (title, query) = if let Some(list_id) = r.query.get_i64("list_id") {
+        let list = match r.musicd.store().list(user_id, list_id)? {
+            Some(l) => l,
+            None => {
+                return Ok(not_found());
+            }
+        };
+
+        (list.name, format!("list_id={}", list_id))
+    } else if let Some(album_id) = r.query.get_i64("album_id") {
+        let album = match index.album(album_id)? {
+            Some(a) => a,
+            None => {
+                return Ok(not_found());
+            }
+        };
+
+        (album.name, format!("album_id={}", album_id))
+    } else {
+        return Ok(bad_request());
+    };
+
+    let (_, tracks) =
+        crate::query::query_tracks(&index, &HttpQuery::from(&query), user_id, &r.roots)?;
+
+    if tracks.is_empty() && r.query.get_i64("album_id").is_some() {
+        return Ok(not_found());
+    }
+
+    // URLs never carry the token of the request, anyone with the file could
+    // use the account. Players authenticate on their own.
+    let host = r
+        .request
+        .headers()
+        .get("Host")
+        .and_then(|h| h.to_str().ok());
+
+    let base_url = match r.query.get_str("locations") {
+        Some("urls") => match host {
+            Some(host) => Some(format!(
+                "{}://{}",
+                if secure(r) { "https" } else { "http" },
+                host
+            )),
+            None => {
+                return Ok(bad_request());
+            }
+        },
+        Some("paths") | None => None,
+        Some(_) => {
+            return Ok(bad_request());
+        }
+    };
+
+    // Tracks in roots without a file system path are left out
+    let entries: Vec<playlist::Entry> = tracks
+        .into_iter()
+        .filter_map(|t| {
+            let location = match &base_url {
+                Some(base_url) => format!("{}/api/audio_stream?track_id={}", base_url, t.track_id),
+                None => index
+                    .map_fs_path(Path::new(&t.node_path))?
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+
+            Some(playlist::Entry {
+                location,
+                title: Some(t.title),
+                artist: Some(t.artist_name),
+                album: Some(t.album_name),
+                length: Some(t.length),
+            })
+        })
+        .collect();
+
+    let (content_type, extension, text) = match r.query.get_str("format") {
+        Some("m3u8") | None => (
+            "audio/x-mpegurl; charset=utf-8",
+            "m3u8",
+            playlist::write_m3u8(&entries),
+        ),
+        Some("xspf") => (
+            "application/xspf+xml; charset=utf-8",
+            "xspf",
+            playlist::write_xspf(&title, &entries),
+        ),
+        Some(_) => {
+            return Ok(bad_request());
+        }
+    };
+
+    Ok(Response::builder()
+        .header("Content-Type", content_type)
+        .header("Content-Disposition", attachment(&title, extension))
+        .body(text.into())
+        .unwrap())
+}
+
+fn api_nodes(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (total, items) = crate::query::query_nodes(&r.musicd.index(), &r.query, &r.roots)?;
 
     Ok(json_ok(
         &json!({
@@ -458,8 +1592,9 @@ fn api_tracks(r: &ApiRequest) -> Result<Response<Body>, Error> {
     ))
 }
 
-fn api_artists(r: &ApiRequest) -> Result<Response<Body>, Error> {
-    let (total, items) = crate::query::query_artists(&r.musicd.index(), &r.query)?;
+fn api_tracks(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (total, items) =
+        crate::query::query_tracks(&r.musicd.index(), &r.query, r.user().user_id, &r.roots)?;
 
     Ok(json_ok(
         &json!({
@@ -470,8 +1605,9 @@ fn api_artists(r: &ApiRequest) -> Result<Response<Body>, Error> {
     ))
 }
 
-fn api_albums(r: &ApiRequest) -> Result<Response<Body>, Error> {
-    let (total, items) = crate::query::query_albums(&r.musicd.index(), &r.query)?;
+fn api_artists(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (total, items) =
+        crate::query::query_artists(&r.musicd.index(), &r.query, r.user().user_id, &r.roots)?;
 
     Ok(json_ok(
         &json!({
@@ -482,8 +1618,9 @@ fn api_albums(r: &ApiRequest) -> Result<Response<Body>, Error> {
     ))
 }
 
-fn api_images(r: &ApiRequest) -> Result<Response<Body>, Error> {
-    let (total, items) = crate::query::query_images(&r.musicd.index(), &r.query)?;
+fn api_albums(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (total, items) =
+        crate::query::query_albums(&r.musicd.index(), &r.query, r.user().user_id, &r.roots)?;
 
     Ok(json_ok(
         &json!({
@@ -494,34 +1631,1736 @@ fn api_images(r: &ApiRequest) -> Result<Response<Body>, Error> {
     ))
 }
 
-fn api_scan(r: &ApiRequest) -> Result<Response<Body>, Error> {
-    if let Some(action) = r.query.get_str("action") {
-        match action {
-            "start" => r.musicd.scan_thread.start(r.musicd.index()),
-            "restart" => {
-                r.musicd.scan_thread.stop();
-                r.musicd.scan_thread.start(r.musicd.index());
-            }
-            "stop" => {
-                r.musicd.scan_thread.stop();
-            }
-            _ => {}
-        }
-    }
+fn api_images(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (total, items) = crate::query::query_images(&r.musicd.index(), &r.query, &r.roots)?;
 
     Ok(json_ok(
         &json!({
-            "running": r.musicd.scan_thread.is_running()
+            "total": total,
+            "items": items
         })
         .to_string(),
     ))
 }
 
-static SHARE_HTML: &[u8] = include_bytes!("./share.html");
+const EVENTS_KEEPALIVE: Duration = Duration::from_secs(30);
+
+// Server-Sent Events stream of the events visible to the user
+fn api_events(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let musicd = r.musicd.clone();
+    let user_id = r.user().user_id;
+    let roots = r.roots.clone();
+    let bearer = bearer_token(r).map(auth::hash_token);
+    let session = r
+        .cookies
+        .get(auth::SESSION_COOKIE)
+        .map(|t| auth::hash_token(t));
+    let mut events = r.musicd.events.subscribe();
+
+    let (mut sender, receiver) =
+        tokio::sync::mpsc::channel::<Result<String, Box<dyn StdError + Send + Sync>>>(16);
+
+    // Comments keep proxies from timing out idle connections and detect
+    // disconnected clients
+    let mut keepalive = tokio::time::interval_at(
+        tokio::time::Instant::now() + EVENTS_KEEPALIVE,
+        EVENTS_KEEPALIVE,
+    );
 
-fn res_share(_r: &ApiRequest) -> Result<Response<Body>, Error> {
-    Ok(Response::builder()
-        .header("Content-Type", "text/html; charset=utf-8")
-        .body(SHARE_HTML.into())
-        .unwrap())
+    tokio::spawn(async move {
+        loop {
+            let message = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        if !event.visible_to(user_id, &roots) {
+                            continue;
+                        }
+                        event.to_sse()
+                    }
+                    Err(broadcast::RecvError::Lagged(_)) => Event::global("resync", json!({})).to_sse(),
+                    Err(broadcast::RecvError::Closed) => break,
+                },
+                _ = keepalive.tick() => {
+                    // The stream outlives the request, so the session or token
+                    // is checked again and a change of roots ends the stream
+                    match stream_roots(&musicd, user_id, bearer.as_deref(), session.as_deref()) {
+                        Ok(Some(current)) if current == roots => ":\n\n".to_string(),
+                        _ => break,
+                    }
+                }
+            };
+
+            if sender.send(Ok(message)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(Body::wrap_stream(receiver))
+        .unwrap())
+}
+
+// Looks up the credentials of an event stream again, returns the roots they
+// still grant or None once the session or API token is gone
+fn stream_roots(
+    musicd: &Musicd,
+    user_id: i64,
+    bearer: Option<&str>,
+    session: Option<&str>,
+) -> Result<Option<Vec<String>>, Error> {
+    let store = musicd.store();
+
+    let found = if !store.auth_required()? {
+        store.default_user()?.map(|u| (u, None))
+    } else if let Some(token_hash) = bearer {
+        store
+            .use_api_token(token_hash, auth::now())?
+            .map(|(u, api_token_id)| (u, Some(api_token_id)))
+    } else if let Some(token_hash) = session {
+        store
+            .session_user(token_hash, auth::now())?
+            .map(|u| (u, None))
+    } else {
+        None
+    };
+
+    match found {
+        Some((user, api_token_id)) if user.user_id == user_id => {
+            Ok(Some(store.allowed_roots(&user, api_token_id)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+// Anyone can see whether a scan is running, only admins can control it
+fn api_scan(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    if let Some(action) = r.query.get_str("action") {
+        if !r.user().admin {
+            return Ok(forbidden());
+        }
+
+        match action {
+            "start" => r.musicd.scan_thread.start(r.musicd.index()),
+            "restart" => {
+                r.musicd.scan_thread.stop();
+                r.musicd.scan_thread.start(r.musicd.index());
+            }
+            "stop" => {
+                r.musicd.scan_thread.stop();
+            }
+            _ => {}
+        }
+    }
+
+    Ok(json_ok(
+        &json!({
+            "running": r.musicd.scan_thread.is_running()
+        })
+        .to_string(),
+    ))
+}
+
+fn api_users(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    if !r.user().admin {
+        return Ok(forbidden());
+    }
+
+    let items = r.musicd.store().users()?;
+
+    Ok(json_ok(
+        &json!({
+            "total": items.len(),
+            "items": items
+        })
+        .to_string(),
+    ))
+}
+
+fn api_user_create(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    if !r.user().admin {
+        return Ok(forbidden());
+    }
+
+    let (name, password) = match (r.query.get_str("name"), r.query.get_str("password")) {
+        (Some(n), Some(p)) if !n.is_empty() && !p.is_empty() => (n, p),
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    let store = r.musicd.store();
+
+    if store.user_by_name(name)?.is_some() {
+        return Ok(bad_request());
+    }
+
+    let user = store.create_user(
+        name,
+        Some(&auth::hash_password(password)),
+        r.query.get_i64("admin").unwrap_or(0) != 0,
+    )?;
+
+    Ok(json_ok(&json!(user).to_string()))
+}
+
+fn api_user_delete(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    if !r.user().admin {
+        return Ok(forbidden());
+    }
+
+    let user_id = match r.query.get_i64("user_id") {
+        Some(id) => id,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    // Admins can't lock themselves out
+    if user_id == r.user().user_id {
+        return Ok(bad_request());
+    }
+
+    if !r.musicd.store().delete_user(user_id)? {
+        return Ok(not_found());
+    }
+
+    Ok(Response::builder().body(OK.into()).unwrap())
+}
+
+fn api_roots(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let store = r.musicd.store();
+
+    let mut items = Vec::new();
+
+    for root_name in r.roots.iter() {
+        // Only admins see who else can access a root
+        if r.user().admin {
+            items.push(json!({
+                "name": root_name,
+                "users": store.root_users(root_name)?,
+            }));
+        } else {
+            items.push(json!({ "name": root_name }));
+        }
+    }
+
+    Ok(json_ok(
+        &json!({
+            "total": items.len(),
+            "items": items
+        })
+        .to_string(),
+    ))
+}
+
+fn api_root_user_add(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    if !r.user().admin {
+        return Ok(forbidden());
+    }
+
+    let (root_name, user_id) = match (r.query.get_str("root"), r.query.get_i64("user_id")) {
+        (Some(root), Some(user)) => (root, user),
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    let store = r.musicd.store();
+
+    if !r.roots.iter().any(|name| name == root_name) || store.user(user_id)?.is_none() {
+        return Ok(not_found());
+    }
+
+    store.add_root_user(root_name, user_id)?;
+
+    Ok(Response::builder().body(OK.into()).unwrap())
+}
+
+fn api_root_user_remove(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    if !r.user().admin {
+        return Ok(forbidden());
+    }
+
+    let (root_name, user_id) = match (r.query.get_str("root"), r.query.get_i64("user_id")) {
+        (Some(root), Some(user)) => (root, user),
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    if !r.musicd.store().remove_root_user(root_name, user_id)? {
+        return Ok(not_found());
+    }
+
+    Ok(Response::builder().body(OK.into()).unwrap())
+}
+
+fn api_subsonic_password_create(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let password = auth::generate_token();
+
+    r.musicd
+        .store()
+        .set_subsonic_password(r.user().user_id, Some(&password))?;
+
+    // Replaces any previous password
+    Ok(json_ok(
+        &json!({
+            "user": r.user().name,
+            "password": password
+        })
+        .to_string(),
+    ))
+}
+
+fn api_subsonic_password_delete(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    r.musicd
+        .store()
+        .set_subsonic_password(r.user().user_id, None)?;
+
+    Ok(Response::builder().body(OK.into()).unwrap())
+}
+
+fn api_scrobbling(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let store = r.musicd.store();
+
+    let config = store.scrobble_config(r.user().user_id)?;
+    let queued = store.scrobble_queue_length(r.user().user_id)?;
+
+    // The token is never given out
+    Ok(json_ok(
+        &json!({
+            "config": config,
+            "queued": queued
+        })
+        .to_string(),
+    ))
+}
+
+fn api_scrobbling_save(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    // Not "token", which authenticates the request itself
+    let token = match r.query.get_str("service_token") {
+        Some(t) if !t.is_empty() => t,
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    // Any ListenBrainz compatible service, ListenBrainz itself by default
+    let url = r.query.get_str("url").unwrap_or(scrobble::DEFAULT_URL);
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Ok(bad_request());
+    }
+
+    r.musicd
+        .store()
+        .set_scrobble_config(r.user().user_id, url, token)?;
+
+    Ok(Response::builder().body(OK.into()).unwrap())
+}
+
+fn api_scrobbling_delete(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    if !r.musicd.store().delete_scrobble_config(r.user().user_id)? {
+        return Ok(not_found());
+    }
+
+    Ok(Response::builder().body(OK.into()).unwrap())
+}
+
+fn api_track_play(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let track_id = match r.query.get_i64("track_id") {
+        Some(id) => id,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    let index = r.musicd.index();
+
+    let track = match index.track(track_id)? {
+        Some(t) => t,
+        None => {
+            return Ok(not_found());
+        }
+    };
+
+    if !node_allowed(r, &index, track.node_id)? {
+        return Ok(not_found());
+    }
+
+    // Playback started, sent to the scrobbling service without recording a
+    // play
+    if r.query.get_i64("now_playing").unwrap_or(0) != 0 {
+        r.musicd.events.emit(Event::user(
+            r.user().user_id,
+            "now_playing",
+            json!({ "track_id": track.track_id }),
+        ));
+
+        return Ok(Response::builder().body(OK.into()).unwrap());
+    }
+
+    // Seconds listened, if the whole track wasn't
+    let duration = match r.query.get_str("duration").map(|d| d.parse::<f64>()) {
+        Some(Ok(d)) if d >= 0f64 => Some(d),
+        Some(_) => {
+            return Ok(bad_request());
+        }
+        None => None,
+    };
+
+    let time = auth::now();
+
+    r.musicd.store().register_track_play(
+        r.user().user_id,
+        &track,
+        time,
+        duration,
+        r.query.get_str("client"),
+    )?;
+
+    r.musicd.events.emit(Event::user(
+        r.user().user_id,
+        "track_played",
+        json!({ "track_id": track.track_id, "time": time }),
+    ));
+
+    Ok(Response::builder().body(OK.into()).unwrap())
+}
+
+// Plays of the user, most recent first
+fn api_history(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (since, until) = match history::period(&r.query, auth::now(), "all") {
+        Some(p) => p,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    let (total, items) = history::query_history(
+        &r.musicd.index(),
+        &r.query,
+        r.user().user_id,
+        since,
+        until,
+        &r.roots,
+    )?;
+
+    Ok(json_ok(
+        &json!({
+            "total": total,
+            "items": items
+        })
+        .to_string(),
+    ))
+}
+
+// Most played tracks, albums or artists of a period, the last month by default
+fn api_history_top(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (since, until) = match history::period(&r.query, auth::now(), "month") {
+        Some(p) => p,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    let items = history::query_top(
+        &r.musicd.index(),
+        r.query.get_str("type").unwrap_or("tracks"),
+        r.user().user_id,
+        since,
+        until,
+        r.query.get_i64("limit").unwrap_or(10),
+        &r.roots,
+    )?;
+
+    match items {
+        Some(items) => Ok(json_ok(&json!({ "items": items }).to_string())),
+        None => Ok(bad_request()),
+    }
+}
+
+fn api_history_summary(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let year = match r.query.get_str("year") {
+        Some(year) => match year.parse() {
+            Ok(year) if (1..=9999).contains(&year) => Some(year),
+            _ => {
+                return Ok(bad_request());
+            }
+        },
+        None => None,
+    };
+
+    let summary = history::summary(&r.musicd.index(), r.user().user_id, year, &r.roots)?;
+
+    Ok(json_ok(&serde_json::to_string(&summary).unwrap()))
+}
+
+// Next tracks of a radio seeded by a track, album or artist. Clients keep the
+// radio going by excluding the tracks they've already queued.
+fn api_radio(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let seed = if let Some(track_id) = r.query.get_i64("track_id") {
+        Seed::Track(track_id)
+    } else if let Some(album_id) = r.query.get_i64("album_id") {
+        Seed::Album(album_id)
+    } else if let Some(artist_id) = r.query.get_i64("artist_id") {
+        Seed::Artist(artist_id)
+    } else {
+        return Ok(bad_request());
+    };
+
+    let mut exclude = Vec::new();
+
+    for track_id in r
+        .query
+        .get_str("exclude")
+        .unwrap_or("")
+        .split(',')
+        .filter(|t| !t.is_empty())
+    {
+        match track_id.parse() {
+            Ok(id) => exclude.push(id),
+            Err(_) => {
+                return Ok(bad_request());
+            }
+        }
+    }
+
+    let limit = r.query.get_i64("limit").unwrap_or(20).clamp(1, 100) as usize;
+
+    let index = r.musicd.index();
+
+    let track_ids =
+        radio::radio_tracks(&index, r.user().user_id, &seed, &exclude, limit, &r.roots)?;
+
+    let mut query = HttpQuery::from("");
+    query.set(
+        "track_ids",
+        &track_ids
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+            .join(","),
+    );
+
+    let (_, mut items) = crate::query::query_tracks(&index, &query, r.user().user_id, &r.roots)?;
+
+    // In radio order instead of album order
+    items.sort_by_key(|t| track_ids.iter().position(|id| *id == t.track_id));
+
+    Ok(json_ok(&json!({ "items": items }).to_string()))
+}
+
+// Sets the rating and/or favorite flag of a track, album or artist
+fn api_rating(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let rating = match r.query.get_str("rating").map(|v| v.parse::<i64>()) {
+        Some(Ok(v)) if (0..=5).contains(&v) => Some(v),
+        Some(_) => {
+            return Ok(bad_request());
+        }
+        None => None,
+    };
+
+    let favorite = r.query.get_i64("favorite").map(|v| v != 0);
+
+    let index = r.musicd.index();
+    let store = r.musicd.store();
+    let user_id = r.user().user_id;
+
+    let (item, result) = if let Some(track_id) = r.query.get_i64("track_id") {
+        let track = match index.track(track_id)? {
+            Some(t) if node_allowed(r, &index, t.node_id)? => t,
+            _ => {
+                return Ok(not_found());
+            }
+        };
+
+        (
+            json!({ "track_id": track_id }),
+            store.set_track_rating(user_id, &track, rating, favorite)?,
+        )
+    } else if let Some(album_id) = r.query.get_i64("album_id") {
+        let album = match index.album(album_id)? {
+            Some(a) => a,
+            None => {
+                return Ok(not_found());
+            }
+        };
+
+        if !any_track_allowed(r, &index, index.tracks_by_album(album_id)?)? {
+            return Ok(not_found());
+        }
+
+        (
+            json!({ "album_id": album_id }),
+            store.set_album_rating(user_id, &album, rating, favorite)?,
+        )
+    } else if let Some(artist_id) = r.query.get_i64("artist_id") {
+        let artist = match index.artist(artist_id)? {
+            Some(a) => a,
+            None => {
+                return Ok(not_found());
+            }
+        };
+
+        if !any_track_allowed(r, &index, index.tracks_by_artist(artist_id)?)? {
+            return Ok(not_found());
+        }
+
+        (
+            json!({ "artist_id": artist_id }),
+            store.set_artist_rating(user_id, &artist, rating, favorite)?,
+        )
+    } else {
+        return Ok(bad_request());
+    };
+
+    let mut data = item;
+    data["rating"] = json!(result.rating);
+    data["favorite"] = json!(result.favorite);
+
+    r.musicd
+        .events
+        .emit(Event::user(user_id, "rating_changed", data.clone()));
+
+    Ok(json_ok(&data.to_string()))
+}
+
+fn any_track_allowed(r: &ApiRequest, index: &Index, tracks: Vec<Track>) -> Result<bool, Error> {
+    for track in tracks {
+        if node_allowed(r, index, track.node_id)? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+// Bookmarked tracks, most recently saved first
+fn api_bookmarks(r: &mut ApiRequest) -> Result<Response<Body>, Error> {
+    r.query.set("bookmarked", "1");
+
+    api_tracks(r)
+}
+
+fn api_bookmark_save(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let track_id = match r.query.get_i64("track_id") {
+        Some(id) => id,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    let position = match r.query.get_str("position").map(|p| p.parse::<f64>()) {
+        Some(Ok(p)) if p >= 0f64 => p,
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    let index = r.musicd.index();
+
+    let track = match index.track(track_id)? {
+        Some(t) => t,
+        None => {
+            return Ok(not_found());
+        }
+    };
+
+    if !node_allowed(r, &index, track.node_id)? {
+        return Ok(not_found());
+    }
+
+    r.musicd
+        .store()
+        .set_bookmark(r.user().user_id, &track, position, auth::now())?;
+
+    r.musicd.events.emit(Event::user(
+        r.user().user_id,
+        "bookmark_changed",
+        json!({ "track_id": track_id, "position": position }),
+    ));
+
+    Ok(Response::builder().body(OK.into()).unwrap())
+}
+
+fn api_bookmark_delete(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let track_id = match r.query.get_i64("track_id") {
+        Some(id) => id,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    let index = r.musicd.index();
+
+    let track = match index.track(track_id)? {
+        Some(t) => t,
+        None => {
+            return Ok(not_found());
+        }
+    };
+
+    if !node_allowed(r, &index, track.node_id)? {
+        return Ok(not_found());
+    }
+
+    if !r.musicd.store().delete_bookmark(r.user().user_id, &track)? {
+        return Ok(not_found());
+    }
+
+    r.musicd.events.emit(Event::user(
+        r.user().user_id,
+        "bookmark_changed",
+        json!({ "track_id": track_id, "position": null }),
+    ));
+
+    Ok(Response::builder().body(OK.into()).unwrap())
+}
+
+fn api_lists(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let mut items = r.musicd.store().lists(r.user().user_id)?;
+
+    for list in items.iter_mut().filter(|l| l.rules.is_some()) {
+        list.track_count = crate::query::count_list_tracks(
+            &r.musicd.index(),
+            list.list_id,
+            r.user().user_id,
+            &r.roots,
+        )?;
+    }
+
+    Ok(json_ok(
+        &json!({
+            "total": items.len(),
+            "items": items
+        })
+        .to_string(),
+    ))
+}
+
+fn api_list_create(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let name = match r.query.get_str("name") {
+        Some(n) if !n.is_empty() => n,
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    // Lists with rules are smart lists
+    let rules = match r.query.get_str("rules").map(Rules::parse) {
+        Some(Ok(rules)) => Some(rules),
+        Some(Err(e)) => {
+            debug!("invalid list rules: {}", e);
+            return Ok(bad_request());
+        }
+        None => None,
+    };
+
+    let mut list = r
+        .musicd
+        .store()
+        .create_list(r.user().user_id, name, rules.as_ref())?;
+
+    if list.rules.is_some() {
+        list.track_count = crate::query::count_list_tracks(
+            &r.musicd.index(),
+            list.list_id,
+            r.user().user_id,
+            &r.roots,
+        )?;
+    }
+
+    r.musicd
+        .events
+        .emit(Event::user(r.user().user_id, "list_created", json!(list)));
+
+    Ok(json_ok(&json!(list).to_string()))
+}
+
+fn api_list_delete(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let list_id = match r.query.get_i64("list_id") {
+        Some(id) => id,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    if !r.musicd.store().delete_list(r.user().user_id, list_id)? {
+        return Ok(not_found());
+    }
+
+    r.musicd.events.emit(Event::user(
+        r.user().user_id,
+        "list_deleted",
+        json!({ "list_id": list_id }),
+    ));
+
+    Ok(Response::builder().body(OK.into()).unwrap())
+}
+
+fn api_list_rules(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let list_id = match r.query.get_i64("list_id") {
+        Some(id) => id,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    let rules = match r.query.get_str("rules").map(Rules::parse) {
+        Some(Ok(rules)) => rules,
+        Some(Err(e)) => {
+            debug!("invalid list rules: {}", e);
+            return Ok(bad_request());
+        }
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    if !r
+        .musicd
+        .store()
+        .set_list_rules(r.user().user_id, list_id, &rules)?
+    {
+        return Ok(not_found());
+    }
+
+    r.musicd.events.emit(Event::user(
+        r.user().user_id,
+        "list_changed",
+        json!({ "list_id": list_id, "track_id": null }),
+    ));
+
+    Ok(Response::builder().body(OK.into()).unwrap())
+}
+
+fn list_changed(r: &ApiRequest, list_id: i64, track_id: i64) {
+    r.musicd.events.emit(Event::user(
+        r.user().user_id,
+        "list_changed",
+        json!({ "list_id": list_id, "track_id": track_id }),
+    ));
+}
+
+fn api_list_track_add(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (list_id, track_id) = match (r.query.get_i64("list_id"), r.query.get_i64("track_id")) {
+        (Some(l), Some(t)) => (l, t),
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    let store = r.musicd.store();
+
+    match store.list(r.user().user_id, list_id)? {
+        Some(l) if l.rules.is_some() => {
+            return Ok(bad_request());
+        }
+        Some(_) => {}
+        None => {
+            return Ok(not_found());
+        }
+    }
+
+    let index = r.musicd.index();
+
+    let track = match index.track(track_id)? {
+        Some(t) => t,
+        None => {
+            return Ok(not_found());
+        }
+    };
+
+    if !node_allowed(r, &index, track.node_id)? {
+        return Ok(not_found());
+    }
+
+    store.add_list_track(list_id, &track)?;
+
+    list_changed(r, list_id, track_id);
+
+    Ok(Response::builder().body(OK.into()).unwrap())
+}
+
+fn api_list_track_remove(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (list_id, track_id) = match (r.query.get_i64("list_id"), r.query.get_i64("track_id")) {
+        (Some(l), Some(t)) => (l, t),
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    let store = r.musicd.store();
+
+    if store.list(r.user().user_id, list_id)?.is_none() {
+        return Ok(not_found());
+    }
+
+    let index = r.musicd.index();
+
+    let track = match index.track(track_id)? {
+        Some(t) => t,
+        None => {
+            return Ok(not_found());
+        }
+    };
+
+    if !node_allowed(r, &index, track.node_id)? {
+        return Ok(not_found());
+    }
+
+    if !store.remove_list_track(list_id, &track)? {
+        return Ok(not_found());
+    }
+
+    list_changed(r, list_id, track_id);
+
+    Ok(Response::builder().body(OK.into()).unwrap())
+}
+
+// Queue entries are the first matching index track visible to the user, or
+// null for tracks missing from the index
+fn playback_session_json(r: &ApiRequest, session: &PlaybackSession) -> Result<Value, Error> {
+    let index = r.musicd.index();
+
+    let mut track_ids: Vec<Option<i64>> = Vec::new();
+
+    for tracks in r
+        .musicd
+        .store()
+        .playback_session_tracks(session.playback_session_id)?
+    {
+        let mut track_id = None;
+
+        for track in tracks {
+            if node_allowed(r, &index, track.node_id)? {
+                track_id = Some(track.track_id);
+                break;
+            }
+        }
+
+        track_ids.push(track_id);
+    }
+
+    let current_track_id = session
+        .current_index
+        .and_then(|i| track_ids.get(i as usize).cloned())
+        .unwrap_or_default();
+
+    let mut value = json!(session);
+    value["track_ids"] = json!(track_ids);
+    value["current_track_id"] = json!(current_track_id);
+
+    Ok(value)
+}
+
+fn playback_session_changed(r: &ApiRequest, device: &str) {
+    r.musicd.events.emit(Event::user(
+        r.user().user_id,
+        "playback_session_changed",
+        json!({ "device": device }),
+    ));
+}
+
+fn api_playback_sessions(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let sessions = r.musicd.store().playback_sessions(r.user().user_id)?;
+
+    let mut items = Vec::new();
+    for session in sessions.iter() {
+        items.push(playback_session_json(r, session)?);
+    }
+
+    Ok(json_ok(
+        &json!({
+            "total": items.len(),
+            "items": items
+        })
+        .to_string(),
+    ))
+}
+
+fn api_playback_session(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let device = match r.query.get_str("device") {
+        Some(d) => d,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    let session = r
+        .musicd
+        .store()
+        .playback_session(r.user().user_id, device)?;
+
+    match session {
+        Some(session) => Ok(json_ok(&playback_session_json(r, &session)?.to_string())),
+        None => Ok(not_found()),
+    }
+}
+
+// Only the given fields are changed, "track_ids" replaces the whole queue
+fn api_playback_session_save(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let device = match r.query.get_str("device") {
+        Some(d) if !d.is_empty() => d,
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    let store = r.musicd.store();
+    let user_id = r.user().user_id;
+    let now = auth::now();
+
+    let mut session = match store.playback_session(user_id, device)? {
+        Some(s) => s,
+        None => PlaybackSession {
+            playback_session_id: 0,
+            device: device.to_string(),
+            current_index: None,
+            position: 0f64,
+            playing: false,
+            shuffle: false,
+            repeat: "off".to_string(),
+            updated: now,
+        },
+    };
+
+    let tracks = match r.query.get_str("track_ids") {
+        Some(track_ids) => {
+            let index = r.musicd.index();
+            let mut tracks = Vec::new();
+
+            for track_id in track_ids.split(',').filter(|t| !t.is_empty()) {
+                let track_id: i64 = match track_id.parse() {
+                    Ok(id) => id,
+                    Err(_) => {
+                        return Ok(bad_request());
+                    }
+                };
+
+                match index.track(track_id)? {
+                    Some(t) if node_allowed(r, &index, t.node_id)? => tracks.push(t),
+                    _ => {
+                        return Ok(not_found());
+                    }
+                }
+            }
+
+            Some(tracks)
+        }
+        None => None,
+    };
+
+    if let Some(current_index) = r.query.get_str("current_index") {
+        session.current_index = match current_index {
+            "" => None,
+            i => match i.parse() {
+                Ok(i) => Some(i),
+                Err(_) => {
+                    return Ok(bad_request());
+                }
+            },
+        };
+    }
+
+    if let Some(position) = r.query.get_str("position") {
+        session.position = match position.parse::<f64>() {
+            Ok(p) if p >= 0f64 => p,
+            _ => {
+                return Ok(bad_request());
+            }
+        };
+    }
+
+    if let Some(playing) = r.query.get_i64("playing") {
+        session.playing = playing != 0;
+    }
+
+    if let Some(shuffle) = r.query.get_i64("shuffle") {
+        session.shuffle = shuffle != 0;
+    }
+
+    if let Some(repeat) = r.query.get_str("repeat") {
+        match repeat {
+            "off" | "all" | "one" => session.repeat = repeat.to_string(),
+            _ => {
+                return Ok(bad_request());
+            }
+        }
+    }
+
+    session.updated = now;
+
+    let session = store.save_playback_session(user_id, &session, tracks.as_deref())?;
+
+    playback_session_changed(r, device);
+
+    Ok(json_ok(&playback_session_json(r, &session)?.to_string()))
+}
+
+fn api_playback_session_delete(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let device = match r.query.get_str("device") {
+        Some(d) => d,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    if !r
+        .musicd
+        .store()
+        .delete_playback_session(r.user().user_id, device)?
+    {
+        return Ok(not_found());
+    }
+
+    playback_session_changed(r, device);
+
+    Ok(Response::builder().body(OK.into()).unwrap())
+}
+
+// Continues the session of device `from` on device `to`, pausing `from`
+fn api_playback_session_handoff(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (from, to) = match (r.query.get_str("from"), r.query.get_str("to")) {
+        (Some(f), Some(t)) if !t.is_empty() && f != t => (f, t),
+        _ => {
+            return Ok(bad_request());
+        }
+    };
+
+    let store = r.musicd.store();
+
+    let session = match store.hand_off_playback_session(r.user().user_id, from, to, auth::now())? {
+        Some(s) => s,
+        None => {
+            return Ok(not_found());
+        }
+    };
+
+    r.musicd.events.emit(Event::user(
+        r.user().user_id,
+        "playback_session_handoff",
+        json!({ "from": from, "to": to }),
+    ));
+
+    Ok(json_ok(&playback_session_json(r, &session)?.to_string()))
+}
+
+fn api_shares(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let items = r.musicd.store().shares(r.user().user_id)?;
+
+    Ok(json_ok(
+        &json!({
+            "total": items.len(),
+            "items": items
+        })
+        .to_string(),
+    ))
+}
+
+fn api_share_create(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let now = auth::now();
+
+    let expires = match r.query.get_str("expires_in") {
+        Some(_) => match r.query.get_i64("expires_in") {
+            Some(seconds) if seconds > 0 => Some(now + seconds),
+            _ => {
+                return Ok(bad_request());
+            }
+        },
+        None => None,
+    };
+
+    let play_limit = match r.query.get_str("play_limit") {
+        Some(_) => match r.query.get_i64("play_limit") {
+            Some(limit) if limit > 0 => Some(limit),
+            _ => {
+                return Ok(bad_request());
+            }
+        },
+        None => None,
+    };
+
+    let store = r.musicd.store();
+    let index = r.musicd.index();
+
+    let user_id = r.user().user_id;
+    let token = auth::generate_token();
+    let token_hash = auth::hash_token(&token);
+
+    let share = if let Some(track_id) = r.query.get_i64("track_id") {
+        let track = match index.track(track_id)? {
+            Some(t) if node_allowed(r, &index, t.node_id)? => t,
+            _ => {
+                return Ok(not_found());
+            }
+        };
+
+        store.create_track_share(user_id, &token_hash, &track, now, expires, play_limit)?
+    } else if let Some(album_id) = r.query.get_i64("album_id") {
+        let album = match index.album(album_id)? {
+            Some(a) => a,
+            None => {
+                return Ok(not_found());
+            }
+        };
+
+        let mut visible = false;
+        for track in index.tracks_by_album(album_id)? {
+            if node_allowed(r, &index, track.node_id)? {
+                visible = true;
+                break;
+            }
+        }
+
+        if !visible {
+            return Ok(not_found());
+        }
+
+        store.create_album_share(user_id, &token_hash, &album, now, expires, play_limit)?
+    } else if let Some(list_id) = r.query.get_i64("list_id") {
+        let list = match store.list(user_id, list_id)? {
+            Some(l) => l,
+            None => {
+                return Ok(not_found());
+            }
+        };
+
+        // Smart list tracks depend on the roots of the user
+        if list.rules.is_some() {
+            return Ok(bad_request());
+        }
+
+        store.create_list_share(user_id, &token_hash, &list, now, expires, play_limit)?
+    } else {
+        return Ok(bad_request());
+    };
+
+    // The token itself can't be retrieved later
+    Ok(json_ok(
+        &json!({
+            "share_id": share.share_id,
+            "item_type": share.item_type,
+            "name": share.name,
+            "created": share.created,
+            "expires": share.expires,
+            "play_limit": share.play_limit,
+            "token": token,
+            "url": format!("/share?s={}", token)
+        })
+        .to_string(),
+    ))
+}
+
+fn api_share_delete(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let share_id = match r.query.get_i64("share_id") {
+        Some(id) => id,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    if !r.musicd.store().delete_share(r.user().user_id, share_id)? {
+        return Ok(not_found());
+    }
+
+    Ok(Response::builder().body(OK.into()).unwrap())
+}
+
+// Resolves the share given in a public share request. The request gets the
+// root access of the share owner and the returned tracks are limited to it.
+fn open_share(r: &mut ApiRequest) -> Result<Option<(Share, Vec<Track>)>, Error> {
+    let token_hash = match r.query.get_str("s") {
+        Some(token) => auth::hash_token(token),
+        None => return Ok(None),
+    };
+
+    let store = r.musicd.store();
+
+    let share = match store.valid_share(&token_hash, auth::now())? {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+
+    let owner = match store.user(share.user_id)? {
+        Some(u) => u,
+        None => return Ok(None),
+    };
+
+    r.roots = store.allowed_roots(&owner, None)?;
+    r.user = Some(owner);
+
+    let index = r.musicd.index();
+
+    let mut tracks = Vec::new();
+    for track in store.share_tracks(&share)? {
+        if node_allowed(r, &index, track.node_id)? {
+            tracks.push(track);
+        }
+    }
+
+    // Files with identical metadata all match a shared track
+    if share.item_type == "track" {
+        tracks.truncate(1);
+    }
+
+    Ok(Some((share, tracks)))
+}
+
+fn api_share(r: &mut ApiRequest) -> Result<Response<Body>, Error> {
+    let (share, tracks) = match open_share(r)? {
+        Some(s) => s,
+        None => {
+            return Ok(not_found());
+        }
+    };
+
+    let index = r.musicd.index();
+
+    let mut items = Vec::new();
+
+    for track in tracks {
+        let image_id = index.album(track.album_id)?.and_then(|a| a.image_id);
+
+        items.push(json!({
+            "track_id": track.track_id,
+            "title": track.title,
+            "artist_name": track.artist_name,
+            "album_name": track.album_name,
+            "album_artist_name": track.album_artist_name,
+            "length": track.length,
+            "image_id": image_id
+        }));
+    }
+
+    Ok(json_ok(
+        &json!({
+            "item_type": share.item_type,
+            "name": share.name,
+            "expires": share.expires,
+            "plays_left": share.play_limit.map(|l| l - share.play_count),
+            "total": items.len(),
+            "items": items
+        })
+        .to_string(),
+    ))
+}
+
+fn api_share_stream(r: &mut ApiRequest) -> Result<Response<Body>, Error> {
+    let (share, tracks) = match open_share(r)? {
+        Some(s) => s,
+        None => {
+            return Ok(not_found());
+        }
+    };
+
+    let track_id = match r.query.get_i64("track_id") {
+        Some(id) => id,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    let track = match tracks.iter().find(|t| t.track_id == track_id) {
+        Some(t) => t,
+        None => {
+            return Ok(not_found());
+        }
+    };
+
+    if !r
+        .musicd
+        .store()
+        .register_share_play(share.share_id, track_id, track.length, auth::now())?
+    {
+        return Ok(forbidden());
+    }
+
+    // The user is the owner of the share, whose bookmarks aren't for visitors
+    r.query.set("resume", "0");
+
+    api_audio_stream(r)
+}
+
+fn api_share_image(r: &mut ApiRequest) -> Result<Response<Body>, Error> {
+    let (_share, tracks) = match open_share(r)? {
+        Some(s) => s,
+        None => {
+            return Ok(not_found());
+        }
+    };
+
+    let image_id = match r.query.get_i64("image_id") {
+        Some(id) => id,
+        None => {
+            return Ok(bad_request());
+        }
+    };
+
+    // Only album images of the shared tracks are available
+    let index = r.musicd.index();
+
+    let mut found = false;
+    for track in tracks.iter() {
+        if index.album(track.album_id)?.and_then(|a| a.image_id) == Some(image_id) {
+            found = true;
+            break;
+        }
+    }
+
+    if !found {
+        return Ok(not_found());
+    }
+
+    api_image_file(r)
+}
+
+static SHARE_HTML: &[u8] = include_bytes!("./share.html");
+
+fn res_share(_r: &ApiRequest) -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(SHARE_HTML.into())
+        .unwrap())
+}
+
+#[cfg(test)]
+fn test_musicd(name: &str) -> (PathBuf, Arc<Musicd>, Vec<crate::query::TestRootItems>) {
+    use crate::cache::CacheSource;
+    use crate::events::Events;
+    use crate::scan::ScanThread;
+    use crate::store::StoreSource;
+
+    let dir = std::env::temp_dir().join(format!("musicd2-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let (index_source, items) = crate::query::test_library(&dir);
+    let store_source = StoreSource::create(dir.join("store.db"), index_source.get().unwrap())
+        .unwrap()
+        .unwrap();
+    let events = Events::new();
+
+    let musicd = Arc::new(Musicd {
+        cache_source: CacheSource::create(None, 0).unwrap().unwrap(),
+        index_source,
+        store_source,
+        scan_thread: ScanThread::new(false, events.clone()),
+        session_lifetime: 3600,
+        tls: false,
+        players: crate::mpd::Players::new(),
+        upnp: None,
+        events,
+    });
+
+    (dir, musicd, items)
+}
+
+#[cfg(test)]
+async fn test_request(
+    musicd: &Arc<Musicd>,
+    method: Method,
+    uri: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Response<Body> {
+    let mut request = Request::builder().method(method).uri(uri);
+
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+
+    process_request(
+        request.body(Body::from(body.to_string())).unwrap(),
+        musicd.clone(),
+    )
+    .await
+    .unwrap()
+}
+
+// Every route but logging in and public shares requires authentication once
+// a user has a password
+#[tokio::test]
+async fn test_authentication() {
+    let (dir, musicd, _) = test_musicd("authentication");
+    let store = musicd.store();
+
+    let user = store
+        .create_user("user", Some(&auth::hash_password("secret")), false)
+        .unwrap();
+
+    let protected = [
+        (Method::GET, "/api/audio_stream?track_id=1"),
+        (Method::GET, "/api/image_file?image_id=1"),
+        (Method::GET, "/api/nodes"),
+        (Method::GET, "/api/tracks"),
+        (Method::GET, "/api/artists"),
+        (Method::GET, "/api/albums"),
+        (Method::GET, "/api/images"),
+        (Method::GET, "/api/scan"),
+        (Method::POST, "/api/scan?action=start"),
+        (Method::GET, "/api/lists"),
+        (Method::GET, "/api/api_tokens"),
+        (Method::POST, "/api/api_tokens?name=x"),
+        (Method::GET, "/api/users"),
+        (Method::GET, "/api/unknown"),
+    ];
+
+    for (method, uri) in protected.iter() {
+        let response = test_request(&musicd, method.clone(), uri, &[], "").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", uri);
+
+        let response = test_request(
+            &musicd,
+            method.clone(),
+            uri,
+            &[("Cookie", "musicd2-session=invalid")],
+            "",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", uri);
+    }
+
+    let response = test_request(&musicd, Method::GET, "/api/auth", &[], "").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let form = [("Content-Type", "application/x-www-form-urlencoded")];
+
+    let response = test_request(
+        &musicd,
+        Method::POST,
+        "/api/auth",
+        &form,
+        "user=user&password=wrong",
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Passwords in URLs are refused
+    let response = test_request(
+        &musicd,
+        Method::POST,
+        "/api/auth?user=user&password=secret",
+        &[],
+        "",
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = test_request(
+        &musicd,
+        Method::POST,
+        "/api/auth",
+        &form,
+        "user=user&password=secret",
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let set_cookie = response.headers()["Set-Cookie"].to_str().unwrap();
+    let cookie = set_cookie.split(';').next().unwrap().to_string();
+    assert!(cookie.starts_with("musicd2-session="));
+    assert!(set_cookie.contains("HttpOnly"));
+
+    let session = [("Cookie", cookie.as_str())];
+
+    let response = test_request(&musicd, Method::GET, "/api/auth", &session, "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = test_request(&musicd, Method::GET, "/api/lists", &session, "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Only admins control scans and users
+    let response = test_request(&musicd, Method::POST, "/api/scan?action=stop", &session, "").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = test_request(&musicd, Method::GET, "/api/users", &session, "").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = test_request(&musicd, Method::POST, "/api/logout", &session, "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = test_request(&musicd, Method::GET, "/api/lists", &session, "").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // API tokens are accepted as bearer tokens only
+    store
+        .create_api_token(
+            user.user_id,
+            "player",
+            &auth::hash_token("token"),
+            1000,
+            &[],
+        )
+        .unwrap();
+
+    let response = test_request(
+        &musicd,
+        Method::GET,
+        "/api/lists",
+        &[("Authorization", "Bearer token")],
+        "",
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = test_request(&musicd, Method::GET, "/api/lists?token=token", &[], "").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = test_request(
+        &musicd,
+        Method::GET,
+        "/api/lists",
+        &[("Authorization", "Bearer other")],
+        "",
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// Media of roots hidden from the user is treated as missing
+#[tokio::test]
+async fn test_root_access() {
+    let (dir, musicd, items) = test_musicd("root-access");
+    let store = musicd.store();
+
+    let admin = store
+        .create_user("admin", Some(&auth::hash_password("secret")), true)
+        .unwrap();
+    let user = store
+        .create_user("user", Some(&auth::hash_password("secret")), false)
+        .unwrap();
+    store.add_root_user("ab", admin.user_id).unwrap();
+
+    store
+        .create_api_token(user.user_id, "user", &auth::hash_token("user"), 1000, &[])
+        .unwrap();
+    store
+        .create_api_token(
+            admin.user_id,
+            "admin",
+            &auth::hash_token("admin"),
+            1000,
+            &[],
+        )
+        .unwrap();
+
+    let hidden = [
+        format!("/api/audio_stream?track_id={}", items[1].track_id),
+        format!("/api/image_file?image_id={}", items[1].image_id),
+        format!("/api/track_waveform?track_id={}", items[1].track_id),
+    ];
+
+    for uri in hidden.iter() {
+        let response = test_request(
+            &musicd,
+            Method::GET,
+            uri,
+            &[("Authorization", "Bearer user")],
+            "",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+    }
+
+    let response = test_request(
+        &musicd,
+        Method::GET,
+        "/api/tracks",
+        &[("Authorization", "Bearer user")],
+        "",
+    )
+    .await;
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let tracks: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(tracks["total"], 1);
+    assert_eq!(tracks["items"][0]["track_id"], items[0].track_id);
+
+    let request = ApiRequest {
+        request: Request::new(Body::empty()),
+        musicd: musicd.clone(),
+        query: HttpQuery::from(""),
+        cookies: HashMap::new(),
+        user: Some(user.clone()),
+        roots: store.allowed_roots(&user, None).unwrap(),
+    };
+    assert_eq!(request.roots, vec!["a"]);
+
+    let index = musicd.index();
+    let track = index.track(items[1].track_id).unwrap().unwrap();
+    assert!(!node_allowed(&request, &index, track.node_id).unwrap());
+
+    let track = index.track(items[0].track_id).unwrap().unwrap();
+    assert!(node_allowed(&request, &index, track.node_id).unwrap());
+
+    // Admins see every root
+    assert_eq!(store.allowed_roots(&admin, None).unwrap(), vec!["a", "ab"]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_stream_roots() {
+    let (dir, musicd, _items) = test_musicd("stream-roots");
+    let store = musicd.store();
+
+    let admin = store
+        .create_user("admin", Some(&auth::hash_password("secret")), true)
+        .unwrap();
+    let user = store
+        .create_user("user", Some(&auth::hash_password("secret")), false)
+        .unwrap();
+
+    let session = auth::hash_token("session");
+    store
+        .create_session(user.user_id, &session, auth::now(), auth::now() + 3600)
+        .unwrap();
+    let token = auth::hash_token("token");
+    let api_token_id = store
+        .create_api_token(user.user_id, "token", &token, 1000, &[])
+        .unwrap()
+        .api_token_id;
+
+    assert_eq!(
+        stream_roots(&musicd, user.user_id, None, Some(&session)).unwrap(),
+        Some(vec!["a".to_string(), "ab".to_string()])
+    );
+    assert_eq!(
+        stream_roots(&musicd, user.user_id, Some(&token), None).unwrap(),
+        Some(vec!["a".to_string(), "ab".to_string()])
+    );
+    assert_eq!(
+        stream_roots(&musicd, user.user_id, None, None).unwrap(),
+        None
+    );
+    assert_eq!(
+        stream_roots(&musicd, admin.user_id, None, Some(&session)).unwrap(),
+        None
+    );
+
+    // Roots taken away show up in the next check
+    store.add_root_user("ab", admin.user_id).unwrap();
+    assert_eq!(
+        stream_roots(&musicd, user.user_id, None, Some(&session)).unwrap(),
+        Some(vec!["a".to_string()])
+    );
+
+    store.delete_session(&session).unwrap();
+    assert_eq!(
+        stream_roots(&musicd, user.user_id, None, Some(&session)).unwrap(),
+        None
+    );
+
+    store.delete_api_token(user.user_id, api_token_id).unwrap();
+    assert_eq!(
+        stream_roots(&musicd, user.user_id, Some(&token), None).unwrap(),
+        None
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
 }
\ No newline at end of file
@@ -1,7 +1,8 @@
 use std::collections::{BTreeMap, HashMap};
 
+use hyper::body::HttpBody;
 use hyper::header::ToStrError;
-use hyper::HeaderMap;
+use hyper::{Body, HeaderMap};
 
 pub fn parse_cookies(headers: &HeaderMap) -> Result<HashMap<String, String>, ToStrError> {
     let mut cookies: HashMap<String, String> = HashMap::new();
@@ -26,6 +27,40 @@ pub fn parse_cookies(headers: &HeaderMap) -> Result<HashMap<String, String>, ToS
     Ok(cookies)
 }
 
+// Reads the whole body, None if it's longer than `limit` bytes
+pub async fn read_body(body: &mut Body, limit: usize) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let mut result = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+
+        if result.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+
+        result.extend_from_slice(&chunk);
+    }
+
+    Ok(Some(result))
+}
+
+pub fn escape_xml(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&apos;"),
+            c => result.push(c),
+        }
+    }
+
+    result
+}
+
 #[derive(Debug)]
 pub struct HttpQuery {
     value: BTreeMap<String, String>,
@@ -86,6 +121,10 @@ impl HttpQuery {
         }
     }
 
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.value.insert(key.to_string(), value.to_string());
+    }
+
     pub fn get_i64(&self, key: &str) -> Option<i64> {
         match self.get_str(key) {
             Some(s) => match s.parse() {
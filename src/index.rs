@@ -39,6 +39,14 @@ pub struct Node {
     pub modified: i64,
 }
 
+// Columns read by `Index::_get_track`, in order
+const TRACK_COLUMNS: &str = "Track.track_id, Track.node_id, Track.stream_index, \
+    Track.track_index, Track.start, Track.number, Track.title, Track.artist_id, \
+    Track.artist_name, Track.album_id, Track.album_name, Track.album_artist_id, \
+    Track.album_artist_name, Track.length, Track.codec, Track.container, Track.bitrate, \
+    Track.sample_rate, Track.bit_depth, Track.channels, Track.file_size, Track.lossless, \
+    Track.genre, Track.year";
+
 #[derive(Debug, Clone)]
 pub struct Track {
     pub track_id: i64,
@@ -55,6 +63,26 @@ pub struct Track {
     pub album_artist_id: Option<i64>,
     pub album_artist_name: Option<String>,
     pub length: f64,
+    pub codec: Option<String>,
+    pub container: Option<String>,
+    pub bitrate: Option<i64>,
+    pub sample_rate: Option<i64>,
+    pub bit_depth: Option<i64>,
+    pub channels: Option<i64>,
+    pub file_size: Option<i64>,
+    pub lossless: Option<bool>,
+    pub genre: Option<String>,
+    pub year: Option<i64>,
+}
+
+// Continuous part of a track within a single stream
+#[derive(Debug, Clone)]
+pub struct TrackSegment {
+    pub node_id: i64,
+    pub stream_index: i64,
+    pub track_index: Option<i64>,
+    pub start: f64,
+    pub length: f64,
 }
 
 #[derive(Debug, Clone)]
@@ -178,6 +206,16 @@ impl Index {
         Some(result)
     }
 
+    // Inverse of map_fs_path
+    pub fn map_index_path(&self, fs_path: &Path) -> Option<PathBuf> {
+        self.roots.iter().find_map(|root| {
+            fs_path
+                .strip_prefix(&root.path)
+                .ok()
+                .map(|rest| Path::new(&root.name).join(rest))
+        })
+    }
+
     fn _get_node(row: &Row) -> Result<Node> {
         let node_type: i64 = row.get(1)?;
         let name_bytes: Vec<u8> = row.get(4)?;
@@ -294,8 +332,8 @@ impl Index {
 
     pub fn create_node(&self, node: &Node) -> Result<Node> {
         let mut st = self.conn.prepare(
-            "INSERT INTO Node (node_type, parent_id, master_id, name, path, modified)
-            VALUES (?, ?, ?, ?, ?, ?)",
+            "INSERT INTO Node (node_type, parent_id, master_id, name, path, modified, added)
+            VALUES (?, ?, ?, ?, ?, ?, strftime('%s', 'now'))",
         )?;
 
         st.execute(params![
@@ -345,6 +383,21 @@ impl Index {
     pub fn clear_node(&self, node_id: i64) -> Result<()> {
         trace!("clear node node_id={}", node_id);
 
+        // Lengths of the tracks continuing into the node don't include it anymore
+        self.conn.execute(
+            "UPDATE Track
+            SET length = length - (
+                SELECT SUM(TrackContinuation.length)
+                FROM TrackContinuation
+                WHERE TrackContinuation.track_id = Track.track_id AND TrackContinuation.node_id = ?
+            )
+            WHERE track_id IN (SELECT track_id FROM TrackContinuation WHERE node_id = ?)",
+            [node_id, node_id],
+        )?;
+
+        self.conn
+            .execute("DELETE FROM TrackContinuation WHERE node_id = ?", [node_id])?;
+
         self.conn
             .execute("DELETE FROM Track WHERE node_id = ?", &[node_id])?;
 
@@ -370,18 +423,28 @@ impl Index {
             album_artist_id: row.get(11)?,
             album_artist_name: row.get(12)?,
             length: row.get(13)?,
+            codec: row.get(14)?,
+            container: row.get(15)?,
+            bitrate: row.get(16)?,
+            sample_rate: row.get(17)?,
+            bit_depth: row.get(18)?,
+            channels: row.get(19)?,
+            file_size: row.get(20)?,
+            lossless: row.get(21)?,
+            genre: row.get(22)?,
+            year: row.get(23)?,
         })
     }
 
     pub fn track(&self, track_id: i64) -> Result<Option<Track>> {
         trace!("get track track_id={}", track_id);
 
-        let mut st = self.conn
-            .prepare(
-                "SELECT track_id, node_id, stream_index, track_index, start, number, title, artist_id, artist_name, album_id, album_name, album_artist_id, album_artist_name, length
-                FROM Track
-                WHERE track_id = ?"
-            )?;
+        let mut st = self.conn.prepare(&format!(
+            "SELECT {}
+            FROM Track
+            WHERE track_id = ?",
+            TRACK_COLUMNS
+        ))?;
 
         let mut rows = st.query(&[track_id])?;
 
@@ -392,11 +455,133 @@ impl Index {
         }
     }
 
+    pub fn tracks_by_album(&self, album_id: i64) -> Result<Vec<Track>> {
+        trace!("get tracks album_id={}", album_id);
+
+        let mut st = self.conn.prepare(&format!(
+            "SELECT {}
+            FROM Track
+            INNER JOIN Node ON Node.node_id = Track.node_id
+            WHERE Track.album_id = ?
+            ORDER BY Node.path, Track.track_index, Track.start, Track.number",
+            TRACK_COLUMNS
+        ))?;
+
+        let mut rows = st.query([album_id])?;
+
+        let mut result = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            result.push(Self::_get_track(row)?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn tracks_by_node(&self, node_id: i64) -> Result<Vec<Track>> {
+        trace!("get tracks node_id={}", node_id);
+
+        let mut st = self.conn.prepare(&format!(
+            "SELECT {}
+            FROM Track
+            WHERE Track.node_id = ?
+            ORDER BY Track.track_index, Track.start, Track.number",
+            TRACK_COLUMNS
+        ))?;
+
+        let mut rows = st.query([node_id])?;
+
+        let mut result = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            result.push(Self::_get_track(row)?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn tracks_by_artist(&self, artist_id: i64) -> Result<Vec<Track>> {
+        trace!("get tracks artist_id={}", artist_id);
+
+        let mut st = self.conn.prepare(&format!(
+            "SELECT {}
+            FROM Track
+            WHERE Track.artist_id = ?
+            ORDER BY Track.track_id",
+            TRACK_COLUMNS
+        ))?;
+
+        let mut rows = st.query([artist_id])?;
+
+        let mut result = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            result.push(Self::_get_track(row)?);
+        }
+
+        Ok(result)
+    }
+
+    // Tracks matching the metadata of a store track
+    pub fn tracks_by_store_track(&self, store_track_id: i64) -> Result<Vec<Track>> {
+        trace!("get tracks store_track_id={}", store_track_id);
+
+        let mut st = self.conn.prepare(&format!(
+            "SELECT {}
+            FROM StoreTrack
+            INNER JOIN Track ON
+                Track.title = StoreTrack.title
+                AND Track.artist_name = StoreTrack.artist_name
+                AND Track.album_name = StoreTrack.album_name
+            WHERE StoreTrack.store_track_id = ?
+            ORDER BY Track.track_id",
+            TRACK_COLUMNS
+        ))?;
+
+        let mut rows = st.query([store_track_id])?;
+
+        let mut result = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            result.push(Self::_get_track(row)?);
+        }
+
+        Ok(result)
+    }
+
+    // Tracks of a store list in list order
+    pub fn tracks_by_store_list(&self, list_id: i64) -> Result<Vec<Track>> {
+        trace!("get tracks list_id={}", list_id);
+
+        let mut st = self.conn.prepare(&format!(
+            "SELECT {}
+            FROM StoreListTrack
+            INNER JOIN StoreTrack ON StoreTrack.store_track_id = StoreListTrack.store_track_id
+            INNER JOIN Track ON
+                Track.title = StoreTrack.title
+                AND Track.artist_name = StoreTrack.artist_name
+                AND Track.album_name = StoreTrack.album_name
+            WHERE StoreListTrack.list_id = ?
+            ORDER BY StoreListTrack.sort_index, Track.track_id",
+            TRACK_COLUMNS
+        ))?;
+
+        let mut rows = st.query([list_id])?;
+
+        let mut result = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            result.push(Self::_get_track(row)?);
+        }
+
+        Ok(result)
+    }
+
     pub fn create_track(&self, track: &Track) -> Result<Track> {
         let mut st = self.conn
             .prepare(
-                "INSERT INTO Track (node_id, stream_index, track_index, start, number, title, artist_id, artist_name, album_id, album_name, album_artist_id, album_artist_name, length)
-                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+                "INSERT INTO Track (node_id, stream_index, track_index, start, number, title, artist_id, artist_name, album_id, album_name, album_artist_id, album_artist_name, length, codec, container, bitrate, sample_rate, bit_depth, channels, file_size, lossless, genre, year)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
             )?;
 
         st.execute(params![
@@ -413,6 +598,16 @@ impl Index {
             track.album_artist_id,
             track.album_artist_name,
             track.length,
+            track.codec,
+            track.container,
+            track.bitrate,
+            track.sample_rate,
+            track.bit_depth,
+            track.channels,
+            track.file_size,
+            track.lossless,
+            track.genre,
+            track.year,
         ])?;
 
         let result = self.track(self.conn.last_insert_rowid())?.unwrap();
@@ -422,6 +617,73 @@ impl Index {
         Ok(result)
     }
 
+    // Attaches the first `length` seconds of a stream in `node_id` to the end
+    // of `track_id`, whose length includes them
+    pub fn create_track_continuation(
+        &self,
+        track_id: i64,
+        node_id: i64,
+        stream_index: i64,
+        track_index: Option<i64>,
+        length: f64,
+    ) -> Result<()> {
+        trace!(
+            "create track continuation track_id={} node_id={}",
+            track_id,
+            node_id
+        );
+
+        self.conn.execute(
+            "INSERT INTO TrackContinuation (track_id, node_id, stream_index, track_index, length)
+            VALUES (?, ?, ?, ?, ?)",
+            params![track_id, node_id, stream_index, track_index, length],
+        )?;
+
+        self.conn.execute(
+            "UPDATE Track SET length = length + ? WHERE track_id = ?",
+            params![length, track_id],
+        )?;
+
+        Ok(())
+    }
+
+    // The part of the track in its own stream followed by its continuations
+    // in the next files
+    pub fn track_segments(&self, track: &Track) -> Result<Vec<TrackSegment>> {
+        trace!("get track segments track_id={}", track.track_id);
+
+        let mut st = self.conn.prepare(
+            "SELECT node_id, stream_index, track_index, length
+            FROM TrackContinuation
+            WHERE track_id = ?
+            ORDER BY rowid",
+        )?;
+
+        let continuations = st
+            .query_map([track.track_id], |row| {
+                Ok(TrackSegment {
+                    node_id: row.get(0)?,
+                    stream_index: row.get(1)?,
+                    track_index: row.get(2)?,
+                    start: 0f64,
+                    length: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut segments = vec![TrackSegment {
+            node_id: track.node_id,
+            stream_index: track.stream_index,
+            track_index: track.track_index,
+            start: track.start.unwrap_or_default(),
+            length: track.length - continuations.iter().map(|c| c.length).sum::<f64>(),
+        }];
+
+        segments.extend(continuations);
+
+        Ok(segments)
+    }
+
     fn _get_image(row: &Row) -> Result<Image> {
         Ok(Image {
             image_id: row.get(0)?,
@@ -570,6 +832,27 @@ impl Index {
         Ok(result)
     }
 
+    pub fn albums_by_name(&self, name: &str, artist_name: Option<&str>) -> Result<Vec<Album>> {
+        trace!("get albums name={}", name);
+
+        let mut st = self.conn.prepare(
+            "SELECT Album.album_id, Album.name, Album.artist_id, Album.artist_name, Album.image_id
+                FROM Album
+                WHERE Album.name = ? AND Album.artist_name IS ?
+                ORDER BY Album.album_id",
+        )?;
+
+        let mut rows = st.query(params![name, artist_name])?;
+
+        let mut result = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            result.push(Self::_get_album(row)?);
+        }
+
+        Ok(result)
+    }
+
     pub fn find_album(&self, track_node_id: i64, album_name: &str) -> Result<Option<Album>> {
         trace!(
             "find album track_node_id={} album_name={}",
@@ -759,7 +1042,8 @@ impl Index {
         trace!("debug truncate");
 
         self.conn.execute_batch(
-            "DELETE FROM Track;
+            "DELETE FROM TrackContinuation;
+            DELETE FROM Track;
             DELETE FROM Image;
             DELETE FROM Artist;
             DELETE FROM Album;
@@ -0,0 +1,182 @@
+use std::env;
+use std::ffi::CString;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+
+pub enum Bind {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+// First file descriptor passed by systemd socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+impl Bind {
+    // Either "unix:<path>" or a socket address
+    pub fn parse(s: &str) -> Option<Bind> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            Some(Bind::Unix(PathBuf::from(path)))
+        } else {
+            s.parse().ok().map(Bind::Tcp)
+        }
+    }
+}
+
+fn user_id(name: &str) -> io::Result<libc::uid_t> {
+    if let Ok(uid) = name.parse() {
+        return Ok(uid);
+    }
+
+    let c_name = CString::new(name)?;
+    let passwd = unsafe { libc::getpwnam(c_name.as_ptr()) };
+
+    if passwd.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown user '{}'", name),
+        ));
+    }
+
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+fn group_id(name: &str) -> io::Result<libc::gid_t> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+
+    let c_name = CString::new(name)?;
+    let group = unsafe { libc::getgrnam(c_name.as_ptr()) };
+
+    if group.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown group '{}'", name),
+        ));
+    }
+
+    Ok(unsafe { (*group).gr_gid })
+}
+
+// Owner is "user", "user:group" or ":group", ids are accepted as well
+fn chown(path: &Path, owner: &str) -> io::Result<()> {
+    let mut parts = owner.splitn(2, ':');
+
+    // -1 leaves the id unchanged
+    let uid = match parts.next() {
+        Some(user) if !user.is_empty() => user_id(user)?,
+        _ => libc::uid_t::MAX,
+    };
+
+    let gid = match parts.next() {
+        Some(group) if !group.is_empty() => group_id(group)?,
+        _ => libc::gid_t::MAX,
+    };
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+
+    if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+pub fn bind(bind: &Bind, mode: Option<u32>, owner: Option<&str>) -> io::Result<Listener> {
+    match bind {
+        Bind::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+        Bind::Unix(path) => {
+            // Remove a socket left behind by a previous run
+            if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                if metadata.file_type().is_socket() {
+                    std::fs::remove_file(path)?;
+                }
+            }
+
+            // Nobody else may connect before the mode and owner are set
+            let umask = if mode.is_some() || owner.is_some() {
+                Some(unsafe { libc::umask(0o077) })
+            } else {
+                None
+            };
+
+            let listener = UnixListener::bind(path);
+
+            if let Some(umask) = umask {
+                unsafe { libc::umask(umask) };
+            }
+
+            let listener = listener?;
+
+            if let Some(owner) = owner {
+                chown(path, owner)?;
+            }
+
+            if let Some(mode) = mode {
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+            }
+
+            Ok(Listener::Unix(listener))
+        }
+    }
+}
+
+// Takes the socket passed by systemd socket activation, if any
+pub fn systemd_listener() -> io::Result<Option<Listener>> {
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|p| p.parse::<u32>().ok());
+    let fds = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<i32>().ok())
+        .unwrap_or(0);
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    if pid != Some(std::process::id()) || fds < 1 {
+        return Ok(None);
+    }
+
+    if fds > 1 {
+        warn!("using only the first of {} sockets passed by systemd", fds);
+    }
+
+    let fd = SD_LISTEN_FDS_START;
+
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+    let result = unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len)
+    };
+
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    match addr.ss_family as libc::c_int {
+        libc::AF_UNIX => Ok(Some(Listener::Unix(unsafe {
+            UnixListener::from_raw_fd(fd)
+        }))),
+        libc::AF_INET | libc::AF_INET6 => {
+            Ok(Some(Listener::Tcp(unsafe { TcpListener::from_raw_fd(fd) })))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "unsupported socket passed by systemd",
+        )),
+    }
+}
@@ -2,20 +2,32 @@
 extern crate log;
 
 mod audio_stream;
+mod auth;
 mod cache;
 mod cue;
 mod db_meta;
+mod events;
+mod history;
 mod http_api;
 mod http_util;
 mod index;
+mod listener;
 mod logger;
 mod lyrics;
 mod media;
+mod mpd;
 mod musicd_c;
+mod playlist;
 mod query;
+mod radio;
 mod scan;
 mod schema;
+mod scrobble;
+mod smart_list;
 mod store;
+mod subsonic;
+mod tls;
+mod upnp;
 
 use std::ffi::OsStr;
 use std::net::SocketAddr;
@@ -26,16 +38,24 @@ use std::sync::Arc;
 use clap::Arg;
 
 use cache::{Cache, CacheSource};
+use events::Events;
 use index::{Index, IndexSource};
+use listener::{Bind, Listener};
 use scan::ScanThread;
 use store::{Store, StoreSource};
+use tls::{TlsConfig, TlsReloader};
+use upnp::UpnpConfig;
 
 pub struct Musicd {
     cache_source: CacheSource,
     index_source: IndexSource,
     store_source: StoreSource,
     scan_thread: ScanThread,
-    password: String,
+    session_lifetime: i64,
+    tls: bool,
+    players: mpd::Players,
+    upnp: Option<UpnpConfig>,
+    events: Events,
 }
 
 pub struct Root {
@@ -65,10 +85,22 @@ impl Musicd {
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let matches = clap::App::new("musicd2")
         .version(MUSICD_VERSION)
+        .arg(
+            Arg::with_name("add-user")
+                .long("add-user")
+                .help("Create a user or change the password of an existing one and exit")
+                .value_names(&["name", "password"])
+                .number_of_values(2),
+        )
+        .arg(
+            Arg::with_name("admin")
+                .long("admin")
+                .help("Give the user created with --add-user administrator rights"),
+        )
         .arg(
             Arg::with_name("bind")
                 .long("bind")
-                .help("HTTP server address and port")
+                .help("HTTP server address and port, or unix:<path> for a Unix socket")
                 .default_value("127.0.0.1:6801"),
         )
         .arg(
@@ -88,6 +120,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .long("disable-cache")
                 .help("Disable any use of cache"),
         )
+        .arg(
+            Arg::with_name("exact-duration")
+                .long("exact-duration")
+                .help("Determine track durations by demuxing when headers are missing or inaccurate"),
+        )
+        .arg(
+            Arg::with_name("hash-password")
+                .long("hash-password")
+                .help("Print a hash of the given password for --password-hash and exit")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("log-level")
                 .long("log-level")
@@ -95,6 +138,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .default_value("info")
                 .possible_values(&["error", "warn", "info", "debug", "trace"]),
         )
+        .arg(
+            Arg::with_name("mpd-bind")
+                .long("mpd-bind")
+                .help("Address and port of the MPD protocol server, e.g. 127.0.0.1:6600")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("no-initial-scan")
                 .long("no-initial-scan")
@@ -103,9 +152,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .arg(
             Arg::with_name("password")
                 .long("password")
-                .help("Authentication password, empty disables authentication")
+                .help("Password of the admin user, authentication is disabled if no user has a password")
                 .default_value(""),
         )
+        .arg(
+            Arg::with_name("password-hash")
+                .long("password-hash")
+                .help("Password hash of the admin user, overrides --password")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("redirect-bind")
+                .long("redirect-bind")
+                .help("Address and port of a plain HTTP server redirecting to HTTPS")
+                .takes_value(true)
+                .requires("tls-cert"),
+        )
         .arg(
             Arg::with_name("root")
                 .long("root")
@@ -115,16 +177,82 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .multiple(true)
                 .number_of_values(2),
         )
+        .arg(
+            Arg::with_name("session-lifetime")
+                .long("session-lifetime")
+                .help("Login session lifetime in seconds")
+                .default_value("2592000"),
+        )
+        .arg(
+            Arg::with_name("socket-mode")
+                .long("socket-mode")
+                .help("Octal permissions of the Unix socket, e.g. 660")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("socket-owner")
+                .long("socket-owner")
+                .help("Owner of the Unix socket as user, user:group or :group")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .help("PEM certificate chain file, enables HTTPS, reloaded on SIGHUP")
+                .takes_value(true)
+                .requires("tls-key"),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .help("PEM PKCS #8 private key file of the certificate")
+                .takes_value(true)
+                .requires("tls-cert"),
+        )
+        .arg(
+            Arg::with_name("upnp")
+                .long("upnp")
+                .help("Announce a UPnP/DLNA media server on the local network")
+                .conflicts_with("tls-cert"),
+        )
+        .arg(
+            Arg::with_name("upnp-name")
+                .long("upnp-name")
+                .help("Name of the UPnP media server")
+                .default_value("musicd2"),
+        )
+        .arg(
+            Arg::with_name("upnp-token")
+                .long("upnp-token")
+                .help("API token used by UPnP clients, required if authentication is enabled")
+                .takes_value(true)
+                .requires("upnp"),
+        )
         .get_matches();
 
-    let bind: SocketAddr = matches
-        .value_of("bind")
-        .unwrap()
-        .parse()
-        .expect("invalid bind address");
+    if let Some(password) = matches.value_of("hash-password") {
+        println!("{}", auth::hash_password(password));
+        return Ok(());
+    }
+
+    let bind = Bind::parse(matches.value_of("bind").unwrap()).expect("invalid bind address");
+
+    let socket_mode = matches
+        .value_of("socket-mode")
+        .map(|m| u32::from_str_radix(m, 8).expect("invalid socket mode"));
+
+    let redirect_bind: Option<SocketAddr> = matches
+        .value_of("redirect-bind")
+        .map(|b| b.parse().expect("invalid redirect bind address"));
+
+    let mpd_bind: Option<SocketAddr> = matches
+        .value_of("mpd-bind")
+        .map(|b| b.parse().expect("invalid MPD bind address"));
 
     let cache_limit = clap::value_t_or_exit!(matches.value_of("cache-limit"), usize);
 
+    let session_lifetime = clap::value_t_or_exit!(matches.value_of("session-lifetime"), i64);
+
     let directory = &shellexpand::tilde(matches.value_of("directory").unwrap()).into_owned();
     let directory = Path::new(directory);
 
@@ -169,14 +297,106 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .unwrap()
         .unwrap();
 
-    let scan_thread = scan::ScanThread::new();
+    let store = store_source.get(index_source.get().unwrap()).unwrap();
+
+    if let Some(mut values) = matches.values_of("add-user") {
+        let name = values.next().unwrap();
+        let password_hash = auth::hash_password(values.next().unwrap());
+
+        match store.user_by_name(name).unwrap() {
+            Some(user) => {
+                store
+                    .set_user_password(user.user_id, &password_hash)
+                    .unwrap();
+                info!("changed password of user '{}'", name);
+            }
+            None => {
+                store
+                    .create_user(name, Some(&password_hash), matches.is_present("admin"))
+                    .unwrap();
+                info!("created user '{}'", name);
+            }
+        }
+
+        return Ok(());
+    }
+
+    // The plain password is only kept around as a salted hash. A stored hash
+    // still matching it is kept, changing it ends the sessions of the admin.
+    let password_hash = match matches.value_of("password-hash") {
+        Some(hash) => Some(hash.to_string()),
+        None => match matches.value_of("password").unwrap() {
+            "" => None,
+            password => match store.user_by_name("admin").unwrap() {
+                Some(admin) => admin
+                    .password_hash
+                    .filter(|hash| auth::verify_password(hash, password))
+                    .or_else(|| Some(auth::hash_password(password))),
+                None => Some(auth::hash_password(password)),
+            },
+        },
+    };
+
+    store.ensure_admin(password_hash.as_deref()).unwrap();
+
+    let tls = match (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+        (Some(cert_path), Some(key_path)) => Some(
+            TlsReloader::create(TlsConfig {
+                cert_path: PathBuf::from(shellexpand::tilde(cert_path).into_owned()),
+                key_path: PathBuf::from(shellexpand::tilde(key_path).into_owned()),
+            })
+            .expect("can't load TLS certificate"),
+        ),
+        _ => None,
+    };
+
+    // Sockets passed by systemd socket activation override --bind
+    let listener = match listener::systemd_listener().expect("can't use socket passed by systemd") {
+        Some(l) => {
+            info!("using socket passed by systemd");
+            l
+        }
+        None => listener::bind(&bind, socket_mode, matches.value_of("socket-owner"))
+            .expect("can't bind"),
+    };
+
+    let https_port = match &listener {
+        Listener::Tcp(l) => l.local_addr().unwrap().port(),
+        Listener::Unix(_) => {
+            if tls.is_some() {
+                return Err("TLS is not supported on Unix sockets".into());
+            }
+            if matches.is_present("upnp") {
+                return Err("UPnP is not supported on Unix sockets".into());
+            }
+            0
+        }
+    };
+
+    let upnp = if matches.is_present("upnp") {
+        Some(UpnpConfig {
+            uuid: upnp::device_uuid(directory),
+            name: matches.value_of("upnp-name").unwrap().to_string(),
+            token: matches.value_of("upnp-token").map(|t| t.to_string()),
+        })
+    } else {
+        None
+    };
+
+    let events = Events::new();
+
+    let scan_thread = ScanThread::new(matches.is_present("exact-duration"), events.clone());
 
     let musicd = Arc::new(Musicd {
         cache_source,
         index_source,
         store_source,
         scan_thread,
-        password: matches.value_of("password").unwrap().to_string(),
+        session_lifetime,
+        tls: tls.is_some(),
+        players: mpd::Players::new(),
+        upnp: upnp.clone(),
+        events,
     });
 
     let index = musicd.index();
@@ -190,7 +410,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut store = musicd.store();
     store.synchronize().unwrap();
 
-    http_api::run_api(musicd.clone(), bind).await;
+    if let Some(redirect_bind) = redirect_bind {
+        tokio::spawn(http_api::run_redirect(redirect_bind, https_port));
+    }
+
+    if let Some(mpd_bind) = mpd_bind {
+        tokio::spawn(mpd::run_mpd(musicd.clone(), mpd_bind));
+    }
+
+    if let Some(upnp) = upnp {
+        tokio::spawn(upnp::run_ssdp(upnp, https_port));
+    }
+
+    tokio::spawn(scrobble::run_scrobbler(musicd.clone()));
+
+    http_api::run_api(musicd.clone(), listener, tls).await;
 
     Ok(())
 }
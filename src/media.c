@@ -32,14 +32,15 @@ static struct TrackInfo *try_get_track_info(
     const AVFormatContext *avctx,
     int stream_index,
     int track_index,
-    const char *path);
+    const char *path,
+    int exact_duration);
 
 static struct ImageInfo *try_get_image_info(
     const AVFormatContext *avctx,
     int stream_index,
     const char *path);
 
-struct MediaInfo *media_info_from_path(const char *path) {
+struct MediaInfo *media_info_from_path(const char *path, int exact_duration) {
     const AVOutputFormat *fmt = av_guess_format(NULL, path, NULL);
 
     if (!fmt) {
@@ -68,10 +69,48 @@ struct MediaInfo *media_info_from_path(const char *path) {
     struct TrackInfo *track_cur = NULL;
     struct ImageInfo *image_cur = NULL;
 
+    // Cue sheet embedded in FLAC Vorbis comments or APE tags
+    const AVDictionaryEntry *cuesheet = av_dict_get(avctx->metadata, "cuesheet", NULL, 0);
+    if (cuesheet) {
+        media_info->cuesheet = av_strdup(cuesheet->value);
+    }
+
     // av_dump_format(avctx, 0, NULL, 0);
 
+    // Chapters of the primary audio stream are indexed as separate tracks
+    int chapter_stream_index = -1;
+
+    if (avctx->nb_chapters > 0) {
+        chapter_stream_index = av_find_best_stream(avctx, AVMEDIA_TYPE_AUDIO, -1, -1, NULL, 0);
+    }
+
+    if (chapter_stream_index >= 0) {
+        for (unsigned int i = 0; i < avctx->nb_chapters; ++i) {
+            struct TrackInfo *track_info = try_get_track_info(
+                avctx, chapter_stream_index, i + 1, path, 0);
+            if (!track_info) {
+                continue;
+            }
+
+            if (!track_cur) {
+                media_info->tracks = track_info;
+            } else {
+                track_cur->next = track_info;
+            }
+
+            track_cur = track_info;
+        }
+
+        if (!track_cur) {
+            // No usable chapters, fall back to indexing the whole stream
+            chapter_stream_index = -1;
+        }
+    }
+
     for (unsigned int i = 0; i < avctx->nb_streams; ++i) {
-        struct TrackInfo *track_info = try_get_track_info(avctx, i, 0, path);
+        struct TrackInfo *track_info = (int)i == chapter_stream_index
+            ? NULL
+            : try_get_track_info(avctx, i, 0, path, exact_duration);
         if (track_info) {
             if (!track_cur) {
                 media_info->tracks = track_info;
@@ -111,11 +150,78 @@ static char *extract_name_from_path(const char *path) {
     return av_strndup(start, end - start);
 }
 
+int media_chapter_bounds(
+    const AVFormatContext *avctx,
+    int track_index,
+    double *start,
+    double *length
+) {
+    if (track_index < 1 || (unsigned int)track_index > avctx->nb_chapters) {
+        return 0;
+    }
+
+    const AVChapter *chapter = avctx->chapters[track_index - 1];
+
+    *start = chapter->start * av_q2d(chapter->time_base);
+    *length = (chapter->end - chapter->start) * av_q2d(chapter->time_base);
+
+    return *length > 0;
+}
+
+/*
+ * Determines stream length by demuxing every packet of the stream. This is
+ * slow but accurate, unlike container headers of e.g. VBR MP3 files without
+ * Xing headers, for which FFmpeg only estimates duration from bitrate.
+ */
+static double demux_stream_length(const char *path, int stream_index) {
+    AVFormatContext *avctx = NULL;
+    if (avformat_open_input(&avctx, path, NULL, NULL) < 0) {
+        return 0;
+    }
+
+    if (avformat_find_stream_info(avctx, NULL) < 0
+        || avctx->nb_streams <= (unsigned int)stream_index) {
+        avformat_close_input(&avctx);
+        return 0;
+    }
+
+    const AVStream *stream = avctx->streams[stream_index];
+
+    int64_t start_pts = AV_NOPTS_VALUE;
+    int64_t end_pts = 0;
+
+    AVPacket packet = { .data = NULL, .size = 0 };
+    av_init_packet(&packet);
+
+    while (av_read_frame(avctx, &packet) >= 0) {
+        if (packet.stream_index == stream_index && packet.pts != AV_NOPTS_VALUE) {
+            if (start_pts == AV_NOPTS_VALUE || packet.pts < start_pts) {
+                start_pts = packet.pts;
+            }
+
+            if (packet.pts + packet.duration > end_pts) {
+                end_pts = packet.pts + packet.duration;
+            }
+        }
+
+        av_packet_unref(&packet);
+    }
+
+    double length = start_pts == AV_NOPTS_VALUE
+        ? 0
+        : (end_pts - start_pts) * av_q2d(stream->time_base);
+
+    avformat_close_input(&avctx);
+
+    return length;
+}
+
 static struct TrackInfo *try_get_track_info(
     const AVFormatContext *avctx,
     int stream_index,
     int track_index,
-    const char *path
+    const char *path,
+    int exact_duration
 ) {
     const AVStream *stream = avctx->streams[stream_index];
 
@@ -123,9 +229,26 @@ static struct TrackInfo *try_get_track_info(
         return NULL;
     }
 
-    double length = avctx->duration > 0
-        ? avctx->duration / (double)AV_TIME_BASE
-        : stream->duration * (double)av_q2d(stream->time_base);
+    double start = 0;
+    double length = 0;
+
+    if (track_index > 0) {
+        if (!media_chapter_bounds(avctx, track_index, &start, &length)) {
+            return NULL;
+        }
+    } else {
+        length = avctx->duration > 0
+            ? avctx->duration / (double)AV_TIME_BASE
+            : stream->duration * (double)av_q2d(stream->time_base);
+
+        if (exact_duration
+            && (length <= 0 || avctx->duration_estimation_method == AVFMT_DURATION_FROM_BITRATE)) {
+            double demuxed_length = demux_stream_length(path, stream_index);
+            if (demuxed_length > 0) {
+                length = demuxed_length;
+            }
+        }
+    }
 
     if (length <= 0) {
         return NULL;
@@ -137,16 +260,27 @@ static struct TrackInfo *try_get_track_info(
     track_info->stream_index = stream_index;
     track_info->track_index = track_index;
 
+    track_info->start = start;
     track_info->length = length;
 
     const char *tmp = get_metadata(avctx, stream_index, "track");
-    if (tmp) {
+    if (tmp && track_index == 0) {
         sscanf(tmp, "%d", &track_info->number);
     } else {
         track_info->number = track_index;
     }
 
-    track_info->title = copy_metadata(avctx, stream_index, "title");
+    if (track_index > 0) {
+        const AVDictionaryEntry *entry = av_dict_get(
+            avctx->chapters[track_index - 1]->metadata, "title", NULL, 0);
+        if (entry) {
+            track_info->title = av_strdup(entry->value);
+        }
+    }
+
+    if (!track_info->title) {
+        track_info->title = copy_metadata(avctx, stream_index, "title");
+    }
     if (!track_info->title) {
         track_info->title = copy_metadata(avctx, stream_index, "song");
     }
@@ -172,6 +306,33 @@ static struct TrackInfo *try_get_track_info(
         track_info->album_artist = copy_metadata(avctx, stream_index, "album artist");
     }
 
+    const AVCodecParameters *codecpar = stream->codecpar;
+    const AVCodecDescriptor *codec_desc = avcodec_descriptor_get(codecpar->codec_id);
+
+    track_info->codec = av_strdup(avcodec_get_name(codecpar->codec_id));
+    track_info->container = av_strdup(avctx->iformat->name);
+    track_info->bitrate = codecpar->bit_rate > 0 ? codecpar->bit_rate : avctx->bit_rate;
+    track_info->sample_rate = codecpar->sample_rate;
+    track_info->channels = codecpar->channels;
+    track_info->lossless = codec_desc && (codec_desc->props & AV_CODEC_PROP_LOSSLESS) ? 1 : 0;
+
+    // Coded sample size of lossy codecs says nothing about resolution
+    track_info->bit_depth = codecpar->bits_per_raw_sample > 0
+        ? codecpar->bits_per_raw_sample
+        : (track_info->lossless ? codecpar->bits_per_coded_sample : 0);
+
+    track_info->genre = copy_metadata(avctx, stream_index, "genre");
+
+    // Dates are usually full dates or plain years, only the year is kept
+    tmp = get_metadata(avctx, stream_index, "date");
+    if (!tmp) {
+        tmp = get_metadata(avctx, stream_index, "year");
+    }
+    if (tmp && sscanf(tmp, "%d", &track_info->year) == 1
+        && (track_info->year < 1 || track_info->year > 9999)) {
+        track_info->year = 0;
+    }
+
     return track_info;
 }
 
@@ -210,22 +371,15 @@ static struct ImageInfo *try_get_image_info(
     return image_info;
 }
 
-//     // TODO multiple tracks in single file
-//     // char *tmp;
-//     // int track_count;
-//     // tmp = copy_metadata(avctx, "tracks");
-//     // if (tmp) {
-//     //     sscanf(tmp, "%d", &track_count);
-//     // } else {
-//     //     track_count = 1;
-//     // }
-
 static void track_info_free(struct TrackInfo *track_info) {
     while (track_info) {
         free(track_info->title);
         free(track_info->artist);
         free(track_info->album);
         free(track_info->album_artist);
+        free(track_info->codec);
+        free(track_info->container);
+        free(track_info->genre);
 
         struct TrackInfo *prev = track_info;
         track_info = track_info->next;
@@ -246,6 +400,7 @@ static void image_info_free(struct ImageInfo *image_info) {
 void media_info_free(struct MediaInfo *media_info) {
     track_info_free(media_info->tracks);
     image_info_free(media_info->images);
+    free(media_info->cuesheet);
     free(media_info);
 }
 
@@ -1,6 +1,6 @@
 
 use std::ffi::{CStr, CString};
-use std::os::raw::c_char;
+use std::os::raw::{c_char, c_int};
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 
@@ -15,10 +15,25 @@ unsafe fn convert_string(s: *const c_char) -> String {
     String::from_utf8_lossy(CStr::from_ptr(s).to_bytes()).into_owned()
 }
 
-pub fn media_info_from_path(path: &Path) -> Option<(Vec<Track>, Vec<Image>)> {
+pub struct MediaInfo {
+    pub tracks: Vec<Track>,
+    pub images: Vec<Image>,
+    pub cuesheet: Option<String>,
+}
+
+fn non_zero(v: i64) -> Option<i64> {
+    if v > 0 {
+        Some(v)
+    } else {
+        None
+    }
+}
+
+pub fn media_info_from_path(path: &Path, exact_duration: bool) -> Option<MediaInfo> {
     let tmp_path = CString::new(path.as_os_str().as_bytes()).unwrap();
 
-    let file_info = unsafe { musicd_c::media_info_from_path(tmp_path.as_ptr()) };
+    let file_info =
+        unsafe { musicd_c::media_info_from_path(tmp_path.as_ptr(), exact_duration as c_int) };
     if file_info.is_null() {
         return None;
     }
@@ -36,6 +51,7 @@ pub fn media_info_from_path(path: &Path) -> Option<(Vec<Track>, Vec<Image>)> {
                 node_id: 0i64,
                 stream_index: i64::from(track_info.stream_index),
                 track_index: Some(i64::from(track_info.track_index)),
+                // Chapter bounds are resolved from track_index when streaming
                 start: None,
                 number: i64::from(track_info.number),
                 title: convert_string(track_info.title).trim().to_string(),
@@ -50,6 +66,29 @@ pub fn media_info_from_path(path: &Path) -> Option<(Vec<Track>, Vec<Image>)> {
                     Some(convert_string(track_info.album_artist).trim().to_string())
                 },
                 length: track_info.duration,
+                codec: if track_info.codec.is_null() {
+                    None
+                } else {
+                    Some(convert_string(track_info.codec))
+                },
+                container: if track_info.container.is_null() {
+                    None
+                } else {
+                    Some(convert_string(track_info.container))
+                },
+                bitrate: non_zero(track_info.bitrate),
+                sample_rate: non_zero(i64::from(track_info.sample_rate)),
+                bit_depth: non_zero(i64::from(track_info.bit_depth)),
+                channels: non_zero(i64::from(track_info.channels)),
+                file_size: None,
+                lossless: Some(track_info.lossless != 0),
+                genre: if track_info.genre.is_null() {
+                    None
+                } else {
+                    Some(convert_string(track_info.genre).trim().to_string())
+                        .filter(|g| !g.is_empty())
+                },
+                year: non_zero(i64::from(track_info.year)),
             }
         });
 
@@ -74,11 +113,23 @@ pub fn media_info_from_path(path: &Path) -> Option<(Vec<Track>, Vec<Image>)> {
         cur = unsafe { (*cur).next };
     }
 
+    let cuesheet = unsafe {
+        if (*file_info).cuesheet.is_null() {
+            None
+        } else {
+            Some(convert_string((*file_info).cuesheet))
+        }
+    };
+
     unsafe {
         musicd_c::media_info_free(file_info);
     }
 
-    Some((tracks, images))
+    Some(MediaInfo {
+        tracks,
+        images,
+        cuesheet,
+    })
 }
 
 pub fn media_image_data_read(path: &Path, stream_index: i32) -> Option<Vec<u8>> {
@@ -106,4 +157,35 @@ pub fn media_image_data_read(path: &Path, stream_index: i32) -> Option<Vec<u8>>
     }
 
     Some(result)
-}
\ No newline at end of file
+}
+
+pub fn media_waveform_read(
+    path: &Path,
+    stream_index: i32,
+    track_index: i32,
+    start: f64,
+    length: f64,
+    points: usize,
+) -> Option<(Vec<f32>, Vec<f32>)> {
+    let tmp_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+
+    let options = musicd_c::WaveformOptions {
+        path: tmp_path.as_ptr(),
+        stream_index,
+        track_index,
+        start,
+        length,
+        points: points as i32,
+    };
+
+    let mut min = vec![0f32; points];
+    let mut max = vec![0f32; points];
+
+    unsafe {
+        if musicd_c::media_waveform_read(&options, min.as_mut_ptr(), max.as_mut_ptr()) == 0 {
+            return None;
+        }
+    }
+
+    Some((min, max))
+}
@@ -0,0 +1,1961 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::auth;
+use crate::http_util::HttpQuery;
+use crate::index::{Index, NodeType};
+use crate::query::{self, NodeItem, TrackItem};
+use crate::store::User;
+use crate::Musicd;
+
+const MPD_VERSION: &str = "0.21.0";
+
+const MAX_LINE_LENGTH: u64 = 64 * 1024;
+
+// Same as the default max_command_list_size of MPD
+const MAX_COMMAND_LIST_SIZE: usize = 2048 * 1024;
+
+const MAX_EXPRESSION_DEPTH: usize = 64;
+
+// Persistent errors such as running out of file descriptors would otherwise
+// make the accept loop spin
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+// Error codes of the protocol
+const ACK_ERROR_ARG: i64 = 2;
+const ACK_ERROR_PASSWORD: i64 = 3;
+const ACK_ERROR_PERMISSION: i64 = 4;
+const ACK_ERROR_UNKNOWN: i64 = 5;
+const ACK_ERROR_NO_EXIST: i64 = 50;
+const ACK_ERROR_SYSTEM: i64 = 52;
+
+const COMMANDS: &[&str] = &[
+    "add",
+    "addid",
+    "clear",
+    "clearerror",
+    "close",
+    "commands",
+    "consume",
+    "currentsong",
+    "decoders",
+    "delete",
+    "deleteid",
+    "disableoutput",
+    "enableoutput",
+    "find",
+    "idle",
+    "list",
+    "listplaylist",
+    "listplaylistinfo",
+    "listplaylists",
+    "load",
+    "lsinfo",
+    "move",
+    "moveid",
+    "next",
+    "noidle",
+    "notcommands",
+    "outputs",
+    "password",
+    "pause",
+    "ping",
+    "play",
+    "playid",
+    "playlistid",
+    "playlistinfo",
+    "plchanges",
+    "plchangesposid",
+    "previous",
+    "random",
+    "repeat",
+    "rescan",
+    "search",
+    "seek",
+    "seekcur",
+    "seekid",
+    "setvol",
+    "shuffle",
+    "single",
+    "stats",
+    "status",
+    "stop",
+    "tagtypes",
+    "toggleoutput",
+    "update",
+    "urlhandlers",
+];
+
+// Available without a password
+const PUBLIC_COMMANDS: &[&str] = &["close", "commands", "notcommands", "password", "ping"];
+
+const TAG_TYPES: &[&str] = &["Artist", "Album", "AlbumArtist", "Title", "Track"];
+
+struct Ack {
+    code: i64,
+    message: String,
+}
+
+impl Ack {
+    fn new(code: i64, message: &str) -> Ack {
+        Ack {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Ack {
+    fn from(err: rusqlite::Error) -> Ack {
+        error!("database error in MPD command: {}", err);
+        Ack::new(ACK_ERROR_SYSTEM, "database error")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PlayState {
+    Stop,
+    Play,
+    Pause,
+}
+
+struct QueueItem {
+    id: i64,
+    track_id: i64,
+    length: f64,
+}
+
+// Playback isn't done by the server, the player only keeps track of the
+// queue and the position so that clients can stream the current track
+struct Player {
+    queue: Vec<QueueItem>,
+    next_id: i64,
+    version: i64,
+    current: Option<usize>,
+    state: PlayState,
+    // Position when playback was last started, paused or seeked
+    elapsed: f64,
+    started: Option<Instant>,
+    repeat: bool,
+    random: bool,
+    single: bool,
+    consume: bool,
+    volume: i64,
+}
+
+impl Player {
+    fn new() -> Player {
+        Player {
+            queue: Vec::new(),
+            next_id: 1,
+            version: 1,
+            current: None,
+            state: PlayState::Stop,
+            elapsed: 0f64,
+            started: None,
+            repeat: false,
+            random: false,
+            single: false,
+            consume: false,
+            volume: 100,
+        }
+    }
+
+    fn elapsed(&self) -> f64 {
+        self.elapsed
+            + self
+                .started
+                .map(|s| s.elapsed().as_secs_f64())
+                .unwrap_or_default()
+    }
+
+    fn position(&self, id: i64) -> Option<usize> {
+        self.queue.iter().position(|item| item.id == id)
+    }
+
+    fn playlist_changed(&mut self) {
+        self.version += 1;
+    }
+
+    fn start(&mut self, pos: usize, elapsed: f64) {
+        self.current = Some(pos);
+        self.state = PlayState::Play;
+        self.elapsed = elapsed;
+        self.started = Some(Instant::now());
+    }
+
+    fn stop(&mut self) {
+        self.state = PlayState::Stop;
+        self.elapsed = 0f64;
+        self.started = None;
+    }
+
+    fn set_pause(&mut self, pause: bool) {
+        match (self.state, pause) {
+            (PlayState::Play, true) => {
+                self.elapsed = self.elapsed();
+                self.started = None;
+                self.state = PlayState::Pause;
+            }
+            (PlayState::Pause, false) => {
+                self.started = Some(Instant::now());
+                self.state = PlayState::Play;
+            }
+            _ => {}
+        }
+    }
+
+    fn seek(&mut self, pos: usize, elapsed: f64) {
+        let state = self.state;
+        self.start(pos, elapsed.max(0f64));
+
+        if state == PlayState::Pause {
+            self.set_pause(true);
+        }
+    }
+
+    fn next_position(&self, forced: bool) -> Option<usize> {
+        let current = self.current?;
+
+        if self.single && !forced {
+            return if self.repeat { Some(current) } else { None };
+        }
+
+        if self.random && self.queue.len() > 1 {
+            return Some(rand::thread_rng().gen_range(0, self.queue.len()));
+        }
+
+        if current + 1 < self.queue.len() {
+            Some(current + 1)
+        } else if self.repeat && !self.queue.is_empty() {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    fn next(&mut self, forced: bool) {
+        let next = self.next_position(forced);
+
+        if self.consume {
+            if let Some(current) = self.current {
+                self.queue.remove(current);
+                self.playlist_changed();
+
+                let next = next.and_then(|n| match n {
+                    n if n > current => Some(n - 1),
+                    n if n == current && !self.queue.is_empty() => {
+                        Some(n.min(self.queue.len() - 1))
+                    }
+                    n if n < current => Some(n),
+                    _ => None,
+                });
+
+                return self.go_to(next);
+            }
+        }
+
+        self.go_to(next);
+    }
+
+    fn go_to(&mut self, pos: Option<usize>) {
+        match pos {
+            Some(pos) if self.state != PlayState::Stop => self.start(pos, 0f64),
+            Some(pos) => self.current = Some(pos),
+            None => {
+                self.current = None;
+                self.stop();
+            }
+        }
+    }
+
+    // Moves on to the following tracks as time passes, returns true if the
+    // current track changed
+    fn update(&mut self) -> bool {
+        let mut changed = false;
+
+        while self.state == PlayState::Play {
+            let length = match self.current.and_then(|c| self.queue.get(c)) {
+                Some(item) if item.length > 0f64 => item.length,
+                _ => break,
+            };
+
+            let elapsed = self.elapsed();
+            if elapsed < length {
+                break;
+            }
+
+            // The track ends where its remaining length from the last start
+            // runs out
+            let started =
+                self.started.unwrap() + Duration::from_secs_f64((length - self.elapsed).max(0f64));
+            self.next(false);
+            changed = true;
+
+            if self.state == PlayState::Play {
+                self.elapsed = 0f64;
+                self.started = Some(started);
+            }
+
+            if self.queue.is_empty() {
+                break;
+            }
+        }
+
+        changed
+    }
+
+    // Keeps the current track current when the queue is reordered
+    fn remove(&mut self, start: usize, end: usize) {
+        self.queue.drain(start..end);
+
+        if let Some(current) = self.current {
+            if current >= end {
+                self.current = Some(current - (end - start));
+            } else if current >= start {
+                self.current = None;
+                self.stop();
+            }
+        }
+
+        self.playlist_changed();
+    }
+
+    fn move_item(&mut self, from: usize, to: usize) {
+        let item = self.queue.remove(from);
+        self.queue.insert(to, item);
+
+        if let Some(current) = self.current {
+            self.current = Some(if current == from {
+                to
+            } else if from < current && to >= current {
+                current - 1
+            } else if from > current && to <= current {
+                current + 1
+            } else {
+                current
+            });
+        }
+
+        self.playlist_changed();
+    }
+}
+
+// Players of each user, shared by all connections of the user
+pub struct Players {
+    players: Mutex<HashMap<i64, Player>>,
+    changes: broadcast::Sender<(i64, &'static str)>,
+}
+
+impl Players {
+    pub fn new() -> Players {
+        let (changes, _) = broadcast::channel(16);
+
+        Players {
+            players: Mutex::new(HashMap::new()),
+            changes,
+        }
+    }
+
+    fn with<F, R>(&self, user_id: i64, f: F) -> R
+    where
+        F: FnOnce(&mut Player) -> R,
+    {
+        let mut players = self.players.lock().unwrap();
+        let player = players.entry(user_id).or_insert_with(Player::new);
+
+        if player.update() {
+            self.notify(user_id, "player");
+        }
+
+        f(player)
+    }
+
+    fn notify(&self, user_id: i64, subsystem: &'static str) {
+        // Fails only if nobody is listening
+        let _ = self.changes.send((user_id, subsystem));
+    }
+
+    // Track and position currently playing for the user
+    pub fn current_track(&self, user_id: i64) -> Option<(i64, f64)> {
+        self.with(user_id, |player| {
+            if player.state == PlayState::Stop {
+                return None;
+            }
+
+            let item = player.queue.get(player.current?)?;
+            Some((item.track_id, player.elapsed()))
+        })
+    }
+}
+
+pub async fn run_mpd(musicd: Arc<Musicd>, bind: SocketAddr) {
+    let mut listener = TcpListener::bind(&bind)
+        .await
+        .expect("can't bind MPD listener");
+
+    info!("MPD server listening on {}", bind);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                debug!("MPD connection from {}", addr);
+                tokio::spawn(run_connection(musicd.clone(), stream));
+            }
+            Err(e) => {
+                error!("accepting MPD connection failed: {}", e);
+                tokio::time::delay_for(ACCEPT_ERROR_DELAY).await;
+            }
+        }
+    }
+}
+
+enum Event {
+    Line(String),
+    Changed(i64, &'static str),
+    Closed,
+}
+
+struct Session {
+    musicd: Arc<Musicd>,
+    user: Option<User>,
+    roots: Vec<String>,
+    // Changes not yet reported by idle
+    pending: HashSet<&'static str>,
+    idle: Option<Vec<String>>,
+    command_list: Option<CommandList>,
+    closed: bool,
+}
+
+struct CommandList {
+    list_ok: bool,
+    commands: Vec<String>,
+    // Total length of the commands
+    size: usize,
+}
+
+impl CommandList {
+    fn new(list_ok: bool) -> CommandList {
+        CommandList {
+            list_ok,
+            commands: Vec::new(),
+            size: 0,
+        }
+    }
+}
+
+async fn run_connection(musicd: Arc<Musicd>, stream: TcpStream) {
+    let (reader, mut writer) = tokio::io::split(stream);
+
+    // Client commands and player changes are merged into one stream of events
+    // as idle waits for either
+    let (sender, mut events) = mpsc::channel::<Event>(16);
+
+    // The tasks end with the connection, when their guards are dropped
+    let (_reader_guard, mut reader_closed) = oneshot::channel::<()>();
+    let (_changes_guard, mut changes_closed) = oneshot::channel::<()>();
+
+    let mut line_sender = sender.clone();
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(reader);
+        let mut buf = Vec::new();
+
+        loop {
+            buf.clear();
+
+            let mut line_reader = (&mut reader).take(MAX_LINE_LENGTH);
+
+            let result = tokio::select! {
+                result = line_reader.read_until(b'\n', &mut buf) => result,
+                _ = &mut reader_closed => break,
+            };
+
+            // Lines too long to buffer close the connection as in MPD
+            let line = match result {
+                Ok(n) if n > 0 && (buf.ends_with(b"\n") || n < MAX_LINE_LENGTH as usize) => {
+                    String::from_utf8(buf.clone()).ok()
+                }
+                _ => None,
+            };
+
+            match line {
+                Some(line) => {
+                    let line = line.trim_end_matches('\n').trim_end_matches('\r');
+
+                    if line_sender
+                        .send(Event::Line(line.to_string()))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                None => {
+                    let _ = line_sender.send(Event::Closed).await;
+                    break;
+                }
+            }
+        }
+    });
+
+    let mut changes = musicd.players.changes.subscribe();
+    let mut change_sender = sender;
+    tokio::spawn(async move {
+        loop {
+            let change = tokio::select! {
+                change = changes.recv() => change,
+                _ = &mut changes_closed => break,
+            };
+
+            match change {
+                Ok((user_id, subsystem)) => {
+                    if change_sender
+                        .send(Event::Changed(user_id, subsystem))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(broadcast::RecvError::Lagged(_)) => {}
+                Err(broadcast::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let mut session = Session {
+        musicd,
+        user: None,
+        roots: Vec::new(),
+        pending: HashSet::new(),
+        idle: None,
+        command_list: None,
+        closed: false,
+    };
+
+    match session.authenticate_default() {
+        Ok(_) => {}
+        Err(ack) => {
+            error!("MPD authentication failed: {}", ack.message);
+            return;
+        }
+    }
+
+    let mut output = format!("OK MPD {}\n", MPD_VERSION);
+
+    loop {
+        if !output.is_empty() {
+            if writer.write_all(output.as_bytes()).await.is_err() {
+                break;
+            }
+            output.clear();
+        }
+
+        if session.closed {
+            break;
+        }
+
+        match events.recv().await {
+            Some(Event::Line(line)) => output = session.process_line(&line),
+            Some(Event::Changed(user_id, subsystem)) => {
+                if session.user.as_ref().map(|u| u.user_id) == Some(user_id) {
+                    session.pending.insert(subsystem);
+                    output = session.idle_output(false);
+                }
+            }
+            Some(Event::Closed) | None => break,
+        }
+    }
+}
+
+// Splits a command line into arguments, handling quoting
+fn parse_args(line: &str) -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+
+        let first = match chars.peek() {
+            Some(c) => *c,
+            None => break,
+        };
+
+        let mut arg = String::new();
+
+        if first == '"' {
+            chars.next();
+
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => arg.push(chars.next()?),
+                    c => arg.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                arg.push(*c);
+                chars.next();
+            }
+        }
+
+        args.push(arg);
+    }
+
+    Some(args)
+}
+
+#[derive(Debug, PartialEq)]
+enum FilterOp {
+    Equals,
+    NotEquals,
+    Contains,
+}
+
+#[derive(Debug, PartialEq)]
+struct Filter {
+    tag: String,
+    op: FilterOp,
+    value: String,
+}
+
+// Parses filter expressions like ((artist == "a") AND (album == 'b'))
+fn parse_expression(s: &str) -> Option<Vec<Filter>> {
+    fn skip_whitespace(s: &[char], i: &mut usize) {
+        while *i < s.len() && s[*i].is_whitespace() {
+            *i += 1;
+        }
+    }
+
+    fn parse(s: &[char], i: &mut usize, depth: usize, filters: &mut Vec<Filter>) -> Option<()> {
+        if depth > MAX_EXPRESSION_DEPTH {
+            return None;
+        }
+
+        skip_whitespace(s, i);
+
+        if s.get(*i) != Some(&'(') {
+            return None;
+        }
+        *i += 1;
+        skip_whitespace(s, i);
+
+        if s.get(*i) == Some(&'(') {
+            loop {
+                parse(s, i, depth + 1, filters)?;
+                skip_whitespace(s, i);
+
+                if s[*i..].starts_with(&['A', 'N', 'D']) {
+                    *i += 3;
+                } else {
+                    break;
+                }
+            }
+        } else {
+            let mut tag = String::new();
+            while *i < s.len() && !s[*i].is_whitespace() {
+                tag.push(s[*i]);
+                *i += 1;
+            }
+            skip_whitespace(s, i);
+
+            let mut op = String::new();
+            while *i < s.len() && !s[*i].is_whitespace() {
+                op.push(s[*i]);
+                *i += 1;
+            }
+            skip_whitespace(s, i);
+
+            let op = match op.as_str() {
+                "==" => FilterOp::Equals,
+                "!=" => FilterOp::NotEquals,
+                "contains" => FilterOp::Contains,
+                _ => return None,
+            };
+
+            let quote = *s.get(*i)?;
+            if quote != '"' && quote != '\'' {
+                return None;
+            }
+            *i += 1;
+
+            let mut value = String::new();
+            loop {
+                match *s.get(*i)? {
+                    c if c == quote => break,
+                    '\\' => {
+                        *i += 1;
+                        value.push(*s.get(*i)?);
+                    }
+                    c => value.push(c),
+                }
+                *i += 1;
+            }
+            *i += 1;
+
+            filters.push(Filter {
+                tag: tag.to_lowercase(),
+                op,
+                value,
+            });
+        }
+
+        skip_whitespace(s, i);
+
+        if s.get(*i) != Some(&')') {
+            return None;
+        }
+        *i += 1;
+
+        Some(())
+    }
+
+    let chars: Vec<char> = s.chars().collect();
+    let mut filters = Vec::new();
+    let mut i = 0;
+
+    parse(&chars, &mut i, 1, &mut filters)?;
+
+    Some(filters)
+}
+
+// Either a filter expression or tag and value pairs, followed by optional
+// "sort" and "window" arguments
+fn parse_filters(args: &[String], exact: bool) -> Option<(Vec<Filter>, Option<Range>)> {
+    let mut filters = Vec::new();
+    let mut window = None;
+    let mut i = 0;
+
+    while i < args.len() {
+        let arg = &args[i];
+
+        if arg.starts_with('(') {
+            filters.extend(parse_expression(arg)?);
+            i += 1;
+        } else if arg == "sort" || arg == "group" {
+            i += 2;
+        } else if arg == "window" {
+            window = Some(parse_range(args.get(i + 1)?)?);
+            i += 2;
+        } else {
+            filters.push(Filter {
+                tag: arg.to_lowercase(),
+                op: if exact {
+                    FilterOp::Equals
+                } else {
+                    FilterOp::Contains
+                },
+                value: args.get(i + 1)?.clone(),
+            });
+            i += 2;
+        }
+    }
+
+    Some((filters, window))
+}
+
+// Positions from START to END, exclusive
+type Range = (usize, usize);
+
+// "START:END", "START:" or a single position
+fn parse_range(s: &str) -> Option<Range> {
+    let mut parts = s.splitn(2, ':');
+    let start: usize = parts.next()?.parse().ok()?;
+
+    match parts.next() {
+        Some("") => Some((start, usize::MAX)),
+        Some(end) => Some((start, end.parse().ok()?)),
+        None => Some((start, start.checked_add(1)?)),
+    }
+}
+
+fn parse_bool(args: &[String]) -> Result<bool, Ack> {
+    match args.first().map(|a| a.as_str()) {
+        Some("0") => Ok(false),
+        Some("1") => Ok(true),
+        _ => Err(Ack::new(ACK_ERROR_ARG, "Boolean (0/1) expected")),
+    }
+}
+
+fn arg<T: std::str::FromStr>(args: &[String], i: usize) -> Result<T, Ack> {
+    match args.get(i).and_then(|a| a.parse().ok()) {
+        Some(v) => Ok(v),
+        None => Err(Ack::new(ACK_ERROR_ARG, "Invalid argument")),
+    }
+}
+
+fn new_query(params: &[(&str, String)]) -> HttpQuery {
+    let mut query = HttpQuery::from("");
+
+    for (key, value) in params {
+        query.set(key, value);
+    }
+
+    query
+}
+
+// Tracks within one file, such as those of cue sheets, get their own URIs
+// Cue sheet tracks and chapters share their file, so they are told apart by
+// their numbers
+fn in_shared_file(track: &TrackItem) -> bool {
+    track.start.is_some() || track.track_index.unwrap_or(0) > 0
+}
+
+fn track_uri(track: &TrackItem) -> String {
+    if in_shared_file(track) {
+        format!("{}/track{:04}", track.node_path, track.number)
+    } else {
+        track.node_path.clone()
+    }
+}
+
+fn tag_value(track: &TrackItem, tag: &str) -> Option<String> {
+    match tag {
+        "artist" => Some(track.artist_name.clone()),
+        "album" => Some(track.album_name.clone()),
+        "albumartist" => Some(
+            track
+                .album_artist_name
+                .clone()
+                .unwrap_or_else(|| track.artist_name.clone()),
+        ),
+        "title" => Some(track.title.clone()),
+        "track" => Some(track.number.to_string()),
+        "file" => Some(track_uri(track)),
+        _ => None,
+    }
+}
+
+fn filter_matches(track: &TrackItem, filter: &Filter, exact: bool) -> bool {
+    let values = if filter.tag == "any" {
+        ["artist", "album", "albumartist", "title", "file"]
+            .iter()
+            .filter_map(|tag| tag_value(track, tag))
+            .collect()
+    } else {
+        match tag_value(track, &filter.tag) {
+            Some(v) => vec![v],
+            None => Vec::new(),
+        }
+    };
+
+    let matches = |value: &String| match filter.op {
+        FilterOp::Contains => value.to_lowercase().contains(&filter.value.to_lowercase()),
+        _ if exact => value == &filter.value,
+        _ => value.to_lowercase() == filter.value.to_lowercase(),
+    };
+
+    if filter.op == FilterOp::NotEquals {
+        !values.iter().any(matches)
+    } else {
+        values.iter().any(matches)
+    }
+}
+
+// Responses are line based, so line breaks in tags and paths would inject
+// lines of their own
+fn line_value(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+fn write_song(out: &mut String, track: &TrackItem) {
+    out.push_str(&format!("file: {}\n", line_value(&track_uri(track))));
+    out.push_str(&format!("Title: {}\n", line_value(&track.title)));
+    out.push_str(&format!("Artist: {}\n", line_value(&track.artist_name)));
+    out.push_str(&format!("Album: {}\n", line_value(&track.album_name)));
+
+    if let Some(album_artist) = &track.album_artist_name {
+        out.push_str(&format!("AlbumArtist: {}\n", line_value(album_artist)));
+    }
+
+    out.push_str(&format!("Track: {}\n", track.number));
+    out.push_str(&format!("Time: {}\n", track.length.round() as i64));
+    out.push_str(&format!("duration: {:.3}\n", track.length));
+}
+
+impl Session {
+    fn authenticate_default(&mut self) -> Result<(), Ack> {
+        let store = self.musicd.store();
+
+        if !store.auth_required()? {
+            if let Some(user) = store.default_user()? {
+                self.set_user(user)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_user(&mut self, user: User) -> Result<(), Ack> {
+        self.roots = self.musicd.store().allowed_roots(&user, None)?;
+        self.user = Some(user);
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn user_id(&self) -> i64 {
+        self.user.as_ref().map(|u| u.user_id).unwrap_or_default()
+    }
+
+    fn players(&self) -> &Players {
+        &self.musicd.players
+    }
+
+    fn notify(&self, subsystem: &'static str) {
+        self.players().notify(self.user_id(), subsystem);
+    }
+
+    fn process_line(&mut self, line: &str) -> String {
+        if self.idle.is_some() {
+            // Anything but noidle is a protocol error while idling
+            return if line.trim() == "noidle" {
+                self.idle_output(true)
+            } else {
+                self.closed = true;
+                String::new()
+            };
+        }
+
+        if let Some(command_list) = &mut self.command_list {
+            if line.trim() != "command_list_end" {
+                // Lists are buffered before authentication, too large ones
+                // close the connection as in MPD
+                command_list.size += line.len();
+                if command_list.size > MAX_COMMAND_LIST_SIZE {
+                    self.command_list = None;
+                    self.closed = true;
+                    return String::new();
+                }
+
+                command_list.commands.push(line.to_string());
+                return String::new();
+            }
+
+            let CommandList {
+                list_ok, commands, ..
+            } = self.command_list.take().unwrap();
+
+            let mut output = String::new();
+
+            for (i, command) in commands.iter().enumerate() {
+                match self.execute(command) {
+                    Ok(o) => {
+                        output.push_str(&o);
+                        if list_ok {
+                            output.push_str("list_OK\n");
+                        }
+                    }
+                    Err(ack) => {
+                        output.push_str(&format_ack(&ack, i, command));
+                        return output;
+                    }
+                }
+            }
+
+            output.push_str("OK\n");
+            return output;
+        }
+
+        match line.trim() {
+            "command_list_begin" => {
+                self.command_list = Some(CommandList::new(false));
+                return String::new();
+            }
+            "command_list_ok_begin" => {
+                self.command_list = Some(CommandList::new(true));
+                return String::new();
+            }
+            _ => {}
+        }
+
+        match self.execute(line) {
+            Ok(output) => {
+                if self.idle.is_some() || self.closed {
+                    output
+                } else {
+                    output + "OK\n"
+                }
+            }
+            Err(ack) => format_ack(&ack, 0, line),
+        }
+    }
+
+    // Reports pending changes the idling client is interested in, or
+    // nothing if there are none unless `force` is set
+    fn idle_output(&mut self, force: bool) -> String {
+        let subsystems = match &self.idle {
+            Some(s) => s,
+            None => return String::new(),
+        };
+
+        let changed: Vec<&'static str> = self
+            .pending
+            .iter()
+            .filter(|p| subsystems.is_empty() || subsystems.iter().any(|s| s == *p))
+            .cloned()
+            .collect();
+
+        if changed.is_empty() && !force {
+            return String::new();
+        }
+
+        let mut output = String::new();
+
+        for subsystem in changed {
+            self.pending.remove(subsystem);
+            output.push_str(&format!("changed: {}\n", subsystem));
+        }
+
+        self.idle = None;
+        output.push_str("OK\n");
+        output
+    }
+
+    fn execute(&mut self, line: &str) -> Result<String, Ack> {
+        let args = match parse_args(line) {
+            Some(a) => a,
+            None => return Err(Ack::new(ACK_ERROR_ARG, "Invalid quoting")),
+        };
+
+        let (command, args) = match args.split_first() {
+            Some((c, a)) => (c.as_str(), a),
+            None => return Err(Ack::new(ACK_ERROR_UNKNOWN, "No command given")),
+        };
+
+        if self.user.is_none() && !PUBLIC_COMMANDS.contains(&command) {
+            return Err(Ack::new(
+                ACK_ERROR_PERMISSION,
+                &format!("you don't have permission for \"{}\"", command),
+            ));
+        }
+
+        let user_id = self.user_id();
+
+        match command {
+            "ping" | "clearerror" => Ok(String::new()),
+            "close" => {
+                self.closed = true;
+                Ok(String::new())
+            }
+            "password" => self.password(args),
+            "idle" => {
+                self.idle = Some(args.iter().map(|a| a.to_lowercase()).collect());
+                Ok(self.idle_output(false))
+            }
+            "commands" => Ok(COMMANDS
+                .iter()
+                .map(|c| format!("command: {}\n", c))
+                .collect()),
+            "notcommands" => Ok(String::new()),
+            "tagtypes" => {
+                if args.is_empty() {
+                    Ok(TAG_TYPES
+                        .iter()
+                        .map(|t| format!("tagtype: {}\n", t))
+                        .collect())
+                } else {
+                    Ok(String::new())
+                }
+            }
+            "urlhandlers" | "decoders" => Ok(String::new()),
+            "outputs" => Ok("outputid: 0\n\
+                 outputname: musicd2 stream\n\
+                 plugin: httpd\n\
+                 outputenabled: 1\n"
+                .to_string()),
+            "enableoutput" | "disableoutput" | "toggleoutput" => Ok(String::new()),
+            "update" | "rescan" => {
+                // Scans cover the libraries of all users
+                if !self.user.as_ref().is_some_and(|u| u.admin) {
+                    return Err(Ack::new(
+                        ACK_ERROR_PERMISSION,
+                        &format!("you don't have permission for \"{}\"", command),
+                    ));
+                }
+
+                self.musicd.scan_thread.start(self.musicd.index());
+                self.notify("update");
+                Ok("updating_db: 1\n".to_string())
+            }
+            "stats" => self.stats(),
+            "status" => Ok(self.status()),
+            "currentsong" => self.current_song(),
+            "lsinfo" => self.lsinfo(args),
+            "list" => self.list(args),
+            "find" => self.find(args, true),
+            "search" => self.find(args, false),
+            "add" | "addid" => self.add(args, command == "addid"),
+            "clear" => {
+                self.players().with(user_id, |p| {
+                    p.queue.clear();
+                    p.current = None;
+                    p.stop();
+                    p.playlist_changed();
+                });
+                self.notify("playlist");
+                self.notify("player");
+                Ok(String::new())
+            }
+            "delete" => {
+                let (start, end) = match args.first().and_then(|a| parse_range(a)) {
+                    Some(r) => r,
+                    None => return Err(Ack::new(ACK_ERROR_ARG, "Invalid range")),
+                };
+
+                self.players().with(user_id, |p| {
+                    let end = end.min(p.queue.len());
+                    if start >= end {
+                        return Err(Ack::new(ACK_ERROR_ARG, "Bad song index"));
+                    }
+                    p.remove(start, end);
+                    Ok(())
+                })?;
+
+                self.notify("playlist");
+                Ok(String::new())
+            }
+            "deleteid" => {
+                let id: i64 = arg(args, 0)?;
+
+                self.players().with(user_id, |p| match p.position(id) {
+                    Some(pos) => {
+                        p.remove(pos, pos + 1);
+                        Ok(())
+                    }
+                    None => Err(Ack::new(ACK_ERROR_NO_EXIST, "No such song")),
+                })?;
+
+                self.notify("playlist");
+                Ok(String::new())
+            }
+            "move" | "moveid" => {
+                let to: usize = arg(args, 1)?;
+
+                self.players().with(user_id, |p| {
+                    let from = if command == "moveid" {
+                        p.position(arg(args, 0)?)
+                    } else {
+                        Some(arg(args, 0)?)
+                    };
+
+                    match from {
+                        Some(from) if from < p.queue.len() && to < p.queue.len() => {
+                            p.move_item(from, to);
+                            Ok(())
+                        }
+                        _ => Err(Ack::new(ACK_ERROR_ARG, "Bad song index")),
+                    }
+                })?;
+
+                self.notify("playlist");
+                Ok(String::new())
+            }
+            "shuffle" => {
+                self.players().with(user_id, |p| {
+                    let current_id = p.current.and_then(|c| p.queue.get(c)).map(|i| i.id);
+                    p.queue.shuffle(&mut rand::thread_rng());
+                    p.current = current_id.and_then(|id| p.position(id));
+                    p.playlist_changed();
+                });
+
+                self.notify("playlist");
+                Ok(String::new())
+            }
+            "playlistinfo" | "playlistid" => {
+                let range = match (command, args.first()) {
+                    (_, None) => None,
+                    ("playlistid", Some(_)) => {
+                        let id: i64 = arg(args, 0)?;
+                        match self.players().with(user_id, |p| p.position(id)) {
+                            Some(pos) => Some((pos, pos + 1)),
+                            None => return Err(Ack::new(ACK_ERROR_NO_EXIST, "No such song")),
+                        }
+                    }
+                    (_, Some(a)) => match parse_range(a) {
+                        Some(r) => Some(r),
+                        None => return Err(Ack::new(ACK_ERROR_ARG, "Invalid range")),
+                    },
+                };
+
+                self.queue_info(range, false)
+            }
+            "plchanges" | "plchangesposid" => {
+                let version: i64 = arg(args, 0)?;
+
+                // Changes aren't tracked per item, everything is reported
+                // if the queue has changed
+                if version >= self.players().with(user_id, |p| p.version) {
+                    return Ok(String::new());
+                }
+
+                self.queue_info(None, command == "plchangesposid")
+            }
+            "play" | "playid" => {
+                self.players().with(user_id, |p| {
+                    let pos = match (command, args.first()) {
+                        (_, None) => p
+                            .current
+                            .or(if p.queue.is_empty() { None } else { Some(0) }),
+                        ("playid", Some(_)) => p.position(arg(args, 0)?),
+                        (_, Some(_)) => Some(arg(args, 0)?),
+                    };
+
+                    match pos {
+                        Some(pos) if pos < p.queue.len() => {
+                            if p.state == PlayState::Pause
+                                && Some(pos) == p.current
+                                && args.is_empty()
+                            {
+                                p.set_pause(false);
+                            } else {
+                                p.start(pos, 0f64);
+                            }
+                            Ok(())
+                        }
+                        None if p.queue.is_empty() => Ok(()),
+                        _ => Err(Ack::new(ACK_ERROR_ARG, "Bad song index")),
+                    }
+                })?;
+
+                self.notify("player");
+                Ok(String::new())
+            }
+            "pause" => {
+                let pause = if args.is_empty() {
+                    None
+                } else {
+                    Some(parse_bool(args)?)
+                };
+
+                self.players().with(user_id, |p| {
+                    let pause = pause.unwrap_or(p.state == PlayState::Play);
+                    p.set_pause(pause);
+                });
+
+                self.notify("player");
+                Ok(String::new())
+            }
+            "stop" => {
+                self.players().with(user_id, |p| p.stop());
+                self.notify("player");
+                Ok(String::new())
+            }
+            "next" => {
+                self.players().with(user_id, |p| {
+                    if p.state != PlayState::Stop {
+                        p.next(true);
+                    }
+                });
+                self.notify("player");
+                Ok(String::new())
+            }
+            "previous" => {
+                self.players().with(user_id, |p| {
+                    if let Some(current) = p.current {
+                        if p.state != PlayState::Stop {
+                            p.start(current.saturating_sub(1), 0f64);
+                        }
+                    }
+                });
+                self.notify("player");
+                Ok(String::new())
+            }
+            "seek" | "seekid" | "seekcur" => {
+                let time_arg = if command == "seekcur" { 0 } else { 1 };
+                let time = match args.get(time_arg) {
+                    Some(t) => t.clone(),
+                    None => return Err(Ack::new(ACK_ERROR_ARG, "Missing time")),
+                };
+
+                self.players().with(user_id, |p| {
+                    let pos = match command {
+                        "seekid" => p.position(arg(args, 0)?),
+                        "seek" => Some(arg(args, 0)?),
+                        _ => p.current,
+                    };
+
+                    let pos = match pos {
+                        Some(pos) if pos < p.queue.len() => pos,
+                        _ => return Err(Ack::new(ACK_ERROR_ARG, "Bad song index")),
+                    };
+
+                    // seekcur accepts relative times
+                    let seconds: f64 = match time.trim_start_matches('+').parse() {
+                        Ok(s) => s,
+                        Err(_) => return Err(Ack::new(ACK_ERROR_ARG, "Invalid time")),
+                    };
+
+                    let elapsed = if command == "seekcur"
+                        && (time.starts_with('+') || time.starts_with('-'))
+                    {
+                        p.elapsed() + seconds
+                    } else {
+                        seconds
+                    };
+
+                    p.seek(pos, elapsed);
+                    Ok(())
+                })?;
+
+                self.notify("player");
+                Ok(String::new())
+            }
+            "setvol" => {
+                let volume: i64 = arg(args, 0)?;
+                self.players()
+                    .with(user_id, |p| p.volume = volume.clamp(0, 100));
+                self.notify("mixer");
+                Ok(String::new())
+            }
+            "repeat" | "random" | "single" | "consume" => {
+                let value = parse_bool(args)?;
+
+                self.players().with(user_id, |p| match command {
+                    "repeat" => p.repeat = value,
+                    "random" => p.random = value,
+                    "single" => p.single = value,
+                    _ => p.consume = value,
+                });
+
+                self.notify("options");
+                Ok(String::new())
+            }
+            "listplaylists" => {
+                let lists = self.musicd.store().lists(user_id)?;
+                Ok(lists
+                    .iter()
+                    .map(|l| format!("playlist: {}\n", line_value(&l.name)))
+                    .collect())
+            }
+            "listplaylist" | "listplaylistinfo" => {
+                let tracks = self.list_tracks(args)?;
+                let mut output = String::new();
+
+                for track in tracks {
+                    if command == "listplaylist" {
+                        output.push_str(&format!("file: {}\n", line_value(&track_uri(&track))));
+                    } else {
+                        write_song(&mut output, &track);
+                    }
+                }
+
+                Ok(output)
+            }
+            "load" => {
+                let tracks = self.list_tracks(args)?;
+                self.enqueue(&tracks, None);
+                Ok(String::new())
+            }
+            _ => Err(Ack::new(
+                ACK_ERROR_UNKNOWN,
+                &format!("unknown command \"{}\"", command),
+            )),
+        }
+    }
+
+    // Passwords are API tokens, which identify the user
+    fn password(&mut self, args: &[String]) -> Result<String, Ack> {
+        let password = match args.first() {
+            Some(p) => p,
+            None => return Err(Ack::new(ACK_ERROR_ARG, "Missing password")),
+        };
+
+        let store = self.musicd.store();
+
+        match store.use_api_token(&auth::hash_token(password), auth::now())? {
+            Some((user, api_token_id)) => {
+                self.roots = store.allowed_roots(&user, Some(api_token_id))?;
+                self.user = Some(user);
+                self.pending.clear();
+                Ok(String::new())
+            }
+            None => Err(Ack::new(ACK_ERROR_PASSWORD, "incorrect password")),
+        }
+    }
+
+    fn stats(&self) -> Result<String, Ack> {
+        let index = self.musicd.index();
+        let none = new_query(&[("limit", "0".to_string())]);
+
+        let (artists, _) = query::query_artists(&index, &none, self.user_id(), &self.roots)?;
+        let (albums, _) = query::query_albums(&index, &none, self.user_id(), &self.roots)?;
+        let (songs, _) = query::query_tracks(&index, &none, self.user_id(), &self.roots)?;
+
+        Ok(format!(
+            "artists: {}\nalbums: {}\nsongs: {}\nuptime: 0\nplaytime: 0\ndb_playtime: 0\n",
+            artists, albums, songs
+        ))
+    }
+
+    fn status(&self) -> String {
+        self.players().with(self.user_id(), |p| {
+            let mut output = format!(
+                "volume: {}\nrepeat: {}\nrandom: {}\nsingle: {}\nconsume: {}\n\
+                 playlist: {}\nplaylistlength: {}\nstate: {}\n",
+                p.volume,
+                p.repeat as i64,
+                p.random as i64,
+                p.single as i64,
+                p.consume as i64,
+                p.version,
+                p.queue.len(),
+                match p.state {
+                    PlayState::Stop => "stop",
+                    PlayState::Play => "play",
+                    PlayState::Pause => "pause",
+                }
+            );
+
+            if let Some(current) = p.current {
+                let item = &p.queue[current];
+                output.push_str(&format!("song: {}\nsongid: {}\n", current, item.id));
+
+                if p.state != PlayState::Stop {
+                    let elapsed = p.elapsed();
+                    output.push_str(&format!(
+                        "time: {}:{}\nelapsed: {:.3}\nduration: {:.3}\n",
+                        elapsed as i64,
+                        item.length.round() as i64,
+                        elapsed,
+                        item.length
+                    ));
+                }
+
+                if let Some(next) = p.next_position(false) {
+                    output.push_str(&format!(
+                        "nextsong: {}\nnextsongid: {}\n",
+                        next, p.queue[next].id
+                    ));
+                }
+            }
+
+            output
+        })
+    }
+
+    fn track(&self, track_id: i64) -> Result<Option<TrackItem>, Ack> {
+        let (_, mut tracks) = query::query_tracks(
+            &self.musicd.index(),
+            &new_query(&[("track_id", track_id.to_string())]),
+            self.user_id(),
+            &self.roots,
+        )?;
+
+        Ok(tracks.pop())
+    }
+
+    fn current_song(&self) -> Result<String, Ack> {
+        let current = self.players().with(self.user_id(), |p| {
+            p.current.map(|c| (c, p.queue[c].id, p.queue[c].track_id))
+        });
+
+        let mut output = String::new();
+
+        if let Some((pos, id, track_id)) = current {
+            if let Some(track) = self.track(track_id)? {
+                write_song(&mut output, &track);
+                output.push_str(&format!("Pos: {}\nId: {}\n", pos, id));
+            }
+        }
+
+        Ok(output)
+    }
+
+    fn queue_info(&self, range: Option<(usize, usize)>, pos_id_only: bool) -> Result<String, Ack> {
+        let items: Vec<(usize, i64, i64)> = self.players().with(self.user_id(), |p| {
+            let (start, end) = range.unwrap_or((0, p.queue.len()));
+
+            p.queue
+                .iter()
+                .enumerate()
+                .skip(start)
+                .take(end.saturating_sub(start))
+                .map(|(pos, item)| (pos, item.id, item.track_id))
+                .collect()
+        });
+
+        if range.is_some() && items.is_empty() {
+            return Err(Ack::new(ACK_ERROR_ARG, "Bad song index"));
+        }
+
+        let mut output = String::new();
+
+        for (pos, id, track_id) in items {
+            if pos_id_only {
+                output.push_str(&format!("cpos: {}\nId: {}\n", pos, id));
+                continue;
+            }
+
+            // Tracks removed from the index remain in the queue without tags
+            match self.track(track_id)? {
+                Some(track) => write_song(&mut output, &track),
+                None => output.push_str(&format!("file: {}\n", track_id)),
+            }
+
+            output.push_str(&format!("Pos: {}\nId: {}\n", pos, id));
+        }
+
+        Ok(output)
+    }
+
+    fn child_nodes(&self, index: &Index, parent_id: Option<i64>) -> Result<Vec<NodeItem>, Ack> {
+        let parent_id = match parent_id {
+            Some(id) => id.to_string(),
+            None => "null".to_string(),
+        };
+
+        let (_, mut nodes) =
+            query::query_nodes(index, &new_query(&[("parent_id", parent_id)]), &self.roots)?;
+
+        nodes.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(nodes)
+    }
+
+    fn node_tracks(&self, index: &Index, node_id: i64) -> Result<Vec<TrackItem>, Ack> {
+        let (_, mut tracks) = query::query_tracks(
+            index,
+            &new_query(&[("node_id", node_id.to_string())]),
+            self.user_id(),
+            &self.roots,
+        )?;
+
+        tracks.sort_by(|a, b| {
+            a.start
+                .partial_cmp(&b.start)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.number.cmp(&b.number))
+        });
+
+        Ok(tracks)
+    }
+
+    // Finds the node of a directory or file URI, or the file node and track
+    // number of a track within a file
+    fn resolve_uri(&self, index: &Index, uri: &str) -> Result<(NodeItem, Option<i64>), Ack> {
+        let uri = uri.trim_matches('/');
+        let not_found = || Ack::new(ACK_ERROR_NO_EXIST, "No such file or directory");
+
+        let path = Path::new(uri);
+        let (node_path, number) = match index.node_by_path(path)? {
+            Some(_) => (path, None),
+            None => {
+                let number = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .filter(|n| n.starts_with("track"))
+                    .and_then(|n| n[5..].parse::<i64>().ok());
+
+                match (number, path.parent()) {
+                    (Some(number), Some(parent)) => (parent, Some(number)),
+                    _ => return Err(not_found()),
+                }
+            }
+        };
+
+        let node = match index.node_by_path(node_path)? {
+            Some(n) => n,
+            None => return Err(not_found()),
+        };
+
+        // Goes through the query to apply root access
+        let (_, nodes) = query::query_nodes(
+            index,
+            &new_query(&[(
+                "parent_id",
+                match node.parent_id {
+                    Some(id) => id.to_string(),
+                    None => "null".to_string(),
+                },
+            )]),
+            &self.roots,
+        )?;
+
+        match nodes.into_iter().find(|n| n.node_id == node.node_id) {
+            Some(node) => Ok((node, number)),
+            None => Err(not_found()),
+        }
+    }
+
+    fn uri_tracks(&self, index: &Index, uri: &str) -> Result<Vec<TrackItem>, Ack> {
+        if uri.trim_matches('/').is_empty() {
+            let mut tracks = Vec::new();
+            for node in self.child_nodes(index, None)? {
+                self.collect_tracks(index, &node, &mut tracks)?;
+            }
+            return Ok(tracks);
+        }
+
+        let (node, number) = self.resolve_uri(index, uri)?;
+
+        let mut tracks = Vec::new();
+        self.collect_tracks(index, &node, &mut tracks)?;
+
+        if let Some(number) = number {
+            tracks.retain(|t| in_shared_file(t) && t.number == number);
+        }
+
+        if tracks.is_empty() && number.is_some() {
+            return Err(Ack::new(ACK_ERROR_NO_EXIST, "No such file or directory"));
+        }
+
+        Ok(tracks)
+    }
+
+    fn collect_tracks(
+        &self,
+        index: &Index,
+        node: &NodeItem,
+        tracks: &mut Vec<TrackItem>,
+    ) -> Result<(), Ack> {
+        match node.node_type {
+            NodeType::Directory => {
+                for child in self.child_nodes(index, Some(node.node_id))? {
+                    self.collect_tracks(index, &child, tracks)?;
+                }
+            }
+            NodeType::File => tracks.extend(self.node_tracks(index, node.node_id)?),
+            NodeType::Other => {}
+        }
+
+        Ok(())
+    }
+
+    fn lsinfo(&self, args: &[String]) -> Result<String, Ack> {
+        let index = self.musicd.index();
+        let uri = args.first().map(|a| a.as_str()).unwrap_or_default();
+
+        let mut output = String::new();
+
+        let (nodes, file_tracks) = if uri.trim_matches('/').is_empty() {
+            (self.child_nodes(&index, None)?, Vec::new())
+        } else {
+            match self.resolve_uri(&index, uri)? {
+                (ref node, None) if node.node_type == NodeType::Directory => {
+                    (self.child_nodes(&index, Some(node.node_id))?, Vec::new())
+                }
+                _ => (Vec::new(), self.uri_tracks(&index, uri)?),
+            }
+        };
+
+        for node in nodes {
+            match node.node_type {
+                NodeType::Directory => {
+                    output.push_str(&format!("directory: {}\n", line_value(&node.path)));
+                }
+                NodeType::File => {
+                    for track in self.node_tracks(&index, node.node_id)? {
+                        write_song(&mut output, &track);
+                    }
+                }
+                NodeType::Other => {}
+            }
+        }
+
+        for track in file_tracks {
+            write_song(&mut output, &track);
+        }
+
+        Ok(output)
+    }
+
+    // Prefilters in the database as far as possible, the filters are then
+    // matched exactly
+    fn filter_tracks(&self, filters: &[Filter], exact: bool) -> Result<Vec<TrackItem>, Ack> {
+        let mut params: Vec<(&str, String)> = Vec::new();
+
+        for filter in filters {
+            let key = match filter.tag.as_str() {
+                "artist" => "artist_name",
+                "album" => "album_name",
+                "albumartist" => "album_artist_name",
+                "title" => "title",
+                "any" if filter.op == FilterOp::Contains => {
+                    params.push(("search", filter.value.clone()));
+                    continue;
+                }
+                "any" | "file" | "track" => continue,
+                tag => {
+                    return Err(Ack::new(
+                        ACK_ERROR_ARG,
+                        &format!("Unsupported tag \"{}\"", tag),
+                    ))
+                }
+            };
+
+            match filter.op {
+                FilterOp::Equals => params.push((key, filter.value.clone())),
+                FilterOp::Contains => params.push((key, format!("%{}%", filter.value))),
+                FilterOp::NotEquals => {}
+            }
+        }
+
+        let (_, tracks) = query::query_tracks(
+            &self.musicd.index(),
+            &new_query(&params),
+            self.user_id(),
+            &self.roots,
+        )?;
+
+        Ok(tracks
+            .into_iter()
+            .filter(|t| filters.iter().all(|f| filter_matches(t, f, exact)))
+            .collect())
+    }
+
+    fn find(&self, args: &[String], exact: bool) -> Result<String, Ack> {
+        let (filters, window) = match parse_filters(args, exact) {
+            Some(f) => f,
+            None => return Err(Ack::new(ACK_ERROR_ARG, "Invalid filter")),
+        };
+
+        let tracks = self.filter_tracks(&filters, exact)?;
+        let (start, end) = window.unwrap_or((0, usize::MAX));
+
+        let mut output = String::new();
+
+        for track in tracks.iter().skip(start).take(end.saturating_sub(start)) {
+            write_song(&mut output, track);
+        }
+
+        Ok(output)
+    }
+
+    fn list(&self, args: &[String]) -> Result<String, Ack> {
+        let tag = match args.first() {
+            Some(t) => t.to_lowercase(),
+            None => return Err(Ack::new(ACK_ERROR_ARG, "Missing tag type")),
+        };
+
+        // "list album <artist>" is the legacy form of filtering by artist
+        let filters = if tag == "album" && args.len() == 2 && !args[1].starts_with('(') {
+            vec![Filter {
+                tag: "artist".to_string(),
+                op: FilterOp::Equals,
+                value: args[1].clone(),
+            }]
+        } else {
+            match parse_filters(&args[1..], true) {
+                Some((f, _)) => f,
+                None => return Err(Ack::new(ACK_ERROR_ARG, "Invalid filter")),
+            }
+        };
+
+        let name = match tag.as_str() {
+            "artist" => "Artist",
+            "album" => "Album",
+            "albumartist" => "AlbumArtist",
+            "title" => "Title",
+            "track" => "Track",
+            "file" => "file",
+            // Tags that aren't indexed have no values
+            _ => return Ok(String::new()),
+        };
+
+        let mut values: Vec<String> = self
+            .filter_tracks(&filters, true)?
+            .iter()
+            .filter_map(|t| tag_value(t, &tag))
+            .collect();
+
+        values.sort();
+        values.dedup();
+
+        Ok(values
+            .iter()
+            .map(|v| format!("{}: {}\n", name, line_value(v)))
+            .collect())
+    }
+
+    fn enqueue(&self, tracks: &[TrackItem], pos: Option<usize>) -> Option<i64> {
+        let id = self.players().with(self.user_id(), |p| {
+            let start = pos.unwrap_or(p.queue.len()).min(p.queue.len());
+            let mut first_id = None;
+
+            for (pos, track) in (start..).zip(tracks) {
+                let id = p.next_id;
+                p.next_id += 1;
+                first_id = first_id.or(Some(id));
+
+                p.queue.insert(
+                    pos,
+                    QueueItem {
+                        id,
+                        track_id: track.track_id,
+                        length: track.length,
+                    },
+                );
+
+                if let Some(current) = p.current {
+                    if current >= pos {
+                        p.current = Some(current + 1);
+                    }
+                }
+            }
+
+            p.playlist_changed();
+            first_id
+        });
+
+        self.notify("playlist");
+        id
+    }
+
+    fn add(&self, args: &[String], with_id: bool) -> Result<String, Ack> {
+        let uri = match args.first() {
+            Some(u) => u,
+            None => return Err(Ack::new(ACK_ERROR_ARG, "Missing URI")),
+        };
+
+        let pos = match args.get(1) {
+            Some(_) => Some(arg(args, 1)?),
+            None => None,
+        };
+
+        let mut tracks = self.uri_tracks(&self.musicd.index(), uri)?;
+
+        // addid adds a single song
+        if with_id {
+            tracks.truncate(1);
+        }
+
+        match self.enqueue(&tracks, pos) {
+            Some(id) if with_id => Ok(format!("Id: {}\n", id)),
+            _ => Ok(String::new()),
+        }
+    }
+
+    fn list_tracks(&self, args: &[String]) -> Result<Vec<TrackItem>, Ack> {
+        let name = match args.first() {
+            Some(n) => n,
+            None => return Err(Ack::new(ACK_ERROR_ARG, "Missing playlist name")),
+        };
+
+        let list = match self
+            .musicd
+            .store()
+            .lists(self.user_id())?
+            .into_iter()
+            .find(|l| &l.name == name)
+        {
+            Some(l) => l,
+            None => return Err(Ack::new(ACK_ERROR_NO_EXIST, "No such playlist")),
+        };
+
+        let (_, tracks) = query::query_tracks(
+            &self.musicd.index(),
+            &new_query(&[("list_id", list.list_id.to_string())]),
+            self.user_id(),
+            &self.roots,
+        )?;
+
+        Ok(tracks)
+    }
+}
+
+fn format_ack(ack: &Ack, list_index: usize, line: &str) -> String {
+    let command = line.split_whitespace().next().unwrap_or_default();
+
+    format!(
+        "ACK [{}@{}] {{{}}} {}\n",
+        ack.code, list_index, command, ack.message
+    )
+}
+
+#[test]
+fn test_parse_args() {
+    assert_eq!(
+        parse_args("find artist \"Foo \\\"Bar\\\"\" album Baz").unwrap(),
+        vec!["find", "artist", "Foo \"Bar\"", "album", "Baz"]
+    );
+    assert!(parse_args("find \"unterminated").is_none());
+}
+
+#[test]
+fn test_parse_expression() {
+    assert_eq!(
+        parse_expression("((Artist == \"A \\\"B\\\"\") AND (album contains 'c'))").unwrap(),
+        vec![
+            Filter {
+                tag: "artist".to_string(),
+                op: FilterOp::Equals,
+                value: "A \"B\"".to_string()
+            },
+            Filter {
+                tag: "album".to_string(),
+                op: FilterOp::Contains,
+                value: "c".to_string()
+            }
+        ]
+    );
+    assert!(parse_expression("(artist =~ \"a\")").is_none());
+
+    let nested = |depth| {
+        format!(
+            "{}(artist == \"a\"){}",
+            "(".repeat(depth - 1),
+            ")".repeat(depth - 1)
+        )
+    };
+    assert_eq!(
+        parse_expression(&nested(MAX_EXPRESSION_DEPTH))
+            .unwrap()
+            .len(),
+        1
+    );
+    assert!(parse_expression(&nested(MAX_EXPRESSION_DEPTH + 1)).is_none());
+    assert!(parse_expression(&"(".repeat(64 * 1024)).is_none());
+}
+
+#[test]
+fn test_parse_range() {
+    assert_eq!(parse_range("3"), Some((3, 4)));
+    assert_eq!(parse_range("3:7"), Some((3, 7)));
+    assert_eq!(parse_range("3:"), Some((3, usize::MAX)));
+    assert_eq!(parse_range(&usize::MAX.to_string()), None);
+    assert_eq!(parse_range("a:7"), None);
+}
+
+#[test]
+fn test_player_update_after_seek() {
+    let mut player = Player::new();
+    for id in 1..=2 {
+        player.queue.push(QueueItem {
+            id,
+            track_id: id,
+            length: 10f64,
+        });
+    }
+
+    // Seeked to 8 s three seconds ago, the second track has played for 1 s
+    player.start(0, 0f64);
+    player.seek(0, 8f64);
+    player.started = Instant::now().checked_sub(Duration::from_secs(3));
+
+    assert!(player.update());
+    assert_eq!(player.current, Some(1));
+
+    let elapsed = player.elapsed();
+    assert!(elapsed > 0.9 && elapsed < 1.5, "elapsed {}", elapsed);
+}
+
+#[test]
+fn test_line_value() {
+    assert_eq!(line_value("Title\nOK\r\n"), "Title OK  ");
+    assert_eq!(line_value("Plain"), "Plain");
+}
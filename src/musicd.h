@@ -13,6 +13,7 @@
 struct MediaInfo {
     struct TrackInfo *tracks;
     struct ImageInfo *images;
+    char *cuesheet;
 };
 
 struct TrackInfo {
@@ -26,6 +27,15 @@ struct TrackInfo {
     char *album_artist;
     double start;
     double length;
+    char *codec;
+    char *container;
+    int64_t bitrate;
+    int32_t sample_rate;
+    int32_t bit_depth;
+    int32_t channels;
+    int32_t lossless;
+    char *genre;
+    int32_t year;
 };
 
 struct ImageInfo {
@@ -45,6 +55,15 @@ struct AudioStreamOptions {
     char *target_codec;
 };
 
+struct WaveformOptions {
+    char *path;
+    int32_t stream_index;
+    int32_t track_index;
+    double start;
+    double length;
+    int32_t points;
+};
+
 struct AudioStream {
     AVFormatContext *in_ctx, *out_ctx;
     AVStream *in_stream, *out_stream;
@@ -72,7 +91,13 @@ void musicd_log_setup(void (*callback)(int level, const char *));
 
 void lav_error(const char *msg, int lav_result);
 
-struct MediaInfo *media_info_from_path(const char *path);
+int media_chapter_bounds(
+    const AVFormatContext *avctx,
+    int track_index,
+    double *start,
+    double *length);
+
+struct MediaInfo *media_info_from_path(const char *path, int exact_duration);
 void media_info_free(struct MediaInfo *media_info);
 
 struct AudioStream *audio_stream_open(const struct AudioStreamOptions *options);
@@ -87,4 +112,9 @@ int media_image_data_read(
     int32_t stream_index,
     uint8_t **out_data,
     size_t *out_len);
-void media_image_data_free(uint8_t *data);
\ No newline at end of file
+void media_image_data_free(uint8_t *data);
+
+int media_waveform_read(
+    const struct WaveformOptions *options,
+    float *out_min,
+    float *out_max);
\ No newline at end of file
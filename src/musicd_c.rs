@@ -5,6 +5,7 @@ use std::os::raw::{c_char, c_int, c_void};
 pub struct MediaInfo {
     pub tracks: *const TrackInfo,
     pub images: *const ImageInfo,
+    pub cuesheet: *const c_char,
 }
 
 #[repr(C)]
@@ -19,6 +20,15 @@ pub struct TrackInfo {
     pub album_artist: *const c_char,
     pub start: f64,
     pub duration: f64,
+    pub codec: *const c_char,
+    pub container: *const c_char,
+    pub bitrate: i64,
+    pub sample_rate: i32,
+    pub bit_depth: i32,
+    pub channels: i32,
+    pub lossless: i32,
+    pub genre: *const c_char,
+    pub year: i32,
 }
 
 #[repr(C)]
@@ -40,6 +50,17 @@ pub struct AudioStreamOptions {
     pub target_codec: *const c_char,
 }
 
+#[repr(C)]
+pub struct WaveformOptions {
+    pub path: *const c_char,
+    pub stream_index: i32,
+    pub track_index: i32,
+    pub start: f64,
+    pub length: f64,
+    pub points: i32,
+}
+
+#[allow(clippy::enum_variant_names)]
 pub enum LogLevel {
     LogLevelError = 1,
     LogLevelWarn = 2,
@@ -51,7 +72,7 @@ pub enum LogLevel {
 extern "C" {
     pub fn musicd_log_setup(callback: extern "C" fn(level: c_int, message: *const c_char));
 
-    pub fn media_info_from_path(path: *const c_char) -> *const MediaInfo;
+    pub fn media_info_from_path(path: *const c_char, exact_duration: c_int) -> *const MediaInfo;
     pub fn media_info_free(track: *const MediaInfo);
 
     pub fn audio_stream_open(config: *const AudioStreamOptions) -> *const c_void;
@@ -69,4 +90,10 @@ extern "C" {
         out_len: *mut usize,
     ) -> c_int;
     pub fn media_image_data_free(data: *mut u8);
+
+    pub fn media_waveform_read(
+        options: *const WaveformOptions,
+        out_min: *mut f32,
+        out_max: *mut f32,
+    ) -> c_int;
 }
\ No newline at end of file
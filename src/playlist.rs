@@ -0,0 +1,457 @@
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+use encoding_rs::WINDOWS_1252;
+
+use crate::http_util::{escape_xml, HttpQuery};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    M3u,
+    Pls,
+    Xspf,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Playlist {
+    pub title: Option<String>,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Entry {
+    // Path or URL as written in the playlist
+    pub location: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    // Seconds
+    pub length: Option<f64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Location {
+    Path(PathBuf),
+    // Audio stream URL of this server
+    TrackId(i64),
+    Unknown,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "m3u" | "m3u8" => Some(Format::M3u),
+            "pls" => Some(Format::Pls),
+            "xspf" => Some(Format::Xspf),
+            _ => None,
+        }
+    }
+
+    pub fn detect(text: &str) -> Format {
+        let start = text.trim_start();
+
+        if start.starts_with("<?xml") || start.starts_with("<playlist") {
+            Format::Xspf
+        } else if start
+            .get(..10)
+            .is_some_and(|s| s.eq_ignore_ascii_case("[playlist]"))
+        {
+            Format::Pls
+        } else {
+            Format::M3u
+        }
+    }
+}
+
+// Plain M3U files are often in the legacy encoding of the system that wrote them
+pub fn decode(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s.to_string(),
+        Err(_) => WINDOWS_1252
+            .decode_without_bom_handling(bytes)
+            .0
+            .into_owned(),
+    }
+}
+
+pub fn parse(text: &str, format: Format) -> Playlist {
+    match format {
+        Format::M3u => parse_m3u(text),
+        Format::Pls => parse_pls(text),
+        Format::Xspf => parse_xspf(text),
+    }
+}
+
+fn parse_m3u(text: &str) -> Playlist {
+    let mut playlist = Playlist::default();
+    let mut info: Option<Entry> = None;
+
+    for line in text.lines().map(|l| l.trim()) {
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<seconds>,<artist> - <title>
+            let mut parts = extinf.splitn(2, ',');
+            let length = parts.next().and_then(|l| l.trim().parse::<f64>().ok());
+            let name = parts.next().unwrap_or_default().trim();
+
+            let (artist, title) = match name.find(" - ") {
+                Some(i) => (Some(name[..i].to_string()), name[i + 3..].to_string()),
+                None => (None, name.to_string()),
+            };
+
+            info = Some(Entry {
+                title: Some(title).filter(|t| !t.is_empty()),
+                artist,
+                length: length.filter(|l| *l >= 0f64),
+                ..Default::default()
+            });
+        } else if let Some(title) = line.strip_prefix("#PLAYLIST:") {
+            playlist.title = Some(title.trim().to_string());
+        } else if !line.starts_with('#') {
+            playlist.entries.push(Entry {
+                location: line.to_string(),
+                ..info.take().unwrap_or_default()
+            });
+        }
+    }
+
+    playlist
+}
+
+fn parse_pls(text: &str) -> Playlist {
+    let mut entries: BTreeMap<u32, Entry> = BTreeMap::new();
+
+    for line in text.lines().map(|l| l.trim()) {
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap_or_default();
+        let value = match parts.next() {
+            Some(v) => v.trim(),
+            None => continue,
+        };
+
+        // File1=, Title1=, Length1=
+        let digits = key.find(|c: char| c.is_ascii_digit()).unwrap_or(key.len());
+        let number: u32 = match key[digits..].parse() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+
+        let entry = entries.entry(number).or_default();
+
+        match key[..digits].to_ascii_lowercase().as_str() {
+            "file" => entry.location = value.to_string(),
+            "title" => entry.title = Some(value.to_string()),
+            // -1 for streams of unknown length
+            "length" => entry.length = value.parse().ok().filter(|l| *l >= 0f64),
+            _ => {}
+        }
+    }
+
+    Playlist {
+        title: None,
+        entries: entries
+            .into_values()
+            .filter(|e| !e.location.is_empty())
+            .collect(),
+    }
+}
+
+fn unescape_xml(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(i) = rest.find('&') {
+        result.push_str(&rest[..i]);
+        rest = &rest[i..];
+
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+
+        let entity = &rest[1..end];
+
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match (entity.strip_prefix("#x"), entity.strip_prefix('#')) {
+                (Some(hex), _) => u32::from_str_radix(hex, 16)
+                    .ok()
+                    .and_then(std::char::from_u32),
+                (None, Some(decimal)) => decimal.parse().ok().and_then(std::char::from_u32),
+                (None, None) => None,
+            },
+        };
+
+        match c {
+            Some(c) => {
+                result.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+// Text of the first <name> element in `xml`, enough for XSPF which has no
+// attributes of interest
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&close)?;
+
+    Some(&xml[start..end])
+}
+
+fn parse_xspf(text: &str) -> Playlist {
+    let mut playlist = Playlist::default();
+
+    let (head, mut rest) = match text.find("<trackList>") {
+        Some(i) => text.split_at(i),
+        None => (text, ""),
+    };
+
+    playlist.title = xml_element(head, "title").map(|t| unescape_xml(t.trim()));
+
+    while let Some(start) = rest.find("<track>") {
+        let end = match rest[start..].find("</track>") {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        let track = &rest[start..end];
+        let text = |name| xml_element(track, name).map(|t| unescape_xml(t.trim()));
+
+        if let Some(location) = text("location") {
+            playlist.entries.push(Entry {
+                location,
+                title: text("title"),
+                artist: text("creator"),
+                album: text("album"),
+                length: text("duration")
+                    .and_then(|d| d.parse::<f64>().ok())
+                    .map(|d| d / 1000f64),
+            });
+        }
+
+        rest = &rest[end..];
+    }
+
+    playlist
+}
+
+fn decode_percent(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if bytes.get(i + 1).is_some_and(u8::is_ascii_hexdigit)
+                && bytes.get(i + 2).is_some_and(u8::is_ascii_hexdigit) =>
+            {
+                result.push(u8::from_str_radix(&s[i + 1..i + 3], 16).unwrap());
+                i += 3;
+            }
+            b => {
+                result.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+fn encode_percent(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+
+    for &b in s.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                result.push(b as char)
+            }
+            b => result.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    result
+}
+
+// Removes . and .. lexically, .. at the start is dropped
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                result.pop();
+            }
+            c => result.push(c),
+        }
+    }
+
+    result
+}
+
+pub fn parse_location(location: &str) -> Location {
+    if let Some(path) = location.strip_prefix("file://") {
+        // file:///path or file://localhost/path
+        let path = &path[path.find('/').unwrap_or(0)..];
+
+        return Location::Path(PathBuf::from(decode_percent(path)));
+    }
+
+    if location.starts_with("http://") || location.starts_with("https://") {
+        let (path, query) = match location.find('?') {
+            Some(i) => (&location[..i], &location[i + 1..]),
+            None => (location, ""),
+        };
+
+        if path.ends_with("/api/audio_stream") {
+            if let Some(track_id) = HttpQuery::from(query).get_i64("track_id") {
+                return Location::TrackId(track_id);
+            }
+        }
+
+        return Location::Unknown;
+    }
+
+    // Playlists written on Windows
+    Location::Path(PathBuf::from(location.replace('\\', "/")))
+}
+
+// Locations are paths or URLs, which are written as is
+pub fn write_m3u8(entries: &[Entry]) -> String {
+    let mut result = String::from("#EXTM3U\n");
+
+    for entry in entries {
+        let name = match (&entry.artist, &entry.title) {
+            (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+            (None, Some(title)) => title.clone(),
+            _ => String::new(),
+        };
+
+        result.push_str(&format!(
+            "#EXTINF:{},{}\n{}\n",
+            entry.length.map_or(-1, |l| l.round() as i64),
+            name,
+            entry.location
+        ));
+    }
+
+    result
+}
+
+pub fn write_xspf(title: &str, entries: &[Entry]) -> String {
+    let mut result = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+        <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n\
+        <title>{}</title>\n\
+        <trackList>\n",
+        escape_xml(title)
+    );
+
+    for entry in entries {
+        // XSPF locations are URIs
+        let location = if entry.location.starts_with('/') {
+            format!("file://{}", encode_percent(&entry.location))
+        } else {
+            entry.location.clone()
+        };
+
+        result.push_str("<track>\n");
+        result.push_str(&format!("<location>{}</location>\n", escape_xml(&location)));
+
+        for (name, value) in &[
+            ("title", &entry.title),
+            ("creator", &entry.artist),
+            ("album", &entry.album),
+        ] {
+            if let Some(value) = value {
+                result.push_str(&format!("<{}>{}</{}>\n", name, escape_xml(value), name));
+            }
+        }
+
+        if let Some(length) = entry.length {
+            result.push_str(&format!(
+                "<duration>{}</duration>\n",
+                (length * 1000f64) as i64
+            ));
+        }
+
+        result.push_str("</track>\n");
+    }
+
+    result.push_str("</trackList>\n</playlist>\n");
+    result
+}
+
+#[test]
+fn test_parse() {
+    let m3u = parse(
+        "#EXTM3U\n#EXTINF:123,Artist - Title\nmusic/a.flac\n\nC:\\Music\\b.mp3\n",
+        Format::M3u,
+    );
+    assert_eq!(m3u.entries.len(), 2);
+    assert_eq!(m3u.entries[0].artist.as_deref(), Some("Artist"));
+    assert_eq!(m3u.entries[0].length, Some(123f64));
+    assert_eq!(m3u.entries[1].title, None);
+
+    let pls = parse(
+        "[playlist]\nFile2=b.mp3\nFile1=a.mp3\nTitle1=A\nLength1=-1\nNumberOfEntries=2\n",
+        Format::Pls,
+    );
+    assert_eq!(pls.entries[0].location, "a.mp3");
+    assert_eq!(pls.entries[0].length, None);
+    assert_eq!(pls.entries[1].location, "b.mp3");
+
+    let xspf = parse(
+        &write_xspf(
+            "A & B",
+            &[Entry {
+                location: "/music/a b.flac".to_string(),
+                length: Some(1.5),
+                ..Default::default()
+            }],
+        ),
+        Format::Xspf,
+    );
+    assert_eq!(xspf.title.as_deref(), Some("A & B"));
+    assert_eq!(xspf.entries[0].location, "file:///music/a%20b.flac");
+    assert_eq!(xspf.entries[0].length, Some(1.5));
+}
+
+#[test]
+fn test_parse_location() {
+    assert_eq!(
+        parse_location("file:///music/a%20b.flac"),
+        Location::Path(PathBuf::from("/music/a b.flac"))
+    );
+    assert_eq!(
+        parse_location("https://host/api/audio_stream?track_id=5&token=x"),
+        Location::TrackId(5)
+    );
+    assert_eq!(parse_location("http://radio/stream"), Location::Unknown);
+    assert_eq!(
+        normalize(Path::new("music/list/../a/./b.flac")),
+        PathBuf::from("music/a/b.flac")
+    );
+}
@@ -3,11 +3,12 @@ use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
 
 use rusqlite::types::ToSql;
-use rusqlite::{Connection, Statement};
+use rusqlite::{Connection, OptionalExtension, Statement};
 use serde::Serialize;
 
 use crate::http_util::HttpQuery;
 use crate::index::{Index, NodeType};
+use crate::smart_list::Rules;
 
 struct QueryOptions {
     clauses: Vec<String>,
@@ -49,6 +50,12 @@ impl QueryOptions {
         }
     }
 
+    // Only matches rows whose node, found by `node_id_column`, is in one of `roots`
+    pub fn filter_roots(&mut self, node_id_column: &str, roots: &[String]) {
+        let (clause, values) = roots_clause(node_id_column, roots);
+        self.filter_values(&clause, values);
+    }
+
     pub fn bind_filter_i64(&mut self, query: &HttpQuery, key: &str, clause: &str) {
         if let Some(value) = query.get_i64(key) {
             self.filter_value(clause, value);
@@ -129,22 +136,64 @@ impl QueryOptions {
     }
 }
 
+pub fn roots_clause(node_id_column: &str, roots: &[String]) -> (String, Vec<Box<dyn ToSql>>) {
+    if roots.is_empty() {
+        return ("0".to_string(), Vec::new());
+    }
+
+    let mut conditions: Vec<&str> = Vec::new();
+    let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+
+    // Paths are stored as blobs, so the prefix is compared as one
+    for root in roots {
+        conditions.push("access_node.path = ? OR substr(access_node.path, 1, ?) = ?");
+        values.push(Box::new(root.as_bytes().to_vec()));
+        values.push(Box::new(root.len() as i64 + 1));
+        values.push(Box::new(format!("{}/", root).into_bytes()));
+    }
+
+    (
+        format!(
+            "EXISTS (
+                SELECT access_node.node_id
+                FROM Node AS access_node
+                WHERE access_node.node_id = {} AND ({})
+            )",
+            node_id_column,
+            conditions.join(" OR ")
+        ),
+        values,
+    )
+}
+
+// Filters by the user's "rating" and "favorite", given their SQL expressions
+fn filter_rating(opts: &mut QueryOptions, query: &HttpQuery, rating: &str, favorite: &str) {
+    if let Some(value) = query.get_i64("rating") {
+        opts.filter_value(&format!("{} = ?", rating), value);
+    }
+
+    if let Some(value) = query.get_i64("favorite") {
+        opts.filter_value(&format!("{} = ?", favorite), value != 0);
+    }
+}
+
 #[derive(Serialize)]
 pub struct NodeItem {
-    node_id: i64,
-    parent_id: Option<i64>,
-    node_type: NodeType,
-    name: String,
-    path: String,
-    track_count: i64,
-    image_count: i64,
-    all_track_count: i64,
-    all_image_count: i64,
+    pub node_id: i64,
+    pub parent_id: Option<i64>,
+    pub node_type: NodeType,
+    pub name: String,
+    pub path: String,
+    pub track_count: i64,
+    pub image_count: i64,
+    pub all_track_count: i64,
+    pub all_image_count: i64,
 }
 
 pub fn query_nodes(
     index: &Index,
     query: &HttpQuery,
+    roots: &[String],
 ) -> Result<(i64, Vec<NodeItem>), rusqlite::Error> {
     let mut opts = QueryOptions::new();
 
@@ -160,6 +209,8 @@ pub fn query_nodes(
         }
     }
 
+    opts.filter_roots("Node.node_id", roots);
+
     opts.bind_range(&query);
 
     let conn = index.connection();
@@ -254,27 +305,106 @@ pub fn query_nodes(
     Ok((total, items))
 }
 
+// Selects the tracks of a smart list, invalid rules select nothing
+fn filter_smart_list(opts: &mut QueryOptions, rules: &str, user_id: i64, roots: &[String]) {
+    let compiled = match Rules::parse(rules).and_then(|r| r.compile(user_id)) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("invalid smart list rules: {}", e);
+            opts.filter("0");
+            return;
+        }
+    };
+
+    match compiled.limit {
+        // Limited to the visible tracks before any paging
+        Some(limit) => {
+            let (roots_clause, roots_values) = roots_clause("Track.node_id", roots);
+            let mut values = compiled.values;
+            values.extend(roots_values);
+
+            opts.filter_values(
+                &format!(
+                    "Track.track_id IN (
+                        SELECT Track.track_id
+                        FROM Track
+                        WHERE {} AND {}
+                        ORDER BY {}
+                        LIMIT {}
+                    )",
+                    compiled.clause, roots_clause, compiled.order, limit
+                ),
+                values,
+            );
+        }
+        None => opts.filter_values(&compiled.clause, compiled.values),
+    }
+
+    opts.order_string(&compiled.order);
+}
+
 #[derive(Serialize)]
 pub struct TrackItem {
-    track_id: i64,
-    node_id: i64,
-    number: i64,
-    title: String,
-    artist_id: i64,
-    artist_name: String,
-    album_id: i64,
-    album_name: String,
-    length: f64,
-    node_path: String,
+    pub track_id: i64,
+    pub node_id: i64,
+    pub number: i64,
+    pub title: String,
+    pub artist_id: i64,
+    pub artist_name: String,
+    pub album_id: i64,
+    pub album_name: String,
+    pub album_artist_name: Option<String>,
+    pub start: Option<f64>,
+    // Chapter of the file, if above zero
+    pub track_index: Option<i64>,
+    pub length: f64,
+    pub node_path: String,
+    pub codec: Option<String>,
+    pub container: Option<String>,
+    pub bitrate: Option<i64>,
+    pub sample_rate: Option<i64>,
+    pub bit_depth: Option<i64>,
+    pub channels: Option<i64>,
+    pub file_size: Option<i64>,
+    pub lossless: Option<bool>,
+    pub play_count: Option<i64>,
+    pub last_play: Option<i64>,
+    pub image_id: Option<i64>,
+    pub bookmark_position: Option<f64>,
+    pub rating: i64,
+    pub favorite: bool,
+    pub genre: Option<String>,
+    pub year: Option<i64>,
 }
 
+// Play statistics and lists are those of `user_id`. All queries only return
+// items from `roots`.
 pub fn query_tracks(
     index: &Index,
     query: &HttpQuery,
+    user_id: i64,
+    roots: &[String],
 ) -> Result<(i64, Vec<TrackItem>), rusqlite::Error> {
     let mut opts = QueryOptions::new();
 
     opts.bind_filter_i64(&query, "track_id", "Track.track_id = ?");
+
+    if let Some(track_ids) = query.get_str("track_ids") {
+        let track_ids: Vec<Box<dyn ToSql>> = track_ids
+            .split(',')
+            .filter_map(|id| id.parse::<i64>().ok())
+            .map(|id| Box::new(id) as Box<dyn ToSql>)
+            .collect();
+
+        opts.filter_values(
+            &format!(
+                "Track.track_id IN ({})",
+                vec!["?"; track_ids.len()].join(", ")
+            ),
+            track_ids,
+        );
+    }
+
     opts.bind_filter_i64(&query, "node_id", "Track.node_id = ?");
     opts.bind_filter_i64(&query, "number", "Track.number = ?");
     opts.bind_filter_str(&query, "title", "Track.title LIKE ? COLLATE NOCASE");
@@ -290,6 +420,13 @@ pub fn query_tracks(
         "album_name",
         "Track.album_name LIKE ? COLLATE NOCASE",
     );
+    opts.bind_filter_str(
+        query,
+        "album_artist_name",
+        "Track.album_artist_name LIKE ? COLLATE NOCASE",
+    );
+    opts.bind_filter_str(query, "genre", "Track.genre LIKE ? COLLATE NOCASE");
+    opts.bind_filter_i64(query, "year", "Track.year = ?");
 
     if let Some(search) = query.get_str("search") {
         let mut values: Vec<Box<dyn ToSql>> = Vec::new();
@@ -303,7 +440,105 @@ pub fn query_tracks(
         );
     }
 
-    opts.order_string("Track.album_name, Track.number, Track.title");
+    opts.filter_roots("Track.node_id", roots);
+
+    let bookmark = format!(
+        "FROM StoreTrack
+        INNER JOIN StoreBookmark ON StoreBookmark.store_track_id = StoreTrack.store_track_id
+        WHERE
+            StoreBookmark.user_id = {} AND
+            StoreTrack.title = Track.title AND
+            StoreTrack.artist_name = Track.artist_name AND
+            StoreTrack.album_name = Track.album_name",
+        user_id
+    );
+
+    let track_rating = format!(
+        "FROM StoreTrack
+        INNER JOIN StoreTrackRating ON StoreTrackRating.store_track_id = StoreTrack.store_track_id
+        WHERE
+            StoreTrackRating.user_id = {} AND
+            StoreTrack.title = Track.title AND
+            StoreTrack.artist_name = Track.artist_name AND
+            StoreTrack.album_name = Track.album_name",
+        user_id
+    );
+    let rating = format!(
+        "COALESCE((SELECT StoreTrackRating.rating {}), 0)",
+        track_rating
+    );
+    let favorite = format!(
+        "COALESCE((SELECT StoreTrackRating.favorite {}), 0)",
+        track_rating
+    );
+
+    filter_rating(&mut opts, query, &rating, &favorite);
+
+    let bookmarked = query.get_i64("bookmarked").unwrap_or(0) != 0;
+    if bookmarked {
+        opts.filter(&format!(
+            "EXISTS (SELECT StoreBookmark.position {})",
+            bookmark
+        ));
+    }
+
+    let smart_rules = match query.get_i64("list_id") {
+        Some(list_id) => index
+            .connection()
+            .query_row(
+                "SELECT rules FROM StoreList WHERE list_id = ? AND user_id = ?",
+                [list_id, user_id],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()?
+            .flatten(),
+        None => None,
+    };
+
+    if let Some(rules) = smart_rules {
+        filter_smart_list(&mut opts, &rules, user_id, roots);
+    } else if let Some(list_id) = query.get_i64("list_id") {
+        opts.filter_value(
+            &format!(
+                "EXISTS (
+                    SELECT StoreListTrack.list_id
+                    FROM StoreListTrack
+                    INNER JOIN StoreTrack ON StoreTrack.store_track_id = StoreListTrack.store_track_id
+                    INNER JOIN StoreList ON StoreList.list_id = StoreListTrack.list_id
+                    WHERE
+                        StoreListTrack.list_id = ? AND
+                        StoreList.user_id = {} AND
+                        StoreTrack.title = Track.title AND
+                        StoreTrack.artist_name = Track.artist_name AND
+                        StoreTrack.album_name = Track.album_name
+                )",
+                user_id
+            ),
+            list_id,
+        );
+
+        opts.order_string(&format!(
+            "(
+                SELECT MIN(StoreListTrack.sort_index)
+                FROM StoreListTrack
+                INNER JOIN StoreTrack ON StoreTrack.store_track_id = StoreListTrack.store_track_id
+                WHERE
+                    StoreListTrack.list_id = {} AND
+                    StoreTrack.title = Track.title AND
+                    StoreTrack.artist_name = Track.artist_name AND
+                    StoreTrack.album_name = Track.album_name
+            )",
+            list_id
+        ));
+    } else if bookmarked {
+        // Most recently listened first
+        opts.order_string(&format!(
+            "(SELECT MAX(StoreBookmark.updated) {}) DESC",
+            bookmark
+        ));
+    } else {
+        opts.order_string("Track.album_name, Track.number, Track.title");
+    }
 
     opts.bind_range(&query);
 
@@ -311,26 +546,71 @@ pub fn query_tracks(
 
     let total = opts.get_total(&conn, "SELECT COUNT(Track.track_id) FROM Track")?;
 
+    let user_track = format!(
+        "FROM StoreTrack
+        INNER JOIN StoreUserTrack ON StoreUserTrack.store_track_id = StoreTrack.store_track_id
+        WHERE
+            StoreUserTrack.user_id = {} AND
+            StoreTrack.title = Track.title AND
+            StoreTrack.artist_name = Track.artist_name AND
+            StoreTrack.album_name = Track.album_name",
+        user_id
+    );
+
     let (mut st, values) = opts.into_items_query(
         &conn,
-        "SELECT
-            Track.track_id,
-            Track.node_id,
-            Track.number,
-            Track.title,
-            Track.artist_id,
-            Track.artist_name,
-            Track.album_id,
-            Track.album_name,
-            Track.length,
+        &format!(
+            "SELECT
+                Track.track_id,
+                Track.node_id,
+                Track.number,
+                Track.title,
+                Track.artist_id,
+                Track.artist_name,
+                Track.album_id,
+                Track.album_name,
+                Track.length,
 
-            (
-                SELECT Node.path
-                FROM Node
-                WHERE Node.node_id = Track.node_id
-            ) AS node_path
+                (
+                    SELECT Node.path
+                    FROM Node
+                    WHERE Node.node_id = Track.node_id
+                ) AS node_path,
+
+                Track.codec,
+                Track.container,
+                Track.bitrate,
+                Track.sample_rate,
+                Track.bit_depth,
+                Track.channels,
+                Track.file_size,
+                Track.lossless,
+
+                (SELECT StoreUserTrack.play_count {}) AS play_count,
+                (SELECT StoreUserTrack.last_play {}) AS last_play,
+
+                (
+                    SELECT Album.image_id
+                    FROM Album
+                    WHERE Album.album_id = Track.album_id
+                ) AS image_id,
 
-        FROM Track",
+                Track.album_artist_name,
+                Track.start,
+
+                (SELECT StoreBookmark.position {}) AS bookmark_position,
+
+                {} AS rating,
+                {} AS favorite,
+
+                Track.genre,
+                Track.year,
+
+                Track.track_index
+
+            FROM Track",
+            user_track, user_track, bookmark, rating, favorite
+        ),
     )?;
 
     let mut rows = st.query(&values)?;
@@ -349,31 +629,101 @@ pub fn query_tracks(
             artist_name: row.get(5)?,
             album_id: row.get(6)?,
             album_name: row.get(7)?,
+            album_artist_name: row.get(21)?,
+            start: row.get(22)?,
+            track_index: row.get(28)?,
             length: row.get(8)?,
             node_path: OsStr::from_bytes(&path).to_string_lossy().to_string(),
+            codec: row.get(10)?,
+            container: row.get(11)?,
+            bitrate: row.get(12)?,
+            sample_rate: row.get(13)?,
+            bit_depth: row.get(14)?,
+            channels: row.get(15)?,
+            file_size: row.get(16)?,
+            lossless: row.get(17)?,
+            play_count: row.get(18)?,
+            last_play: row.get(19)?,
+            image_id: row.get(20)?,
+            bookmark_position: row.get(23)?,
+            rating: row.get(24)?,
+            favorite: row.get(25)?,
+            genre: row.get(26)?,
+            year: row.get(27)?,
         });
     }
 
     Ok((total, items))
 }
 
+// Number of visible tracks of a list, needed for smart lists as their tracks
+// aren't stored
+pub fn count_list_tracks(
+    index: &Index,
+    list_id: i64,
+    user_id: i64,
+    roots: &[String],
+) -> Result<i64, rusqlite::Error> {
+    let mut query = HttpQuery::from("");
+    query.set("list_id", &list_id.to_string());
+    query.set("limit", "0");
+
+    Ok(query_tracks(index, &query, user_id, roots)?.0)
+}
+
 #[derive(Serialize)]
 pub struct ArtistItem {
-    artist_id: i64,
-    name: String,
-    track_count: i64,
+    pub artist_id: i64,
+    pub name: String,
+    pub track_count: i64,
+    pub album_count: i64,
+    pub rating: i64,
+    pub favorite: bool,
 }
 
 pub fn query_artists(
     index: &Index,
     query: &HttpQuery,
+    user_id: i64,
+    roots: &[String],
 ) -> Result<(i64, Vec<ArtistItem>), rusqlite::Error> {
     let mut opts = QueryOptions::new();
 
+    let artist_rating = format!(
+        "FROM StoreArtistRating
+        WHERE
+            StoreArtistRating.user_id = {} AND
+            StoreArtistRating.artist_name = Artist.name",
+        user_id
+    );
+    let rating = format!(
+        "COALESCE((SELECT StoreArtistRating.rating {}), 0)",
+        artist_rating
+    );
+    let favorite = format!(
+        "COALESCE((SELECT StoreArtistRating.favorite {}), 0)",
+        artist_rating
+    );
+
+    filter_rating(&mut opts, query, &rating, &favorite);
+
     opts.bind_filter_i64(&query, "artist_id", "Artist.artist_id = ?");
     opts.bind_filter_str(&query, "name", "Artist.name LIKE ? COLLATE NOCASE");
     opts.bind_filter_str(&query, "search", "Artist.name LIKE ? COLLATE NOCASE");
 
+    let (clause, values) = roots_clause("Track.node_id", roots);
+    opts.filter_values(
+        &format!(
+            "EXISTS (
+                SELECT Track.track_id
+                FROM Track
+                WHERE Track.artist_id = Artist.artist_id AND {}
+            )",
+            clause
+        ),
+        values,
+    );
+
     opts.order_string("Artist.name");
 
     opts.bind_range(&query);
@@ -382,12 +732,42 @@ pub fn query_artists(
 
     let total = opts.get_total(&conn, "SELECT COUNT(Artist.artist_id) FROM Artist")?;
 
-    let (mut st, values) = opts.into_items_query(&conn,
-        "SELECT
+    // Counts only include what's in the roots too, the values of the selected
+    // columns are bound before the filters
+    let (track_roots_clause, mut values) = roots_clause("Track.node_id", roots);
+    let (album_roots_clause, album_roots_values) = roots_clause("Track.node_id", roots);
+    values.extend(album_roots_values);
+
+    let (mut st, filter_values) = opts.into_items_query(
+        &conn,
+        &format!(
+            "SELECT
             Artist.artist_id,
             Artist.name,
-            (SELECT count(Track.track_id) FROM Track WHERE Track.artist_id = Artist.artist_id) AS track_count
-        FROM Artist")?;
+            (
+                SELECT count(Track.track_id)
+                FROM Track
+                WHERE Track.artist_id = Artist.artist_id AND {}
+            ) AS track_count,
+            (
+                SELECT count(Album.album_id)
+                FROM Album
+                WHERE
+                    Album.artist_id = Artist.artist_id AND
+                    EXISTS (
+                        SELECT Track.track_id
+                        FROM Track
+                        WHERE Track.album_id = Album.album_id AND {}
+                    )
+            ) AS album_count,
+            {} AS rating,
+            {} AS favorite
+        FROM Artist",
+            track_roots_clause, album_roots_clause, rating, favorite
+        ),
+    )?;
+
+    values.extend(filter_values);
 
     let mut rows = st.query(&values)?;
 
@@ -398,6 +778,9 @@ pub fn query_artists(
             artist_id: row.get(0)?,
             name: row.get(1)?,
             track_count: row.get(2)?,
+            album_count: row.get(3)?,
+            rating: row.get(4)?,
+            favorite: row.get(5)?,
         });
     }
 
@@ -406,20 +789,43 @@ pub fn query_artists(
 
 #[derive(Serialize)]
 pub struct AlbumItem {
-    album_id: i64,
-    name: String,
-    artist_id: Option<i64>,
-    artist_name: Option<String>,
-    image_id: Option<i64>,
-    track_count: i64,
+    pub album_id: i64,
+    pub name: String,
+    pub artist_id: Option<i64>,
+    pub artist_name: Option<String>,
+    pub image_id: Option<i64>,
+    pub track_count: i64,
+    pub rating: i64,
+    pub favorite: bool,
 }
 
 pub fn query_albums(
     index: &Index,
     query: &HttpQuery,
+    user_id: i64,
+    roots: &[String],
 ) -> Result<(i64, Vec<AlbumItem>), rusqlite::Error> {
     let mut opts = QueryOptions::new();
 
+    let album_rating = format!(
+        "FROM StoreAlbumRating
+        WHERE
+            StoreAlbumRating.user_id = {} AND
+            StoreAlbumRating.album_name = Album.name AND
+            StoreAlbumRating.artist_name = COALESCE(Album.artist_name, '')",
+        user_id
+    );
+    let rating = format!(
+        "COALESCE((SELECT StoreAlbumRating.rating {}), 0)",
+        album_rating
+    );
+    let favorite = format!(
+        "COALESCE((SELECT StoreAlbumRating.favorite {}), 0)",
+        album_rating
+    );
+
+    filter_rating(&mut opts, query, &rating, &favorite);
+
     opts.bind_filter_i64(&query, "album_id", "Album.album_id = ?");
     opts.bind_filter_str(&query, "name", "Album.name LIKE ? COLLATE NOCASE");
     opts.bind_filter_i64(&query, "artist_id", "Album.artist_id = ?");
@@ -437,7 +843,43 @@ pub fn query_albums(
         opts.filter_values("(Album.name LIKE ? OR Album.artist_name LIKE ?)", values);
     }
 
-    opts.order_string("Album.artist_name, Album.name");
+    // Albums have the genres and the earliest year of their tracks
+    opts.bind_filter_str(
+        query,
+        "genre",
+        "EXISTS (
+            SELECT Track.track_id
+            FROM Track
+            WHERE Track.album_id = Album.album_id AND Track.genre LIKE ? COLLATE NOCASE
+        )",
+    );
+
+    let year = "(SELECT MIN(Track.year) FROM Track WHERE Track.album_id = Album.album_id)";
+
+    opts.bind_filter_i64(query, "from_year", &format!("{} >= ?", year));
+    opts.bind_filter_i64(query, "to_year", &format!("{} <= ?", year));
+
+    let (clause, values) = roots_clause("Track.node_id", roots);
+    opts.filter_values(
+        &format!(
+            "EXISTS (
+                SELECT Track.track_id
+                FROM Track
+                WHERE Track.album_id = Album.album_id AND {}
+            )",
+            clause
+        ),
+        values,
+    );
+
+    opts.order_string(&match query.get_str("sort") {
+        Some("name") => "Album.name".to_string(),
+        Some("newest") => "Album.album_id DESC".to_string(),
+        Some("random") => "RANDOM()".to_string(),
+        Some("year") => format!("{}, Album.name", year),
+        Some("year_desc") => format!("{} DESC, Album.name", year),
+        _ => "Album.artist_name, Album.name".to_string(),
+    });
 
     opts.bind_range(&query);
 
@@ -445,15 +887,32 @@ pub fn query_albums(
 
     let total = opts.get_total(&conn, "SELECT COUNT(Album.album_id) FROM Album")?;
 
-    let (mut st, values) = opts.into_items_query(&conn,
-        "SELECT
+    // Counts only include what's in the roots too, the values of the selected
+    // columns are bound before the filters
+    let (track_roots_clause, mut values) = roots_clause("Track.node_id", roots);
+
+    let (mut st, filter_values) = opts.into_items_query(
+        &conn,
+        &format!(
+            "SELECT
             Album.album_id,
             Album.name,
             Album.artist_id,
             Album.artist_name,
             Album.image_id,
-            (SELECT count(Track.track_id) FROM Track WHERE Track.album_id = Album.album_id) AS track_count
-        FROM Album")?;
+            (
+                SELECT count(Track.track_id)
+                FROM Track
+                WHERE Track.album_id = Album.album_id AND {}
+            ) AS track_count,
+            {} AS rating,
+            {} AS favorite
+        FROM Album",
+            track_roots_clause, rating, favorite
+        ),
+    )?;
+
+    values.extend(filter_values);
 
     let mut rows = st.query(&values)?;
 
@@ -467,6 +926,8 @@ pub fn query_albums(
             artist_name: row.get(3)?,
             image_id: row.get(4)?,
             track_count: row.get(5)?,
+            rating: row.get(6)?,
+            favorite: row.get(7)?,
         });
     }
 
@@ -475,14 +936,15 @@ pub fn query_albums(
 
 #[derive(Serialize)]
 pub struct ImageItem {
-    image_id: i64,
-    node_id: i64,
-    description: String,
+    pub image_id: i64,
+    pub node_id: i64,
+    pub description: String,
 }
 
 pub fn query_images(
     index: &Index,
     query: &HttpQuery,
+    roots: &[String],
 ) -> Result<(i64, Vec<ImageItem>), rusqlite::Error> {
     let mut opts = QueryOptions::new();
 
@@ -491,6 +953,8 @@ pub fn query_images(
     opts.bind_filter_str(&query, "description", "Image.description = ?");
     opts.bind_filter_i64(&query, "album_id", "(SELECT album_id FROM AlbumImage WHERE AlbumImage.album_id = ? AND AlbumImage.image_id = Image.image_id LIMIT 1) IS NOT NULL");
 
+    opts.filter_roots("Image.node_id", roots);
+
     opts.bind_range(&query);
 
     let conn = index.connection();
@@ -519,4 +983,174 @@ pub fn query_images(
     }
 
     Ok((total, items))
+}
+// Items of the two roots of `test_library`
+#[cfg(test)]
+pub(crate) struct TestRootItems {
+    pub track_id: i64,
+    pub image_id: i64,
+}
+
+// Roots "a" and "ab" with a track and an image each, "ab" sharing the prefix
+// of "a"
+#[cfg(test)]
+pub(crate) fn test_library(
+    dir: &std::path::Path,
+) -> (crate::index::IndexSource, Vec<TestRootItems>) {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use crate::index::{Image, IndexSource, Node};
+    use crate::Root;
+
+    let roots = ["a", "ab"];
+
+    let index_source = IndexSource::create(
+        dir.join("index.db"),
+        Arc::new(
+            roots
+                .iter()
+                .map(|name| Root {
+                    name: name.to_string(),
+                    path: dir.join(name),
+                })
+                .collect(),
+        ),
+    )
+    .unwrap()
+    .unwrap();
+
+    let index = index_source.get().unwrap();
+
+    let node = |node_type, parent_id, path: &str| {
+        let path = PathBuf::from(path);
+
+        index
+            .create_node(&Node {
+                node_id: 0,
+                node_type,
+                parent_id,
+                master_id: None,
+                name: PathBuf::from(path.file_name().unwrap()),
+                path,
+                modified: 0,
+            })
+            .unwrap()
+            .node_id
+    };
+
+    let mut items = Vec::new();
+
+    for root in roots.iter() {
+        let dir_id = node(NodeType::Directory, None, root);
+        let track_node_id = node(NodeType::File, Some(dir_id), &format!("{}/track.mp3", root));
+        let image_node_id = node(NodeType::File, Some(dir_id), &format!("{}/cover.jpg", root));
+
+        let artist = index.create_artist(&format!("Artist {}", root)).unwrap();
+        let album = index.create_album(&format!("Album {}", root)).unwrap();
+
+        let track = index
+            .create_track(&crate::index::Track {
+                track_id: 0,
+                node_id: track_node_id,
+                stream_index: 0,
+                track_index: None,
+                start: None,
+                number: 1,
+                title: format!("Track {}", root),
+                artist_id: artist.artist_id,
+                artist_name: artist.name.clone(),
+                album_id: album.album_id,
+                album_name: album.name.clone(),
+                album_artist_id: None,
+                album_artist_name: None,
+                length: 100f64,
+                codec: None,
+                container: None,
+                bitrate: None,
+                sample_rate: None,
+                bit_depth: None,
+                channels: None,
+                file_size: None,
+                lossless: None,
+                genre: None,
+                year: None,
+            })
+            .unwrap();
+
+        let image = index
+            .create_image(&Image {
+                image_id: 0,
+                node_id: image_node_id,
+                stream_index: None,
+                description: "cover".to_string(),
+                width: 500,
+                height: 500,
+            })
+            .unwrap();
+
+        index.process_node_updates(dir_id).unwrap();
+
+        items.push(TestRootItems {
+            track_id: track.track_id,
+            image_id: image.image_id,
+        });
+    }
+
+    (index_source, items)
+}
+
+#[test]
+fn test_roots() {
+    let dir = std::env::temp_dir().join(format!("musicd2-roots-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let (index_source, items) = test_library(&dir);
+    let index = index_source.get().unwrap();
+
+    let roots = vec!["a".to_string()];
+    let all = HttpQuery::from("");
+
+    let (total, nodes) = query_nodes(&index, &all, &roots).unwrap();
+    assert_eq!(total, 3);
+    assert!(nodes
+        .iter()
+        .all(|n| n.path == "a" || n.path.starts_with("a/")));
+
+    let (total, tracks) = query_tracks(&index, &all, 0, &roots).unwrap();
+    assert_eq!(total, 1);
+    assert_eq!(tracks[0].track_id, items[0].track_id);
+
+    let hidden_track = HttpQuery::from(&format!("track_id={}", items[1].track_id));
+    assert_eq!(query_tracks(&index, &hidden_track, 0, &roots).unwrap().0, 0);
+
+    let (_, albums) = query_albums(&index, &all, 0, &roots).unwrap();
+    assert_eq!(
+        albums.iter().map(|a| a.name.as_str()).collect::<Vec<_>>(),
+        vec!["Album a"]
+    );
+
+    let (_, artists) = query_artists(&index, &all, 0, &roots).unwrap();
+    assert_eq!(
+        artists.iter().map(|a| a.name.as_str()).collect::<Vec<_>>(),
+        vec!["Artist a"]
+    );
+
+    let (total, images) = query_images(&index, &all, &roots).unwrap();
+    assert_eq!(total, 1);
+    assert_eq!(images[0].image_id, items[0].image_id);
+
+    // Nothing is visible without roots
+    assert_eq!(query_nodes(&index, &all, &[]).unwrap().0, 0);
+    assert_eq!(query_tracks(&index, &all, 0, &[]).unwrap().0, 0);
+    assert!(query_albums(&index, &all, 0, &[]).unwrap().1.is_empty());
+    assert!(query_artists(&index, &all, 0, &[]).unwrap().1.is_empty());
+    assert_eq!(query_images(&index, &all, &[]).unwrap().0, 0);
+
+    // Both roots
+    let both = vec!["a".to_string(), "ab".to_string()];
+    assert_eq!(query_tracks(&index, &all, 0, &both).unwrap().0, 2);
+
+    std::fs::remove_dir_all(&dir).unwrap();
 }
\ No newline at end of file
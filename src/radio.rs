@@ -0,0 +1,398 @@
+use std::collections::{HashMap, HashSet};
+
+use rand::Rng;
+use rusqlite::{Result, NO_PARAMS};
+
+use crate::index::Index;
+use crate::query::roots_clause;
+
+// Score contributions of each signal
+const SAME_ARTIST: f64 = 3.0;
+const SAME_ALBUM_ARTIST: f64 = 2.0;
+const SAME_GENRE: f64 = 3.0;
+// Falls linearly to nothing at YEAR_RANGE years apart
+const NEAR_YEAR: f64 = 2.0;
+const YEAR_RANGE: f64 = 10.0;
+// Per list containing both the seed and the track
+const SHARED_LIST: f64 = 1.5;
+const MAX_SHARED_LISTS: i64 = 3;
+// Per play within HISTORY_WINDOW seconds of a seed play
+const PLAYED_NEAR: f64 = 0.5;
+const MAX_PLAYED_NEAR: i64 = 5;
+const HISTORY_WINDOW: i64 = 30 * 60;
+// Scaled by the share of plays skipped
+const SKIPPED: f64 = 5.0;
+// Plays shorter than this share of the track count as skips
+const SKIP_FRACTION: f64 = 0.5;
+// Random variation so that repeated requests don't return the same tracks
+const JITTER: f64 = 1.0;
+// Tracks per artist and album in one batch, unless there isn't enough else
+const MAX_PER_ARTIST: usize = 2;
+const MAX_PER_ALBUM: usize = 2;
+
+pub enum Seed {
+    Track(i64),
+    Album(i64),
+    Artist(i64),
+}
+
+// Store tracks are matched by these, like everywhere else
+type Key = (String, String, String);
+
+struct Candidate {
+    track_id: i64,
+    key: Key,
+    artist_id: i64,
+    album_id: i64,
+    album_artist_id: Option<i64>,
+    genre: Option<String>,
+    year: Option<i64>,
+    length: f64,
+}
+
+// What the seed tracks have in common
+struct Profile {
+    artist_ids: HashSet<i64>,
+    album_artist_ids: HashSet<i64>,
+    genres: HashSet<String>,
+    year: Option<f64>,
+}
+
+// Per-user signals by store track
+#[derive(Default)]
+struct Signals {
+    shared_lists: HashMap<Key, i64>,
+    played_near: HashMap<Key, i64>,
+    // Plays and skips
+    plays: HashMap<Key, (i64, i64)>,
+}
+
+impl Seed {
+    fn contains(&self, candidate: &Candidate) -> bool {
+        match *self {
+            Seed::Track(track_id) => candidate.track_id == track_id,
+            Seed::Album(album_id) => candidate.album_id == album_id,
+            Seed::Artist(artist_id) => {
+                candidate.artist_id == artist_id || candidate.album_artist_id == Some(artist_id)
+            }
+        }
+    }
+}
+
+impl Profile {
+    fn from(seeds: &[&Candidate]) -> Profile {
+        let years: Vec<i64> = seeds.iter().filter_map(|c| c.year).collect();
+
+        Profile {
+            artist_ids: seeds.iter().map(|c| c.artist_id).collect(),
+            album_artist_ids: seeds.iter().filter_map(|c| c.album_artist_id).collect(),
+            genres: seeds
+                .iter()
+                .filter_map(|c| c.genre.as_ref().map(|g| g.to_lowercase()))
+                .collect(),
+            year: if years.is_empty() {
+                None
+            } else {
+                Some(years.iter().sum::<i64>() as f64 / years.len() as f64)
+            },
+        }
+    }
+}
+
+fn score(profile: &Profile, signals: &Signals, candidate: &Candidate) -> f64 {
+    let mut score = 0f64;
+
+    if profile.artist_ids.contains(&candidate.artist_id) {
+        score += SAME_ARTIST;
+    }
+
+    if let Some(album_artist_id) = candidate.album_artist_id {
+        if profile.album_artist_ids.contains(&album_artist_id) {
+            score += SAME_ALBUM_ARTIST;
+        }
+    }
+
+    if let Some(genre) = &candidate.genre {
+        if profile.genres.contains(&genre.to_lowercase()) {
+            score += SAME_GENRE;
+        }
+    }
+
+    if let (Some(seed_year), Some(year)) = (profile.year, candidate.year) {
+        let distance = (seed_year - year as f64).abs();
+        score += NEAR_YEAR * (1f64 - distance / YEAR_RANGE).max(0f64);
+    }
+
+    if let Some(lists) = signals.shared_lists.get(&candidate.key) {
+        score += SHARED_LIST * (*lists).min(MAX_SHARED_LISTS) as f64;
+    }
+
+    if let Some(plays) = signals.played_near.get(&candidate.key) {
+        score += PLAYED_NEAR * (*plays).min(MAX_PLAYED_NEAR) as f64;
+    }
+
+    if let Some((plays, skips)) = signals.plays.get(&candidate.key) {
+        score -= SKIPPED * *skips as f64 / *plays as f64;
+    }
+
+    score
+}
+
+fn load_candidates(index: &Index, roots: &[String]) -> Result<Vec<Candidate>> {
+    let (roots_clause, roots_values) = roots_clause("Track.node_id", roots);
+
+    let mut st = index.connection().prepare(&format!(
+        "SELECT
+            Track.track_id,
+            Track.title,
+            Track.artist_name,
+            Track.album_name,
+            Track.artist_id,
+            Track.album_id,
+            Track.album_artist_id,
+            Track.genre,
+            Track.year,
+            Track.length
+        FROM Track
+        WHERE {}",
+        roots_clause
+    ))?;
+
+    let rows = st.query_map(&roots_values, |row| {
+        Ok(Candidate {
+            track_id: row.get(0)?,
+            key: (row.get(1)?, row.get(2)?, row.get(3)?),
+            artist_id: row.get(4)?,
+            album_id: row.get(5)?,
+            album_artist_id: row.get(6)?,
+            genre: row.get(7)?,
+            year: row.get(8)?,
+            length: row.get(9)?,
+        })
+    })?;
+
+    rows.collect()
+}
+
+fn load_signals(
+    index: &Index,
+    user_id: i64,
+    seeds: &[&Candidate],
+    candidates: &[Candidate],
+) -> Result<Signals> {
+    let conn = index.connection();
+
+    let mut store_tracks: HashMap<i64, Key> = HashMap::new();
+
+    let mut st =
+        conn.prepare("SELECT store_track_id, title, artist_name, album_name FROM StoreTrack")?;
+    let mut rows = st.query(NO_PARAMS)?;
+
+    while let Some(row) = rows.next()? {
+        store_tracks.insert(row.get(0)?, (row.get(1)?, row.get(2)?, row.get(3)?));
+    }
+
+    let seed_keys: HashSet<&Key> = seeds.iter().map(|c| &c.key).collect();
+
+    // Ids are integers, so they're safe to format into the queries
+    let seed_store_track_ids = store_tracks
+        .iter()
+        .filter(|(_, key)| seed_keys.contains(key))
+        .map(|(id, _)| id.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut signals = Signals::default();
+
+    let count = |sql: &str| -> Result<HashMap<Key, i64>> {
+        let mut counts = HashMap::new();
+
+        let mut st = conn.prepare(sql)?;
+        let mut rows = st.query([user_id])?;
+
+        while let Some(row) = rows.next()? {
+            if let Some(key) = store_tracks.get(&row.get(0)?) {
+                counts.insert(key.clone(), row.get(1)?);
+            }
+        }
+
+        Ok(counts)
+    };
+
+    signals.shared_lists = count(&format!(
+        "SELECT other.store_track_id, COUNT(DISTINCT seed.list_id)
+        FROM StoreListTrack AS seed
+        INNER JOIN StoreList ON StoreList.list_id = seed.list_id
+        INNER JOIN StoreListTrack AS other ON other.list_id = seed.list_id
+        WHERE
+            (StoreList.user_id = ? OR StoreList.user_id IS NULL) AND
+            seed.store_track_id IN ({})
+        GROUP BY other.store_track_id",
+        seed_store_track_ids
+    ))?;
+
+    signals.played_near = count(&format!(
+        "SELECT other.store_track_id, COUNT(*)
+        FROM StorePlay AS seed
+        INNER JOIN StorePlay AS other ON
+            other.user_id = seed.user_id AND
+            other.time BETWEEN seed.time - {window} AND seed.time + {window} AND
+            other.play_id != seed.play_id
+        WHERE seed.user_id = ? AND seed.store_track_id IN ({ids})
+        GROUP BY other.store_track_id",
+        window = HISTORY_WINDOW,
+        ids = seed_store_track_ids
+    ))?;
+
+    // Skips are plays cut short, compared to the length of the indexed track
+    let lengths: HashMap<&Key, f64> = candidates.iter().map(|c| (&c.key, c.length)).collect();
+
+    let mut st =
+        conn.prepare("SELECT store_track_id, duration FROM StorePlay WHERE user_id = ?")?;
+    let mut rows = st.query([user_id])?;
+
+    while let Some(row) = rows.next()? {
+        let key = match store_tracks.get(&row.get(0)?) {
+            Some(key) => key,
+            None => continue,
+        };
+
+        let length = match lengths.get(key) {
+            Some(length) => *length,
+            None => continue,
+        };
+
+        let duration: f64 = row.get(1)?;
+
+        let entry = signals.plays.entry(key.clone()).or_insert((0, 0));
+        entry.0 += 1;
+        if duration < length * SKIP_FRACTION {
+            entry.1 += 1;
+        }
+    }
+
+    Ok(signals)
+}
+
+// Picks the best scored tracks, limiting tracks of the same artist and album
+// while there are others left
+fn pick(mut scored: Vec<(f64, &Candidate)>, limit: usize) -> Vec<i64> {
+    scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut picked: Vec<&Candidate> = Vec::new();
+    let mut keys: HashSet<&Key> = HashSet::new();
+
+    for limited in &[true, false] {
+        for (_, candidate) in &scored {
+            if picked.len() >= limit {
+                break;
+            }
+
+            // The same song is often in the index more than once
+            if keys.contains(&candidate.key) {
+                continue;
+            }
+
+            if *limited {
+                let artist = picked.iter().filter(|c| c.artist_id == candidate.artist_id);
+                let album = picked.iter().filter(|c| c.album_id == candidate.album_id);
+
+                if artist.count() >= MAX_PER_ARTIST || album.count() >= MAX_PER_ALBUM {
+                    continue;
+                }
+            }
+
+            keys.insert(&candidate.key);
+            picked.push(candidate);
+        }
+    }
+
+    picked.iter().map(|c| c.track_id).collect()
+}
+
+// Next `limit` tracks of a radio based on `seed`, not repeating `exclude`.
+// Tracks of a track or album seed are never included, those of an artist seed
+// are. Empty if the seed has no accessible tracks.
+pub fn radio_tracks(
+    index: &Index,
+    user_id: i64,
+    seed: &Seed,
+    exclude: &[i64],
+    limit: usize,
+    roots: &[String],
+) -> Result<Vec<i64>> {
+    let candidates = load_candidates(index, roots)?;
+
+    let seeds: Vec<&Candidate> = candidates.iter().filter(|c| seed.contains(c)).collect();
+    if seeds.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let profile = Profile::from(&seeds);
+    let signals = load_signals(index, user_id, &seeds, &candidates)?;
+
+    let exclude: HashSet<i64> = exclude.iter().cloned().collect();
+    let mut rng = rand::thread_rng();
+
+    let scored = candidates
+        .iter()
+        .filter(|c| !exclude.contains(&c.track_id))
+        .filter(|c| match seed {
+            Seed::Artist(_) => true,
+            _ => !seed.contains(c),
+        })
+        .map(|c| (score(&profile, &signals, c) + rng.gen::<f64>() * JITTER, c))
+        .collect();
+
+    Ok(pick(scored, limit))
+}
+
+#[test]
+fn test_score() {
+    let candidate = |track_id, artist_id, genre: &str, year| Candidate {
+        track_id,
+        key: (track_id.to_string(), String::new(), String::new()),
+        artist_id,
+        album_id: track_id,
+        album_artist_id: None,
+        genre: Some(genre.to_string()),
+        year: Some(year),
+        length: 200f64,
+    };
+
+    let seed = candidate(1, 1, "Jazz", 1960);
+    let profile = Profile::from(&[&seed]);
+    let mut signals = Signals::default();
+
+    let same_artist = candidate(2, 1, "Blues", 1990);
+    let same_genre = candidate(3, 2, "jazz", 1965);
+    let unrelated = candidate(4, 3, "Metal", 1990);
+
+    assert_eq!(score(&profile, &signals, &same_artist), SAME_ARTIST);
+    assert_eq!(
+        score(&profile, &signals, &same_genre),
+        SAME_GENRE + NEAR_YEAR / 2f64
+    );
+    assert_eq!(score(&profile, &signals, &unrelated), 0f64);
+
+    signals.shared_lists.insert(unrelated.key.clone(), 10);
+    signals.plays.insert(unrelated.key.clone(), (4, 2));
+    assert_eq!(
+        score(&profile, &signals, &unrelated),
+        SHARED_LIST * MAX_SHARED_LISTS as f64 - SKIPPED / 2f64
+    );
+
+    let candidates = [
+        candidate(5, 1, "", 0),
+        candidate(6, 1, "", 0),
+        candidate(7, 1, "", 0),
+        candidate(8, 2, "", 0),
+    ];
+    let scored = candidates
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (10f64 - i as f64, c))
+        .collect();
+
+    assert_eq!(pick(scored, 3), vec![5, 6, 8]);
+}
@@ -8,9 +8,12 @@ use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
 
 use crate::cue;
+use crate::events::{Event, Events};
 use crate::index::{Image, Index, Node, NodeType, Track};
 use crate::media;
 
@@ -53,42 +56,77 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 pub struct ScanThread {
     stop: Arc<AtomicBool>,
+    running: Arc<AtomicBool>,
     join_handle: Mutex<Option<JoinHandle<ScanStat>>>,
+    exact_duration: bool,
+    events: Events,
 }
 
+// Minimum interval of scan progress events
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
 impl ScanThread {
-    pub fn new() -> ScanThread {
+    pub fn new(exact_duration: bool, events: Events) -> ScanThread {
         ScanThread {
             stop: Arc::new(AtomicBool::new(false)),
+            running: Arc::new(AtomicBool::new(false)),
             join_handle: Mutex::new(None),
+            exact_duration,
+            events,
         }
     }
 
     pub fn is_running(&self) -> bool {
-        self.join_handle.lock().unwrap().is_some()
+        self.running.load(Ordering::Relaxed)
     }
 
     pub fn start(&self, index: Index) {
-        {
-            if self.join_handle.lock().unwrap().is_some() {
-                return;
-            }
+        let mut join_handle = self.join_handle.lock().unwrap();
+
+        if self.running.load(Ordering::Relaxed) {
+            return;
         }
 
-        let stop = self.stop.clone();
+        // Finished scans are joined only when starting the next one
+        if let Some(handle) = join_handle.take() {
+            handle.join().unwrap();
+        }
 
-        let mut join_handle = self.join_handle.lock().unwrap();
+        let stop = self.stop.clone();
+        let running = self.running.clone();
+        let exact_duration = self.exact_duration;
+        let events = self.events.clone();
 
         self.stop.store(false, Ordering::Relaxed);
+        self.running.store(true, Ordering::Relaxed);
 
         *join_handle = Some(std::thread::spawn(move || {
+            events.emit(Event::global("scan_started", json!({})));
+
             let mut scan = Scan {
                 stop,
                 stop_detected: false,
                 index,
+                exact_duration,
+                events: events.clone(),
+                progress: Default::default(),
+                last_progress: Instant::now(),
             };
 
-            scan.scan_core()
+            let stat = scan.scan_core();
+
+            running.store(false, Ordering::Relaxed);
+
+            events.emit(Event::global(
+                "scan_finished",
+                json!({
+                    "tracks": stat.tracks,
+                    "images": stat.images,
+                    "interrupted": scan.stop_detected
+                }),
+            ));
+
+            stat
         }));
     }
 
@@ -107,6 +145,11 @@ struct Scan {
     stop: Arc<AtomicBool>,
     stop_detected: bool,
     index: Index,
+    exact_duration: bool,
+    events: Events,
+    // Totals so far for progress events
+    progress: ScanStat,
+    last_progress: Instant,
 }
 
 enum NodeArg<'a> {
@@ -150,6 +193,39 @@ impl Scan {
         stop
     }
 
+    fn report_progress(&mut self, path: &Path) {
+        if self.last_progress.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+
+        self.last_progress = Instant::now();
+
+        self.events.emit(Event::node(
+            path,
+            "scan_progress",
+            json!({
+                "path": path.to_string_lossy(),
+                "tracks": self.progress.tracks,
+                "images": self.progress.images
+            }),
+        ));
+    }
+
+    fn delete_node(&mut self, node: &Node) -> Result<()> {
+        self.index.delete_node(node.node_id)?;
+
+        self.events.emit(Event::node(
+            &node.path,
+            "node_removed",
+            json!({
+                "node_id": node.node_id,
+                "path": node.path.to_string_lossy()
+            }),
+        ));
+
+        Ok(())
+    }
+
     fn scan_core(&mut self) -> ScanStat {
         info!("started");
 
@@ -278,9 +354,37 @@ impl Scan {
             } else {
                 self.index.clear_node(node.node_id)?;
 
+                // Nodes not scanned before had nothing to remove
+                if node.modified != 0 {
+                    self.events.emit(Event::node(
+                        &node.path,
+                        "tracks_removed",
+                        json!({
+                            "node_id": node.node_id,
+                            "path": node.path.to_string_lossy()
+                        }),
+                    ));
+                }
+
                 self.process_file_node(parent, &node, &fs_path)?
             };
 
+            if let Some(stat) = &result {
+                self.progress.add(stat);
+
+                if stat.tracks > 0 {
+                    self.events.emit(Event::node(
+                        &node.path,
+                        "tracks_added",
+                        json!({
+                            "node_id": node.node_id,
+                            "path": node.path.to_string_lossy(),
+                            "tracks": stat.tracks
+                        }),
+                    ));
+                }
+            }
+
             Ok(result)
         } else {
             Ok(None)
@@ -337,7 +441,7 @@ impl Scan {
                 );
 
                 if let Some(node) = node {
-                    self.index.delete_node(node.node_id)?;
+                    self.delete_node(&node)?;
                 }
 
                 return Err(Error::OtherError);
@@ -353,7 +457,7 @@ impl Scan {
                 error!("invalid modified '{}'", fs_path.to_string_lossy());
 
                 if let Some(node) = node {
-                    self.index.delete_node(node.node_id)?;
+                    self.delete_node(&node)?;
                 }
 
                 return Err(Error::OtherError);
@@ -376,7 +480,7 @@ impl Scan {
                     n.node_type,
                     node_type
                 );
-                self.index.delete_node(n.node_id)?;
+                self.delete_node(n)?;
 
                 node = None;
             }
@@ -398,7 +502,20 @@ impl Scan {
                     modified: 0,
                 };
 
-                self.index.create_node(&node)?
+                let node = self.index.create_node(&node)?;
+
+                self.events.emit(Event::node(
+                    &node.path,
+                    "node_added",
+                    json!({
+                        "node_id": node.node_id,
+                        "parent_id": node.parent_id,
+                        "node_type": node.node_type,
+                        "path": node.path.to_string_lossy()
+                    }),
+                ));
+
+                node
             }
         };
 
@@ -420,6 +537,8 @@ impl Scan {
     ) -> Result<Option<ScanStat>> {
         debug!("directory '{}'", fs_path.to_string_lossy());
 
+        self.report_progress(&node.path);
+
         let mut stat = ScanStat {
             ..Default::default()
         };
@@ -503,8 +622,14 @@ impl Scan {
 
         debug!("cue file '{}'", fs_path.to_string_lossy());
 
-        let cue_text = std::fs::read_to_string(&fs_path)?;
-        let cue = cue::parse_cue(&cue_text);
+        let cue_text = cue::decode_cue(&fs::read(fs_path)?);
+        let cue = match cue::parse_cue(&cue_text) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("invalid cue file '{}': {}", fs_path.to_string_lossy(), e);
+                return Ok(None);
+            }
+        };
 
         if cue.files.is_empty() {
             debug!("no file entries in cue file, ignoring");
@@ -515,104 +640,204 @@ impl Scan {
             ..Default::default()
         };
 
-        for file in cue.files {
-            if file.tracks.is_empty() {
-                continue;
-            }
+        // Last track of the previous file, continued by the beginning of the next file if the
+        // first track there doesn't start right away
+        let mut previous: Option<Track> = None;
 
+        for file in &cue.files {
             let file_node = match self.prepare_node(
                 Some(parent),
                 NodeArg::Name(Path::new(OsStr::from_bytes(&file.path.as_bytes()))),
             ) {
                 Ok(n) => n,
-                Err(_) => continue,
+                Err(_) => {
+                    previous = None;
+                    continue;
+                }
             };
 
-            let file_tracks = match media::media_info_from_path(&file_node.fs_path) {
-                Some(t) => t.0,
-                None => continue,
-            };
+            // Referenced files belong to the cue sheet even if no track starts in them
+            self.index
+                .set_node_master(file_node.node.node_id, node.node_id)?;
+
+            let file_tracks =
+                match media::media_info_from_path(&file_node.fs_path, self.exact_duration) {
+                    Some(m) => m.tracks,
+                    None => {
+                        previous = None;
+                        continue;
+                    }
+                };
 
-            let file_track = match file_tracks.first() {
+            // Cue positions refer to the whole stream, not to chapters
+            let file_track = match file_tracks.iter().find(|t| t.track_index == Some(0)) {
                 Some(t) => t,
-                None => continue,
+                None => {
+                    debug!(
+                        "no audio stream in '{}'",
+                        file_node.fs_path.to_string_lossy()
+                    );
+                    previous = None;
+                    continue;
+                }
             };
 
-            let mut tracks: Vec<Track> = Vec::new();
-
-            for cue_track in file.tracks {
-                tracks.push(Track {
-                    track_id: 0,
-                    node_id: file_node.node.node_id,
-                    stream_index: file_track.stream_index,
-                    track_index: file_track.track_index,
-                    number: i64::from(cue_track.number),
-                    title: cue_track.title.trim().to_string(),
-                    artist_id: 0, // Resolved later
-                    artist_name: cue_track.performer.trim().to_string(),
-                    album_id: 0, // Resolved later
-                    album_name: cue.title.trim().to_string(),
-                    album_artist_id: None,
-                    album_artist_name: if cue.performer.len() > 0 {
-                        Some(cue.performer.trim().to_string())
-                    } else {
-                        None
-                    },
-                    start: Some(cue_track.start as f64),
-                    length: 0f64,
-                });
-            }
+            let file_size = match fs::metadata(&file_node.fs_path) {
+                Ok(m) => m.len() as i64,
+                Err(e) => {
+                    error!(
+                        "metadata error '{}': {}",
+                        file_node.fs_path.to_string_lossy(),
+                        e
+                    );
+                    previous = None;
+                    continue;
+                }
+            };
 
-            // Calculate lengths
-            let mut last_start = file_track.length;
-            for track in tracks.iter_mut().rev() {
-                let start = track.start.unwrap_or(0f64);
-                track.length = last_start - start;
-                last_start = start;
-            }
+            let mut tracks = Self::cue_tracks(&cue, &file.tracks, file_track);
 
             self.index.clear_node(file_node.node.node_id)?;
 
-            for track in tracks.iter_mut() {
-                track.artist_id = match self.index.artist_by_name(&track.artist_name)? {
-                    Some(a) => a,
-                    None => self.index.create_artist(&track.artist_name)?,
-                }
-                .artist_id;
+            // Audio before the first INDEX 01 of the file is the continuation of the previous
+            // file's last track, except for a pregap of the first track starting the file
+            let head_length = match file.tracks.first() {
+                Some(t) => match t.pregap_start() {
+                    Some(pregap_start) if pregap_start <= 0f64 => 0f64,
+                    _ => t.start,
+                },
+                None => file_track.length,
+            };
 
-                track.album_id = match self
-                    .index
-                    .find_album(file_node.node.node_id, &track.album_name)?
-                {
-                    Some(a) => a,
-                    None => self.index.create_album(&track.album_name)?,
-                }
-                .album_id;
-
-                if let Some(album_artist_name) = &track.album_artist_name {
-                    track.album_artist_id = Some(
-                        match self.index.artist_by_name(&album_artist_name)? {
-                            Some(a) => a,
-                            None => self.index.create_artist(&album_artist_name)?,
-                        }
-                        .artist_id,
-                    );
+            if head_length > 0f64 {
+                if let Some(previous) = &previous {
+                    self.index.create_track_continuation(
+                        previous.track_id,
+                        file_node.node.node_id,
+                        file_track.stream_index,
+                        file_track.track_index,
+                        head_length,
+                    )?;
                 }
+            }
+
+            for track in tracks.iter_mut() {
+                track.node_id = file_node.node.node_id;
+                track.file_size = Some(file_size);
 
-                self.index.create_track(&track)?;
+                self.create_track(track)?;
 
                 stat.tracks += 1;
             }
 
-            self.index
-                .set_node_master(file_node.node.node_id, node.node_id)?;
             self.index
                 .set_node_modified(file_node.node.node_id, file_node.modified)?;
+
+            // A file without tracks of its own continues the same track further
+            if let Some(last) = tracks.pop() {
+                previous = Some(last);
+            }
         }
 
         Ok(Some(stat))
     }
 
+    // Splits `file_track` spanning a whole disc image into tracks described by `cue_tracks`.
+    fn cue_tracks(cue: &cue::Cue, cue_tracks: &[cue::Track], file_track: &Track) -> Vec<Track> {
+        let mut tracks: Vec<Track> = Vec::new();
+
+        for cue_track in cue_tracks.iter().filter(|t| t.is_audio()) {
+            tracks.push(Track {
+                number: i64::from(cue_track.number),
+                title: cue_track.title.trim().to_string(),
+                artist_name: cue_track.performer.trim().to_string(),
+                album_name: cue.title.trim().to_string(),
+                album_artist_name: if !cue.performer.is_empty() {
+                    Some(cue.performer.trim().to_string())
+                } else {
+                    None
+                },
+                start: Some(cue_track.start),
+                length: 0f64,
+                genre: match &cue.genre {
+                    Some(genre) if !genre.trim().is_empty() => Some(genre.trim().to_string()),
+                    _ => file_track.genre.clone(),
+                },
+                year: cue
+                    .date
+                    .as_deref()
+                    .and_then(Self::cue_year)
+                    .or(file_track.year),
+                ..Self::cue_file_track(file_track)
+            });
+        }
+
+        // Calculate lengths
+        let mut last_start = file_track.length;
+        for track in tracks.iter_mut().rev() {
+            let start = track.start.unwrap_or(0f64);
+            track.length = last_start - start;
+            last_start = start;
+        }
+
+        tracks
+    }
+
+    // Year of a REM DATE, usually just the year but sometimes a full date.
+    fn cue_year(date: &str) -> Option<i64> {
+        let digits: String = date
+            .trim()
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+
+        match digits.parse() {
+            Ok(year) if (1..=9999).contains(&year) => Some(year),
+            _ => None,
+        }
+    }
+
+    // Copy of `file_track` as the base of a track in its cue sheet, with the
+    // index references reset.
+    fn cue_file_track(file_track: &Track) -> Track {
+        Track {
+            track_id: 0,
+            artist_id: 0, // Resolved later
+            album_id: 0,  // Resolved later
+            album_artist_id: None,
+            ..file_track.clone()
+        }
+    }
+
+    // Resolves artist and album references of `track` and stores it in the index.
+    fn create_track(&mut self, track: &mut Track) -> Result<()> {
+        track.artist_id = match self.index.artist_by_name(&track.artist_name)? {
+            Some(a) => a,
+            None => self.index.create_artist(&track.artist_name)?,
+        }
+        .artist_id;
+
+        track.album_id = match self.index.find_album(track.node_id, &track.album_name)? {
+            Some(a) => a,
+            None => self.index.create_album(&track.album_name)?,
+        }
+        .album_id;
+
+        if let Some(album_artist_name) = &track.album_artist_name {
+            track.album_artist_id = Some(
+                match self.index.artist_by_name(album_artist_name)? {
+                    Some(a) => a,
+                    None => self.index.create_artist(album_artist_name)?,
+                }
+                .artist_id,
+            );
+        }
+
+        track.track_id = self.index.create_track(track)?.track_id;
+
+        Ok(())
+    }
+
     // This list is what extensions image crate recognizes
     const IMAGE_EXTENSIONS: &'static [&'static str] = &[
         "jpg", "jpeg", "png", "gif", "webp", "tif", "tiff", "tga", "bmp", "ico", "hdr", "pbm",
@@ -669,53 +894,82 @@ impl Scan {
     fn try_process_audio_file(&mut self, node: &Node, fs_path: &Path) -> Result<Option<ScanStat>> {
         debug!("try audio file '{}'", fs_path.to_string_lossy());
 
-        let (mut tracks, mut images) = match media::media_info_from_path(&fs_path) {
+        let media_info = match media::media_info_from_path(fs_path, self.exact_duration) {
             Some(m) => m,
             None => return Ok(None),
         };
 
+        let file_size = match fs::metadata(fs_path) {
+            Ok(m) => m.len() as i64,
+            Err(e) => {
+                error!("metadata error '{}': {}", fs_path.to_string_lossy(), e);
+                return Ok(None);
+            }
+        };
+
         let mut stat = ScanStat {
             ..Default::default()
         };
 
+        let mut tracks = media_info.tracks;
+
         for track in tracks.iter_mut() {
             track.node_id = node.node_id;
+            track.file_size = Some(file_size);
+        }
 
-            track.artist_id = match self.index.artist_by_name(&track.artist_name)? {
-                Some(a) => a,
-                None => self.index.create_artist(&track.artist_name)?,
-            }
-            .artist_id;
-
-            track.album_id = match self.index.find_album(node.node_id, &track.album_name)? {
-                Some(a) => a,
-                None => self.index.create_album(&track.album_name)?,
-            }
-            .album_id;
-
-            if let Some(album_artist_name) = &track.album_artist_name {
-                track.album_artist_id = Some(
-                    match self.index.artist_by_name(&album_artist_name)? {
-                        Some(a) => a,
-                        None => self.index.create_artist(&album_artist_name)?,
-                    }
-                    .artist_id,
-                );
-            }
+        if let Some(cue_tracks) = Self::embedded_cue_tracks(&media_info.cuesheet, &tracks) {
+            debug!("using embedded cue sheet");
+            tracks = cue_tracks;
+        }
 
-            self.index.create_track(track)?;
+        for track in tracks.iter_mut() {
+            self.create_track(track)?;
 
             stat.tracks += 1;
         }
 
-        for image in images.iter_mut() {
+        for mut image in media_info.images {
             image.node_id = node.node_id;
 
-            self.index.create_image(image)?;
+            self.index.create_image(&image)?;
 
             stat.images += 1;
         }
 
         Ok(Some(stat))
     }
-}
\ No newline at end of file
+
+    // Splits a single track disc image into tracks if it carries an embedded cue sheet.
+    fn embedded_cue_tracks(cuesheet: &Option<String>, tracks: &[Track]) -> Option<Vec<Track>> {
+        let cuesheet = cuesheet.as_ref()?;
+
+        // Chapters, which FFmpeg reads from binary cue sheet blocks, take precedence
+        let file_track = match tracks {
+            [t] if t.track_index.unwrap_or(0) == 0 => t,
+            _ => return None,
+        };
+
+        let cue = match cue::parse_cue(cuesheet) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("invalid embedded cue sheet: {}", e);
+                return None;
+            }
+        };
+
+        // The FILE entry names the original image, which may not match the file at hand.
+        // Positions are relative to their own FILE, so those of several files can't be
+        // placed within this one.
+        let cue_tracks = match cue.files.as_slice() {
+            [f] if !f.tracks.is_empty() => &f.tracks,
+            [_] => return None,
+            _ => {
+                debug!("embedded cue sheet refers to several files");
+                return None;
+            }
+        };
+
+        Some(Self::cue_tracks(&cue, cue_tracks, file_track))
+    }
+}
@@ -1,20 +1,22 @@
-pub const SCHEMA_VERSION: u32 = 1;
-
 pub const META_SCHEMA: &str = "
 CREATE TABLE IF NOT EXISTS Musicd (
     key TEXT PRIMARY KEY,
     value);
 ";
 
-pub const CACHE_SCHEMA: &str = "
+// Each schema is a list of migrations, schema version being the number of
+// migrations applied. Existing migrations must never be modified.
+
+pub const CACHE_SCHEMA: &[&str] = &["
 CREATE TABLE Cache (
     key TEXT PRIMARY KEY,
     value BLOB,
     size INTEGER,
     last_access INTEGER);
-";
+"];
 
-pub const INDEX_SCHEMA: &str = "
+pub const INDEX_SCHEMA: &[&str] = &[
+    "
 CREATE TABLE Node (
     node_id INTEGER PRIMARY KEY AUTOINCREMENT,
     node_type INTEGER NOT NULL,
@@ -113,9 +115,132 @@ CREATE TABLE StoreListTrack (
     store_track_id INTEGER NOT NULL,
     FOREIGN KEY(list_id) REFERENCES StoreList(list_id) ON DELETE CASCADE,
     FOREIGN KEY(store_track_id) REFERENCES StoreTrack(store_track_id) ON DELETE CASCADE);
-";
+",
+    "
+ALTER TABLE Track ADD COLUMN codec TEXT;
+ALTER TABLE Track ADD COLUMN container TEXT;
+ALTER TABLE Track ADD COLUMN bitrate INTEGER;
+ALTER TABLE Track ADD COLUMN sample_rate INTEGER;
+ALTER TABLE Track ADD COLUMN bit_depth INTEGER;
+ALTER TABLE Track ADD COLUMN channels INTEGER;
+ALTER TABLE Track ADD COLUMN file_size INTEGER;
+ALTER TABLE Track ADD COLUMN lossless INTEGER;
+
+-- Force rescan of all files to fill in the new columns
+UPDATE Node SET modified = 0;
+",
+    "
+-- Beginning of a file continuing a cue track that starts in the previous file
+CREATE TABLE TrackContinuation (
+    track_id INTEGER NOT NULL,
+    node_id INTEGER NOT NULL,
+    stream_index INTEGER NOT NULL,
+    track_index INTEGER,
+    length REAL NOT NULL,
+    FOREIGN KEY(track_id) REFERENCES Track(track_id) ON DELETE CASCADE,
+    FOREIGN KEY(node_id) REFERENCES Node(node_id) ON DELETE CASCADE);
+
+CREATE INDEX TrackContinuation_track_id ON TrackContinuation (track_id);
+CREATE INDEX TrackContinuation_node_id ON TrackContinuation (node_id);
+
+-- Continuations were indexed as separate tracks before
+UPDATE Node SET modified = 0;
+",
+    "
+DROP TABLE StoreListTrack;
+DROP TABLE StoreList;
+DROP TABLE StoreTrack;
+
+-- Store tracks are matched by metadata so that they survive rescans
+CREATE TABLE StoreTrack (
+    store_track_id INTEGER PRIMARY KEY,
+    title TEXT NOT NULL,
+    artist_name TEXT NOT NULL,
+    album_name TEXT NOT NULL);
 
-pub const STORE_SCHEMA: &str = "
+CREATE INDEX StoreTrack_title ON StoreTrack (title, artist_name, album_name);
+CREATE INDEX Track_title ON Track (title, artist_name, album_name);
+
+CREATE TABLE StoreUserTrack (
+    user_id INTEGER NOT NULL,
+    store_track_id INTEGER NOT NULL,
+    play_count INTEGER NOT NULL,
+    last_play INTEGER NOT NULL,
+    PRIMARY KEY(user_id, store_track_id));
+
+CREATE TABLE StoreList (
+    list_id INTEGER PRIMARY KEY,
+    user_id INTEGER,
+    name TEXT NOT NULL);
+
+CREATE TABLE StoreListTrack (
+    list_id INTEGER NOT NULL,
+    store_track_id INTEGER NOT NULL,
+    sort_index INTEGER,
+    FOREIGN KEY(list_id) REFERENCES StoreList(list_id) ON DELETE CASCADE);
+
+CREATE INDEX StoreListTrack_list_id ON StoreListTrack (list_id);
+",
+    "
+CREATE TABLE StoreBookmark (
+    user_id INTEGER NOT NULL,
+    store_track_id INTEGER NOT NULL,
+    position REAL NOT NULL,
+    updated INTEGER NOT NULL,
+    PRIMARY KEY(user_id, store_track_id));
+",
+    "
+CREATE TABLE StoreTrackRating (
+    user_id INTEGER NOT NULL,
+    store_track_id INTEGER NOT NULL,
+    rating INTEGER NOT NULL,
+    favorite INTEGER NOT NULL,
+    PRIMARY KEY(user_id, store_track_id));
+
+CREATE TABLE StoreAlbumRating (
+    user_id INTEGER NOT NULL,
+    album_name TEXT NOT NULL,
+    artist_name TEXT NOT NULL,
+    rating INTEGER NOT NULL,
+    favorite INTEGER NOT NULL,
+    PRIMARY KEY(user_id, album_name, artist_name));
+
+CREATE TABLE StoreArtistRating (
+    user_id INTEGER NOT NULL,
+    artist_name TEXT NOT NULL,
+    rating INTEGER NOT NULL,
+    favorite INTEGER NOT NULL,
+    PRIMARY KEY(user_id, artist_name));
+",
+    "
+-- Existing files are assumed to have been added when last modified
+ALTER TABLE Node ADD COLUMN added INTEGER;
+UPDATE Node SET added = modified;
+
+ALTER TABLE StoreList ADD COLUMN rules TEXT;
+",
+    "
+CREATE TABLE StorePlay (
+    play_id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    store_track_id INTEGER NOT NULL,
+    time INTEGER NOT NULL,
+    duration REAL NOT NULL,
+    client TEXT);
+
+CREATE INDEX StorePlay_user_id_time ON StorePlay (user_id, time);
+",
+    "
+ALTER TABLE Track ADD COLUMN genre TEXT;
+ALTER TABLE Track ADD COLUMN year INTEGER;
+
+-- Force rescan of all files to fill in the new columns
+UPDATE Node SET modified = 0;
+",
+];
+
+pub const STORE_SCHEMA: &[&str] = &[
+    "
 CREATE TABLE Track (
     store_track_id INTEGER PRIMARY KEY AUTOINCREMENT,
     title TEXT NOT NULL,
@@ -135,4 +260,201 @@ CREATE TABLE ListTrack (
     sort_index INTEGER,
     FOREIGN KEY(list_id) REFERENCES List(list_id) ON DELETE CASCADE,
     FOREIGN KEY(store_track_id) REFERENCES Track(store_track_id) ON DELETE CASCADE);
-";
+",
+    "
+CREATE TABLE Session (
+    session_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    token_hash TEXT NOT NULL UNIQUE,
+    created INTEGER NOT NULL,
+    expires INTEGER NOT NULL);
+
+CREATE TABLE ApiToken (
+    api_token_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    created INTEGER NOT NULL,
+    last_used INTEGER);
+",
+    "
+CREATE TABLE User (
+    user_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    password_hash TEXT,
+    admin INTEGER NOT NULL);
+
+CREATE TABLE UserTrack (
+    user_id INTEGER NOT NULL,
+    store_track_id INTEGER NOT NULL,
+    play_count INTEGER NOT NULL,
+    last_play INTEGER NOT NULL,
+    PRIMARY KEY(user_id, store_track_id),
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE,
+    FOREIGN KEY(store_track_id) REFERENCES Track(store_track_id) ON DELETE CASCADE);
+
+CREATE INDEX Track_title ON Track (title, artist_name, album_name);
+
+ALTER TABLE List ADD COLUMN user_id INTEGER REFERENCES User(user_id) ON DELETE CASCADE;
+ALTER TABLE Session ADD COLUMN user_id INTEGER REFERENCES User(user_id) ON DELETE CASCADE;
+ALTER TABLE ApiToken ADD COLUMN user_id INTEGER REFERENCES User(user_id) ON DELETE CASCADE;
+
+-- Sessions can't be attributed to users
+DELETE FROM Session;
+",
+    "
+-- Roots listed here are only visible to the listed users and admins
+CREATE TABLE RootUser (
+    root_name TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    PRIMARY KEY(root_name, user_id),
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE);
+
+-- Tokens listed here are limited to the listed roots
+CREATE TABLE ApiTokenRoot (
+    api_token_id INTEGER NOT NULL,
+    root_name TEXT NOT NULL,
+    PRIMARY KEY(api_token_id, root_name),
+    FOREIGN KEY(api_token_id) REFERENCES ApiToken(api_token_id) ON DELETE CASCADE);
+",
+    "
+-- Shared item is a store track, an album identified by name or a list
+CREATE TABLE Share (
+    share_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    item_type TEXT NOT NULL,
+    name TEXT NOT NULL,
+    store_track_id INTEGER,
+    album_name TEXT,
+    album_artist_name TEXT,
+    list_id INTEGER,
+    created INTEGER NOT NULL,
+    expires INTEGER,
+    play_limit INTEGER,
+    play_count INTEGER NOT NULL,
+    -- Streams of the track last played by a share, until it would have ended,
+    -- are seeks within the same play
+    last_track_id INTEGER,
+    last_play_end INTEGER,
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE,
+    FOREIGN KEY(store_track_id) REFERENCES Track(store_track_id) ON DELETE CASCADE,
+    FOREIGN KEY(list_id) REFERENCES List(list_id) ON DELETE CASCADE);
+
+CREATE INDEX Share_user_id ON Share (user_id);
+",
+    "
+-- Subsonic token authentication needs the password in plain text, so it's
+-- separate from the login password
+ALTER TABLE User ADD COLUMN subsonic_password TEXT;
+",
+    "
+-- Playback state of each device of a user. Queues consist of store tracks so
+-- that they survive rescans.
+CREATE TABLE PlaybackSession (
+    playback_session_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    device TEXT NOT NULL,
+    current_index INTEGER,
+    position REAL NOT NULL,
+    playing INTEGER NOT NULL,
+    shuffle INTEGER NOT NULL,
+    repeat TEXT NOT NULL,
+    updated INTEGER NOT NULL,
+    UNIQUE(user_id, device),
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE);
+
+CREATE TABLE PlaybackSessionTrack (
+    playback_session_id INTEGER NOT NULL,
+    store_track_id INTEGER NOT NULL,
+    sort_index INTEGER NOT NULL,
+    FOREIGN KEY(playback_session_id) REFERENCES PlaybackSession(playback_session_id) ON DELETE CASCADE,
+    FOREIGN KEY(store_track_id) REFERENCES Track(store_track_id) ON DELETE CASCADE);
+
+CREATE INDEX PlaybackSessionTrack_playback_session_id ON PlaybackSessionTrack (playback_session_id);
+",
+    "
+-- Saved playback position of long tracks, such as audiobooks and mixes
+CREATE TABLE Bookmark (
+    user_id INTEGER NOT NULL,
+    store_track_id INTEGER NOT NULL,
+    position REAL NOT NULL,
+    updated INTEGER NOT NULL,
+    PRIMARY KEY(user_id, store_track_id),
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE,
+    FOREIGN KEY(store_track_id) REFERENCES Track(store_track_id) ON DELETE CASCADE);
+",
+    "
+-- Ratings are 0 to 5, 0 meaning unrated. Albums and artists are identified by
+-- name like tracks, albums without an artist have an empty artist_name.
+CREATE TABLE TrackRating (
+    user_id INTEGER NOT NULL,
+    store_track_id INTEGER NOT NULL,
+    rating INTEGER NOT NULL,
+    favorite INTEGER NOT NULL,
+    PRIMARY KEY(user_id, store_track_id),
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE,
+    FOREIGN KEY(store_track_id) REFERENCES Track(store_track_id) ON DELETE CASCADE);
+
+CREATE TABLE AlbumRating (
+    user_id INTEGER NOT NULL,
+    album_name TEXT NOT NULL,
+    artist_name TEXT NOT NULL,
+    rating INTEGER NOT NULL,
+    favorite INTEGER NOT NULL,
+    PRIMARY KEY(user_id, album_name, artist_name),
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE);
+
+CREATE TABLE ArtistRating (
+    user_id INTEGER NOT NULL,
+    artist_name TEXT NOT NULL,
+    rating INTEGER NOT NULL,
+    favorite INTEGER NOT NULL,
+    PRIMARY KEY(user_id, artist_name),
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE);
+",
+    "
+-- Smart lists have no tracks of their own but rules selecting index tracks,
+-- stored as JSON
+ALTER TABLE List ADD COLUMN rules TEXT;
+",
+    "
+-- ListenBrainz compatible scrobbling service of a user
+CREATE TABLE ScrobbleConfig (
+    user_id INTEGER PRIMARY KEY,
+    url TEXT NOT NULL,
+    token TEXT NOT NULL,
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE);
+
+-- Listens waiting to be submitted. Tracks are copied by value so that the
+-- queue is independent of the index.
+CREATE TABLE ScrobbleQueue (
+    scrobble_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    listened_at INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    artist_name TEXT NOT NULL,
+    album_name TEXT NOT NULL,
+    length REAL NOT NULL,
+    attempts INTEGER NOT NULL,
+    next_attempt INTEGER NOT NULL,
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE);
+
+CREATE INDEX ScrobbleQueue_user_id ON ScrobbleQueue (user_id);
+",
+    "
+-- Every registered play, UserTrack only keeps the totals. Plays from before
+-- this table existed aren't known.
+CREATE TABLE Play (
+    play_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    store_track_id INTEGER NOT NULL,
+    time INTEGER NOT NULL,
+    -- Seconds actually listened
+    duration REAL NOT NULL,
+    -- Player application, if known
+    client TEXT,
+    FOREIGN KEY(user_id) REFERENCES User(user_id) ON DELETE CASCADE,
+    FOREIGN KEY(store_track_id) REFERENCES Track(store_track_id) ON DELETE CASCADE);
+
+CREATE INDEX Play_user_id_time ON Play (user_id, time);
+",
+];
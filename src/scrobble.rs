@@ -0,0 +1,235 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use rusqlite::Result;
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+use tokio::time::{timeout_at, Instant};
+
+use crate::auth;
+use crate::events::Event;
+use crate::store::{Listen, ScrobbleConfig};
+use crate::Musicd;
+
+pub const DEFAULT_URL: &str = "https://api.listenbrainz.org";
+
+// Due listens are looked for at least this often even without new plays
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// Well below the 1000 listens ListenBrainz accepts per request
+const MAX_BATCH: i64 = 100;
+const MAX_RETRY_DELAY: i64 = 6 * 60 * 60;
+
+enum Outcome {
+    Accepted,
+    // Retried later, e.g. the service is unreachable or the token is invalid
+    Failed,
+    // The service won't ever accept the listens
+    Rejected,
+}
+
+fn track_metadata(title: &str, artist_name: &str, album_name: &str, length: f64) -> Value {
+    json!({
+        "artist_name": artist_name,
+        "track_name": title,
+        "release_name": album_name,
+        "additional_info": {
+            "duration_ms": (length * 1000f64) as i64,
+            "submission_client": "musicd2",
+            "submission_client_version": env!("CARGO_PKG_VERSION"),
+        },
+    })
+}
+
+fn listens_payload(listens: &[Listen]) -> Value {
+    json!({
+        "listen_type": if listens.len() == 1 { "single" } else { "import" },
+        "payload": listens
+            .iter()
+            .map(|l| {
+                json!({
+                    "listened_at": l.listened_at,
+                    "track_metadata": track_metadata(
+                        &l.title,
+                        &l.artist_name,
+                        &l.album_name,
+                        l.length
+                    ),
+                })
+            })
+            .collect::<Vec<_>>(),
+    })
+}
+
+async fn submit(config: &ScrobbleConfig, payload: &Value) -> Outcome {
+    let url = format!("{}/1/submit-listens", config.url.trim_end_matches('/'));
+
+    let result = async {
+        reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?
+            .post(&url)
+            .header("Authorization", format!("Token {}", config.token))
+            .header("Content-Type", "application/json")
+            .body(payload.to_string())
+            .send()
+            .await
+    }
+    .await;
+
+    match result {
+        Ok(response) if response.status().is_success() => Outcome::Accepted,
+        Ok(response) if response.status().as_u16() == 400 => {
+            warn!("'{}' rejected listens: {}", url, response.status());
+            Outcome::Rejected
+        }
+        Ok(response) => {
+            warn!("'{}' failed: {}", url, response.status());
+            Outcome::Failed
+        }
+        Err(e) => {
+            warn!("'{}' failed: {}", url, e);
+            Outcome::Failed
+        }
+    }
+}
+
+// Submits the due listens of every user, oldest first
+async fn submit_listens(musicd: &Musicd) -> Result<()> {
+    let store = musicd.store();
+
+    for user_id in store.scrobble_users(auth::now())? {
+        let config = match store.scrobble_config(user_id)? {
+            Some(c) => c,
+            None => continue,
+        };
+
+        'batches: loop {
+            let listens = store.due_listens(user_id, auth::now(), MAX_BATCH)?;
+            if listens.is_empty() {
+                break;
+            }
+
+            let scrobble_ids: Vec<i64> = listens.iter().map(|l| l.scrobble_id).collect();
+
+            match submit(&config, &listens_payload(&listens)).await {
+                Outcome::Accepted => store.delete_listens(&scrobble_ids)?,
+                Outcome::Rejected if listens.len() > 1 => {
+                    // A single bad listen rejects the whole batch, so find it
+                    // by submitting the listens one at a time
+                    for (i, listen) in listens.iter().enumerate() {
+                        match submit(&config, &listens_payload(&listens[i..=i])).await {
+                            Outcome::Accepted | Outcome::Rejected => {
+                                store.delete_listens(&[listen.scrobble_id])?
+                            }
+                            Outcome::Failed => {
+                                store.postpone_listens(
+                                    &scrobble_ids[i..],
+                                    auth::now(),
+                                    MAX_RETRY_DELAY,
+                                )?;
+                                break 'batches;
+                            }
+                        }
+                    }
+                }
+                Outcome::Rejected => store.delete_listens(&scrobble_ids)?,
+                Outcome::Failed => {
+                    store.postpone_listens(&scrobble_ids, auth::now(), MAX_RETRY_DELAY)?;
+                    // Remaining listens of the user would fail the same way
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// "Now playing" notifications are best effort and never retried
+async fn submit_now_playing(musicd: &Musicd, user_id: i64, track_id: i64) -> Result<()> {
+    let config = match musicd.store().scrobble_config(user_id)? {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+
+    let track = match musicd.index().track(track_id)? {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+
+    let payload = json!({
+        "listen_type": "playing_now",
+        "payload": [{
+            "track_metadata": track_metadata(
+                &track.title,
+                &track.artist_name,
+                &track.album_name,
+                track.length
+            ),
+        }],
+    });
+
+    submit(&config, &payload).await;
+
+    Ok(())
+}
+
+// Submits queued listens as plays are registered, and retries failed ones
+pub async fn run_scrobbler(musicd: Arc<Musicd>) {
+    let mut events = musicd.events.subscribe();
+
+    loop {
+        if let Err(e) = submit_listens(&musicd).await {
+            error!("scrobbling failed: {}", e);
+        }
+
+        let deadline = Instant::now() + POLL_INTERVAL;
+
+        loop {
+            let event: Event = match timeout_at(deadline, events.recv()).await {
+                Ok(Ok(event)) => event,
+                Ok(Err(broadcast::RecvError::Lagged(_))) | Err(_) => break,
+                Ok(Err(broadcast::RecvError::Closed)) => return,
+            };
+
+            match (event.name, event.user_id, event.data["track_id"].as_i64()) {
+                ("track_played", _, _) => break,
+                ("now_playing", Some(user_id), Some(track_id)) => {
+                    if let Err(e) = submit_now_playing(&musicd, user_id, track_id).await {
+                        error!("scrobbling failed: {}", e);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[test]
+fn test_listens_payload() {
+    let listen = |scrobble_id| Listen {
+        scrobble_id,
+        listened_at: 1_600_000_000,
+        title: "Title".to_string(),
+        artist_name: "Artist".to_string(),
+        album_name: "Album".to_string(),
+        length: 201.5,
+    };
+
+    let payload = listens_payload(&[listen(1)]);
+    assert_eq!(payload["listen_type"], "single");
+    assert_eq!(payload["payload"][0]["listened_at"], 1_600_000_000);
+    assert_eq!(
+        payload["payload"][0]["track_metadata"]["track_name"],
+        "Title"
+    );
+    assert_eq!(
+        payload["payload"][0]["track_metadata"]["additional_info"]["duration_ms"],
+        201_500
+    );
+
+    let payload = listens_payload(&[listen(1), listen(2)]);
+    assert_eq!(payload["listen_type"], "import");
+    assert_eq!(payload["payload"].as_array().unwrap().len(), 2);
+}
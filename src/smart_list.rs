@@ -0,0 +1,250 @@
+use rusqlite::types::ToSql;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// Saved definition of a smart list, e.g.
+// {"rules": [{"field": "rating", "op": ">=", "value": 4}], "sort": "random", "limit": 50}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    pub rules: Vec<Rule>,
+    // Tracks matching any of the rules instead of all
+    #[serde(default)]
+    pub any: bool,
+    pub sort: Option<String>,
+    #[serde(default)]
+    pub descending: bool,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub field: String,
+    pub op: String,
+    pub value: Value,
+}
+
+// SQL clause over Track, ready for QueryOptions
+pub struct Compiled {
+    pub clause: String,
+    pub values: Vec<Box<dyn ToSql>>,
+    pub order: String,
+    pub limit: Option<i64>,
+}
+
+enum FieldType {
+    Text,
+    Number,
+    // Unix time
+    Time,
+}
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// Per-user data of the store track matching Track
+fn user_data(table: &str, column: &str, user_id: i64) -> String {
+    format!(
+        "COALESCE((
+            SELECT Store{table}.{column}
+            FROM StoreTrack
+            INNER JOIN Store{table} ON Store{table}.store_track_id = StoreTrack.store_track_id
+            WHERE
+                Store{table}.user_id = {user_id} AND
+                StoreTrack.title = Track.title AND
+                StoreTrack.artist_name = Track.artist_name AND
+                StoreTrack.album_name = Track.album_name
+        ), 0)",
+        table = table,
+        column = column,
+        user_id = user_id
+    )
+}
+
+fn field(name: &str, user_id: i64) -> Option<(String, FieldType)> {
+    let column = |c: &str| Some((c.to_string(), FieldType::Number));
+    let text = |c: &str| Some((c.to_string(), FieldType::Text));
+    let user = |t: &str, c: &str, f: FieldType| Some((user_data(t, c, user_id), f));
+
+    match name {
+        "title" => text("Track.title"),
+        "artist_name" => text("Track.artist_name"),
+        "album_name" => text("Track.album_name"),
+        "album_artist_name" => text("COALESCE(Track.album_artist_name, '')"),
+        "codec" => text("COALESCE(Track.codec, '')"),
+        "number" => column("Track.number"),
+        "length" => column("Track.length"),
+        "bitrate" => column("Track.bitrate"),
+        "sample_rate" => column("Track.sample_rate"),
+        "bit_depth" => column("Track.bit_depth"),
+        "channels" => column("Track.channels"),
+        "file_size" => column("Track.file_size"),
+        "lossless" => column("COALESCE(Track.lossless, 0)"),
+        "play_count" => user("UserTrack", "play_count", FieldType::Number),
+        "last_play" => user("UserTrack", "last_play", FieldType::Time),
+        "rating" => user("TrackRating", "rating", FieldType::Number),
+        "favorite" => user("TrackRating", "favorite", FieldType::Number),
+        "added" => Some((
+            "(SELECT Node.added FROM Node WHERE Node.node_id = Track.node_id)".to_string(),
+            FieldType::Time,
+        )),
+        _ => None,
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::Bool(b) => Some(if *b { 1f64 } else { 0f64 }),
+        _ => None,
+    }
+}
+
+impl Rule {
+    fn compile(&self, user_id: i64) -> Result<(String, Box<dyn ToSql>), String> {
+        let (expr, field_type) = match field(&self.field, user_id) {
+            Some(f) => f,
+            None => return Err(format!("unknown field '{}'", self.field)),
+        };
+
+        let invalid = || format!("invalid value for '{}' '{}'", self.field, self.op);
+
+        match field_type {
+            FieldType::Text => {
+                let value = self.value.as_str().ok_or_else(invalid)?;
+
+                // LIKE patterns are escaped so that only the operator matches
+                let escaped = value
+                    .replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_");
+
+                let (clause, value) = match self.op.as_str() {
+                    "is" => ("{} = ? COLLATE NOCASE", value.to_string()),
+                    "is_not" => ("{} != ? COLLATE NOCASE", value.to_string()),
+                    "contains" => ("{} LIKE ? ESCAPE '\\'", format!("%{}%", escaped)),
+                    "not_contains" => ("{} NOT LIKE ? ESCAPE '\\'", format!("%{}%", escaped)),
+                    "starts_with" => ("{} LIKE ? ESCAPE '\\'", format!("{}%", escaped)),
+                    "ends_with" => ("{} LIKE ? ESCAPE '\\'", format!("%{}", escaped)),
+                    _ => return Err(format!("unknown operator '{}'", self.op)),
+                };
+
+                Ok((clause.replace("{}", &expr), Box::new(value)))
+            }
+            FieldType::Number | FieldType::Time => {
+                let value = number(&self.value).ok_or_else(invalid)?;
+
+                let comparison = match self.op.as_str() {
+                    "=" | "!=" | "<" | "<=" | ">" | ">=" => self.op.as_str(),
+                    "within_days" | "not_within_days" => {
+                        if let FieldType::Number = field_type {
+                            return Err(format!("unknown operator '{}'", self.op));
+                        }
+
+                        // Never played tracks have no last play within any period
+                        let clause = format!(
+                            "{} {} strftime('%s', 'now') - ?",
+                            expr,
+                            if self.op == "within_days" { ">=" } else { "<" }
+                        );
+
+                        return Ok((clause, Box::new((value * SECONDS_PER_DAY as f64) as i64)));
+                    }
+                    _ => return Err(format!("unknown operator '{}'", self.op)),
+                };
+
+                Ok((format!("{} {} ?", expr, comparison), Box::new(value)))
+            }
+        }
+    }
+}
+
+impl Rules {
+    pub fn parse(json: &str) -> Result<Rules, String> {
+        let rules: Rules = serde_json::from_str(json).map_err(|e| e.to_string())?;
+
+        // Catch invalid rules when saving instead of when fetching
+        rules.compile(0)?;
+
+        Ok(rules)
+    }
+
+    pub fn compile(&self, user_id: i64) -> Result<Compiled, String> {
+        let mut clauses = Vec::new();
+        let mut values = Vec::new();
+
+        for rule in &self.rules {
+            let (clause, value) = rule.compile(user_id)?;
+            clauses.push(clause);
+            values.push(value);
+        }
+
+        let clause = if clauses.is_empty() {
+            "1".to_string()
+        } else {
+            format!(
+                "({})",
+                clauses.join(if self.any { " OR " } else { " AND " })
+            )
+        };
+
+        let order = match self.sort.as_deref() {
+            None => "Track.album_name, Track.number, Track.title".to_string(),
+            Some("random") => "RANDOM()".to_string(),
+            Some(sort) => match field(sort, user_id) {
+                Some((expr, _)) => {
+                    format!("{} {}", expr, if self.descending { "DESC" } else { "ASC" })
+                }
+                None => return Err(format!("unknown sort '{}'", sort)),
+            },
+        };
+
+        if let Some(limit) = self.limit {
+            if limit < 1 {
+                return Err("invalid limit".to_string());
+            }
+        }
+
+        Ok(Compiled {
+            clause,
+            values,
+            order,
+            limit: self.limit,
+        })
+    }
+}
+
+#[test]
+fn test_compile() {
+    let rules = Rules::parse(
+        r#"{
+            "rules": [
+                {"field": "artist_name", "op": "contains", "value": "50%"},
+                {"field": "added", "op": "within_days", "value": 30},
+                {"field": "length", "op": ">", "value": 300}
+            ],
+            "sort": "added",
+            "descending": true,
+            "limit": 10
+        }"#,
+    )
+    .unwrap();
+
+    let compiled = rules.compile(1).unwrap();
+
+    assert!(compiled
+        .clause
+        .starts_with("(Track.artist_name LIKE ? ESCAPE '\\' AND "));
+    assert!(compiled.clause.ends_with(" AND Track.length > ?)"));
+    assert_eq!(compiled.values.len(), 3);
+    assert!(compiled.order.ends_with(" DESC"));
+    assert_eq!(compiled.limit, Some(10));
+
+    assert!(Rules::parse(r#"{"rules": [{"field": "path", "op": "is", "value": ""}]}"#).is_err());
+    assert!(Rules::parse(r#"{"rules": [{"field": "title", "op": ">", "value": ""}]}"#).is_err());
+    assert!(
+        Rules::parse(r#"{"rules": [{"field": "length", "op": "within_days", "value": 1}]}"#)
+            .is_err()
+    );
+    assert!(Rules::parse(r#"{"rules": [], "limit": 0}"#).is_err());
+}
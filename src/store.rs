@@ -2,23 +2,113 @@
 use std::error::Error as StdError;
 use std::path::PathBuf;
 
-use rusqlite::{params, Connection, Result, NO_PARAMS};
+use rusqlite::types::{ToSql, Value};
+use rusqlite::{params, Connection, OptionalExtension, Result, Row, NO_PARAMS};
+use serde::Serialize;
 
 use crate::db_meta;
-use crate::index::Index;
+use crate::index::{Album, Artist, Index, Track};
 use crate::schema;
+use crate::smart_list::Rules;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct User {
+    pub user_id: i64,
+    pub name: String,
+    #[serde(skip)]
+    pub password_hash: Option<String>,
+    pub admin: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiToken {
+    pub api_token_id: i64,
+    pub name: String,
+    pub created: i64,
+    pub last_used: Option<i64>,
+    // Empty if not limited
+    pub roots: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct List {
+    pub list_id: i64,
+    pub name: String,
+    // Static tracks only, smart lists have none
+    pub track_count: i64,
+    // Rules of a smart list
+    pub rules: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Share {
+    pub share_id: i64,
+    #[serde(skip)]
+    pub user_id: i64,
+    // "track", "album" or "list"
+    pub item_type: String,
+    pub name: String,
+    #[serde(skip)]
+    pub store_track_id: Option<i64>,
+    #[serde(skip)]
+    pub album_name: Option<String>,
+    #[serde(skip)]
+    pub album_artist_name: Option<String>,
+    #[serde(skip)]
+    pub list_id: Option<i64>,
+    pub created: i64,
+    pub expires: Option<i64>,
+    pub play_limit: Option<i64>,
+    pub play_count: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlaybackSession {
+    pub playback_session_id: i64,
+    pub device: String,
+    // Queue index of the current track
+    pub current_index: Option<i64>,
+    // Seconds into the current track as of `updated`
+    pub position: f64,
+    pub playing: bool,
+    pub shuffle: bool,
+    // "off", "all" or "one"
+    pub repeat: String,
+    pub updated: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Rating {
+    // 0 to 5, 0 if unrated
+    pub rating: i64,
+    pub favorite: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScrobbleConfig {
+    pub url: String,
+    #[serde(skip)]
+    pub token: String,
+}
 
 #[derive(Debug, Clone)]
-struct StoreTrack {
-    store_track_id: i64,
-    title: String,
-    artist_name: String,
-    album_name: String,
-    length: i64,
-    play_count: Option<i64>,
-    last_play: Option<i64>,
+pub struct Listen {
+    pub scrobble_id: i64,
+    pub listened_at: i64,
+    pub title: String,
+    pub artist_name: String,
+    pub album_name: String,
+    pub length: f64,
 }
 
+// Rating tables with their key columns, mirrored in the index with a "Store"
+// prefix
+const RATING_TABLES: &[(&str, &[&str])] = &[
+    ("TrackRating", &["user_id", "store_track_id"]),
+    ("AlbumRating", &["user_id", "album_name", "artist_name"]),
+    ("ArtistRating", &["user_id", "artist_name"]),
+];
+
 pub struct StoreSource {
     db_path: PathBuf,
 }
@@ -65,6 +155,7 @@ impl StoreSource {
 }
 
 impl Store {
+    // Rebuilds the copies of store data in the index, used for joining with index data
     pub fn synchronize(&mut self) -> Result<()> {
         debug!("synchronize");
 
@@ -74,101 +165,1865 @@ impl Store {
         index_conn.execute_batch(
             "DELETE FROM StoreListTrack;
             DELETE FROM StoreList;
+            DELETE FROM StoreUserTrack;
+            DELETE FROM StoreBookmark;
+            DELETE FROM StorePlay;
             DELETE FROM StoreTrack;",
         )?;
 
         let mut st = store_conn.prepare(
-            "SELECT store_track_id, title, artist_name, album_name, length, play_count, last_play
+            "SELECT store_track_id, title, artist_name, album_name
             FROM Track",
         )?;
+        let mut rows = st.query(NO_PARAMS)?;
+
+        while let Some(row) = rows.next()? {
+            index_conn.execute(
+                "INSERT INTO StoreTrack (store_track_id, title, artist_name, album_name)
+                VALUES (?, ?, ?, ?)",
+                params![
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?
+                ],
+            )?;
+        }
+
+        let mut st = store_conn.prepare(
+            "SELECT user_id, store_track_id, play_count, last_play
+            FROM UserTrack",
+        )?;
+        let mut rows = st.query(NO_PARAMS)?;
+
+        while let Some(row) = rows.next()? {
+            index_conn.execute(
+                "INSERT INTO StoreUserTrack (user_id, store_track_id, play_count, last_play)
+                VALUES (?, ?, ?, ?)",
+                params![
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, i64>(3)?
+                ],
+            )?;
+        }
+
+        let mut st = store_conn.prepare("SELECT list_id, user_id, name, rules FROM List")?;
+        let mut rows = st.query(NO_PARAMS)?;
+
+        while let Some(row) = rows.next()? {
+            index_conn.execute(
+                "INSERT INTO StoreList (list_id, user_id, name, rules) VALUES (?, ?, ?, ?)",
+                params![
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<i64>>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?
+                ],
+            )?;
+        }
+
+        let mut st = store_conn.prepare(
+            "SELECT list_id, store_track_id, sort_index
+            FROM ListTrack",
+        )?;
+        let mut rows = st.query(NO_PARAMS)?;
+
+        while let Some(row) = rows.next()? {
+            index_conn.execute(
+                "INSERT INTO StoreListTrack (list_id, store_track_id, sort_index) VALUES (?, ?, ?)",
+                params![
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, Option<i64>>(2)?
+                ],
+            )?;
+        }
+
+        let mut st = store_conn.prepare(
+            "SELECT user_id, store_track_id, position, updated
+            FROM Bookmark",
+        )?;
+        let mut rows = st.query(NO_PARAMS)?;
+
+        while let Some(row) = rows.next()? {
+            index_conn.execute(
+                "INSERT INTO StoreBookmark (user_id, store_track_id, position, updated)
+                VALUES (?, ?, ?, ?)",
+                params![
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, f64>(2)?,
+                    row.get::<_, i64>(3)?
+                ],
+            )?;
+        }
 
+        let mut st = store_conn.prepare(
+            "SELECT play_id, user_id, store_track_id, time, duration, client
+            FROM Play",
+        )?;
         let mut rows = st.query(NO_PARAMS)?;
 
-        let mut st = index_conn.prepare(
-            "INSERT OR IGNORE INTO
-                StoreTrack (track_id, store_track_id, play_count, last_play)
-            VALUES
-                (
+        while let Some(row) = rows.next()? {
+            index_conn.execute(
+                "INSERT INTO StorePlay (play_id, user_id, store_track_id, time, duration, client)
+                VALUES (?, ?, ?, ?, ?, ?)",
+                params![
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, f64>(4)?,
+                    row.get::<_, Option<String>>(5)?
+                ],
+            )?;
+        }
+
+        for (table, key) in RATING_TABLES {
+            index_conn.execute(&format!("DELETE FROM Store{}", table), NO_PARAMS)?;
+
+            let columns = format!("{}, rating, favorite", key.join(", "));
+            let placeholders = vec!["?"; key.len() + 2].join(", ");
+
+            let mut st = store_conn.prepare(&format!("SELECT {} FROM {}", columns, table))?;
+            let mut rows = st.query(NO_PARAMS)?;
+
+            while let Some(row) = rows.next()? {
+                let values = (0..key.len() + 2)
+                    .map(|i| row.get::<_, Value>(i))
+                    .collect::<Result<Vec<_>>>()?;
+
+                index_conn.execute(
+                    &format!(
+                        "INSERT INTO Store{} ({}) VALUES ({})",
+                        table, columns, placeholders
+                    ),
+                    &values,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Finds or creates the store entry of an index track. Store tracks are
+    // identified by metadata as index track ids don't survive rescans.
+    pub fn store_track(&self, track: &Track) -> Result<i64> {
+        let store_track_id: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT store_track_id
+                FROM Track
+                WHERE title = ? AND artist_name = ? AND album_name = ?",
+                params![track.title, track.artist_name, track.album_name],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let store_track_id = match store_track_id {
+            Some(id) => id,
+            None => {
+                self.conn.execute(
+                    "INSERT INTO Track (title, artist_name, album_name, length) VALUES (?, ?, ?, ?)",
+                    params![
+                        track.title,
+                        track.artist_name,
+                        track.album_name,
+                        track.length as i64
+                    ],
+                )?;
+
+                let store_track_id = self.conn.last_insert_rowid();
+
+                self.index.connection().execute(
+                    "INSERT INTO StoreTrack (store_track_id, title, artist_name, album_name)
+                    VALUES (?, ?, ?, ?)",
+                    params![
+                        store_track_id,
+                        track.title,
+                        track.artist_name,
+                        track.album_name
+                    ],
+                )?;
+
+                store_track_id
+            }
+        };
+
+        Ok(store_track_id)
+    }
+
+    // `duration` is the time listened, the whole track if not known
+    pub fn register_track_play(
+        &self,
+        user_id: i64,
+        track: &Track,
+        time: i64,
+        duration: Option<f64>,
+        client: Option<&str>,
+    ) -> Result<()> {
+        let store_track_id = self.store_track(track)?;
+        let duration = duration.unwrap_or(track.length);
+
+        self.conn.execute(
+            "INSERT INTO Play (user_id, store_track_id, time, duration, client)
+            VALUES (?, ?, ?, ?, ?)",
+            params![user_id, store_track_id, time, duration, client],
+        )?;
+
+        self.index.connection().execute(
+            "INSERT INTO StorePlay (play_id, user_id, store_track_id, time, duration, client)
+            VALUES (?, ?, ?, ?, ?, ?)",
+            params![
+                self.conn.last_insert_rowid(),
+                user_id,
+                store_track_id,
+                time,
+                duration,
+                client
+            ],
+        )?;
+
+        self.conn.execute(
+            "INSERT OR IGNORE INTO UserTrack (user_id, store_track_id, play_count, last_play)
+            VALUES (?, ?, 0, 0)",
+            params![user_id, store_track_id],
+        )?;
+
+        self.conn.execute(
+            "UPDATE UserTrack
+            SET play_count = play_count + 1, last_play = ?
+            WHERE user_id = ? AND store_track_id = ?",
+            params![time, user_id, store_track_id],
+        )?;
+
+        self.index.connection().execute(
+            "INSERT OR REPLACE INTO StoreUserTrack (user_id, store_track_id, play_count, last_play)
+            VALUES (
+                ?,
+                ?,
+                COALESCE(
                     (
-                        SELECT track_id
-                        FROM Track
-                        WHERE
-                            Track.title = ? AND
-                            Track.artist_name = ? AND
-                            Track.album_name = ?
+                        SELECT play_count
+                        FROM StoreUserTrack
+                        WHERE user_id = ? AND store_track_id = ?
                     ),
-                    ?,
-                    ?,
-                    ?
-                )",
+                    0
+                ) + 1,
+                ?
+            )",
+            params![user_id, store_track_id, user_id, store_track_id, time],
+        )?;
+
+        // Queued only while scrobbling is configured
+        self.conn.execute(
+            "INSERT INTO ScrobbleQueue (
+                user_id, listened_at, title, artist_name, album_name, length, attempts,
+                next_attempt
+            )
+            SELECT user_id, ?, ?, ?, ?, ?, 0, 0
+            FROM ScrobbleConfig
+            WHERE user_id = ?",
+            params![
+                time,
+                track.title,
+                track.artist_name,
+                track.album_name,
+                track.length,
+                user_id
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn bookmark_position(&self, user_id: i64, track: &Track) -> Result<Option<f64>> {
+        self.conn
+            .query_row(
+                "SELECT Bookmark.position
+                FROM Bookmark
+                INNER JOIN Track ON Track.store_track_id = Bookmark.store_track_id
+                WHERE
+                    Bookmark.user_id = ? AND
+                    Track.title = ? AND
+                    Track.artist_name = ? AND
+                    Track.album_name = ?",
+                params![user_id, track.title, track.artist_name, track.album_name],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    pub fn set_bookmark(
+        &self,
+        user_id: i64,
+        track: &Track,
+        position: f64,
+        time: i64,
+    ) -> Result<()> {
+        let store_track_id = self.store_track(track)?;
+
+        self.conn.execute(
+            "INSERT OR REPLACE INTO Bookmark (user_id, store_track_id, position, updated)
+            VALUES (?, ?, ?, ?)",
+            params![user_id, store_track_id, position, time],
+        )?;
+
+        self.index.connection().execute(
+            "INSERT OR REPLACE INTO StoreBookmark (user_id, store_track_id, position, updated)
+            VALUES (?, ?, ?, ?)",
+            params![user_id, store_track_id, position, time],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn delete_bookmark(&self, user_id: i64, track: &Track) -> Result<bool> {
+        let store_track_id: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT store_track_id
+                FROM Track
+                WHERE title = ? AND artist_name = ? AND album_name = ?",
+                params![track.title, track.artist_name, track.album_name],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let store_track_id = match store_track_id {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+
+        let deleted = self.conn.execute(
+            "DELETE FROM Bookmark WHERE user_id = ? AND store_track_id = ?",
+            [user_id, store_track_id],
+        )?;
+
+        self.index.connection().execute(
+            "DELETE FROM StoreBookmark WHERE user_id = ? AND store_track_id = ?",
+            [user_id, store_track_id],
+        )?;
+
+        Ok(deleted > 0)
+    }
+
+    // Updates the given fields of a rating and its index mirror, `key` holds
+    // the key columns of the table in RATING_TABLES
+    fn update_rating(
+        &self,
+        table: &str,
+        key: &[&dyn ToSql],
+        rating: Option<i64>,
+        favorite: Option<bool>,
+    ) -> Result<Rating> {
+        let key_columns = RATING_TABLES
+            .iter()
+            .find(|t| t.0 == table)
+            .expect("unknown rating table")
+            .1;
+
+        let condition = key_columns
+            .iter()
+            .map(|c| format!("{} = ?", c))
+            .collect::<Vec<_>>()
+            .join(" AND ");
+
+        let columns = format!("{}, rating, favorite", key_columns.join(", "));
+        let placeholders = vec!["?"; key.len() + 2].join(", ");
+
+        self.conn.execute(
+            &format!(
+                "INSERT OR IGNORE INTO {} ({}) VALUES ({})",
+                table, columns, placeholders
+            ),
+            key.iter().chain(&[&0 as &dyn ToSql, &false]),
+        )?;
+
+        self.conn.execute(
+            &format!(
+                "UPDATE {}
+                SET rating = COALESCE(?, rating), favorite = COALESCE(?, favorite)
+                WHERE {}",
+                table, condition
+            ),
+            [&rating as &dyn ToSql, &favorite].iter().chain(key),
+        )?;
+
+        let result = self.conn.query_row(
+            &format!("SELECT rating, favorite FROM {} WHERE {}", table, condition),
+            key,
+            |row| {
+                Ok(Rating {
+                    rating: row.get(0)?,
+                    favorite: row.get(1)?,
+                })
+            },
+        )?;
+
+        // Nothing left worth keeping
+        if result.rating == 0 && !result.favorite {
+            self.conn
+                .execute(&format!("DELETE FROM {} WHERE {}", table, condition), key)?;
+            self.index.connection().execute(
+                &format!("DELETE FROM Store{} WHERE {}", table, condition),
+                key,
+            )?;
+        } else {
+            self.index.connection().execute(
+                &format!(
+                    "INSERT OR REPLACE INTO Store{} ({}) VALUES ({})",
+                    table, columns, placeholders
+                ),
+                key.iter()
+                    .chain(&[&result.rating as &dyn ToSql, &result.favorite]),
+            )?;
+        }
+
+        Ok(result)
+    }
+
+    pub fn set_track_rating(
+        &self,
+        user_id: i64,
+        track: &Track,
+        rating: Option<i64>,
+        favorite: Option<bool>,
+    ) -> Result<Rating> {
+        let store_track_id = self.store_track(track)?;
+
+        self.update_rating(
+            "TrackRating",
+            &[&user_id, &store_track_id],
+            rating,
+            favorite,
+        )
+    }
+
+    pub fn set_album_rating(
+        &self,
+        user_id: i64,
+        album: &Album,
+        rating: Option<i64>,
+        favorite: Option<bool>,
+    ) -> Result<Rating> {
+        self.update_rating(
+            "AlbumRating",
+            &[
+                &user_id,
+                &album.name,
+                &album.artist_name.as_deref().unwrap_or_default(),
+            ],
+            rating,
+            favorite,
+        )
+    }
+
+    pub fn set_artist_rating(
+        &self,
+        user_id: i64,
+        artist: &Artist,
+        rating: Option<i64>,
+        favorite: Option<bool>,
+    ) -> Result<Rating> {
+        self.update_rating("ArtistRating", &[&user_id, &artist.name], rating, favorite)
+    }
+
+    fn _get_user(row: &Row) -> Result<User> {
+        Ok(User {
+            user_id: row.get(0)?,
+            name: row.get(1)?,
+            password_hash: row.get(2)?,
+            admin: row.get(3)?,
+        })
+    }
+
+    pub fn users(&self) -> Result<Vec<User>> {
+        let mut st = self.conn.prepare(
+            "SELECT user_id, name, password_hash, admin
+            FROM User
+            ORDER BY user_id",
         )?;
 
+        let mut rows = st.query(NO_PARAMS)?;
+
+        let mut result = Vec::new();
+
         while let Some(row) = rows.next()? {
-            let store_track = StoreTrack {
-                store_track_id: row.get(0)?,
-                title: row.get(1)?,
-                artist_name: row.get(2)?,
-                album_name: row.get(3)?,
-                length: row.get(4)?,
-                play_count: row.get(5)?,
-                last_play: row.get(6)?,
-            };
-
-            trace!("add to index {:?}", store_track);
-
-            st.execute(params![
-                store_track.title,
-                store_track.artist_name,
-                store_track.album_name,
-                store_track.store_track_id,
-                store_track.play_count,
-                store_track.last_play
-            ])?;
+            result.push(Self::_get_user(row)?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn user(&self, user_id: i64) -> Result<Option<User>> {
+        self.conn
+            .query_row(
+                "SELECT user_id, name, password_hash, admin
+                FROM User
+                WHERE user_id = ?",
+                [user_id],
+                Self::_get_user,
+            )
+            .optional()
+    }
+
+    pub fn user_by_name(&self, name: &str) -> Result<Option<User>> {
+        self.conn
+            .query_row(
+                "SELECT user_id, name, password_hash, admin
+                FROM User
+                WHERE name = ?",
+                &[name],
+                Self::_get_user,
+            )
+            .optional()
+    }
+
+    pub fn create_user(
+        &self,
+        name: &str,
+        password_hash: Option<&str>,
+        admin: bool,
+    ) -> Result<User> {
+        self.conn.execute(
+            "INSERT INTO User (name, password_hash, admin) VALUES (?, ?, ?)",
+            params![name, password_hash, admin],
+        )?;
+
+        let result = self.user(self.conn.last_insert_rowid())?.unwrap();
+
+        debug!("create {:?}", result.name);
+
+        Ok(result)
+    }
+
+    // Ends every session of the user, a stolen one doesn't survive the change
+    pub fn set_user_password(&self, user_id: i64, password_hash: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE User SET password_hash = ? WHERE user_id = ?",
+            params![password_hash, user_id],
+        )?;
+
+        self.conn
+            .execute("DELETE FROM Session WHERE user_id = ?", [user_id])?;
+
+        Ok(())
+    }
+
+    pub fn delete_user(&self, user_id: i64) -> Result<bool> {
+        let deleted = self
+            .conn
+            .execute("DELETE FROM User WHERE user_id = ?", [user_id])?;
+
+        self.index
+            .connection()
+            .execute("DELETE FROM StoreUserTrack WHERE user_id = ?", [user_id])?;
+        self.index
+            .connection()
+            .execute("DELETE FROM StoreBookmark WHERE user_id = ?", [user_id])?;
+        self.index
+            .connection()
+            .execute("DELETE FROM StorePlay WHERE user_id = ?", [user_id])?;
+        for (table, _) in RATING_TABLES {
+            self.index.connection().execute(
+                &format!("DELETE FROM Store{} WHERE user_id = ?", table),
+                [user_id],
+            )?;
+        }
+        self.index
+            .connection()
+            .execute("DELETE FROM StoreList WHERE user_id = ?", [user_id])?;
+
+        Ok(deleted > 0)
+    }
+
+    pub fn subsonic_password(&self, user_id: i64) -> Result<Option<String>> {
+        self.conn.query_row(
+            "SELECT subsonic_password FROM User WHERE user_id = ?",
+            [user_id],
+            |row| row.get(0),
+        )
+    }
+
+    pub fn set_subsonic_password(&self, user_id: i64, password: Option<&str>) -> Result<()> {
+        self.conn.execute(
+            "UPDATE User SET subsonic_password = ? WHERE user_id = ?",
+            params![password, user_id],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn scrobble_config(&self, user_id: i64) -> Result<Option<ScrobbleConfig>> {
+        self.conn
+            .query_row(
+                "SELECT url, token FROM ScrobbleConfig WHERE user_id = ?",
+                [user_id],
+                |row| {
+                    Ok(ScrobbleConfig {
+                        url: row.get(0)?,
+                        token: row.get(1)?,
+                    })
+                },
+            )
+            .optional()
+    }
+
+    pub fn set_scrobble_config(&self, user_id: i64, url: &str, token: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO ScrobbleConfig (user_id, url, token) VALUES (?, ?, ?)",
+            params![user_id, url, token],
+        )?;
+
+        // Listens waiting for a retry may succeed with the new configuration
+        self.conn.execute(
+            "UPDATE ScrobbleQueue SET next_attempt = 0 WHERE user_id = ?",
+            [user_id],
+        )?;
+
+        Ok(())
+    }
+
+    // Also drops any listens not yet submitted
+    pub fn delete_scrobble_config(&self, user_id: i64) -> Result<bool> {
+        let deleted = self
+            .conn
+            .execute("DELETE FROM ScrobbleConfig WHERE user_id = ?", [user_id])?;
+
+        self.conn
+            .execute("DELETE FROM ScrobbleQueue WHERE user_id = ?", [user_id])?;
+
+        Ok(deleted > 0)
+    }
+
+    pub fn scrobble_queue_length(&self, user_id: i64) -> Result<i64> {
+        self.conn.query_row(
+            "SELECT COUNT(*) FROM ScrobbleQueue WHERE user_id = ?",
+            [user_id],
+            |row| row.get(0),
+        )
+    }
+
+    // Users with listens due for submission at `now`
+    pub fn scrobble_users(&self, now: i64) -> Result<Vec<i64>> {
+        let mut st = self.conn.prepare(
+            "SELECT DISTINCT user_id FROM ScrobbleQueue WHERE next_attempt <= ? ORDER BY user_id",
+        )?;
+
+        let rows = st.query_map([now], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    // Oldest listens of a user due for submission at `now`
+    pub fn due_listens(&self, user_id: i64, now: i64, limit: i64) -> Result<Vec<Listen>> {
+        let mut st = self.conn.prepare(
+            "SELECT scrobble_id, listened_at, title, artist_name, album_name, length
+            FROM ScrobbleQueue
+            WHERE user_id = ? AND next_attempt <= ?
+            ORDER BY listened_at, scrobble_id
+            LIMIT ?",
+        )?;
+
+        let rows = st.query_map(params![user_id, now, limit], |row| {
+            Ok(Listen {
+                scrobble_id: row.get(0)?,
+                listened_at: row.get(1)?,
+                title: row.get(2)?,
+                artist_name: row.get(3)?,
+                album_name: row.get(4)?,
+                length: row.get(5)?,
+            })
+        })?;
+
+        rows.collect()
+    }
+
+    pub fn delete_listens(&self, scrobble_ids: &[i64]) -> Result<()> {
+        for scrobble_id in scrobble_ids {
+            self.conn.execute(
+                "DELETE FROM ScrobbleQueue WHERE scrobble_id = ?",
+                &[scrobble_id],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // Retried after a delay doubling with each failed attempt, from a minute
+    // up to `max_delay` seconds
+    pub fn postpone_listens(&self, scrobble_ids: &[i64], now: i64, max_delay: i64) -> Result<()> {
+        for scrobble_id in scrobble_ids {
+            self.conn.execute(
+                "UPDATE ScrobbleQueue
+                SET
+                    next_attempt = ? + MIN(60 << MIN(attempts, 20), ?),
+                    attempts = attempts + 1
+                WHERE scrobble_id = ?",
+                params![now, max_delay, scrobble_id],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // The first admin, acting user of requests when authentication is disabled
+    pub fn default_user(&self) -> Result<Option<User>> {
+        self.conn
+            .query_row(
+                "SELECT user_id, name, password_hash, admin
+                FROM User
+                WHERE admin = 1
+                ORDER BY user_id
+                LIMIT 1",
+                NO_PARAMS,
+                Self::_get_user,
+            )
+            .optional()
+    }
+
+    // Makes sure there's an admin user to own data when authentication is
+    // disabled. The legacy single password belongs to this user.
+    pub fn ensure_admin(&self, password_hash: Option<&str>) -> Result<User> {
+        let admin = match self.user_by_name("admin")? {
+            Some(u) => u,
+            None => match self.default_user()? {
+                Some(u) if password_hash.is_none() => u,
+                _ => self.create_user("admin", password_hash, true)?,
+            },
+        };
+
+        if let Some(password_hash) = password_hash {
+            if admin.password_hash.as_deref() != Some(password_hash) {
+                self.set_user_password(admin.user_id, password_hash)?;
+            }
         }
 
+        // Data predating users belongs to the admin
+        self.conn.execute(
+            "UPDATE ApiToken SET user_id = ? WHERE user_id IS NULL",
+            [admin.user_id],
+        )?;
+        self.conn.execute(
+            "UPDATE List SET user_id = ? WHERE user_id IS NULL",
+            [admin.user_id],
+        )?;
+        self.conn.execute(
+            "INSERT OR IGNORE INTO UserTrack (user_id, store_track_id, play_count, last_play)
+            SELECT ?, store_track_id, play_count, COALESCE(last_play, 0)
+            FROM Track
+            WHERE play_count IS NOT NULL",
+            [admin.user_id],
+        )?;
+        self.conn.execute(
+            "UPDATE Track SET play_count = NULL, last_play = NULL",
+            NO_PARAMS,
+        )?;
+
+        Ok(admin)
+    }
+
+    // Authentication is disabled until some user has a password
+    pub fn auth_required(&self) -> Result<bool> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM User WHERE password_hash IS NOT NULL",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?;
+
+        Ok(count > 0)
+    }
+
+    pub fn create_session(
+        &self,
+        user_id: i64,
+        token_hash: &str,
+        created: i64,
+        expires: i64,
+    ) -> Result<()> {
+        // Expired sessions are cleaned up whenever a new one is created
+        self.conn
+            .execute("DELETE FROM Session WHERE expires <= ?", [created])?;
+
+        self.conn.execute(
+            "INSERT INTO Session (user_id, token_hash, created, expires) VALUES (?, ?, ?, ?)",
+            params![user_id, token_hash, created, expires],
+        )?;
+
         Ok(())
     }
 
-    // pub fn store_track(&mut self, track: &Track) -> Result<StoreTrack> {
-    //     let tx = self.conn.transaction()?;
+    pub fn session_user(&self, token_hash: &str, now: i64) -> Result<Option<User>> {
+        self.conn
+            .query_row(
+                "SELECT User.user_id, User.name, User.password_hash, User.admin
+                FROM Session
+                INNER JOIN User ON User.user_id = Session.user_id
+                WHERE Session.token_hash = ? AND Session.expires > ?",
+                params![token_hash, now],
+                Self::_get_user,
+            )
+            .optional()
+    }
+
+    pub fn delete_session(&self, token_hash: &str) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM Session WHERE token_hash = ?", &[token_hash])?;
+
+        Ok(())
+    }
 
-    //     tx.commit()?;
+    fn _get_api_token(row: &Row) -> Result<ApiToken> {
+        Ok(ApiToken {
+            api_token_id: row.get(0)?,
+            name: row.get(1)?,
+            created: row.get(2)?,
+            last_used: row.get(3)?,
+            roots: Vec::new(),
+        })
+    }
 
-    //     Ok(())
-    // }
+    pub fn api_tokens(&self, user_id: i64) -> Result<Vec<ApiToken>> {
+        let mut st = self.conn.prepare(
+            "SELECT api_token_id, name, created, last_used
+            FROM ApiToken
+            WHERE user_id = ?
+            ORDER BY api_token_id",
+        )?;
 
-    // pub fn register_track_play(&mut self, track: &Track) -> Result<()> {
-    //     let mut store_conn = &mut self.conn;
-    //     let mut index_conn = self.index.connection_mut();
+        let mut rows = st.query([user_id])?;
 
-    //     let mut store_tx = store_conn.transaction()?;
-    //     let mut index_tx = index_conn.transaction()?;
+        let mut result = Vec::new();
 
-    //     // let store_track_id: Option<i64> = index_tx
-    //     //     .query_row(
-    //     //         "SELECT store_track_id FROM StoreTrack WHERE track_id = ?",
-    //     //         &[track.track_id],
-    //     //         |row| row.get(0)
-    //     //     )
-    //     //     .optional()?;
+        while let Some(row) = rows.next()? {
+            let mut api_token = Self::_get_api_token(row)?;
+            api_token.roots = self.api_token_roots(api_token.api_token_id)?;
+            result.push(api_token);
+        }
+
+        Ok(result)
+    }
+
+    pub fn api_token_roots(&self, api_token_id: i64) -> Result<Vec<String>> {
+        let mut st = self.conn.prepare(
+            "SELECT root_name
+            FROM ApiTokenRoot
+            WHERE api_token_id = ?
+            ORDER BY root_name",
+        )?;
+
+        let mut rows = st.query([api_token_id])?;
+
+        let mut result = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            result.push(row.get(0)?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn create_api_token(
+        &self,
+        user_id: i64,
+        name: &str,
+        token_hash: &str,
+        created: i64,
+        roots: &[String],
+    ) -> Result<ApiToken> {
+        self.conn.execute(
+            "INSERT INTO ApiToken (user_id, name, token_hash, created) VALUES (?, ?, ?, ?)",
+            params![user_id, name, token_hash, created],
+        )?;
+
+        let api_token_id = self.conn.last_insert_rowid();
+
+        for root in roots {
+            self.conn.execute(
+                "INSERT OR IGNORE INTO ApiTokenRoot (api_token_id, root_name) VALUES (?, ?)",
+                params![api_token_id, root],
+            )?;
+        }
+
+        let mut api_token = self.conn.query_row(
+            "SELECT api_token_id, name, created, last_used
+            FROM ApiToken
+            WHERE api_token_id = ?",
+            [api_token_id],
+            Self::_get_api_token,
+        )?;
+
+        api_token.roots = self.api_token_roots(api_token_id)?;
+
+        Ok(api_token)
+    }
+
+    pub fn delete_api_token(&self, user_id: i64, api_token_id: i64) -> Result<bool> {
+        let deleted = self.conn.execute(
+            "DELETE FROM ApiToken WHERE api_token_id = ? AND user_id = ?",
+            [api_token_id, user_id],
+        )?;
+
+        Ok(deleted > 0)
+    }
+
+    // Marks the token used and returns its user and id
+    pub fn use_api_token(&self, token_hash: &str, now: i64) -> Result<Option<(User, i64)>> {
+        let updated = self.conn.execute(
+            "UPDATE ApiToken SET last_used = ? WHERE token_hash = ?",
+            params![now, token_hash],
+        )?;
+
+        if updated == 0 {
+            return Ok(None);
+        }
+
+        self.conn
+            .query_row(
+                "SELECT User.user_id, User.name, User.password_hash, User.admin, ApiToken.api_token_id
+                FROM ApiToken
+                INNER JOIN User ON User.user_id = ApiToken.user_id
+                WHERE ApiToken.token_hash = ?",
+                &[token_hash],
+                |row| Ok((Self::_get_user(row)?, row.get(4)?)),
+            )
+            .optional()
+    }
+
+    pub fn root_users(&self, root_name: &str) -> Result<Vec<i64>> {
+        let mut st = self.conn.prepare(
+            "SELECT user_id
+            FROM RootUser
+            WHERE root_name = ?
+            ORDER BY user_id",
+        )?;
+
+        let mut rows = st.query(&[root_name])?;
+
+        let mut result = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            result.push(row.get(0)?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn add_root_user(&self, root_name: &str, user_id: i64) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO RootUser (root_name, user_id) VALUES (?, ?)",
+            params![root_name, user_id],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn remove_root_user(&self, root_name: &str, user_id: i64) -> Result<bool> {
+        let deleted = self.conn.execute(
+            "DELETE FROM RootUser WHERE root_name = ? AND user_id = ?",
+            params![root_name, user_id],
+        )?;
+
+        Ok(deleted > 0)
+    }
+
+    // Names of the roots visible to `user`, further limited by the API token
+    // used, if any
+    pub fn allowed_roots(&self, user: &User, api_token_id: Option<i64>) -> Result<Vec<String>> {
+        let token_roots = match api_token_id {
+            Some(id) => self.api_token_roots(id)?,
+            None => Vec::new(),
+        };
+
+        let mut result = Vec::new();
+
+        for root in self.index.roots().iter() {
+            if !token_roots.is_empty() && !token_roots.contains(&root.name) {
+                continue;
+            }
+
+            if !user.admin {
+                let users = self.root_users(&root.name)?;
+                if !users.is_empty() && !users.contains(&user.user_id) {
+                    continue;
+                }
+            }
+
+            result.push(root.name.clone());
+        }
+
+        Ok(result)
+    }
+
+    fn _get_list(row: &Row) -> Result<List> {
+        Ok(List {
+            list_id: row.get(0)?,
+            name: row.get(1)?,
+            track_count: row.get(2)?,
+            rules: row
+                .get::<_, Option<String>>(3)?
+                .and_then(|r| serde_json::from_str(&r).ok()),
+        })
+    }
+
+    pub fn lists(&self, user_id: i64) -> Result<Vec<List>> {
+        let mut st = self.conn.prepare(
+            "SELECT
+                List.list_id,
+                List.name,
+                (SELECT COUNT(*) FROM ListTrack WHERE ListTrack.list_id = List.list_id),
+                List.rules
+            FROM List
+            WHERE List.user_id = ?
+            ORDER BY List.name",
+        )?;
+
+        let mut rows = st.query([user_id])?;
+
+        let mut result = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            result.push(Self::_get_list(row)?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn list(&self, user_id: i64, list_id: i64) -> Result<Option<List>> {
+        self.conn
+            .query_row(
+                "SELECT
+                    List.list_id,
+                    List.name,
+                    (SELECT COUNT(*) FROM ListTrack WHERE ListTrack.list_id = List.list_id),
+                    List.rules
+                FROM List
+                WHERE List.list_id = ? AND List.user_id = ?",
+                [list_id, user_id],
+                Self::_get_list,
+            )
+            .optional()
+    }
+
+    // Creates a smart list if `rules` is set
+    pub fn create_list(&self, user_id: i64, name: &str, rules: Option<&Rules>) -> Result<List> {
+        let rules = rules.map(|r| serde_json::to_string(r).unwrap());
+
+        self.conn.execute(
+            "INSERT INTO List (user_id, name, rules) VALUES (?, ?, ?)",
+            params![user_id, name, rules],
+        )?;
+
+        let list_id = self.conn.last_insert_rowid();
+
+        self.index.connection().execute(
+            "INSERT INTO StoreList (list_id, user_id, name, rules) VALUES (?, ?, ?, ?)",
+            params![list_id, user_id, name, rules],
+        )?;
+
+        Ok(self.list(user_id, list_id)?.unwrap())
+    }
+
+    // Creates a list of `tracks` at once, nothing is left of it if adding any
+    // of the tracks fails
+    pub fn import_list(&self, user_id: i64, name: &str, tracks: &[Track]) -> Result<List> {
+        self.transaction(|| {
+            let list = self.create_list(user_id, name, None)?;
+
+            for track in tracks {
+                self.add_list_track(list.list_id, track)?;
+            }
+
+            Ok(self.list(user_id, list.list_id)?.unwrap())
+        })
+    }
+
+    // Only smart lists can have their rules replaced
+    pub fn set_list_rules(&self, user_id: i64, list_id: i64, rules: &Rules) -> Result<bool> {
+        let rules = serde_json::to_string(rules).unwrap();
+
+        let updated = self.conn.execute(
+            "UPDATE List SET rules = ?
+            WHERE list_id = ? AND user_id = ? AND rules IS NOT NULL",
+            params![rules, list_id, user_id],
+        )?;
+
+        self.index.connection().execute(
+            "UPDATE StoreList SET rules = ?
+            WHERE list_id = ? AND user_id = ? AND rules IS NOT NULL",
+            params![rules, list_id, user_id],
+        )?;
+
+        Ok(updated > 0)
+    }
+
+    pub fn delete_list(&self, user_id: i64, list_id: i64) -> Result<bool> {
+        let deleted = self.conn.execute(
+            "DELETE FROM List WHERE list_id = ? AND user_id = ?",
+            [list_id, user_id],
+        )?;
+
+        self.index.connection().execute(
+            "DELETE FROM StoreList WHERE list_id = ? AND user_id = ?",
+            [list_id, user_id],
+        )?;
+
+        Ok(deleted > 0)
+    }
+
+    pub fn add_list_track(&self, list_id: i64, track: &Track) -> Result<()> {
+        let store_track_id = self.store_track(track)?;
+
+        let sort_index: i64 = self.conn.query_row(
+            "SELECT COALESCE(MAX(sort_index), -1) + 1 FROM ListTrack WHERE list_id = ?",
+            [list_id],
+            |row| row.get(0),
+        )?;
+
+        self.conn.execute(
+            "INSERT INTO ListTrack (list_id, store_track_id, sort_index) VALUES (?, ?, ?)",
+            [list_id, store_track_id, sort_index],
+        )?;
+
+        self.index.connection().execute(
+            "INSERT INTO StoreListTrack (list_id, store_track_id, sort_index) VALUES (?, ?, ?)",
+            [list_id, store_track_id, sort_index],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn remove_list_track(&self, list_id: i64, track: &Track) -> Result<bool> {
+        let store_track_id: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT store_track_id
+                FROM Track
+                WHERE title = ? AND artist_name = ? AND album_name = ?",
+                params![track.title, track.artist_name, track.album_name],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let store_track_id = match store_track_id {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+
+        let deleted = self.conn.execute(
+            "DELETE FROM ListTrack WHERE list_id = ? AND store_track_id = ?",
+            [list_id, store_track_id],
+        )?;
+
+        self.index.connection().execute(
+            "DELETE FROM StoreListTrack WHERE list_id = ? AND store_track_id = ?",
+            [list_id, store_track_id],
+        )?;
+
+        Ok(deleted > 0)
+    }
+
+    fn _get_share(row: &Row) -> Result<Share> {
+        Ok(Share {
+            share_id: row.get(0)?,
+            user_id: row.get(1)?,
+            item_type: row.get(2)?,
+            name: row.get(3)?,
+            store_track_id: row.get(4)?,
+            album_name: row.get(5)?,
+            album_artist_name: row.get(6)?,
+            list_id: row.get(7)?,
+            created: row.get(8)?,
+            expires: row.get(9)?,
+            play_limit: row.get(10)?,
+            play_count: row.get(11)?,
+        })
+    }
+
+    pub fn shares(&self, user_id: i64) -> Result<Vec<Share>> {
+        let mut st = self.conn.prepare(
+            "SELECT share_id, user_id, item_type, name, store_track_id, album_name,
+                album_artist_name, list_id, created, expires, play_limit, play_count
+            FROM Share
+            WHERE user_id = ?
+            ORDER BY share_id",
+        )?;
+
+        let mut rows = st.query([user_id])?;
+
+        let mut result = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            result.push(Self::_get_share(row)?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn create_track_share(
+        &self,
+        user_id: i64,
+        token_hash: &str,
+        track: &Track,
+        created: i64,
+        expires: Option<i64>,
+        play_limit: Option<i64>,
+    ) -> Result<Share> {
+        let store_track_id = self.store_track(track)?;
+
+        self.conn.execute(
+            "INSERT INTO Share (user_id, token_hash, item_type, name, store_track_id, created, expires, play_limit, play_count)
+            VALUES (?, ?, 'track', ?, ?, ?, ?, ?, 0)",
+            params![user_id, token_hash, track.title, store_track_id, created, expires, play_limit],
+        )?;
+
+        self.share(self.conn.last_insert_rowid())
+    }
+
+    pub fn create_album_share(
+        &self,
+        user_id: i64,
+        token_hash: &str,
+        album: &Album,
+        created: i64,
+        expires: Option<i64>,
+        play_limit: Option<i64>,
+    ) -> Result<Share> {
+        self.conn.execute(
+            "INSERT INTO Share (user_id, token_hash, item_type, name, album_name, album_artist_name, created, expires, play_limit, play_count)
+            VALUES (?, ?, 'album', ?, ?, ?, ?, ?, ?, 0)",
+            params![
+                user_id,
+                token_hash,
+                album.name,
+                album.name,
+                album.artist_name,
+                created,
+                expires,
+                play_limit
+            ],
+        )?;
+
+        self.share(self.conn.last_insert_rowid())
+    }
+
+    pub fn create_list_share(
+        &self,
+        user_id: i64,
+        token_hash: &str,
+        list: &List,
+        created: i64,
+        expires: Option<i64>,
+        play_limit: Option<i64>,
+    ) -> Result<Share> {
+        self.conn.execute(
+            "INSERT INTO Share (user_id, token_hash, item_type, name, list_id, created, expires, play_limit, play_count)
+            VALUES (?, ?, 'list', ?, ?, ?, ?, ?, 0)",
+            params![user_id, token_hash, list.name, list.list_id, created, expires, play_limit],
+        )?;
+
+        self.share(self.conn.last_insert_rowid())
+    }
+
+    fn share(&self, share_id: i64) -> Result<Share> {
+        self.conn.query_row(
+            "SELECT share_id, user_id, item_type, name, store_track_id, album_name,
+                album_artist_name, list_id, created, expires, play_limit, play_count
+            FROM Share
+            WHERE share_id = ?",
+            [share_id],
+            Self::_get_share,
+        )
+    }
+
+    pub fn delete_share(&self, user_id: i64, share_id: i64) -> Result<bool> {
+        let deleted = self.conn.execute(
+            "DELETE FROM Share WHERE share_id = ? AND user_id = ?",
+            [share_id, user_id],
+        )?;
+
+        Ok(deleted > 0)
+    }
+
+    // Only returns shares that haven't expired or run out of plays
+    pub fn valid_share(&self, token_hash: &str, now: i64) -> Result<Option<Share>> {
+        self.conn
+            .query_row(
+                "SELECT share_id, user_id, item_type, name, store_track_id, album_name,
+                    album_artist_name, list_id, created, expires, play_limit, play_count
+                FROM Share
+                WHERE token_hash = ?
+                    AND (expires IS NULL OR expires > ?)
+                    AND (play_limit IS NULL OR play_count < play_limit)",
+                params![token_hash, now],
+                Self::_get_share,
+            )
+            .optional()
+    }
+
+    // Counts a stream of a shared track as a play unless it's a seek within
+    // the last play, returns false if the play limit has been reached
+    pub fn register_share_play(
+        &self,
+        share_id: i64,
+        track_id: i64,
+        length: f64,
+        now: i64,
+    ) -> Result<bool> {
+        let seeking: i64 = self.conn.query_row(
+            "SELECT COUNT(*)
+            FROM Share
+            WHERE share_id = ? AND last_track_id = ? AND last_play_end >= ?",
+            [share_id, track_id, now],
+            |row| row.get(0),
+        )?;
+
+        if seeking > 0 {
+            return Ok(true);
+        }
+
+        let updated = self.conn.execute(
+            "UPDATE Share
+            SET play_count = play_count + 1, last_track_id = ?, last_play_end = ?
+            WHERE share_id = ? AND (play_limit IS NULL OR play_count < play_limit)",
+            params![track_id, now + length.ceil() as i64, share_id],
+        )?;
+
+        Ok(updated > 0)
+    }
+
+    // Index tracks of the shared item, not yet filtered by root access
+    pub fn share_tracks(&self, share: &Share) -> Result<Vec<Track>> {
+        match share.item_type.as_str() {
+            "track" => self
+                .index
+                .tracks_by_store_track(share.store_track_id.unwrap_or_default()),
+            "album" => {
+                let mut tracks = Vec::new();
+                let albums = self.index.albums_by_name(
+                    share.album_name.as_deref().unwrap_or_default(),
+                    share.album_artist_name.as_deref(),
+                )?;
+                for album in albums {
+                    tracks.extend(self.index.tracks_by_album(album.album_id)?);
+                }
+                Ok(tracks)
+            }
+            "list" => self
+                .index
+                .tracks_by_store_list(share.list_id.unwrap_or_default()),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn _get_playback_session(row: &Row) -> Result<PlaybackSession> {
+        Ok(PlaybackSession {
+            playback_session_id: row.get(0)?,
+            device: row.get(1)?,
+            current_index: row.get(2)?,
+            position: row.get(3)?,
+            playing: row.get(4)?,
+            shuffle: row.get(5)?,
+            repeat: row.get(6)?,
+            updated: row.get(7)?,
+        })
+    }
+
+    pub fn playback_sessions(&self, user_id: i64) -> Result<Vec<PlaybackSession>> {
+        let mut st = self.conn.prepare(
+            "SELECT playback_session_id, device, current_index, position, playing, shuffle,
+                repeat, updated
+            FROM PlaybackSession
+            WHERE user_id = ?
+            ORDER BY updated DESC",
+        )?;
+
+        let mut rows = st.query([user_id])?;
+
+        let mut result = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            result.push(Self::_get_playback_session(row)?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn playback_session(&self, user_id: i64, device: &str) -> Result<Option<PlaybackSession>> {
+        self.conn
+            .query_row(
+                "SELECT playback_session_id, device, current_index, position, playing, shuffle,
+                    repeat, updated
+                FROM PlaybackSession
+                WHERE user_id = ? AND device = ?",
+                params![user_id, device],
+                Self::_get_playback_session,
+            )
+            .optional()
+    }
+
+    // Runs `f` in a transaction, rolled back if it fails. The copies written
+    // to the index are included.
+    fn transaction<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let index_conn = self.index.connection();
+
+        self.conn.execute_batch("BEGIN")?;
+
+        if let Err(e) = index_conn.execute_batch("BEGIN") {
+            self.conn.execute_batch("ROLLBACK")?;
+            return Err(e);
+        }
+
+        let result = f().and_then(|result| {
+            self.conn.execute_batch("COMMIT")?;
+            Ok(result)
+        });
+
+        match result {
+            Ok(result) => {
+                index_conn.execute_batch("COMMIT")?;
+                Ok(result)
+            }
+            Err(e) => {
+                index_conn.execute_batch("ROLLBACK")?;
+                if !self.conn.is_autocommit() {
+                    self.conn.execute_batch("ROLLBACK")?;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Creates or updates the session of `session.device`, the id is ignored.
+    // The queue is replaced with `tracks` if given.
+    pub fn save_playback_session(
+        &self,
+        user_id: i64,
+        session: &PlaybackSession,
+        tracks: Option<&[Track]>,
+    ) -> Result<PlaybackSession> {
+        self.transaction(|| {
+            let session = self._save_playback_session(user_id, session)?;
+
+            if let Some(tracks) = tracks {
+                self._set_playback_session_tracks(session.playback_session_id, tracks)?;
+            }
+
+            Ok(session)
+        })
+    }
+
+    fn _save_playback_session(
+        &self,
+        user_id: i64,
+        session: &PlaybackSession,
+    ) -> Result<PlaybackSession> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO PlaybackSession
+                (user_id, device, position, playing, shuffle, repeat, updated)
+            VALUES (?, ?, 0, 0, 0, 'off', ?)",
+            params![user_id, session.device, session.updated],
+        )?;
+
+        self.conn.execute(
+            "UPDATE PlaybackSession
+            SET current_index = ?, position = ?, playing = ?, shuffle = ?, repeat = ?, updated = ?
+            WHERE user_id = ? AND device = ?",
+            params![
+                session.current_index,
+                session.position,
+                session.playing,
+                session.shuffle,
+                session.repeat,
+                session.updated,
+                user_id,
+                session.device
+            ],
+        )?;
+
+        Ok(self.playback_session(user_id, &session.device)?.unwrap())
+    }
+
+    fn _set_playback_session_tracks(
+        &self,
+        playback_session_id: i64,
+        tracks: &[Track],
+    ) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM PlaybackSessionTrack WHERE playback_session_id = ?",
+            [playback_session_id],
+        )?;
+
+        for (sort_index, track) in tracks.iter().enumerate() {
+            let store_track_id = self.store_track(track)?;
+
+            self.conn.execute(
+                "INSERT INTO PlaybackSessionTrack (playback_session_id, store_track_id, sort_index)
+                VALUES (?, ?, ?)",
+                [playback_session_id, store_track_id, sort_index as i64],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // Index tracks matching each queued store track, in queue order
+    pub fn playback_session_tracks(&self, playback_session_id: i64) -> Result<Vec<Vec<Track>>> {
+        let mut st = self.conn.prepare(
+            "SELECT store_track_id
+            FROM PlaybackSessionTrack
+            WHERE playback_session_id = ?
+            ORDER BY sort_index",
+        )?;
+
+        let mut rows = st.query([playback_session_id])?;
+
+        let mut result = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            result.push(self.index.tracks_by_store_track(row.get(0)?)?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn delete_playback_session(&self, user_id: i64, device: &str) -> Result<bool> {
+        let deleted = self.conn.execute(
+            "DELETE FROM PlaybackSession WHERE user_id = ? AND device = ?",
+            params![user_id, device],
+        )?;
+
+        Ok(deleted > 0)
+    }
+
+    // Copies the session of device `from` to device `to` and pauses the
+    // original. Returns the new session or None if `from` doesn't exist.
+    pub fn hand_off_playback_session(
+        &self,
+        user_id: i64,
+        from: &str,
+        to: &str,
+        now: i64,
+    ) -> Result<Option<PlaybackSession>> {
+        self.transaction(|| self._hand_off_playback_session(user_id, from, to, now))
+    }
+
+    fn _hand_off_playback_session(
+        &self,
+        user_id: i64,
+        from: &str,
+        to: &str,
+        now: i64,
+    ) -> Result<Option<PlaybackSession>> {
+        let source = match self.playback_session(user_id, from)? {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+
+        // Positions are only updated now and then during playback
+        let position = if source.playing {
+            source.position + (now - source.updated).max(0) as f64
+        } else {
+            source.position
+        };
+
+        let target = self._save_playback_session(
+            user_id,
+            &PlaybackSession {
+                device: to.to_string(),
+                position,
+                updated: now,
+                ..source.clone()
+            },
+        )?;
+
+        self.conn.execute(
+            "DELETE FROM PlaybackSessionTrack WHERE playback_session_id = ?",
+            [target.playback_session_id],
+        )?;
+
+        self.conn.execute(
+            "INSERT INTO PlaybackSessionTrack (playback_session_id, store_track_id, sort_index)
+            SELECT ?, store_track_id, sort_index
+            FROM PlaybackSessionTrack
+            WHERE playback_session_id = ?",
+            [target.playback_session_id, source.playback_session_id],
+        )?;
+
+        self.conn.execute(
+            "UPDATE PlaybackSession SET playing = 0, position = ?, updated = ?
+            WHERE playback_session_id = ?",
+            params![position, now, source.playback_session_id],
+        )?;
+
+        Ok(Some(target))
+    }
+}
+
+#[cfg(test)]
+fn test_store(name: &str) -> (PathBuf, Store) {
+    use crate::index::IndexSource;
+    use std::sync::Arc;
+
+    let dir = std::env::temp_dir().join(format!("musicd2-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let index_source = IndexSource::create(dir.join("index.db"), Arc::new(Vec::new()))
+        .unwrap()
+        .unwrap();
+    let store_source = StoreSource::create(dir.join("store.db"), index_source.get().unwrap())
+        .unwrap()
+        .unwrap();
+    let store = store_source.get(index_source.get().unwrap()).unwrap();
+
+    (dir, store)
+}
+
+#[test]
+fn test_sessions() {
+    let (dir, store) = test_store("sessions");
+
+    assert!(!store.auth_required().unwrap());
+    let user = store
+        .create_user("user", Some(&crate::auth::hash_password("secret")), false)
+        .unwrap();
+    assert!(store.auth_required().unwrap());
+
+    store.create_session(user.user_id, "a", 1000, 2000).unwrap();
+
+    assert_eq!(
+        store.session_user("a", 1500).unwrap().unwrap().user_id,
+        user.user_id
+    );
+    assert!(store.session_user("b", 1500).unwrap().is_none());
+
+    // Expired
+    assert!(store.session_user("a", 2000).unwrap().is_none());
+
+    // Logged out
+    store.create_session(user.user_id, "c", 1000, 2000).unwrap();
+    store.delete_session("c").unwrap();
+    assert!(store.session_user("c", 1500).unwrap().is_none());
+
+    // Expired sessions are removed when new ones are created
+    store.create_session(user.user_id, "d", 3000, 4000).unwrap();
+    let sessions: i64 = store
+        .conn
+        .query_row("SELECT COUNT(*) FROM Session", NO_PARAMS, |row| row.get(0))
+        .unwrap();
+    assert_eq!(sessions, 1);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_api_tokens() {
+    let (dir, store) = test_store("api-tokens");
+    let user = store.create_user("user", None, false).unwrap();
+
+    let api_token = store
+        .create_api_token(user.user_id, "player", "hash", 1000, &[])
+        .unwrap();
+    assert_eq!(api_token.last_used, None);
+
+    let (token_user, api_token_id) = store.use_api_token("hash", 1500).unwrap().unwrap();
+    assert_eq!(token_user.user_id, user.user_id);
+    assert_eq!(api_token_id, api_token.api_token_id);
+    assert_eq!(
+        store.api_tokens(user.user_id).unwrap()[0].last_used,
+        Some(1500)
+    );
+
+    assert!(store.use_api_token("other", 1500).unwrap().is_none());
+
+    // Only the owner can delete the token
+    assert!(!store
+        .delete_api_token(user.user_id + 1, api_token.api_token_id)
+        .unwrap());
+    assert!(store
+        .delete_api_token(user.user_id, api_token.api_token_id)
+        .unwrap());
+    assert!(store.use_api_token("hash", 2000).unwrap().is_none());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_set_user_password() {
+    let (dir, store) = test_store("set-user-password");
+    let user = store.create_user("user", Some("old"), false).unwrap();
+    let other = store.create_user("other", Some("other"), false).unwrap();
+
+    store.create_session(user.user_id, "a", 1000, 2000).unwrap();
+    store
+        .create_session(other.user_id, "b", 1000, 2000)
+        .unwrap();
+
+    store.set_user_password(user.user_id, "new").unwrap();
+
+    assert!(store.session_user("a", 1500).unwrap().is_none());
+    assert!(store.session_user("b", 1500).unwrap().is_some());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(test)]
+fn test_track(title: &str) -> Track {
+    Track {
+        track_id: 0,
+        node_id: 0,
+        stream_index: 0,
+        track_index: None,
+        start: None,
+        number: 1,
+        title: title.to_string(),
+        artist_id: 0,
+        artist_name: "Artist".to_string(),
+        album_id: 0,
+        album_name: "Album".to_string(),
+        album_artist_id: None,
+        album_artist_name: None,
+        length: 100f64,
+        codec: None,
+        container: None,
+        bitrate: None,
+        sample_rate: None,
+        bit_depth: None,
+        channels: None,
+        file_size: None,
+        lossless: None,
+        genre: None,
+        year: None,
+    }
+}
+
+#[cfg(test)]
+fn test_queue(store: &Store, playback_session_id: i64) -> Vec<i64> {
+    let mut st = store
+        .conn
+        .prepare(
+            "SELECT store_track_id
+            FROM PlaybackSessionTrack
+            WHERE playback_session_id = ?
+            ORDER BY sort_index",
+        )
+        .unwrap();
+
+    let rows = st
+        .query_map([playback_session_id], |row| row.get(0))
+        .unwrap();
+    rows.collect::<Result<_>>().unwrap()
+}
+
+#[test]
+fn test_playback_session() {
+    let (dir, store) = test_store("playback-session");
+    let user = store.create_user("user", None, false).unwrap();
+
+    let session = PlaybackSession {
+        playback_session_id: 0,
+        device: "phone".to_string(),
+        current_index: Some(1),
+        position: 10f64,
+        playing: true,
+        shuffle: false,
+        repeat: "all".to_string(),
+        updated: 1000,
+    };
+
+    let tracks = [test_track("One"), test_track("Two")];
+    let saved = store
+        .save_playback_session(user.user_id, &session, Some(&tracks))
+        .unwrap();
+
+    let reloaded = store
+        .playback_session(user.user_id, "phone")
+        .unwrap()
+        .unwrap();
+    assert_eq!(reloaded.playback_session_id, saved.playback_session_id);
+    assert_eq!(reloaded.current_index, Some(1));
+    assert_eq!(reloaded.repeat, "all");
+
+    let queue = test_queue(&store, saved.playback_session_id);
+    assert_eq!(queue.len(), 2);
+
+    // The queue is kept unless given
+    let saved = store
+        .save_playback_session(
+            user.user_id,
+            &PlaybackSession {
+                position: 20f64,
+                ..session.clone()
+            },
+            None,
+        )
+        .unwrap();
+    assert_eq!(saved.position, 20f64);
+    assert_eq!(test_queue(&store, saved.playback_session_id), queue);
+
+    let target = store
+        .hand_off_playback_session(user.user_id, "phone", "desktop", 1005)
+        .unwrap()
+        .unwrap();
+    assert_eq!(target.device, "desktop");
+    assert_eq!(target.position, 25f64);
+    assert!(target.playing);
+    assert_eq!(test_queue(&store, target.playback_session_id), queue);
+
+    let source = store
+        .playback_session(user.user_id, "phone")
+        .unwrap()
+        .unwrap();
+    assert!(!source.playing);
+    assert_eq!(source.position, 25f64);
+
+    assert!(store
+        .hand_off_playback_session(user.user_id, "tablet", "desktop", 1010)
+        .unwrap()
+        .is_none());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
 
-    //     // if let Some(track_id) = store_track_id {
-    //     //     store_tx
+#[test]
+fn test_import_list() {
+    let (dir, store) = test_store("import-list");
+    let user = store.create_user("user", None, false).unwrap();
 
-    //     // }
+    let tracks = [test_track("One"), test_track("Two")];
 
-    //     //store_tx.commit()?;
+    let list = store
+        .import_list(user.user_id, "Imported", &tracks)
+        .unwrap();
+    assert_eq!(list.track_count, 2);
 
-    //     Ok(())
-    // }
+    // A failure adding the second track leaves nothing of the list behind
+    store
+        .conn
+        .execute_batch(
+            "CREATE TEMP TRIGGER fail_second BEFORE INSERT ON ListTrack
+            WHEN NEW.sort_index = 1
+            BEGIN SELECT RAISE(ABORT, 'failed'); END",
+        )
+        .unwrap();
 
-    // pub fn create_list(&mut self, name: &str) -> Result<i64> {
-    //     self.conn.execute("INSERT INTO List (name) VALUES (?)", &[name])?;
+    assert!(store.import_list(user.user_id, "Failed", &tracks).is_err());
 
-    //     let list_id = self.conn.last_insert_rowid();
+    let lists = store.lists(user.user_id).unwrap();
+    assert_eq!(lists.len(), 1);
+    assert_eq!(lists[0].name, "Imported");
 
-    //     self.index.connection().execute("INSERT INTO StoreList (list_id, name) VALUES (?, ?)", params![list_id, name])?;
+    let index_lists: i64 = store
+        .index
+        .connection()
+        .query_row("SELECT COUNT(*) FROM StoreList", NO_PARAMS, |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(index_lists, 1);
 
-    //     Ok(list_id)
-    // }
+    std::fs::remove_dir_all(&dir).unwrap();
 }
\ No newline at end of file
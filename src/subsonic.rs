@@ -0,0 +1,1005 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use hyper::{Body, Response};
+use serde_json::{json, Map, Value};
+
+use crate::auth;
+use crate::events::Event;
+use crate::http_api::{api_audio_stream, api_image_file, node_allowed, ApiRequest, Error};
+use crate::http_util::{escape_xml, HttpQuery};
+use crate::index::NodeType;
+use crate::query::{self, AlbumItem, ArtistItem, NodeItem, TrackItem};
+use crate::store::User;
+
+const API_VERSION: &str = "1.16.1";
+
+// Error codes defined by the Subsonic API
+const ERROR_GENERIC: i64 = 0;
+const ERROR_MISSING_PARAMETER: i64 = 10;
+const ERROR_WRONG_CREDENTIALS: i64 = 40;
+const ERROR_NOT_FOUND: i64 = 70;
+
+// Subsonic ids are strings, prefixes keep the different kinds of ids apart
+const DIRECTORY_PREFIX: &str = "nd-";
+const TRACK_PREFIX: &str = "tr-";
+const ARTIST_PREFIX: &str = "ar-";
+const ALBUM_PREFIX: &str = "al-";
+const IMAGE_PREFIX: &str = "im-";
+const LIST_PREFIX: &str = "pl-";
+
+pub(crate) fn process_request(r: &mut ApiRequest) -> Result<Response<Body>, Error> {
+    let path = r.request.uri().path();
+    let method = path["/rest/".len()..].trim_end_matches(".view").to_string();
+
+    if let Some(callback) = r.query.get_str("callback") {
+        if r.query.get_str("f") == Some("jsonp") && !valid_callback(callback) {
+            return Ok(error(r, ERROR_GENERIC, "Invalid callback"));
+        }
+    }
+
+    let user = match authenticate(r)? {
+        Some(u) => u,
+        None => {
+            return Ok(error(
+                r,
+                ERROR_WRONG_CREDENTIALS,
+                "Wrong username or password",
+            ));
+        }
+    };
+
+    r.roots = r.musicd.store().allowed_roots(&user, None)?;
+    r.user = Some(user);
+
+    match method.as_str() {
+        "ping" => Ok(ok(r, json!({}))),
+        "getLicense" => Ok(ok(r, json!({ "license": { "valid": true } }))),
+        "getMusicFolders" => get_music_folders(r),
+        "getIndexes" => get_indexes(r),
+        "getMusicDirectory" => get_music_directory(r),
+        "getArtists" => get_artists(r),
+        "getArtist" => get_artist(r),
+        "getAlbumList2" => get_album_list2(r),
+        "getAlbum" => get_album(r),
+        "search3" => search3(r),
+        "stream" => stream(r),
+        "getCoverArt" => get_cover_art(r),
+        "getPlaylists" => get_playlists(r),
+        "getPlaylist" => get_playlist(r),
+        "scrobble" => scrobble(r),
+        _ => Ok(error(r, ERROR_GENERIC, "Unsupported method")),
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 == 1 {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Supports the plain "p" password, optionally hex encoded, and the "t" token
+// with "s" salt. Both are checked against the separate Subsonic password, the
+// login password is never accepted in URLs.
+fn authenticate(r: &ApiRequest) -> Result<Option<User>, Error> {
+    let store = r.musicd.store();
+
+    if !store.auth_required()? {
+        return Ok(store.default_user()?);
+    }
+
+    let user = match r.query.get_str("u") {
+        Some(name) => match store.user_by_name(name)? {
+            Some(u) => u,
+            None => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+
+    let subsonic_password = store.subsonic_password(user.user_id)?;
+
+    let valid = if let (Some(token), Some(salt)) = (r.query.get_str("t"), r.query.get_str("s")) {
+        match &subsonic_password {
+            Some(password) => {
+                let expected = format!("{:x}", md5::compute(format!("{}{}", password, salt)));
+                expected.eq_ignore_ascii_case(token)
+            }
+            None => false,
+        }
+    } else if let Some(password) = r.query.get_str("p") {
+        let password = if let Some(hex) = password.strip_prefix("enc:") {
+            match decode_hex(hex) {
+                Some(p) => String::from_utf8_lossy(&p).into_owned(),
+                None => return Ok(None),
+            }
+        } else {
+            password.to_string()
+        };
+
+        subsonic_password.as_deref() == Some(password.as_str())
+    } else {
+        false
+    };
+
+    Ok(if valid { Some(user) } else { None })
+}
+
+fn xml_scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::String(s) => Some(escape_xml(s)),
+        _ => None,
+    }
+}
+
+// Follows the mapping between the JSON and XML formats of the API: scalar
+// fields are attributes, objects are child elements and arrays are repeated
+// child elements
+fn write_xml(out: &mut String, name: &str, object: &Map<String, Value>, attributes: &str) {
+    out.push('<');
+    out.push_str(name);
+    out.push_str(attributes);
+
+    for (key, value) in object.iter() {
+        if let Some(scalar) = xml_scalar(value) {
+            out.push_str(&format!(" {}=\"{}\"", key, scalar));
+        }
+    }
+
+    let mut children = String::new();
+
+    for (key, value) in object.iter() {
+        match value {
+            Value::Object(child) => write_xml(&mut children, key, child, ""),
+            Value::Array(items) => {
+                for item in items {
+                    match item {
+                        Value::Object(child) => write_xml(&mut children, key, child, ""),
+                        item => {
+                            if let Some(scalar) = xml_scalar(item) {
+                                children.push_str(&format!("<{}>{}</{}>", key, scalar, key));
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if children.is_empty() {
+        out.push_str("/>");
+    } else {
+        out.push('>');
+        out.push_str(&children);
+        out.push_str(&format!("</{}>", name));
+    }
+}
+
+// Optional fields are left out instead of being null
+fn strip_nulls(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            let nulls: Vec<String> = object
+                .iter()
+                .filter(|(_, v)| v.is_null())
+                .map(|(k, _)| k.clone())
+                .collect();
+
+            for key in nulls {
+                object.remove(&key);
+            }
+
+            for v in object.values_mut() {
+                strip_nulls(v);
+            }
+        }
+        Value::Array(items) => {
+            for v in items.iter_mut() {
+                strip_nulls(v);
+            }
+        }
+        _ => {}
+    }
+}
+
+// The callback is written into a script as is, so only plain JavaScript
+// identifiers and property paths are accepted
+fn valid_callback(callback: &str) -> bool {
+    let mut chars = callback.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '$' => {}
+        _ => return false,
+    }
+
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$' || c == '.')
+}
+
+fn response(r: &ApiRequest, status: &str, content: Value) -> Response<Body> {
+    let mut object = match content {
+        Value::Object(o) => o,
+        _ => Map::new(),
+    };
+
+    object.insert("status".to_string(), json!(status));
+    object.insert("version".to_string(), json!(API_VERSION));
+
+    let mut value = Value::Object(object);
+    strip_nulls(&mut value);
+
+    let object = match value {
+        Value::Object(o) => o,
+        _ => unreachable!(),
+    };
+
+    let format = r.query.get_str("f").unwrap_or("xml");
+
+    let (content_type, body) = match format {
+        "json" | "jsonp" => {
+            let json = json!({ "subsonic-response": object }).to_string();
+
+            match (format, r.query.get_str("callback")) {
+                ("jsonp", Some(callback)) if valid_callback(callback) => (
+                    "text/javascript; charset=utf-8",
+                    format!("{}({});", callback, json),
+                ),
+                _ => ("application/json; charset=utf-8", json),
+            }
+        }
+        _ => {
+            let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+            write_xml(
+                &mut xml,
+                "subsonic-response",
+                &object,
+                " xmlns=\"http://subsonic.org/restapi\"",
+            );
+            ("text/xml; charset=utf-8", xml)
+        }
+    };
+
+    Response::builder()
+        .header("Content-Type", content_type)
+        .body(body.into())
+        .unwrap()
+}
+
+fn ok(r: &ApiRequest, content: Value) -> Response<Body> {
+    response(r, "ok", content)
+}
+
+// Errors are reported with a successful HTTP status
+fn error(r: &ApiRequest, code: i64, message: &str) -> Response<Body> {
+    response(
+        r,
+        "failed",
+        json!({
+            "error": {
+                "code": code,
+                "message": message
+            }
+        }),
+    )
+}
+
+fn missing_parameter(r: &ApiRequest) -> Response<Body> {
+    error(r, ERROR_MISSING_PARAMETER, "Required parameter is missing")
+}
+
+fn not_found(r: &ApiRequest) -> Response<Body> {
+    error(r, ERROR_NOT_FOUND, "The requested data was not found")
+}
+
+fn parse_id(r: &ApiRequest, prefix: &str) -> Option<i64> {
+    let id = r.query.get_str("id")?;
+
+    id.strip_prefix(prefix)?.parse().ok()
+}
+
+fn content_type(path: &str) -> (String, &'static str) {
+    let suffix = Path::new(path)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    let content_type = match suffix.as_str() {
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "m4a" | "mp4" | "aac" => "audio/mp4",
+        "wav" => "audio/x-wav",
+        "wv" => "audio/x-wavpack",
+        "ape" => "audio/x-ape",
+        _ => "application/octet-stream",
+    };
+
+    (suffix, content_type)
+}
+
+fn song(track: &TrackItem, parent: Option<i64>) -> Value {
+    let (suffix, content_type) = content_type(&track.node_path);
+
+    // Streams are transcoded to mp3 unless another format is requested
+    json!({
+        "id": format!("{}{}", TRACK_PREFIX, track.track_id),
+        "parent": parent.map(|p| format!("{}{}", DIRECTORY_PREFIX, p)),
+        "isDir": false,
+        "title": track.title,
+        "album": track.album_name,
+        "artist": track.artist_name,
+        "track": track.number,
+        "coverArt": track.image_id.map(|i| format!("{}{}", IMAGE_PREFIX, i)),
+        "size": track.file_size,
+        "contentType": content_type,
+        "suffix": suffix,
+        "transcodedContentType": "audio/mpeg",
+        "transcodedSuffix": "mp3",
+        "duration": track.length.round() as i64,
+        "bitRate": track.bitrate.map(|b| b / 1000),
+        "path": track.node_path,
+        "playCount": track.play_count,
+        "genre": track.genre,
+        "year": track.year,
+        "albumId": format!("{}{}", ALBUM_PREFIX, track.album_id),
+        "artistId": format!("{}{}", ARTIST_PREFIX, track.artist_id),
+        "type": "music"
+    })
+}
+
+fn album(album: &AlbumItem) -> Value {
+    json!({
+        "id": format!("{}{}", ALBUM_PREFIX, album.album_id),
+        "name": album.name,
+        "artist": album.artist_name,
+        "artistId": album.artist_id.map(|a| format!("{}{}", ARTIST_PREFIX, a)),
+        "coverArt": album.image_id.map(|i| format!("{}{}", IMAGE_PREFIX, i)),
+        "songCount": album.track_count
+    })
+}
+
+fn artist(artist: &ArtistItem) -> Value {
+    json!({
+        "id": format!("{}{}", ARTIST_PREFIX, artist.artist_id),
+        "name": artist.name,
+        "albumCount": artist.album_count
+    })
+}
+
+fn directory(node: &NodeItem) -> Value {
+    json!({
+        "id": format!("{}{}", DIRECTORY_PREFIX, node.node_id),
+        "parent": node.parent_id.map(|p| format!("{}{}", DIRECTORY_PREFIX, p)),
+        "isDir": true,
+        "title": node.name
+    })
+}
+
+// Groups named entries by their first letter like Subsonic does
+fn index_entries(entries: Vec<(String, Value)>, entry_name: &str) -> Vec<Value> {
+    let mut groups: BTreeMap<String, Vec<(String, Value)>> = BTreeMap::new();
+
+    for (name, value) in entries {
+        let letter = match name.chars().next() {
+            Some(c) if c.is_alphabetic() => c.to_uppercase().to_string(),
+            _ => "#".to_string(),
+        };
+
+        groups.entry(letter).or_default().push((name, value));
+    }
+
+    groups
+        .into_iter()
+        .map(|(letter, mut entries)| {
+            entries.sort_by_key(|(name, _)| name.to_lowercase());
+
+            let mut index = Map::new();
+            index.insert("name".to_string(), json!(letter));
+            index.insert(
+                entry_name.to_string(),
+                Value::Array(entries.into_iter().map(|(_, v)| v).collect()),
+            );
+
+            Value::Object(index)
+        })
+        .collect()
+}
+
+fn new_query(params: &[(&str, String)]) -> HttpQuery {
+    let mut query = HttpQuery::from("");
+
+    for (key, value) in params {
+        query.set(key, value);
+    }
+
+    query
+}
+
+fn get_music_folders(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let index = r.musicd.index();
+
+    let folders: Vec<Value> = index
+        .roots()
+        .iter()
+        .enumerate()
+        .filter(|(_, root)| r.roots.contains(&root.name))
+        .map(|(i, root)| json!({ "id": i + 1, "name": root.name }))
+        .collect();
+
+    Ok(ok(r, json!({ "musicFolders": { "musicFolder": folders } })))
+}
+
+fn get_indexes(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let index = r.musicd.index();
+
+    // Music folder ids are 1-based root positions
+    let roots: Vec<String> = match r.query.get_i64("musicFolderId") {
+        Some(id) => match index.roots().get((id - 1) as usize) {
+            Some(root) if id > 0 && r.roots.contains(&root.name) => vec![root.name.clone()],
+            _ => {
+                return Ok(not_found(r));
+            }
+        },
+        None => r.roots.clone(),
+    };
+
+    let (_, root_nodes) = query::query_nodes(
+        &index,
+        &new_query(&[("parent_id", "null".to_string())]),
+        &roots,
+    )?;
+
+    let mut entries = Vec::new();
+
+    for root_node in root_nodes {
+        let (_, nodes) = query::query_nodes(
+            &index,
+            &new_query(&[("parent_id", root_node.node_id.to_string())]),
+            &roots,
+        )?;
+
+        for node in nodes {
+            if node.node_type == NodeType::Directory {
+                entries.push((
+                    node.name.clone(),
+                    json!({
+                        "id": format!("{}{}", DIRECTORY_PREFIX, node.node_id),
+                        "name": node.name
+                    }),
+                ));
+            }
+        }
+    }
+
+    Ok(ok(
+        r,
+        json!({
+            "indexes": {
+                "lastModified": auth::now() * 1000,
+                "ignoredArticles": "",
+                "index": index_entries(entries, "artist")
+            }
+        }),
+    ))
+}
+
+fn get_music_directory(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let node_id = match parse_id(r, DIRECTORY_PREFIX) {
+        Some(id) => id,
+        None => {
+            return Ok(missing_parameter(r));
+        }
+    };
+
+    let index = r.musicd.index();
+
+    let node = match index.node(node_id)? {
+        Some(n) if node_allowed(r, &index, node_id)? => n,
+        _ => {
+            return Ok(not_found(r));
+        }
+    };
+
+    let (_, nodes) = query::query_nodes(
+        &index,
+        &new_query(&[("parent_id", node_id.to_string())]),
+        &r.roots,
+    )?;
+
+    let mut directories = Vec::new();
+    let mut songs = Vec::new();
+
+    for child in nodes {
+        match child.node_type {
+            NodeType::Directory => directories.push((child.name.to_lowercase(), directory(&child))),
+            NodeType::File => {
+                let (_, tracks) = query::query_tracks(
+                    &index,
+                    &new_query(&[("node_id", child.node_id.to_string())]),
+                    r.user().user_id,
+                    &r.roots,
+                )?;
+
+                for track in tracks {
+                    songs.push((child.name.to_lowercase(), song(&track, Some(node_id))));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    directories.sort_by(|a, b| a.0.cmp(&b.0));
+    songs.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let children: Vec<Value> = directories
+        .into_iter()
+        .chain(songs)
+        .map(|(_, v)| v)
+        .collect();
+
+    Ok(ok(
+        r,
+        json!({
+            "directory": {
+                "id": format!("{}{}", DIRECTORY_PREFIX, node_id),
+                "parent": node.parent_id.map(|p| format!("{}{}", DIRECTORY_PREFIX, p)),
+                "name": node.name.to_string_lossy(),
+                "child": children
+            }
+        }),
+    ))
+}
+
+fn get_artists(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let (_, artists) = query::query_artists(
+        &r.musicd.index(),
+        &new_query(&[]),
+        r.user().user_id,
+        &r.roots,
+    )?;
+
+    // Only album artists have albums to browse
+    let entries = artists
+        .iter()
+        .filter(|a| a.album_count > 0)
+        .map(|a| (a.name.clone(), artist(a)))
+        .collect();
+
+    Ok(ok(
+        r,
+        json!({
+            "artists": {
+                "ignoredArticles": "",
+                "index": index_entries(entries, "artist")
+            }
+        }),
+    ))
+}
+
+fn get_artist(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let artist_id = match parse_id(r, ARTIST_PREFIX) {
+        Some(id) => id,
+        None => {
+            return Ok(missing_parameter(r));
+        }
+    };
+
+    let index = r.musicd.index();
+
+    let (_, artists) = query::query_artists(
+        &index,
+        &new_query(&[("artist_id", artist_id.to_string())]),
+        r.user().user_id,
+        &r.roots,
+    )?;
+
+    let artist_item = match artists.first() {
+        Some(a) => a,
+        None => {
+            return Ok(not_found(r));
+        }
+    };
+
+    let (_, albums) = query::query_albums(
+        &index,
+        &new_query(&[
+            ("artist_id", artist_id.to_string()),
+            ("sort", "name".to_string()),
+        ]),
+        r.user().user_id,
+        &r.roots,
+    )?;
+
+    let mut result = artist(artist_item);
+    result["album"] = Value::Array(albums.iter().map(album).collect());
+
+    Ok(ok(r, json!({ "artist": result })))
+}
+
+fn get_album_list2(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let size = r.query.get_i64("size").unwrap_or(10).clamp(0, 500);
+    let offset = r.query.get_i64("offset").unwrap_or(0).max(0);
+
+    let mut params = vec![("limit", size.to_string()), ("offset", offset.to_string())];
+
+    let sort = match r.query.get_str("type") {
+        Some("random") => "random",
+        Some("newest") => "newest",
+        Some("alphabeticalByName") => "name",
+        Some("alphabeticalByArtist") => "artist",
+        Some("byYear") => {
+            let (from_year, to_year) =
+                match (r.query.get_i64("fromYear"), r.query.get_i64("toYear")) {
+                    (Some(from_year), Some(to_year)) => (from_year, to_year),
+                    _ => {
+                        return Ok(missing_parameter(r));
+                    }
+                };
+
+            // A reversed range lists the newest albums first
+            params.push(("from_year", from_year.min(to_year).to_string()));
+            params.push(("to_year", from_year.max(to_year).to_string()));
+
+            if from_year <= to_year {
+                "year"
+            } else {
+                "year_desc"
+            }
+        }
+        Some("byGenre") => {
+            let genre = match r.query.get_str("genre") {
+                Some(g) => g,
+                None => {
+                    return Ok(missing_parameter(r));
+                }
+            };
+
+            params.push(("genre", genre.to_string()));
+
+            "name"
+        }
+        Some("starred") => {
+            params.push(("favorite", "1".to_string()));
+
+            "name"
+        }
+        Some(_) => {
+            // Play statistics and ratings aren't supported, favorites are
+            // starred
+            return Ok(ok(r, json!({ "albumList2": { "album": [] } })));
+        }
+        None => {
+            return Ok(missing_parameter(r));
+        }
+    };
+
+    params.push(("sort", sort.to_string()));
+
+    let (_, albums) = query::query_albums(
+        &r.musicd.index(),
+        &new_query(&params),
+        r.user().user_id,
+        &r.roots,
+    )?;
+
+    Ok(ok(
+        r,
+        json!({
+            "albumList2": {
+                "album": albums.iter().map(album).collect::<Vec<Value>>()
+            }
+        }),
+    ))
+}
+
+fn get_album(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let album_id = match parse_id(r, ALBUM_PREFIX) {
+        Some(id) => id,
+        None => {
+            return Ok(missing_parameter(r));
+        }
+    };
+
+    let index = r.musicd.index();
+
+    let (_, albums) = query::query_albums(
+        &index,
+        &new_query(&[("album_id", album_id.to_string())]),
+        r.user().user_id,
+        &r.roots,
+    )?;
+
+    let album_item = match albums.first() {
+        Some(a) => a,
+        None => {
+            return Ok(not_found(r));
+        }
+    };
+
+    let (_, tracks) = query::query_tracks(
+        &index,
+        &new_query(&[("album_id", album_id.to_string())]),
+        r.user().user_id,
+        &r.roots,
+    )?;
+
+    let mut result = album(album_item);
+    result["songCount"] = json!(tracks.len());
+    result["duration"] = json!(tracks.iter().map(|t| t.length).sum::<f64>().round() as i64);
+    result["song"] = Value::Array(tracks.iter().map(|t| song(t, None)).collect());
+
+    Ok(ok(r, json!({ "album": result })))
+}
+
+fn search3(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    // Clients search with an empty query to list everything
+    let search = r
+        .query
+        .get_str("query")
+        .unwrap_or_default()
+        .trim_matches('"')
+        .to_string();
+
+    let range = |count: &str, offset: &str| {
+        vec![
+            (
+                "limit",
+                r.query.get_i64(count).unwrap_or(20).max(0).to_string(),
+            ),
+            (
+                "offset",
+                r.query.get_i64(offset).unwrap_or(0).max(0).to_string(),
+            ),
+        ]
+    };
+
+    let index = r.musicd.index();
+
+    let mut artist_query = range("artistCount", "artistOffset");
+    let mut album_query = range("albumCount", "albumOffset");
+    let mut song_query = range("songCount", "songOffset");
+
+    if !search.is_empty() {
+        artist_query.push(("search", format!("%{}%", search)));
+        album_query.push(("search", search.clone()));
+        song_query.push(("search", search.clone()));
+    }
+
+    let (_, artists) = query::query_artists(
+        &index,
+        &new_query(&artist_query),
+        r.user().user_id,
+        &r.roots,
+    )?;
+    let (_, albums) =
+        query::query_albums(&index, &new_query(&album_query), r.user().user_id, &r.roots)?;
+    let (_, tracks) =
+        query::query_tracks(&index, &new_query(&song_query), r.user().user_id, &r.roots)?;
+
+    Ok(ok(
+        r,
+        json!({
+            "searchResult3": {
+                "artist": artists.iter().map(artist).collect::<Vec<Value>>(),
+                "album": albums.iter().map(album).collect::<Vec<Value>>(),
+                "song": tracks.iter().map(|t| song(t, None)).collect::<Vec<Value>>()
+            }
+        }),
+    ))
+}
+
+fn stream(r: &mut ApiRequest) -> Result<Response<Body>, Error> {
+    let track_id = match parse_id(r, TRACK_PREFIX) {
+        Some(id) => id,
+        None => {
+            return Ok(missing_parameter(r));
+        }
+    };
+
+    let codec = match r.query.get_str("format") {
+        Some("opus") => "opus",
+        Some("ogg") => "ogg",
+        _ => "mp3",
+    };
+
+    let start = r.query.get_i64("timeOffset").unwrap_or(0);
+
+    r.query = new_query(&[
+        ("track_id", track_id.to_string()),
+        ("codec", codec.to_string()),
+        ("start", start.to_string()),
+    ]);
+
+    api_audio_stream(r)
+}
+
+fn get_cover_art(r: &mut ApiRequest) -> Result<Response<Body>, Error> {
+    let image_id = match parse_id(r, IMAGE_PREFIX) {
+        Some(id) => id,
+        None => match parse_id(r, ALBUM_PREFIX) {
+            Some(album_id) => match r.musicd.index().album(album_id)? {
+                Some(album) => match album.image_id {
+                    Some(id) => id,
+                    None => {
+                        return Ok(not_found(r));
+                    }
+                },
+                None => {
+                    return Ok(not_found(r));
+                }
+            },
+            None => {
+                return Ok(missing_parameter(r));
+            }
+        },
+    };
+
+    let size = r.query.get_i64("size").unwrap_or(0);
+
+    r.query = new_query(&[
+        ("image_id", image_id.to_string()),
+        ("size", size.to_string()),
+    ]);
+
+    api_image_file(r)
+}
+
+fn get_playlists(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let mut lists = r.musicd.store().lists(r.user().user_id)?;
+
+    for list in lists.iter_mut().filter(|l| l.rules.is_some()) {
+        list.track_count =
+            query::count_list_tracks(&r.musicd.index(), list.list_id, r.user().user_id, &r.roots)?;
+    }
+
+    let playlists: Vec<Value> = lists
+        .iter()
+        .map(|list| {
+            json!({
+                "id": format!("{}{}", LIST_PREFIX, list.list_id),
+                "name": list.name,
+                "owner": r.user().name,
+                "public": false,
+                "songCount": list.track_count
+            })
+        })
+        .collect();
+
+    Ok(ok(r, json!({ "playlists": { "playlist": playlists } })))
+}
+
+fn get_playlist(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let list_id = match parse_id(r, LIST_PREFIX) {
+        Some(id) => id,
+        None => {
+            return Ok(missing_parameter(r));
+        }
+    };
+
+    let list = match r.musicd.store().list(r.user().user_id, list_id)? {
+        Some(l) => l,
+        None => {
+            return Ok(not_found(r));
+        }
+    };
+
+    let (_, tracks) = query::query_tracks(
+        &r.musicd.index(),
+        &new_query(&[("list_id", list_id.to_string())]),
+        r.user().user_id,
+        &r.roots,
+    )?;
+
+    Ok(ok(
+        r,
+        json!({
+            "playlist": {
+                "id": format!("{}{}", LIST_PREFIX, list.list_id),
+                "name": list.name,
+                "owner": r.user().name,
+                "public": false,
+                "songCount": tracks.len(),
+                "duration": tracks.iter().map(|t| t.length).sum::<f64>().round() as i64,
+                "entry": tracks.iter().map(|t| song(t, None)).collect::<Vec<Value>>()
+            }
+        }),
+    ))
+}
+
+fn scrobble(r: &ApiRequest) -> Result<Response<Body>, Error> {
+    let track_id = match parse_id(r, TRACK_PREFIX) {
+        Some(id) => id,
+        None => {
+            return Ok(missing_parameter(r));
+        }
+    };
+
+    let index = r.musicd.index();
+
+    let track = match index.track(track_id)? {
+        Some(t) if node_allowed(r, &index, t.node_id)? => t,
+        _ => {
+            return Ok(not_found(r));
+        }
+    };
+
+    // "Now playing" notifications aren't recorded, only passed on to the
+    // scrobbler
+    if r.query.get_str("submission") == Some("false") {
+        r.musicd.events.emit(Event::user(
+            r.user().user_id,
+            "now_playing",
+            json!({ "track_id": track.track_id }),
+        ));
+
+        return Ok(ok(r, json!({})));
+    }
+
+    // Time is given in milliseconds
+    let time = match r.query.get_i64("time") {
+        Some(time) => time / 1000,
+        None => auth::now(),
+    };
+
+    // Subsonic clients always identify themselves
+    r.musicd.store().register_track_play(
+        r.user().user_id,
+        &track,
+        time,
+        None,
+        r.query.get_str("c"),
+    )?;
+
+    r.musicd.events.emit(Event::user(
+        r.user().user_id,
+        "track_played",
+        json!({ "track_id": track.track_id, "time": time }),
+    ));
+
+    Ok(ok(r, json!({})))
+}
+
+#[test]
+fn test_write_xml() {
+    let value = json!({
+        "status": "ok",
+        "musicFolders": {
+            "musicFolder": [
+                { "id": 1, "name": "A & B" },
+                { "id": 2, "name": "C" }
+            ]
+        }
+    });
+
+    let mut xml = String::new();
+    write_xml(
+        &mut xml,
+        "subsonic-response",
+        value.as_object().unwrap(),
+        "",
+    );
+
+    assert_eq!(
+        xml,
+        "<subsonic-response status=\"ok\"><musicFolders>\
+         <musicFolder id=\"1\" name=\"A &amp; B\"/>\
+         <musicFolder id=\"2\" name=\"C\"/>\
+         </musicFolders></subsonic-response>"
+    );
+}
+
+#[test]
+fn test_valid_callback() {
+    assert!(valid_callback("cb"));
+    assert!(valid_callback("$_.jsonp.cb1"));
+    assert!(!valid_callback(""));
+    assert!(!valid_callback("1cb"));
+    assert!(!valid_callback("alert(1);cb"));
+    assert!(!valid_callback("cb</script>"));
+}
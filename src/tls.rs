@@ -0,0 +1,105 @@
+use std::error::Error as StdError;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_tls::TlsAcceptor;
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(std::io::Error),
+    TlsError(native_tls::Error),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::IoError(err)
+    }
+}
+
+impl From<native_tls::Error> for Error {
+    fn from(err: native_tls::Error) -> Error {
+        Error::TlsError(err)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Error::IoError(ref e) => write!(f, "{}", e),
+            Error::TlsError(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl StdError for Error {}
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    // PEM certificate chain and PKCS #8 private key
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+#[derive(Clone)]
+pub struct TlsReloader {
+    config: TlsConfig,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+}
+
+fn load_acceptor(config: &TlsConfig) -> Result<TlsAcceptor, Error> {
+    let cert = std::fs::read(&config.cert_path)?;
+    let key = std::fs::read(&config.key_path)?;
+
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key)?;
+
+    Ok(native_tls::TlsAcceptor::new(identity)?.into())
+}
+
+impl TlsReloader {
+    pub fn create(config: TlsConfig) -> Result<TlsReloader, Error> {
+        info!("using certificate '{}'", config.cert_path.to_string_lossy());
+
+        let acceptor = load_acceptor(&config)?;
+
+        Ok(TlsReloader {
+            config,
+            acceptor: Arc::new(RwLock::new(acceptor)),
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    // Reloads the certificate on SIGHUP, for example after renewal. Failed
+    // reloads keep the previous certificate.
+    pub async fn reload_on_hangup(self) {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("can't listen to SIGHUP: {}", e);
+                return;
+            }
+        };
+
+        while hangup.recv().await.is_some() {
+            match load_acceptor(&self.config) {
+                Ok(acceptor) => {
+                    *self.acceptor.write().unwrap() = acceptor;
+                    info!(
+                        "reloaded certificate '{}'",
+                        self.config.cert_path.to_string_lossy()
+                    );
+                }
+                Err(e) => {
+                    error!(
+                        "can't reload certificate '{}': {}",
+                        self.config.cert_path.to_string_lossy(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
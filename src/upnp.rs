@@ -0,0 +1,1265 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::time::Duration;
+
+use hyper::{Body, Method, Response, StatusCode};
+use tokio::net::UdpSocket;
+
+use crate::auth;
+use crate::http_api::{self, ApiRequest, Error};
+use crate::http_util::{escape_xml, read_body, HttpQuery};
+use crate::index::NodeType;
+use crate::query::{self, AlbumItem, ArtistItem, NodeItem, TrackItem};
+use crate::store::User;
+use crate::MUSICD_VERSION;
+
+const SSDP_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1900;
+
+// Announcements are repeated at half of their lifetime
+const MAX_AGE: u64 = 1800;
+
+const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+const CONTENT_DIRECTORY: &str = "urn:schemas-upnp-org:service:ContentDirectory:1";
+const CONNECTION_MANAGER: &str = "urn:schemas-upnp-org:service:ConnectionManager:1";
+
+// Errors of the UPnP device architecture and ContentDirectory
+const ERROR_INVALID_ACTION: i64 = 401;
+const ERROR_INVALID_ARGS: i64 = 402;
+const ERROR_NO_SUCH_OBJECT: i64 = 701;
+
+const SOURCE_PROTOCOL_INFO: &str = "http-get:*:audio/mpeg:*,http-get:*:image/jpeg:*";
+
+// SOAP requests are small, the largest being searches
+const MAX_ACTION_LENGTH: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct UpnpConfig {
+    pub uuid: String,
+    pub name: String,
+    // API token used for browsing and for signing media URLs, required if
+    // authentication is enabled as renderers can't log in
+    pub token: Option<String>,
+}
+
+// Device UUIDs must stay the same between runs, so one is derived from the
+// data directory
+pub fn device_uuid(directory: &Path) -> String {
+    let hash = md5::compute(directory.to_string_lossy().as_bytes());
+    let hex = format!("{:x}", hash);
+
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+fn server_string() -> String {
+    format!("Linux/1.0 UPnP/1.0 musicd2/{}", MUSICD_VERSION)
+}
+
+// Notification types with their unique service names
+fn notification_types(uuid: &str) -> Vec<(String, String)> {
+    vec![
+        (
+            "upnp:rootdevice".to_string(),
+            format!("uuid:{}::upnp:rootdevice", uuid),
+        ),
+        (format!("uuid:{}", uuid), format!("uuid:{}", uuid)),
+        (
+            DEVICE_TYPE.to_string(),
+            format!("uuid:{}::{}", uuid, DEVICE_TYPE),
+        ),
+        (
+            CONTENT_DIRECTORY.to_string(),
+            format!("uuid:{}::{}", uuid, CONTENT_DIRECTORY),
+        ),
+        (
+            CONNECTION_MANAGER.to_string(),
+            format!("uuid:{}::{}", uuid, CONNECTION_MANAGER),
+        ),
+    ]
+}
+
+// Address of the interface used to reach `target`
+fn local_ip(target: SocketAddr) -> Option<IpAddr> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect(target).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+fn location(ip: IpAddr, http_port: u16) -> String {
+    format!("http://{}:{}/upnp/description.xml", ip, http_port)
+}
+
+// Other UPnP servers on the host may listen to the SSDP port as well
+fn bind_ssdp_socket() -> io::Result<std::net::UdpSocket> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+
+    let one: libc::c_int = 1;
+    let addr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: SSDP_PORT.to_be(),
+        sin_addr: libc::in_addr { s_addr: 0 },
+        sin_zero: [0; 8],
+    };
+
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            &one as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let result = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const _ as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    socket.join_multicast_v4(&SSDP_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_nonblocking(true)?;
+
+    Ok(socket)
+}
+
+fn search_response(st: &str, usn: &str, location: &str) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\n\
+         CACHE-CONTROL: max-age={}\r\n\
+         EXT:\r\n\
+         LOCATION: {}\r\n\
+         SERVER: {}\r\n\
+         ST: {}\r\n\
+         USN: {}\r\n\
+         \r\n",
+        MAX_AGE,
+        location,
+        server_string(),
+        st,
+        usn
+    )
+}
+
+fn notify_message(nt: &str, usn: &str, location: &str) -> String {
+    format!(
+        "NOTIFY * HTTP/1.1\r\n\
+         HOST: {}:{}\r\n\
+         CACHE-CONTROL: max-age={}\r\n\
+         LOCATION: {}\r\n\
+         NT: {}\r\n\
+         NTS: ssdp:alive\r\n\
+         SERVER: {}\r\n\
+         USN: {}\r\n\
+         \r\n",
+        SSDP_ADDR,
+        SSDP_PORT,
+        MAX_AGE,
+        location,
+        nt,
+        server_string(),
+        usn
+    )
+}
+
+// Returns the search target of an M-SEARCH discovery request
+fn parse_search(message: &str) -> Option<&str> {
+    let mut lines = message.lines();
+
+    if !lines.next()?.starts_with("M-SEARCH ") {
+        return None;
+    }
+
+    let mut discover = false;
+    let mut st = None;
+
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next()?.trim();
+        let value = parts.next().unwrap_or_default().trim();
+
+        if name.eq_ignore_ascii_case("MAN") {
+            discover = value == "\"ssdp:discover\"";
+        } else if name.eq_ignore_ascii_case("ST") {
+            st = Some(value);
+        }
+    }
+
+    if discover {
+        st
+    } else {
+        None
+    }
+}
+
+// Answers discovery requests and announces the server periodically
+pub async fn run_ssdp(config: UpnpConfig, http_port: u16) {
+    let socket = match bind_ssdp_socket().and_then(UdpSocket::from_std) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("can't bind SSDP socket: {}", e);
+            return;
+        }
+    };
+
+    info!("announcing UPnP media server '{}'", config.name);
+
+    tokio::spawn(announce(config.clone(), http_port));
+
+    let (mut receiver, mut sender) = socket.split();
+    let mut buf = [0u8; 2048];
+
+    loop {
+        let (len, addr) = match receiver.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                error!("receiving SSDP message failed: {}", e);
+                continue;
+            }
+        };
+
+        let message = String::from_utf8_lossy(&buf[..len]);
+
+        let st = match parse_search(&message) {
+            Some(st) => st,
+            None => continue,
+        };
+
+        let ip = match local_ip(addr) {
+            Some(ip) => ip,
+            None => continue,
+        };
+
+        let location = location(ip, http_port);
+
+        for (nt, usn) in notification_types(&config.uuid) {
+            if st == "ssdp:all" || st == nt {
+                let response = search_response(&nt, &usn, &location);
+
+                if let Err(e) = sender.send_to(response.as_bytes(), &addr).await {
+                    debug!("sending SSDP response to {} failed: {}", addr, e);
+                }
+            }
+        }
+    }
+}
+
+async fn announce(config: UpnpConfig, http_port: u16) {
+    let target = SocketAddr::new(IpAddr::V4(SSDP_ADDR), SSDP_PORT);
+
+    let mut socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("can't bind SSDP announcement socket: {}", e);
+            return;
+        }
+    };
+
+    loop {
+        if let Some(ip) = local_ip(target) {
+            let location = location(ip, http_port);
+
+            for (nt, usn) in notification_types(&config.uuid) {
+                let message = notify_message(&nt, &usn, &location);
+
+                if let Err(e) = socket.send_to(message.as_bytes(), &target).await {
+                    debug!("sending SSDP announcement failed: {}", e);
+                }
+            }
+        }
+
+        tokio::time::delay_for(Duration::from_secs(MAX_AGE / 2)).await;
+    }
+}
+
+// Serves the descriptions and control requests under /upnp/
+pub(crate) async fn process_request(r: &mut ApiRequest) -> Result<Response<Body>, Error> {
+    let config = match &r.musicd.upnp {
+        Some(c) => c.clone(),
+        None => return Ok(status_response(StatusCode::NOT_FOUND)),
+    };
+
+    let path = r.request.uri().path().to_string();
+    let method = r.request.method().as_str().to_string();
+
+    match (method.as_str(), path.as_str()) {
+        ("GET", "/upnp/description.xml") => Ok(xml_response(&device_description(&config))),
+        ("GET", "/upnp/content_directory.xml") => Ok(xml_response(CONTENT_DIRECTORY_SCPD)),
+        ("GET", "/upnp/connection_manager.xml") => Ok(xml_response(CONNECTION_MANAGER_SCPD)),
+        ("POST", "/upnp/control/content_directory") => {
+            let (user, api_token_id) = match authenticate(r, &config)? {
+                Some(u) => u,
+                None => return Ok(status_response(StatusCode::UNAUTHORIZED)),
+            };
+
+            r.roots = r.musicd.store().allowed_roots(&user, api_token_id)?;
+            r.user = Some(user);
+
+            match read_action(r).await? {
+                Some((action, body)) => content_directory(r, &config, &action, &body),
+                None => Ok(status_response(StatusCode::PAYLOAD_TOO_LARGE)),
+            }
+        }
+        ("POST", "/upnp/control/connection_manager") => match read_action(r).await? {
+            Some((action, _)) => connection_manager(&action),
+            None => Ok(status_response(StatusCode::PAYLOAD_TOO_LARGE)),
+        },
+        // Nothing is evented, but subscriptions are accepted so that clients
+        // don't give up
+        ("SUBSCRIBE", "/upnp/event/content_directory")
+        | ("SUBSCRIBE", "/upnp/event/connection_manager") => Ok(Response::builder()
+            .header("SID", format!("uuid:{}", config.uuid))
+            .header("TIMEOUT", format!("Second-{}", MAX_AGE))
+            .body(Body::empty())
+            .unwrap()),
+        ("UNSUBSCRIBE", "/upnp/event/content_directory")
+        | ("UNSUBSCRIBE", "/upnp/event/connection_manager") => Ok(status_response(StatusCode::OK)),
+        _ => Ok(status_response(StatusCode::NOT_FOUND)),
+    }
+}
+
+// Browsing is done as the owner of the configured API token and its roots
+fn authenticate(r: &ApiRequest, config: &UpnpConfig) -> Result<Option<(User, Option<i64>)>, Error> {
+    let store = r.musicd.store();
+
+    if let Some(token) = &config.token {
+        return Ok(store
+            .use_api_token(&auth::hash_token(token), auth::now())?
+            .map(|(u, api_token_id)| (u, Some(api_token_id))));
+    }
+
+    if !store.auth_required()? {
+        return Ok(store.default_user()?.map(|u| (u, None)));
+    }
+
+    Ok(None)
+}
+
+// Browsing requires no authentication, so media URLs don't carry the token
+// but a signature of the item made with it. The signature only grants access
+// to the item.
+fn media_signature(token: &str, item: &str) -> String {
+    let key = blake2b_simd::blake2b(token.as_bytes());
+
+    blake2b_simd::Params::new()
+        .hash_length(32)
+        .key(key.as_bytes())
+        .hash(item.as_bytes())
+        .to_hex()
+        .to_string()
+}
+
+fn media_item(r: &ApiRequest) -> Option<String> {
+    match (r.request.method(), r.request.uri().path()) {
+        (&Method::GET, "/api/audio_stream") => r
+            .query
+            .get_i64("track_id")
+            .map(|id| format!("track_id={}", id)),
+        (&Method::GET, "/api/image_file") => r
+            .query
+            .get_i64("image_id")
+            .map(|id| format!("image_id={}", id)),
+        _ => None,
+    }
+}
+
+// Authenticates a media request by the signature given in `upnp_sig`
+pub(crate) fn authenticate_media(r: &ApiRequest) -> Result<Option<(User, Option<i64>)>, Error> {
+    let config = match &r.musicd.upnp {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+
+    let (token, item) = match (&config.token, media_item(r)) {
+        (Some(token), Some(item)) => (token, item),
+        _ => return Ok(None),
+    };
+
+    let signature = media_signature(token, &item);
+    let given = r.query.get_str("upnp_sig").unwrap_or_default();
+
+    // Compared in constant time
+    let valid = signature.len() == given.len()
+        && signature
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0;
+
+    if !valid {
+        return Ok(None);
+    }
+
+    authenticate(r, config)
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn xml_response(xml: &str) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .body(Body::from(xml.to_string()))
+        .unwrap()
+}
+
+fn device_description(config: &UpnpConfig) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0" xmlns:dlna="urn:schemas-dlna-org:device-1-0">
+  <specVersion><major>1</major><minor>0</minor></specVersion>
+  <device>
+    <deviceType>{}</deviceType>
+    <dlna:X_DLNADOC>DMS-1.50</dlna:X_DLNADOC>
+    <friendlyName>{}</friendlyName>
+    <manufacturer>musicd2</manufacturer>
+    <modelName>musicd2</modelName>
+    <modelNumber>{}</modelNumber>
+    <UDN>uuid:{}</UDN>
+    <serviceList>
+      <service>
+        <serviceType>{}</serviceType>
+        <serviceId>urn:upnp-org:serviceId:ContentDirectory</serviceId>
+        <SCPDURL>/upnp/content_directory.xml</SCPDURL>
+        <controlURL>/upnp/control/content_directory</controlURL>
+        <eventSubURL>/upnp/event/content_directory</eventSubURL>
+      </service>
+      <service>
+        <serviceType>{}</serviceType>
+        <serviceId>urn:upnp-org:serviceId:ConnectionManager</serviceId>
+        <SCPDURL>/upnp/connection_manager.xml</SCPDURL>
+        <controlURL>/upnp/control/connection_manager</controlURL>
+        <eventSubURL>/upnp/event/connection_manager</eventSubURL>
+      </service>
+    </serviceList>
+  </device>
+</root>
+"#,
+        DEVICE_TYPE,
+        escape_xml(&config.name),
+        MUSICD_VERSION,
+        config.uuid,
+        CONTENT_DIRECTORY,
+        CONNECTION_MANAGER
+    )
+}
+
+static CONTENT_DIRECTORY_SCPD: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0">
+  <specVersion><major>1</major><minor>0</minor></specVersion>
+  <actionList>
+    <action>
+      <name>GetSearchCapabilities</name>
+      <argumentList>
+        <argument><name>SearchCaps</name><direction>out</direction><relatedStateVariable>SearchCapabilities</relatedStateVariable></argument>
+      </argumentList>
+    </action>
+    <action>
+      <name>GetSortCapabilities</name>
+      <argumentList>
+        <argument><name>SortCaps</name><direction>out</direction><relatedStateVariable>SortCapabilities</relatedStateVariable></argument>
+      </argumentList>
+    </action>
+    <action>
+      <name>GetSystemUpdateID</name>
+      <argumentList>
+        <argument><name>Id</name><direction>out</direction><relatedStateVariable>SystemUpdateID</relatedStateVariable></argument>
+      </argumentList>
+    </action>
+    <action>
+      <name>Browse</name>
+      <argumentList>
+        <argument><name>ObjectID</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_ObjectID</relatedStateVariable></argument>
+        <argument><name>BrowseFlag</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_BrowseFlag</relatedStateVariable></argument>
+        <argument><name>Filter</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_Filter</relatedStateVariable></argument>
+        <argument><name>StartingIndex</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_Index</relatedStateVariable></argument>
+        <argument><name>RequestedCount</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_Count</relatedStateVariable></argument>
+        <argument><name>SortCriteria</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_SortCriteria</relatedStateVariable></argument>
+        <argument><name>Result</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_Result</relatedStateVariable></argument>
+        <argument><name>NumberReturned</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_Count</relatedStateVariable></argument>
+        <argument><name>TotalMatches</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_Count</relatedStateVariable></argument>
+        <argument><name>UpdateID</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_UpdateID</relatedStateVariable></argument>
+      </argumentList>
+    </action>
+  </actionList>
+  <serviceStateTable>
+    <stateVariable sendEvents="no"><name>SearchCapabilities</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>SortCapabilities</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="yes"><name>SystemUpdateID</name><dataType>ui4</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_ObjectID</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_Result</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="no">
+      <name>A_ARG_TYPE_BrowseFlag</name>
+      <dataType>string</dataType>
+      <allowedValueList><allowedValue>BrowseMetadata</allowedValue><allowedValue>BrowseDirectChildren</allowedValue></allowedValueList>
+    </stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_Filter</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_SortCriteria</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_Index</name><dataType>ui4</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_Count</name><dataType>ui4</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_UpdateID</name><dataType>ui4</dataType></stateVariable>
+  </serviceStateTable>
+</scpd>
+"#;
+
+static CONNECTION_MANAGER_SCPD: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0">
+  <specVersion><major>1</major><minor>0</minor></specVersion>
+  <actionList>
+    <action>
+      <name>GetProtocolInfo</name>
+      <argumentList>
+        <argument><name>Source</name><direction>out</direction><relatedStateVariable>SourceProtocolInfo</relatedStateVariable></argument>
+        <argument><name>Sink</name><direction>out</direction><relatedStateVariable>SinkProtocolInfo</relatedStateVariable></argument>
+      </argumentList>
+    </action>
+    <action>
+      <name>GetCurrentConnectionIDs</name>
+      <argumentList>
+        <argument><name>ConnectionIDs</name><direction>out</direction><relatedStateVariable>CurrentConnectionIDs</relatedStateVariable></argument>
+      </argumentList>
+    </action>
+    <action>
+      <name>GetCurrentConnectionInfo</name>
+      <argumentList>
+        <argument><name>ConnectionID</name><direction>in</direction><relatedStateVariable>A_ARG_TYPE_ConnectionID</relatedStateVariable></argument>
+        <argument><name>RcsID</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_RcsID</relatedStateVariable></argument>
+        <argument><name>AVTransportID</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_AVTransportID</relatedStateVariable></argument>
+        <argument><name>ProtocolInfo</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_ProtocolInfo</relatedStateVariable></argument>
+        <argument><name>PeerConnectionManager</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_ConnectionManager</relatedStateVariable></argument>
+        <argument><name>PeerConnectionID</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_ConnectionID</relatedStateVariable></argument>
+        <argument><name>Direction</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_Direction</relatedStateVariable></argument>
+        <argument><name>Status</name><direction>out</direction><relatedStateVariable>A_ARG_TYPE_ConnectionStatus</relatedStateVariable></argument>
+      </argumentList>
+    </action>
+  </actionList>
+  <serviceStateTable>
+    <stateVariable sendEvents="yes"><name>SourceProtocolInfo</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="yes"><name>SinkProtocolInfo</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="yes"><name>CurrentConnectionIDs</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="no">
+      <name>A_ARG_TYPE_ConnectionStatus</name>
+      <dataType>string</dataType>
+      <allowedValueList><allowedValue>OK</allowedValue><allowedValue>ContentFormatMismatch</allowedValue><allowedValue>InsufficientBandwidth</allowedValue><allowedValue>UnreliableChannel</allowedValue><allowedValue>Unknown</allowedValue></allowedValueList>
+    </stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_ConnectionManager</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="no">
+      <name>A_ARG_TYPE_Direction</name>
+      <dataType>string</dataType>
+      <allowedValueList><allowedValue>Input</allowedValue><allowedValue>Output</allowedValue></allowedValueList>
+    </stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_ProtocolInfo</name><dataType>string</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_ConnectionID</name><dataType>i4</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_AVTransportID</name><dataType>i4</dataType></stateVariable>
+    <stateVariable sendEvents="no"><name>A_ARG_TYPE_RcsID</name><dataType>i4</dataType></stateVariable>
+  </serviceStateTable>
+</scpd>
+"#;
+
+// Action name from the SOAPACTION header, "<service type>#<action>", and the
+// request body, None if the body is too large
+async fn read_action(r: &mut ApiRequest) -> Result<Option<(String, String)>, Error> {
+    let action = r
+        .request
+        .headers()
+        .get("SOAPACTION")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default()
+        .trim_matches('"')
+        .rsplit('#')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+
+    let body = match read_body(r.request.body_mut(), MAX_ACTION_LENGTH).await? {
+        Some(b) => b,
+        None => return Ok(None),
+    };
+
+    Ok(Some((action, String::from_utf8_lossy(&body).into_owned())))
+}
+
+fn unescape_xml(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// Value of an argument element in the SOAP body. Arguments aren't namespaced
+// so a full XML parser isn't needed.
+fn soap_argument(body: &str, name: &str) -> Option<String> {
+    let open = format!("<{}", name);
+    let mut start = 0;
+
+    loop {
+        let i = start + body[start..].find(&open)?;
+        let rest = &body[i + open.len()..];
+
+        // Skip elements whose name only starts with `name`
+        match rest.chars().next()? {
+            '>' | ' ' | '\t' | '\r' | '\n' => {}
+            '/' => return Some(String::new()),
+            _ => {
+                start = i + open.len();
+                continue;
+            }
+        }
+
+        let content_start = i + open.len() + rest.find('>')? + 1;
+        if body[..content_start].ends_with("/>") {
+            return Some(String::new());
+        }
+
+        let content_end = content_start + body[content_start..].find(&format!("</{}>", name))?;
+
+        return Some(unescape_xml(&body[content_start..content_end]));
+    }
+}
+
+fn soap_response(service: &str, action: &str, arguments: &[(&str, String)]) -> Response<Body> {
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{}Response xmlns:u=\"{}\">",
+        action, service
+    );
+
+    for (name, value) in arguments {
+        xml.push_str(&format!("<{}>{}</{}>", name, escape_xml(value), name));
+    }
+
+    xml.push_str(&format!("</u:{}Response></s:Body></s:Envelope>\n", action));
+
+    xml_response(&xml)
+}
+
+fn soap_fault(code: i64, description: &str) -> Response<Body> {
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring>\
+         <detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\">\
+         <errorCode>{}</errorCode><errorDescription>{}</errorDescription>\
+         </UPnPError></detail></s:Fault></s:Body></s:Envelope>\n",
+        code,
+        escape_xml(description)
+    );
+
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .body(Body::from(xml))
+        .unwrap()
+}
+
+fn connection_manager(action: &str) -> Result<Response<Body>, Error> {
+    Ok(match action {
+        "GetProtocolInfo" => soap_response(
+            CONNECTION_MANAGER,
+            action,
+            &[
+                ("Source", SOURCE_PROTOCOL_INFO.to_string()),
+                ("Sink", String::new()),
+            ],
+        ),
+        "GetCurrentConnectionIDs" => soap_response(
+            CONNECTION_MANAGER,
+            action,
+            &[("ConnectionIDs", "0".to_string())],
+        ),
+        "GetCurrentConnectionInfo" => soap_response(
+            CONNECTION_MANAGER,
+            action,
+            &[
+                ("RcsID", "-1".to_string()),
+                ("AVTransportID", "-1".to_string()),
+                ("ProtocolInfo", String::new()),
+                ("PeerConnectionManager", String::new()),
+                ("PeerConnectionID", "-1".to_string()),
+                ("Direction", "Output".to_string()),
+                ("Status", "OK".to_string()),
+            ],
+        ),
+        _ => soap_fault(ERROR_INVALID_ACTION, "Invalid Action"),
+    })
+}
+
+// Object ids of the ContentDirectory. "0" is the root container required by
+// the specification.
+#[derive(Debug, PartialEq)]
+enum Object {
+    Root,
+    Folders,
+    Artists,
+    Albums,
+    Node(i64),
+    Artist(i64),
+    Album(i64),
+    Track(i64),
+}
+
+impl Object {
+    fn parse(id: &str) -> Option<Object> {
+        match id {
+            "0" => return Some(Object::Root),
+            "folders" => return Some(Object::Folders),
+            "artists" => return Some(Object::Artists),
+            "albums" => return Some(Object::Albums),
+            _ => {}
+        }
+
+        let mut parts = id.splitn(2, '-');
+        let prefix = parts.next()?;
+        let id: i64 = parts.next()?.parse().ok()?;
+
+        match prefix {
+            "node" => Some(Object::Node(id)),
+            "artist" => Some(Object::Artist(id)),
+            "album" => Some(Object::Album(id)),
+            "track" => Some(Object::Track(id)),
+            _ => None,
+        }
+    }
+}
+
+// Builds DIDL-Lite fragments with absolute, signed media URLs
+struct Didl<'a> {
+    base_url: String,
+    token: Option<&'a str>,
+}
+
+fn format_duration(seconds: f64) -> String {
+    let millis = (seconds * 1000f64).round() as i64;
+
+    format!(
+        "{}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+impl<'a> Didl<'a> {
+    fn url(&self, path: &str, item: &str) -> String {
+        match self.token {
+            Some(token) => format!(
+                "{}{}?{}&upnp_sig={}",
+                self.base_url,
+                path,
+                item,
+                media_signature(token, item)
+            ),
+            None => format!("{}{}?{}", self.base_url, path, item),
+        }
+    }
+
+    fn image_url(&self, image_id: i64) -> String {
+        format!(
+            "{}&size=500",
+            self.url("/api/image_file", &format!("image_id={}", image_id))
+        )
+    }
+
+    fn container(
+        &self,
+        id: &str,
+        parent_id: &str,
+        class: &str,
+        title: &str,
+        child_count: Option<i64>,
+        extra: &str,
+    ) -> String {
+        format!(
+            "<container id=\"{}\" parentID=\"{}\" restricted=\"1\"{}>\
+             <dc:title>{}</dc:title><upnp:class>{}</upnp:class>{}</container>",
+            escape_xml(id),
+            escape_xml(parent_id),
+            match child_count {
+                Some(c) => format!(" childCount=\"{}\"", c),
+                None => String::new(),
+            },
+            escape_xml(title),
+            class,
+            extra
+        )
+    }
+
+    fn node(&self, node: &NodeItem, parent_id: &str) -> String {
+        self.container(
+            &format!("node-{}", node.node_id),
+            parent_id,
+            "object.container.storageFolder",
+            &node.name,
+            None,
+            "",
+        )
+    }
+
+    fn artist(&self, artist: &ArtistItem) -> String {
+        self.container(
+            &format!("artist-{}", artist.artist_id),
+            "artists",
+            "object.container.person.musicArtist",
+            &artist.name,
+            Some(artist.album_count),
+            "",
+        )
+    }
+
+    fn album(&self, album: &AlbumItem, parent_id: &str) -> String {
+        let mut extra = String::new();
+
+        if let Some(artist_name) = &album.artist_name {
+            extra.push_str(&format!(
+                "<upnp:artist>{}</upnp:artist>",
+                escape_xml(artist_name)
+            ));
+        }
+
+        if let Some(image_id) = album.image_id {
+            extra.push_str(&format!(
+                "<upnp:albumArtURI>{}</upnp:albumArtURI>",
+                escape_xml(&self.image_url(image_id))
+            ));
+        }
+
+        self.container(
+            &format!("album-{}", album.album_id),
+            parent_id,
+            "object.container.album.musicAlbum",
+            &album.name,
+            Some(album.track_count),
+            &extra,
+        )
+    }
+
+    // Streams are transcoded to MP3, which all renderers support
+    fn track(&self, track: &TrackItem, parent_id: &str) -> String {
+        let mut item = format!(
+            "<item id=\"track-{}\" parentID=\"{}\" restricted=\"1\">\
+             <dc:title>{}</dc:title>\
+             <upnp:class>object.item.audioItem.musicTrack</upnp:class>\
+             <dc:creator>{}</dc:creator>\
+             <upnp:artist>{}</upnp:artist>\
+             <upnp:album>{}</upnp:album>\
+             <upnp:originalTrackNumber>{}</upnp:originalTrackNumber>",
+            track.track_id,
+            escape_xml(parent_id),
+            escape_xml(&track.title),
+            escape_xml(&track.artist_name),
+            escape_xml(&track.artist_name),
+            escape_xml(&track.album_name),
+            track.number
+        );
+
+        if let Some(image_id) = track.image_id {
+            item.push_str(&format!(
+                "<upnp:albumArtURI>{}</upnp:albumArtURI>",
+                escape_xml(&self.image_url(image_id))
+            ));
+        }
+
+        item.push_str(&format!(
+            "<res protocolInfo=\"http-get:*:audio/mpeg:*\" duration=\"{}\">{}</res></item>",
+            format_duration(track.length),
+            escape_xml(&self.url("/api/audio_stream", &format!("track_id={}", track.track_id)))
+        ));
+
+        item
+    }
+}
+
+fn didl_lite(entries: &[String]) -> String {
+    format!(
+        "<DIDL-Lite xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\" \
+         xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+         xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\">{}</DIDL-Lite>",
+        entries.concat()
+    )
+}
+
+fn new_query(params: &[(&str, String)]) -> HttpQuery {
+    let mut query = HttpQuery::from("");
+
+    for (key, value) in params {
+        query.set(key, value);
+    }
+
+    query
+}
+
+fn content_directory(
+    r: &ApiRequest,
+    config: &UpnpConfig,
+    action: &str,
+    body: &str,
+) -> Result<Response<Body>, Error> {
+    match action {
+        "GetSearchCapabilities" => Ok(soap_response(
+            CONTENT_DIRECTORY,
+            action,
+            &[("SearchCaps", String::new())],
+        )),
+        "GetSortCapabilities" => Ok(soap_response(
+            CONTENT_DIRECTORY,
+            action,
+            &[("SortCaps", String::new())],
+        )),
+        "GetSystemUpdateID" => Ok(soap_response(
+            CONTENT_DIRECTORY,
+            action,
+            &[("Id", "1".to_string())],
+        )),
+        "Browse" => browse(r, config, body),
+        _ => Ok(soap_fault(ERROR_INVALID_ACTION, "Invalid Action")),
+    }
+}
+
+fn browse(r: &ApiRequest, config: &UpnpConfig, body: &str) -> Result<Response<Body>, Error> {
+    let object = match soap_argument(body, "ObjectID").and_then(|id| Object::parse(&id)) {
+        Some(o) => o,
+        None => return Ok(soap_fault(ERROR_NO_SUCH_OBJECT, "No such object")),
+    };
+
+    let start: i64 = soap_argument(body, "StartingIndex")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0);
+
+    // Zero requests all children
+    let count: i64 = match soap_argument(body, "RequestedCount").and_then(|s| s.parse().ok()) {
+        Some(0) | None => -1,
+        Some(c) => c,
+    };
+
+    let host = match r
+        .request
+        .headers()
+        .get("Host")
+        .and_then(|h| h.to_str().ok())
+    {
+        Some(h) => h,
+        None => return Ok(soap_fault(ERROR_INVALID_ARGS, "Missing Host")),
+    };
+
+    let didl = Didl {
+        base_url: format!("http://{}", host),
+        token: config.token.as_deref(),
+    };
+
+    let result = match soap_argument(body, "BrowseFlag").as_deref() {
+        Some("BrowseMetadata") => metadata(r, config, &didl, &object)?.map(|e| (vec![e], 1)),
+        Some("BrowseDirectChildren") => children(r, &didl, &object, start, count)?,
+        _ => return Ok(soap_fault(ERROR_INVALID_ARGS, "Invalid BrowseFlag")),
+    };
+
+    let (entries, total) = match result {
+        Some(r) => r,
+        None => return Ok(soap_fault(ERROR_NO_SUCH_OBJECT, "No such object")),
+    };
+
+    Ok(soap_response(
+        CONTENT_DIRECTORY,
+        "Browse",
+        &[
+            ("Result", didl_lite(&entries)),
+            ("NumberReturned", entries.len().to_string()),
+            ("TotalMatches", total.to_string()),
+            ("UpdateID", "1".to_string()),
+        ],
+    ))
+}
+
+fn metadata(
+    r: &ApiRequest,
+    config: &UpnpConfig,
+    didl: &Didl,
+    object: &Object,
+) -> Result<Option<String>, Error> {
+    let index = r.musicd.index();
+    let user_id = r.user().user_id;
+
+    Ok(match object {
+        Object::Root => {
+            Some(didl.container("0", "-1", "object.container", &config.name, Some(3), ""))
+        }
+        Object::Folders => {
+            Some(didl.container("folders", "0", "object.container", "Folders", None, ""))
+        }
+        Object::Artists => {
+            Some(didl.container("artists", "0", "object.container", "Artists", None, ""))
+        }
+        Object::Albums => {
+            Some(didl.container("albums", "0", "object.container", "Albums", None, ""))
+        }
+        Object::Node(node_id) => match index.node(*node_id)? {
+            Some(node)
+                if node.node_type == NodeType::Directory
+                    && http_api::node_allowed(r, &index, node.node_id)? =>
+            {
+                let parent_id = match node.parent_id {
+                    Some(p) => format!("node-{}", p),
+                    None => "folders".to_string(),
+                };
+
+                Some(didl.container(
+                    &format!("node-{}", node.node_id),
+                    &parent_id,
+                    "object.container.storageFolder",
+                    &node.name.to_string_lossy(),
+                    None,
+                    "",
+                ))
+            }
+            _ => None,
+        },
+        Object::Artist(artist_id) => {
+            let (_, artists) = query::query_artists(
+                &index,
+                &new_query(&[("artist_id", artist_id.to_string())]),
+                user_id,
+                &r.roots,
+            )?;
+
+            artists.first().map(|a| didl.artist(a))
+        }
+        Object::Album(album_id) => {
+            let (_, albums) = query::query_albums(
+                &index,
+                &new_query(&[("album_id", album_id.to_string())]),
+                user_id,
+                &r.roots,
+            )?;
+
+            albums.first().map(|a| didl.album(a, "albums"))
+        }
+        Object::Track(track_id) => {
+            let (_, tracks) = query::query_tracks(
+                &index,
+                &new_query(&[("track_id", track_id.to_string())]),
+                user_id,
+                &r.roots,
+            )?;
+
+            tracks
+                .first()
+                .map(|t| didl.track(t, &format!("album-{}", t.album_id)))
+        }
+    })
+}
+
+// Folders list subdirectories followed by the tracks of the files in them
+fn folder_entries(
+    r: &ApiRequest,
+    didl: &Didl,
+    parent_id: Option<i64>,
+) -> Result<Vec<String>, Error> {
+    let index = r.musicd.index();
+    let user_id = r.user().user_id;
+
+    let (container_id, query_parent_id) = match parent_id {
+        Some(id) => (format!("node-{}", id), id.to_string()),
+        None => ("folders".to_string(), "null".to_string()),
+    };
+
+    let (_, mut nodes) = query::query_nodes(
+        &index,
+        &new_query(&[("parent_id", query_parent_id)]),
+        &r.roots,
+    )?;
+
+    nodes.sort_by_key(|n| n.name.to_lowercase());
+
+    let mut entries = Vec::new();
+
+    for node in nodes.iter() {
+        if node.node_type == NodeType::Directory {
+            entries.push(didl.node(node, &container_id));
+        }
+    }
+
+    for node in nodes.iter() {
+        if node.node_type != NodeType::File {
+            continue;
+        }
+
+        let (_, mut tracks) = query::query_tracks(
+            &index,
+            &new_query(&[("node_id", node.node_id.to_string())]),
+            user_id,
+            &r.roots,
+        )?;
+
+        tracks.sort_by_key(|t| t.number);
+
+        for track in tracks.iter() {
+            entries.push(didl.track(track, &container_id));
+        }
+    }
+
+    Ok(entries)
+}
+
+fn children(
+    r: &ApiRequest,
+    didl: &Didl,
+    object: &Object,
+    start: i64,
+    count: i64,
+) -> Result<Option<(Vec<String>, i64)>, Error> {
+    let index = r.musicd.index();
+    let user_id = r.user().user_id;
+
+    let range = [("offset", start.to_string()), ("limit", count.to_string())];
+
+    // Fixed and folder listings are paged here, the others in the query
+    let page = |entries: Vec<String>| {
+        let total = entries.len() as i64;
+        let entries = entries
+            .into_iter()
+            .skip(start as usize)
+            .take(if count < 0 {
+                usize::MAX
+            } else {
+                count as usize
+            })
+            .collect();
+
+        Some((entries, total))
+    };
+
+    Ok(match object {
+        Object::Root => page(vec![
+            didl.container("folders", "0", "object.container", "Folders", None, ""),
+            didl.container("artists", "0", "object.container", "Artists", None, ""),
+            didl.container("albums", "0", "object.container", "Albums", None, ""),
+        ]),
+        Object::Folders => page(folder_entries(r, didl, None)?),
+        Object::Node(node_id) => match index.node(*node_id)? {
+            Some(node)
+                if node.node_type == NodeType::Directory
+                    && http_api::node_allowed(r, &index, node.node_id)? =>
+            {
+                page(folder_entries(r, didl, Some(*node_id))?)
+            }
+            _ => None,
+        },
+        Object::Artists => {
+            let (total, artists) =
+                query::query_artists(&index, &new_query(&range), user_id, &r.roots)?;
+
+            Some((artists.iter().map(|a| didl.artist(a)).collect(), total))
+        }
+        Object::Artist(artist_id) => {
+            let mut params = range.to_vec();
+            params.push(("artist_id", artist_id.to_string()));
+
+            let (total, albums) =
+                query::query_albums(&index, &new_query(&params), user_id, &r.roots)?;
+            let parent_id = format!("artist-{}", artist_id);
+
+            Some((
+                albums.iter().map(|a| didl.album(a, &parent_id)).collect(),
+                total,
+            ))
+        }
+        Object::Albums => {
+            let (total, albums) =
+                query::query_albums(&index, &new_query(&range), user_id, &r.roots)?;
+
+            Some((
+                albums.iter().map(|a| didl.album(a, "albums")).collect(),
+                total,
+            ))
+        }
+        Object::Album(album_id) => {
+            let mut params = range.to_vec();
+            params.push(("album_id", album_id.to_string()));
+
+            let (total, tracks) =
+                query::query_tracks(&index, &new_query(&params), user_id, &r.roots)?;
+            let parent_id = format!("album-{}", album_id);
+
+            Some((
+                tracks.iter().map(|t| didl.track(t, &parent_id)).collect(),
+                total,
+            ))
+        }
+        Object::Track(_) => Some((Vec::new(), 0)),
+    })
+}
+
+#[test]
+fn test_soap_argument() {
+    let body = "<s:Envelope><s:Body><u:Browse xmlns:u=\"urn:schemas-upnp-org:service:ContentDirectory:1\">\
+                <ObjectID>album-5</ObjectID><ObjectIDs>x</ObjectIDs><Filter/>\
+                <SortCriteria></SortCriteria><Title>a &amp; b</Title>\
+                </u:Browse></s:Body></s:Envelope>";
+
+    assert_eq!(soap_argument(body, "ObjectID").unwrap(), "album-5");
+    assert_eq!(soap_argument(body, "Filter").unwrap(), "");
+    assert_eq!(soap_argument(body, "SortCriteria").unwrap(), "");
+    assert_eq!(soap_argument(body, "Title").unwrap(), "a & b");
+    assert!(soap_argument(body, "StartingIndex").is_none());
+    assert_eq!(Object::parse("album-5"), Some(Object::Album(5)));
+}
+
+#[test]
+fn test_parse_search() {
+    let message = "M-SEARCH * HTTP/1.1\r\n\
+                   HOST: 239.255.255.250:1900\r\n\
+                   MAN: \"ssdp:discover\"\r\n\
+                   MX: 2\r\n\
+                   ST: urn:schemas-upnp-org:device:MediaServer:1\r\n\r\n";
+
+    assert_eq!(parse_search(message), Some(DEVICE_TYPE));
+    assert_eq!(
+        parse_search("NOTIFY * HTTP/1.1\r\nNT: upnp:rootdevice\r\n\r\n"),
+        None
+    );
+}
+
+#[test]
+fn test_media_signature() {
+    let signature = media_signature("token", "track_id=5");
+
+    assert_eq!(signature.len(), 64);
+    assert!(!signature.contains("token"));
+    assert_eq!(signature, media_signature("token", "track_id=5"));
+    assert_ne!(signature, media_signature("token", "track_id=6"));
+    assert_ne!(signature, media_signature("other", "track_id=5"));
+    assert_ne!(signature, media_signature("token", "image_id=5"));
+}
@@ -0,0 +1,330 @@
+#include "musicd.h"
+
+struct Waveform {
+    AVFormatContext *in_ctx;
+    AVStream *in_stream;
+    AVCodec *decoder;
+    AVCodecContext *dec_ctx;
+    AVFilterGraph *filter_graph;
+    AVFilterContext *abuffer_ctx, *aformat_ctx, *abuffersink_ctx;
+    double start;
+    int started;
+    int64_t skip_samples;
+    int64_t total_samples;
+    int64_t sample_pos;
+    int32_t points;
+    float *out_min;
+    float *out_max;
+};
+
+static void waveform_close(struct Waveform *self) {
+    avfilter_graph_free(&self->filter_graph);
+    avcodec_close(self->dec_ctx);
+    avcodec_free_context(&self->dec_ctx);
+    avformat_close_input(&self->in_ctx);
+}
+
+static int waveform_open(struct Waveform *self, const struct WaveformOptions *options) {
+    int result;
+    char args[512];
+
+    result = avformat_open_input(&self->in_ctx, options->path, NULL, NULL);
+    if (result < 0) {
+        lav_error("avformat_open_input", result);
+        return 0;
+    }
+
+    result = avformat_find_stream_info(self->in_ctx, NULL);
+    if (result < 0) {
+        lav_error("avformat_find_stream_info", result);
+        return 0;
+    }
+
+    if (self->in_ctx->nb_streams <= (uint32_t)options->stream_index) {
+        lav_error("audio stream doesn't exist", 0);
+        return 0;
+    }
+
+    self->in_stream = self->in_ctx->streams[options->stream_index];
+
+    self->decoder = avcodec_find_decoder(self->in_stream->codecpar->codec_id);
+    if (!self->decoder) {
+        lav_error("avcodec_find_decoder", 0);
+        return 0;
+    }
+
+    self->dec_ctx = avcodec_alloc_context3(self->decoder);
+
+    if (avcodec_parameters_to_context(self->dec_ctx, self->in_stream->codecpar)) {
+        lav_error("avcodec_parameters_to_context", 0);
+        return 0;
+    }
+
+    if (avcodec_open2(self->dec_ctx, self->decoder, NULL)) {
+        lav_error("avcodec_open2", 0);
+        return 0;
+    }
+
+    if (!self->dec_ctx->channel_layout) {
+        self->dec_ctx->channel_layout = av_get_default_channel_layout(self->dec_ctx->channels);
+    }
+
+    double start = options->start;
+    double length = options->length;
+
+    if (options->track_index > 0) {
+        // Track is a chapter, start and length are relative to it
+        double chapter_start, chapter_length;
+
+        if (!media_chapter_bounds(
+            self->in_ctx, options->track_index, &chapter_start, &chapter_length)) {
+            lav_error("chapter doesn't exist", 0);
+            return 0;
+        }
+
+        start += chapter_start;
+
+        if (length <= 0 || length > chapter_length - options->start) {
+            length = chapter_length - options->start;
+        }
+    }
+
+    if (start > 0) {
+        int64_t seek_pos = start / av_q2d(self->in_stream->time_base);
+        result = av_seek_frame(self->in_ctx, self->in_stream->index, seek_pos, 0);
+        if (result < 0) {
+            lav_error("av_seek_frame", result);
+            return 0;
+        }
+    }
+
+    self->start = start;
+
+    self->total_samples = length * self->dec_ctx->sample_rate;
+    if (self->total_samples <= 0) {
+        lav_error("invalid waveform length", 0);
+        return 0;
+    }
+
+    const AVFilter *abuffer = avfilter_get_by_name("abuffer");
+    const AVFilter *aformat = avfilter_get_by_name("aformat");
+    const AVFilter *abuffersink = avfilter_get_by_name("abuffersink");
+
+    if (!abuffer || !aformat || !abuffersink) {
+        lav_error("av filters not found", 0);
+        return 0;
+    }
+
+    self->filter_graph = avfilter_graph_alloc();
+
+    snprintf(args, sizeof(args),
+        "time_base=%d/%d:sample_rate=%d:sample_fmt=%s:channel_layout=0x%" PRIx64,
+        self->dec_ctx->time_base.num, self->dec_ctx->time_base.den, self->dec_ctx->sample_rate,
+        av_get_sample_fmt_name(self->dec_ctx->sample_fmt),
+        self->dec_ctx->channel_layout);
+
+    result = avfilter_graph_create_filter(
+        &self->abuffer_ctx, abuffer, "in", args, NULL, self->filter_graph);
+    if (result < 0) {
+        lav_error("avfilter_graph_create_filter", result);
+        return 0;
+    }
+
+    // Mix down to packed mono floats, peaks of individual channels don't matter
+    result = avfilter_graph_create_filter(
+        &self->aformat_ctx, aformat, NULL, "sample_fmts=flt:channel_layouts=mono",
+        NULL, self->filter_graph);
+    if (result < 0) {
+        lav_error("avfilter_graph_create_filter", result);
+        return 0;
+    }
+
+    result = avfilter_graph_create_filter(
+        &self->abuffersink_ctx, abuffersink, "out", NULL, NULL, self->filter_graph);
+    if (result < 0) {
+        lav_error("avfilter_graph_create_filter", result);
+        return 0;
+    }
+
+    result = avfilter_link(self->abuffer_ctx, 0, self->aformat_ctx, 0);
+    if (result < 0) {
+        lav_error("avfilter_link", result);
+        return 0;
+    }
+
+    result = avfilter_link(self->aformat_ctx, 0, self->abuffersink_ctx, 0);
+    if (result < 0) {
+        lav_error("avfilter_link", result);
+        return 0;
+    }
+
+    result = avfilter_graph_config(self->filter_graph, NULL);
+    if (result < 0) {
+        lav_error("avfilter_graph_config", result);
+        return 0;
+    }
+
+    return 1;
+}
+
+static void waveform_feed(struct Waveform *self, const AVFrame *frame) {
+    const float *samples = (const float *)frame->data[0];
+
+    for (int i = 0; i < frame->nb_samples; ++i) {
+        if (self->skip_samples > 0) {
+            --self->skip_samples;
+            continue;
+        }
+
+        if (self->sample_pos >= self->total_samples) {
+            return;
+        }
+
+        int64_t point = self->sample_pos * self->points / self->total_samples;
+        float sample = samples[i];
+
+        if (sample < self->out_min[point]) {
+            self->out_min[point] = sample;
+        }
+        if (sample > self->out_max[point]) {
+            self->out_max[point] = sample;
+        }
+
+        ++self->sample_pos;
+    }
+}
+
+static int waveform_drain(struct Waveform *self, AVFrame *out_frame) {
+    while (1) {
+        int result = av_buffersink_get_frame(self->abuffersink_ctx, out_frame);
+        if (result == AVERROR(EAGAIN) || result == AVERROR_EOF) {
+            return 1;
+        } else if (result < 0) {
+            lav_error("av_buffersink_get_frame", result);
+            return 0;
+        }
+
+        waveform_feed(self, out_frame);
+        av_frame_unref(out_frame);
+    }
+}
+
+static int waveform_decode(struct Waveform *self, AVFrame *in_frame, AVFrame *out_frame) {
+    while (1) {
+        int result = avcodec_receive_frame(self->dec_ctx, in_frame);
+        if (result == AVERROR(EAGAIN)) {
+            return 1;
+        } else if (result == AVERROR_EOF) {
+            result = av_buffersrc_add_frame_flags(self->abuffer_ctx, NULL, 0);
+            if (result < 0) {
+                lav_error("av_buffersrc_add_frame_flags", result);
+                return 0;
+            }
+
+            return waveform_drain(self, out_frame);
+        } else if (result < 0) {
+            lav_error("avcodec_receive_frame", result);
+            return 0;
+        }
+
+        if (!self->started) {
+            // Seeking lands on the frame containing the start or an earlier one,
+            // samples before the start aren't part of the waveform
+            int64_t timestamp = in_frame->best_effort_timestamp;
+
+            if (self->start > 0 && timestamp != AV_NOPTS_VALUE) {
+                double frame_start = timestamp * av_q2d(self->in_stream->time_base);
+
+                if (frame_start < self->start) {
+                    self->skip_samples = (self->start - frame_start) * in_frame->sample_rate;
+                }
+            }
+
+            self->started = 1;
+        }
+
+        result = av_buffersrc_add_frame_flags(self->abuffer_ctx, in_frame, 0);
+        av_frame_unref(in_frame);
+        if (result < 0) {
+            lav_error("av_buffersrc_add_frame_flags", result);
+            return 0;
+        }
+
+        if (!waveform_drain(self, out_frame)) {
+            return 0;
+        }
+    }
+}
+
+int media_waveform_read(
+    const struct WaveformOptions *options,
+    float *out_min,
+    float *out_max
+) {
+    struct Waveform self;
+    memset(&self, 0, sizeof(struct Waveform));
+
+    self.points = options->points;
+    self.out_min = out_min;
+    self.out_max = out_max;
+
+    for (int i = 0; i < options->points; ++i) {
+        out_min[i] = 0;
+        out_max[i] = 0;
+    }
+
+    if (!waveform_open(&self, options)) {
+        waveform_close(&self);
+        return 0;
+    }
+
+    AVPacket packet = { .data = NULL, .size = 0 };
+    av_init_packet(&packet);
+
+    AVFrame *in_frame = av_frame_alloc();
+    AVFrame *out_frame = av_frame_alloc();
+
+    int success = 1;
+
+    while (self.sample_pos < self.total_samples) {
+        int result = av_read_frame(self.in_ctx, &packet);
+        if (result == AVERROR_EOF) {
+            break;
+        } else if (result < 0) {
+            lav_error("av_read_frame", result);
+            success = 0;
+            break;
+        }
+
+        if (packet.stream_index != self.in_stream->index) {
+            av_packet_unref(&packet);
+            continue;
+        }
+
+        result = avcodec_send_packet(self.dec_ctx, &packet);
+        av_packet_unref(&packet);
+        if (result < 0) {
+            lav_error("avcodec_send_packet", result);
+            success = 0;
+            break;
+        }
+
+        if (!waveform_decode(&self, in_frame, out_frame)) {
+            success = 0;
+            break;
+        }
+    }
+
+    if (success && self.sample_pos < self.total_samples) {
+        avcodec_send_packet(self.dec_ctx, NULL);
+        success = waveform_decode(&self, in_frame, out_frame);
+    }
+
+    av_frame_free(&out_frame);
+    av_frame_free(&in_frame);
+    av_packet_unref(&packet);
+
+    waveform_close(&self);
+
+    return success;
+}